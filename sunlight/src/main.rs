@@ -1,34 +1,56 @@
 use sunlight_vm::qemu::vm::*;
+use sunlight_vm::qemu::qmp::qmp;
 
-fn main() {
-	let mut vm = VirtualMachine::new("test")
-		.expect("should be valid VM");
-
-	// build up the VM
-	vm.set_machine_type(MachineType::Q35 { acpi: true, usb: true, hmat: false })
-		.add_device(Cpu {
-			model: String::from("host"),
-			features: vec![],
-			core_count: 2
-		})
-		.add_device(Memory { size: String::from("4G"), prealloc: true })
-		.add_device(GraphicsAdapter::StdVga { ram_size_mb: 8 })
-		.add_device(DiskController::VirtioScsi { id: String::from("scsic") })
-		.add_device(Network::User { id: String::from("usernet") })
-		.add_device(NetworkAdapter::Virtio { id: String::from("net0"), netdev: String::from("usernet"), mac: None })
-		.add_drive(DiskDrive::CdDrive { interface: DiskInterface::Scsi, id: String::from("cd") })
-		.add_drive(DiskDrive::HdDrive { 
-			id: String::from("sdda"), 
-			interface: DiskInterface::Scsi, 
-			image_path: String::from("/home/lily/test.qcow2"), 
-			readonly: false, 
-			format: String::from("qcow2"), 
-			ssd: true, 
-			cache: Some(String::from("writethrough")),
-			aio: Some(String::from("io_uring")) 
-		});
-
-	vm.start();
-
-	//println!("{}", vm.to_command().expect("this should work lol"));
+/// Prints warnings (and worse) from the VM library, which would otherwise go nowhere.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		metadata.level() <= log::Level::Warn
+	}
+
+	fn log(&self, record: &log::Record) {
+		if self.enabled(record.metadata()) {
+			eprintln!("{}: {}", record.level().as_str().to_lowercase(), record.args());
+		}
+	}
+
+	fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+#[tokio::main]
+async fn main() {
+	log::set_logger(&LOGGER).expect("no other logger should be set");
+	log::set_max_level(log::LevelFilter::Warn);
+
+	let path = std::env::args()
+		.nth(1)
+		.unwrap_or(String::from("vm.toml"));
+
+	let mut vm = VirtualMachine::from_config(&path)
+		.expect("should be able to load VM definition");
+
+	for warning in vm.validation_warnings() {
+		eprintln!("warning: {warning}");
+	}
+
+	vm.start()
+		.await
+		.expect("VM should start");
+
+	let status = vm.qmp()
+		.expect("QMP should be connected")
+		.execute(qmp::query_status {})
+		.await
+		.expect("query-status should work");
+
+	println!("VM status: {:?}", status.status);
+
+	let status = vm.wait()
+		.await
+		.expect("should be able to wait on QEMU");
+
+	println!("QEMU exited: {:?}", status);
 }
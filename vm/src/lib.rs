@@ -1,3 +1,3 @@
-//!
+//! Sunlight's VM management library.
 
 pub mod qemu;
@@ -1,3 +1,13 @@
+pub mod binary;
+pub mod config;
+pub mod dbus;
 pub mod enums;
+pub mod ga;
+pub mod image;
+pub mod qmp;
+mod schema;
 pub mod vm;
+
+#[cfg(test)]
+pub(crate) mod mock_qmp;
 //pub mod devices;
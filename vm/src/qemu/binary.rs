@@ -0,0 +1,276 @@
+//! Finding the QEMU binary to run, and what it supports. Probing runs QEMU a few times,
+//! so each binary is only probed once, and the results are shared after that.
+
+use super::enums::*;
+use super::vm::Architecture;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Binaries which have already been probed, by path.
+static PROBED: Mutex<BTreeMap<String, Arc<QemuBinary>>> = Mutex::new(BTreeMap::new());
+
+/// A QEMU version, as reported by `--version`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QemuVersion {
+	pub major: u32,
+	pub minor: u32,
+	pub micro: u32
+}
+
+impl std::fmt::Display for QemuVersion {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+	}
+}
+
+/// A QEMU system emulator binary, and what it was built with.
+pub struct QemuBinary {
+	path: String,
+	version: QemuVersion,
+
+	/// Device names (and aliases) `-device` accepts.
+	devices: HashSet<String>,
+
+	/// Machine type names (and aliases) `-machine` accepts.
+	machines: HashSet<String>
+}
+
+impl QemuBinary {
+	/// Find the system emulator for `arch` on `PATH`, and probe it.
+	pub fn find(arch: &Architecture) -> Result<Arc<QemuBinary>, VMQemuBinaryError> {
+		let name = arch.qemu_binary();
+		let path = std::env::var_os("PATH")
+			.iter()
+			.flat_map(std::env::split_paths)
+			.map(|dir| dir.join(name))
+			.find(|path| path.is_file())
+			.ok_or_else(|| VMQemuBinaryError::NotFound(String::from(name)))?;
+
+		QemuBinary::at(&path.to_string_lossy())
+	}
+
+	/// Probe the QEMU binary at `path`.
+	pub fn at(path: &str) -> Result<Arc<QemuBinary>, VMQemuBinaryError> {
+		if let Some(binary) = PROBED.lock().unwrap().get(path) {
+			return Ok(binary.clone());
+		}
+
+		let version_output = run(path, &["--version"])?;
+		let version = parse_version(&version_output)
+			.ok_or_else(|| VMQemuBinaryError::UnknownVersion(version_output.lines().next().unwrap_or_default().to_string()))?;
+
+		let binary = Arc::new(QemuBinary {
+			path: String::from(path),
+			version,
+			devices: parse_devices(&run(path, &["-device", "help"])?),
+			machines: parse_machines(&run(path, &["-machine", "help"])?)
+		});
+
+		// If another thread probed it meanwhile, either result is as good as the other
+		PROBED.lock().unwrap().insert(String::from(path), binary.clone());
+		Ok(binary)
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	pub fn version(&self) -> QemuVersion {
+		self.version
+	}
+
+	/// Whether `-device {name}` exists in this QEMU.
+	pub fn has_device(&self, name: &str) -> bool {
+		self.devices.contains(name)
+	}
+
+	/// Whether `-machine {name}` exists in this QEMU.
+	pub fn has_machine(&self, name: &str) -> bool {
+		self.machines.contains(name)
+	}
+
+	/// Check that every device and machine type on a command line (from
+	/// [crate::qemu::vm::VirtualMachine::to_arguments_annotated]) exists in this QEMU.
+	pub(crate) fn check_arguments(&self, options: &[(String, Vec<String>)]) -> Result<(), VMQemuProcessStartError> {
+		for (label, args) in options {
+			for pair in args.windows(2) {
+				// The driver (or machine type) always comes first
+				let name = pair[1].split(',').next().unwrap_or_default();
+
+				match pair[0].as_str() {
+					"-device" if !self.has_device(name) => return Err(VMQemuProcessStartError::UnsupportedDevice {
+						option: label.clone(),
+						device: String::from(name)
+					}),
+					// -machine can also just set properties (e.g: memory-backend=), without a type
+					"-machine" if !name.contains('=') && !self.has_machine(name) => return Err(VMQemuProcessStartError::UnsupportedMachine(String::from(name))),
+					_ => {}
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Run QEMU with `args`, which just prints something and exits, returning what it printed.
+fn run(path: &str, args: &[&str]) -> Result<String, VMQemuBinaryError> {
+	let output = std::process::Command::new(path)
+		.args(args)
+		.stdin(std::process::Stdio::null())
+		.output()?;
+
+	if !output.status.success() {
+		return Err(VMQemuBinaryError::ProbeFailed {
+			path: String::from(path),
+			args: args.join(" "),
+			status: output.status
+		});
+	}
+
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)`
+fn parse_version(output: &str) -> Option<QemuVersion> {
+	let version = output.lines()
+		.next()?
+		.split_once("version ")?
+		.1
+		.split_whitespace()
+		.next()?;
+
+	let mut parts = version.split('.').map(|part| {
+		part.chars()
+			.take_while(|c| c.is_ascii_digit())
+			.collect::<String>()
+			.parse::<u32>()
+			.ok()
+	});
+
+	Some(QemuVersion {
+		major: parts.next()??,
+		minor: parts.next()??,
+		micro: parts.next().flatten().unwrap_or(0)
+	})
+}
+
+/// `name "virtio-net-pci", bus PCI, alias "virtio-net", desc "..."`, under a header for each category.
+fn parse_devices(output: &str) -> HashSet<String> {
+	let quoted = |line: &str, key: &str| {
+		let (_, rest) = line.split_once(format!("{key} \"").as_str())?;
+		rest.split_once('"').map(|(value, _)| String::from(value))
+	};
+
+	output.lines()
+		.filter(|line| line.starts_with("name \""))
+		.flat_map(|line| [quoted(line, "name"), quoted(line, "alias")])
+		.flatten()
+		.collect()
+}
+
+/// `q35                  Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-8.2)`, after a `Supported machines are:` header.
+fn parse_machines(output: &str) -> HashSet<String> {
+	output.lines()
+		.skip_while(|line| !line.starts_with("Supported machines"))
+		.skip(1)
+		.filter_map(|line| line.split_whitespace().next())
+		.map(String::from)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DEVICE_HELP: &str = "\
+Controller/Bridge/Hub devices:
+name \"pcie-root-port\", bus PCI
+name \"usb-hub\", bus usb-bus
+
+Network devices:
+name \"virtio-net-pci\", bus PCI, alias \"virtio-net\"
+name \"e1000\", bus PCI, alias \"e1000-82540em\", desc \"Intel Gigabit Ethernet\"
+";
+
+	const MACHINE_HELP: &str = "\
+Supported machines are:
+pc                   Standard PC (i440FX + PIIX, 1996) (alias of pc-i440fx-8.2)
+pc-i440fx-8.2        Standard PC (i440FX + PIIX, 1996) (default)
+q35                  Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-8.2)
+none                 empty machine
+";
+
+	fn binary() -> QemuBinary {
+		QemuBinary {
+			path: String::from("/usr/bin/qemu-system-x86_64"),
+			version: QemuVersion { major: 8, minor: 2, micro: 2 },
+			devices: parse_devices(DEVICE_HELP),
+			machines: parse_machines(MACHINE_HELP)
+		}
+	}
+
+	fn args(options: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+		options.iter()
+			.map(|(label, args)| (String::from(*label), args.iter().map(|arg| String::from(*arg)).collect()))
+			.collect()
+	}
+
+	#[test]
+	fn parses_versions() {
+		assert_eq!(parse_version("QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\nCopyright (c) 2003-2023"), Some(QemuVersion { major: 8, minor: 2, micro: 2 }));
+		assert_eq!(parse_version("QEMU emulator version 9.0.50 (v9.0.0-1234-gdeadbeef)"), Some(QemuVersion { major: 9, minor: 0, micro: 50 }));
+		assert_eq!(parse_version("QEMU emulator version 7.2"), Some(QemuVersion { major: 7, minor: 2, micro: 0 }));
+		assert_eq!(parse_version("something else entirely"), None);
+	}
+
+	#[test]
+	fn parses_devices_and_aliases() {
+		let devices = parse_devices(DEVICE_HELP);
+
+		for name in ["pcie-root-port", "usb-hub", "virtio-net-pci", "virtio-net", "e1000", "e1000-82540em"] {
+			assert!(devices.contains(name), "{name} is missing");
+		}
+
+		assert!(!devices.contains("Intel Gigabit Ethernet"));
+		assert_eq!(devices.len(), 6);
+	}
+
+	#[test]
+	fn parses_machines() {
+		let machines = parse_machines(MACHINE_HELP);
+
+		for name in ["pc", "pc-i440fx-8.2", "q35", "none"] {
+			assert!(machines.contains(name), "{name} is missing");
+		}
+
+		assert!(!machines.contains("Supported"));
+		assert_eq!(machines.len(), 4);
+	}
+
+	#[test]
+	fn accepts_machine_properties() {
+		let options = args(&[
+			("MachineType::Q35", &["-machine", "q35,acpi=on"]),
+			("Memory", &["-m", "1024M", "-machine", "memory-backend=vm.ram"]),
+			("NetworkAdapter::Virtio", &["-device", "virtio-net-pci,netdev=vm.net0,id=vm.nic0"])
+		]);
+
+		assert!(binary().check_arguments(&options).is_ok());
+	}
+
+	#[test]
+	fn rejects_missing_devices_and_machines() {
+		let devices = args(&[("GraphicsAdapter::Qxl", &["-device", "qxl-vga,id=vm.gpu0"])]);
+		assert!(matches!(
+			binary().check_arguments(&devices),
+			Err(VMQemuProcessStartError::UnsupportedDevice { option, device }) if option == "GraphicsAdapter::Qxl" && device == "qxl-vga"
+		));
+
+		let machines = args(&[("MachineType::S390CcwVirtio", &["-machine", "s390-ccw-virtio"])]);
+		assert!(matches!(
+			binary().check_arguments(&machines),
+			Err(VMQemuProcessStartError::UnsupportedMachine(machine)) if machine == "s390-ccw-virtio"
+		));
+	}
+}
@@ -0,0 +1,251 @@
+//! Declarative VM definitions, which can be loaded from (and saved to) TOML files.
+//! See [crate::qemu::vm::VirtualMachine::from_config].
+
+use super::schema::JsonSchema;
+use super::vm::*;
+use serde::{Deserialize, Serialize};
+
+/// A complete VM definition. This mirrors what can be set through the [VirtualMachine] builder.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VmConfig {
+	pub name: String,
+	pub uuid: Option<String>,
+
+	/// Defaults to x86_64.
+	pub arch: Option<Architecture>,
+
+	/// Defaults to KVM.
+	pub accel: Option<Accelerator>,
+
+	pub machine: MachineType,
+	pub display: Option<DisplayBackend>,
+	pub sandbox: Option<Sandbox>,
+	pub log: Option<QemuLog>,
+	pub audio: Option<Audio>,
+
+	/// Defaults to [AudioBackend::None].
+	pub audio_backend: Option<AudioBackend>,
+
+	pub numa: Option<NumaTopology>,
+
+	#[serde(default)]
+	pub serial_bios: bool,
+
+	/// Sized to fit the VM's virtio-serial ports if unset.
+	pub virtio_serial_max_ports: Option<u32>,
+
+	pub firmware: Option<Firmware>,
+	pub boot: Option<BootConfig>,
+
+	/// Defaults to the architecture's QEMU binary on `PATH`.
+	pub qemu_binary: Option<String>,
+
+	/// Defaults to [default_state_dir].
+	pub state_dir: Option<String>,
+
+	#[serde(default)]
+	pub hotplug_ports: Vec<String>,
+
+	#[serde(default)]
+	pub root_ports: Vec<String>,
+
+	#[serde(default)]
+	pub throttle_groups: Vec<ThrottleGroup>,
+
+	#[serde(default)]
+	pub secrets: Vec<Secret>,
+
+	#[serde(default)]
+	pub devices: Vec<DeviceConfig>,
+
+	#[serde(default)]
+	pub drives: Vec<DeviceConfig>
+}
+
+/// Any device which can be put in a [VmConfig]. In TOML, the `device` key says which one
+/// it is, and devices with multiple variants (e.g: [GraphicsAdapter]) are picked with `type`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "device")]
+pub enum DeviceConfig {
+	Cpu(Cpu),
+	Memory(Memory),
+	DiskController(DiskController),
+	UsbController(UsbController),
+	UsbDevice(UsbDevice),
+	InputDevice(InputDevice),
+	DiskDrive(DiskDrive),
+	NvmeSubsystem(NvmeSubsystem),
+	GraphicsAdapter(GraphicsAdapter),
+	Network(Network),
+	NetworkAdapter(NetworkAdapter),
+	Tpm(Tpm),
+	VirtioInputHost(VirtioInputHost),
+	#[cfg(feature = "testing")]
+	EduDevice(EduDevice),
+	VirtioCrypto(VirtioCrypto),
+	Vsock(Vsock),
+	Rng(Rng),
+	Balloon(Balloon),
+	NinePShare(NinePShare),
+	SmartCard(SmartCard),
+	IsaDebugCon(IsaDebugCon),
+	Watchdog(Watchdog),
+	Pvpanic(Pvpanic),
+	PciPassthrough(PciPassthrough),
+	GuestAgent(GuestAgent),
+	SerialConsole(SerialConsole),
+	VirtioSerialChannel(VirtioSerialChannel)
+}
+
+impl VmConfig {
+	/// A JSON Schema describing VM definitions (including every device), with descriptions taken from
+	/// the documentation. Frontends can build forms from it, or check a definition before loading it.
+	pub fn json_schema() -> serde_json::Value {
+		let mut schema = <VmConfig as JsonSchema>::schema();
+		schema["$schema"] = serde_json::Value::from("https://json-schema.org/draft/2020-12/schema");
+		schema["title"] = serde_json::Value::from("VmConfig");
+		schema
+	}
+}
+
+impl DeviceConfig {
+	/// Turn this into a device which can be added to a [VirtualMachine].
+	pub fn into_option<'a>(self) -> Box<dyn QemuOption + 'a> {
+		match self {
+			Self::Cpu(dev) => Box::new(dev),
+			Self::Memory(dev) => Box::new(dev),
+			Self::DiskController(dev) => Box::new(dev),
+			Self::UsbController(dev) => Box::new(dev),
+			Self::UsbDevice(dev) => Box::new(dev),
+			Self::InputDevice(dev) => Box::new(dev),
+			Self::DiskDrive(dev) => Box::new(dev),
+			Self::NvmeSubsystem(dev) => Box::new(dev),
+			Self::GraphicsAdapter(dev) => Box::new(dev),
+			Self::Network(dev) => Box::new(dev),
+			Self::NetworkAdapter(dev) => Box::new(dev),
+			Self::Tpm(dev) => Box::new(dev),
+			Self::VirtioInputHost(dev) => Box::new(dev),
+			#[cfg(feature = "testing")]
+			Self::EduDevice(dev) => Box::new(dev),
+			Self::VirtioCrypto(dev) => Box::new(dev),
+			Self::Vsock(dev) => Box::new(dev),
+			Self::Rng(dev) => Box::new(dev),
+			Self::Balloon(dev) => Box::new(dev),
+			Self::NinePShare(dev) => Box::new(dev),
+			Self::SmartCard(dev) => Box::new(dev),
+			Self::IsaDebugCon(dev) => Box::new(dev),
+			Self::Watchdog(dev) => Box::new(dev),
+			Self::Pvpanic(dev) => Box::new(dev),
+			Self::PciPassthrough(dev) => Box::new(dev),
+			Self::GuestAgent(dev) => Box::new(dev),
+			Self::SerialConsole(dev) => Box::new(dev),
+			Self::VirtioSerialChannel(dev) => Box::new(dev)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::qemu::enums::VMConfigError;
+
+	const DEFINITION: &str = r#"
+name = "fixture"
+uuid = "00000000-0000-0000-0000-000000000001"
+
+[accel]
+type = "Tcg"
+
+[machine]
+type = "Q35"
+acpi = true
+usb = false
+hmat = false
+legacy_root_port = false
+
+[[devices]]
+device = "Cpu"
+model = "qemu64"
+features = []
+core_count = 2
+
+[[devices]]
+device = "Memory"
+size = "2G"
+prealloc = false
+
+[[devices]]
+device = "GraphicsAdapter"
+type = "StdVga"
+vgamem = "16M"
+
+[[devices]]
+device = "Network"
+type = "User"
+id = "lan"
+host_forwards = []
+"#;
+
+	fn temp_path(name: &str) -> std::path::PathBuf {
+		std::env::temp_dir().join(format!("sunlight-test-{}-{name}", std::process::id()))
+	}
+
+	#[test]
+	fn definitions_are_loaded() {
+		let path = temp_path("fixture.toml");
+		std::fs::write(&path, DEFINITION).unwrap();
+		let vm = VirtualMachine::from_config(path.to_str().unwrap());
+		std::fs::remove_file(&path).unwrap();
+
+		let args = vm.unwrap().to_arguments().unwrap();
+		let values = |flag: &str| args.iter()
+			.zip(args.iter().skip(1))
+			.filter(|(arg, _)| *arg == flag)
+			.map(|(_, value)| value.as_str())
+			.collect::<Vec<&str>>();
+
+		assert_eq!(values("-accel"), ["tcg"]);
+		assert_eq!(values("-machine"), ["q35,acpi=on,usb=off,hmat=off"]);
+		assert_eq!(values("-m"), ["2G"]);
+		assert_eq!(values("-device"), ["VGA,vgamem_mb=16,id=vm.vga"]);
+		assert_eq!(values("-netdev"), ["user,id=vm.lan"]);
+
+		assert!(matches!(VirtualMachine::from_config(temp_path("missing.toml").to_str().unwrap()), Err(VMConfigError::IoError(..))));
+		assert!(matches!(toml::from_str::<VmConfig>("name = \"no-machine\""), Err(..)));
+	}
+
+	#[test]
+	fn definitions_round_trip() {
+		let mut vm = VirtualMachine::modern_linux_defaults("round-trip").unwrap();
+		vm.set_uuid("00000000-0000-0000-0000-000000000002")
+			.set_display_backend(DisplayBackend::None)
+			.add_throttle_group(ThrottleGroup { id: String::from("disks"), limits: IoLimits { iops_total: Some(500), ..IoLimits::default() } })
+			.add_drive(DiskDrive::Nvme { id: String::from("root"), image_path: String::from("/tmp/root image.qcow2"), format: DiskFormat::Qcow2, serial: None, namespaces: Vec::new() });
+
+		let path = temp_path("round-trip.toml");
+		std::fs::write(&path, vm.to_config().unwrap()).unwrap();
+		let loaded = VirtualMachine::from_config(path.to_str().unwrap());
+		std::fs::remove_file(&path).unwrap();
+		let loaded = loaded.unwrap();
+
+		assert_eq!(loaded.to_config().unwrap(), vm.to_config().unwrap());
+		assert_eq!(loaded.to_arguments().unwrap(), vm.to_arguments().unwrap());
+	}
+
+	#[test]
+	fn custom_options_cant_be_saved() {
+		struct Opaque;
+		impl QemuOption for Opaque {
+			fn as_options(&self) -> Vec<String> {
+				Vec::new()
+			}
+		}
+
+		let mut vm = VirtualMachine::new("opaque").unwrap();
+		assert!(matches!(vm.to_config(), Err(VMConfigError::NoMachineType)));
+
+		vm.set_machine_type(MachineType::Pc { acpi: true, usb: false })
+			.add_device(Opaque);
+		assert!(matches!(vm.to_config(), Err(VMConfigError::UndescribableDevice(label)) if label == "Opaque"));
+	}
+}
@@ -0,0 +1,231 @@
+//! QEMU's D-Bus display. With [crate::qemu::vm::DisplayBackend::DbusP2p], QEMU exports the guest's
+//! consoles (and its clipboard) over a peer-to-peer D-Bus connection, which Sunlight sets up over
+//! a socket pair once QEMU has started.
+
+use super::enums::*;
+use super::qmp::{qmp, QmpClient};
+use zbus::zvariant::Fd;
+use zbus::{dbus_proxy, Connection, ConnectionBuilder};
+
+/// The name QEMU's end of the socket pair is passed to it under.
+const FD_NAME: &str = "vm.dbus";
+
+/// The VM as a whole, and which consoles it has.
+#[dbus_proxy(
+	interface = "org.qemu.Display1.VM",
+	default_service = "org.qemu",
+	default_path = "/org/qemu/Display1/VM",
+	gen_blocking = false
+)]
+trait Vm {
+	#[dbus_proxy(property)]
+	fn name(&self) -> zbus::Result<String>;
+
+	#[dbus_proxy(property, name = "UUID")]
+	fn uuid(&self) -> zbus::Result<String>;
+
+	/// The indices of the consoles (see [DbusDisplay::console]).
+	#[dbus_proxy(property, name = "ConsoleIDs")]
+	fn console_ids(&self) -> zbus::Result<Vec<u32>>;
+}
+
+/// A guest console (a graphics adapter's head, or a text console).
+#[dbus_proxy(interface = "org.qemu.Display1.Console", default_service = "org.qemu", gen_blocking = false)]
+trait Console {
+	/// Hand QEMU one end of a socket pair, which it connects back over as a D-Bus client.
+	/// The other end has to serve `org.qemu.Display1.Listener` to receive the display's updates.
+	fn register_listener(&self, listener: Fd) -> zbus::Result<()>;
+
+	/// Tell the guest how big the window showing it is, so it can resize its display to fit.
+	#[dbus_proxy(name = "SetUIInfo")]
+	fn set_ui_info(&self, width_mm: u16, height_mm: u16, xoff: i32, yoff: i32, width: u32, height: u32) -> zbus::Result<()>;
+
+	#[dbus_proxy(property)]
+	fn label(&self) -> zbus::Result<String>;
+
+	#[dbus_proxy(property)]
+	fn head(&self) -> zbus::Result<u32>;
+
+	/// `Graphic` or `Text`.
+	#[dbus_proxy(property, name = "Type")]
+	fn console_type(&self) -> zbus::Result<String>;
+
+	#[dbus_proxy(property)]
+	fn width(&self) -> zbus::Result<u32>;
+
+	#[dbus_proxy(property)]
+	fn height(&self) -> zbus::Result<u32>;
+
+	#[dbus_proxy(property)]
+	fn device_address(&self) -> zbus::Result<String>;
+}
+
+/// Keyboard input to a console. Keys are QEMU keycodes (PC scancodes).
+#[dbus_proxy(interface = "org.qemu.Display1.Keyboard", default_service = "org.qemu", gen_blocking = false)]
+trait Keyboard {
+	fn press(&self, keycode: u32) -> zbus::Result<()>;
+
+	fn release(&self, keycode: u32) -> zbus::Result<()>;
+
+	/// The lock keys which are on (scroll lock, num lock and caps lock, as bits 0 to 2).
+	#[dbus_proxy(property)]
+	fn modifiers(&self) -> zbus::Result<u32>;
+}
+
+/// Mouse input to a console.
+#[dbus_proxy(interface = "org.qemu.Display1.Mouse", default_service = "org.qemu", gen_blocking = false)]
+trait Mouse {
+	fn press(&self, button: u32) -> zbus::Result<()>;
+
+	fn release(&self, button: u32) -> zbus::Result<()>;
+
+	/// Only works if [MouseProxy::is_absolute] (e.g: with a USB tablet).
+	fn set_abs_position(&self, x: u32, y: u32) -> zbus::Result<()>;
+
+	fn rel_motion(&self, dx: i32, dy: i32) -> zbus::Result<()>;
+
+	#[dbus_proxy(property)]
+	fn is_absolute(&self) -> zbus::Result<bool>;
+}
+
+/// The guest's clipboard. Selections are 0 for the clipboard, 1 for the primary selection and 2 for the secondary.
+#[dbus_proxy(
+	interface = "org.qemu.Display1.Clipboard",
+	default_service = "org.qemu",
+	default_path = "/org/qemu/Display1/Clipboard",
+	gen_blocking = false
+)]
+trait Clipboard {
+	/// Start sharing the clipboard. QEMU calls back into `org.qemu.Display1.Clipboard`
+	/// on this connection when the guest's clipboard changes.
+	fn register(&self) -> zbus::Result<()>;
+
+	fn unregister(&self) -> zbus::Result<()>;
+
+	/// Tell the guest the host now owns `selection`, with data in any of `mimes`.
+	fn grab(&self, selection: u32, serial: u32, mimes: &[&str]) -> zbus::Result<()>;
+
+	fn release(&self, selection: u32) -> zbus::Result<()>;
+
+	/// Ask the guest for the contents of `selection`, as the first of `mimes` it has. Returns the MIME type and the data.
+	fn request(&self, selection: u32, mimes: &[&str]) -> zbus::Result<(String, Vec<u8>)>;
+}
+
+/// A peer-to-peer D-Bus connection to a VM's display.
+pub struct DbusDisplay {
+	connection: Connection
+}
+
+impl DbusDisplay {
+	/// Set up the connection to a freshly started QEMU with a `-display dbus,p2p=on`.
+	pub(crate) async fn connect(qmp: &QmpClient) -> Result<DbusDisplay, VMDbusConnectionError> {
+		let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+
+		qmp.send_fd(FD_NAME, theirs.into()).await?;
+		qmp.execute(qmp::add_client {
+			protocol: String::from("@dbus-display"),
+			fdname: String::from(FD_NAME),
+			skipauth: None,
+			tls: None
+		}).await?;
+
+		ours.set_nonblocking(true)?;
+
+		let connection = ConnectionBuilder::unix_stream(tokio::net::UnixStream::from_std(ours)?)
+			.p2p()
+			.build()
+			.await?;
+
+		Ok(DbusDisplay {
+			connection
+		})
+	}
+
+	/// The underlying connection, e.g: for serving the clipboard interface QEMU calls back into.
+	pub fn connection(&self) -> &Connection {
+		&self.connection
+	}
+
+	pub async fn vm(&self) -> zbus::Result<VmProxy<'static>> {
+		VmProxy::new(&self.connection).await
+	}
+
+	/// The console with the given index (see [VmProxy::console_ids]).
+	pub async fn console(&self, index: u32) -> zbus::Result<ConsoleProxy<'static>> {
+		ConsoleProxy::builder(&self.connection)
+			.path(console_path(index))?
+			.build()
+			.await
+	}
+
+	pub async fn keyboard(&self, index: u32) -> zbus::Result<KeyboardProxy<'static>> {
+		KeyboardProxy::builder(&self.connection)
+			.path(console_path(index))?
+			.build()
+			.await
+	}
+
+	pub async fn mouse(&self, index: u32) -> zbus::Result<MouseProxy<'static>> {
+		MouseProxy::builder(&self.connection)
+			.path(console_path(index))?
+			.build()
+			.await
+	}
+
+	pub async fn clipboard(&self) -> zbus::Result<ClipboardProxy<'static>> {
+		ClipboardProxy::new(&self.connection).await
+	}
+}
+
+/// Consoles (and their keyboard and mouse) all live on the same object.
+fn console_path(index: u32) -> String {
+	format!("/org/qemu/Display1/Console_{index}")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::qemu::mock_qmp::MockQmp;
+	use crate::qemu::vm::{DisplayBackend, QemuOption};
+	use serde_json::json;
+	use std::time::Duration;
+
+	#[test]
+	fn consoles_are_found_by_index() {
+		assert_eq!(console_path(0), "/org/qemu/Display1/Console_0");
+		assert_eq!(console_path(12), "/org/qemu/Display1/Console_12");
+
+		assert_eq!(DisplayBackend::DbusP2p { gl: false }.as_options(), ["-display", "dbus,p2p=on,gl=off"]);
+		assert_eq!(DisplayBackend::DbusP2p { gl: true }.as_options(), ["-display", "dbus,p2p=on,gl=on"]);
+	}
+
+	#[tokio::test]
+	async fn qemu_refusing_the_connection_is_reported() {
+		let server = MockQmp::start("dbus-refused", |command, _| match command {
+			"add_client" => Err(String::from("QEMU wasn't started with -display dbus,p2p=on")),
+			_ => Ok(json!({}))
+		});
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+
+		let result = DbusDisplay::connect(&qmp).await;
+		assert!(matches!(result, Err(VMDbusConnectionError::QmpCommandFailed(VMQmpCommandError::QemuError(..)))));
+
+		// QEMU's end goes along with the getfd, and add_client refers to it by name
+		let received = server.received();
+		assert_eq!(server.commands(), ["getfd", "add_client"]);
+		assert!(received[0].fd);
+		assert_eq!(received[0].arguments, json!({ "fdname": FD_NAME }));
+		assert_eq!(received[1].arguments["protocol"], "@dbus-display");
+		assert_eq!(received[1].arguments["fdname"], FD_NAME);
+	}
+
+	#[tokio::test]
+	async fn nobody_on_the_other_end_fails_the_connection() {
+		// Takes the connection, then closes QEMU's end of the socket without saying anything
+		let server = MockQmp::start("dbus-closed", |_, _| Ok(json!({})));
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+
+		let result = tokio::time::timeout(Duration::from_secs(5), DbusDisplay::connect(&qmp)).await;
+		assert!(matches!(result, Ok(Err(VMDbusConnectionError::ZbusError(..) | VMDbusConnectionError::IoError(..)))));
+	}
+}
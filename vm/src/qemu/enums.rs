@@ -5,6 +5,58 @@ pub enum VMQemuProcessStartError {
 	#[error("no QEMU machine type specified")]
 	NoMachineType,
 
+	#[error("KVM was requested, but /dev/kvm is missing or inaccessible (is the kvm module loaded, and is the user in the kvm group?)")]
+	KvmUnavailable,
+
+	/// The accelerator (e.g: HVF) only exists on another host OS.
+	#[error("{0} isn't available on this host")]
+	AcceleratorUnavailable(String),
+
+	#[error("a PCI device was added to a VM whose architecture has no PCI bus")]
+	PciDeviceWithoutPci,
+
+	#[error("PCIe root ports (including reserved hotplug ports) are only supported on the Q35 machine type")]
+	RootPortsRequireQ35,
+
+	#[error("a device asked to be placed on root port \"{0}\", which doesn't exist")]
+	UnknownRootPort(String),
+
+	#[error("more than one device asked to be placed on root port \"{0}\"")]
+	RootPortInUse(String),
+
+	#[error("there are too many PCIe root ports")]
+	TooManyRootPorts,
+
+	#[error("swtpm exited ({0}) before creating its control socket")]
+	SwtpmExited(std::process::ExitStatus),
+
+	#[error("timed out waiting for swtpm to create its control socket")]
+	SwtpmTimeout,
+
+	#[error("couldn't find out what the QEMU binary supports")]
+	QemuBinaryFailure(#[from] VMQemuBinaryError),
+
+	#[error("failure creating an overlay image")]
+	ImageFailure(#[from] VMImageError),
+
+	/// The installed QEMU was built without a device the VM uses.
+	#[error("{option} needs the {device} device, which this QEMU doesn't have")]
+	UnsupportedDevice {
+		option: String,
+		device: String
+	},
+
+	#[error("this QEMU doesn't have the {0} machine type")]
+	UnsupportedMachine(String),
+
+	/// The sga device sends BIOS output to the serial port, which goes nowhere without a console.
+	#[error("serial BIOS output was enabled, but there's no serial console")]
+	SerialBiosWithoutConsole,
+
+	/// One or more options (devices, drives, or VM settings) failed to validate.
+	#[error("{} option(s) failed validation: {}", .0.len(), .0.iter().map(|err| err.to_string()).collect::<Vec<String>>().join("; "))]
+	ValidationFailed(Vec<OptionValidationError>),
+
 	#[error("error building QEMU command line from devices")]
 	ErrorBuildingCommandLine,
 
@@ -12,8 +64,203 @@ pub enum VMQemuProcessStartError {
 	IoError(#[from] std::io::Error)
 }
 
+/// Why a device (or other option) isn't going to work, from [crate::qemu::vm::QemuOption::validate].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+	#[error("no machine type specified")]
+	MissingMachineType,
+
+	#[error("requires {requires}")]
+	UnsupportedMachineType {
+		requires: &'static str
+	},
+
+	#[error("the machine type doesn't match the VM's architecture")]
+	ArchitectureMismatch,
+
+	#[error("the VM has no UUID")]
+	MissingUuid,
+
+	#[error("VM UUID {vm} doesn't match the device's UUID {device}")]
+	UuidMismatch {
+		vm: String,
+		device: String
+	},
+
+	#[error("{field} can't be empty")]
+	EmptyField {
+		field: &'static str
+	},
+
+	#[error("invalid {field}: {reason}")]
+	InvalidField {
+		field: &'static str,
+		reason: &'static str
+	},
+
+	#[error("{path} doesn't exist")]
+	PathNotFound {
+		path: String
+	},
+
+	#[error("directory {path} isn't writable")]
+	DirectoryNotWritable {
+		path: String
+	},
+
+	#[error("another device already has the id {id}")]
+	DuplicateId {
+		id: String
+	},
+
+	#[error("the virtio-serial controller has room for {max_ports} ports (one of which is reserved), but {ports} were added")]
+	TooManyVirtioSerialPorts {
+		ports: u32,
+		max_ports: u32
+	},
+
+	#[error("virtio-serial max_ports has to be between 2 and {0}")]
+	InvalidVirtioSerialMaxPorts(u32),
+
+	#[error("host port {port}/{proto} is forwarded to the guest more than once")]
+	HostPortClash {
+		proto: &'static str,
+		port: u16
+	},
+
+	#[error("throttle group {group} doesn't exist")]
+	UnknownThrottleGroup {
+		group: String
+	},
+
+	#[error("secret {secret} doesn't exist")]
+	UnknownSecret {
+		secret: String
+	},
+
+	#[error("there's no USB bus (enable USB on the machine type, or add a USB controller)")]
+	NoUsbBus,
+
+	#[error("host USB device {device} isn't plugged in")]
+	UsbDeviceNotFound {
+		device: String
+	},
+
+	#[error("there's no GL-capable graphics adapter")]
+	NoGlAdapter,
+
+	#[error("the display doesn't render with GL (use DisplayBackend::EglHeadless, or another display with gl)")]
+	NoGlDisplay,
+
+	#[error("there's no host display to open a window on (neither DISPLAY nor WAYLAND_DISPLAY is set)")]
+	NoHostDisplay,
+
+	#[error("SPICE isn't enabled (set the display to DisplayBackend::Spice)")]
+	NoSpice,
+
+	#[error("there's no CPU configured")]
+	NoCpu,
+
+	#[error("there's no memory configured")]
+	NoMemory,
+
+	#[error("guest memory isn't shared (give the memory a backend with share enabled)")]
+	MemoryNotShared,
+
+	#[error("some NUMA nodes use a memdev and others the legacy mem= form, but QEMU needs them all to use the same one")]
+	MixedNumaMemory,
+
+	#[error("{bridge} isn't a network bridge")]
+	NoSuchBridge {
+		bridge: String
+	},
+
+	#[error("the sandbox denies spawning processes, but a configured device needs a helper process (e.g: qemu-bridge-helper)")]
+	SandboxForbidsHelper,
+
+	#[error("{path} isn't on a hugetlbfs mount with {size} pages")]
+	NoHugepageMount {
+		path: String,
+		size: String
+	},
+
+	#[error("host PCI device {address} isn't bound to vfio-pci")]
+	NotBoundToVfio {
+		address: String
+	},
+
+	#[error("host PCI device {address} isn't in an IOMMU group (is the IOMMU enabled?)")]
+	NoIommuGroup {
+		address: String
+	},
+
+	#[error("host PCI device {address} shares an IOMMU group with a passed through device, but is still in use by the host")]
+	IommuGroupNotViable {
+		address: String
+	}
+}
+
+/// A [ValidationError], along with the label of the option it came from.
+#[derive(Error, Debug, Clone)]
+#[error("{option}: {error}")]
+pub struct OptionValidationError {
+	pub option: String,
+
+	#[source]
+	pub error: ValidationError
+}
+
+/// Something about a device (or other option) which will work, but probably isn't what was wanted,
+/// from [crate::qemu::vm::QemuOption::warnings].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationWarning {
+	#[error("share-rw is on, so the image will be corrupted unless every guest using it runs a cluster filesystem")]
+	SharedWritableImage,
+
+	#[error("there are {queues} queues, but the VM only has {vcpus} vCPUs to service them")]
+	MoreQueuesThanVcpus { queues: u8, vcpus: u32 }
+}
+
+/// A [ValidationWarning], along with the label of the option it came from.
+#[derive(Error, Debug, Clone)]
+#[error("{option}: {warning}")]
+pub struct OptionValidationWarning {
+	pub option: String,
+
+	#[source]
+	pub warning: ValidationWarning
+}
+
+#[derive(Error, Debug)]
+#[error("unknown QEMU log category \"{0}\"")]
+pub struct UnknownLogCategory(pub String);
+
+#[derive(Error, Debug)]
+#[error("invalid size \"{0}\" (expected e.g: 4G or 512M)")]
+pub struct InvalidByteSize(pub String);
+
+#[derive(Error, Debug)]
+#[error("invalid {kind} \"{value}\"")]
+pub struct InvalidDiskOption {
+	pub kind: &'static str,
+	pub value: String
+}
+
+#[derive(Error, Debug)]
+#[error("invalid MAC address \"{addr}\": {reason}")]
+pub struct InvalidMacAddr {
+	pub addr: String,
+	pub reason: &'static str
+}
+
 #[derive(Error, Debug)]
 pub enum VMQmpConnectionError {
+	#[error("QEMU exited ({0}) before its QMP socket could be connected to")]
+	QemuExited(std::process::ExitStatus),
+
+	#[error("timed out waiting for QEMU to create its QMP socket")]
+	SocketTimeout,
+
 	#[error(transparent)]
 	IoError(#[from] std::io::Error)
 }
@@ -24,8 +271,82 @@ pub enum VMQmpHandshakeError {
 	IoError(#[from] std::io::Error)
 }
 
+/// Errors from finding (and probing) a QEMU binary.
+#[derive(Error, Debug)]
+pub enum VMQemuBinaryError {
+	#[error("{0} isn't on PATH (is QEMU installed?)")]
+	NotFound(String),
+
+	#[error("`{path} {args}` failed with {status}")]
+	ProbeFailed {
+		path: String,
+		args: String,
+		status: std::process::ExitStatus
+	},
+
+	#[error("couldn't make sense of QEMU's version: {0}")]
+	UnknownVersion(String),
+
+	#[error(transparent)]
+	IoError(#[from] std::io::Error)
+}
+
+/// Errors from managing disk images with qemu-img.
+#[derive(Error, Debug)]
+pub enum VMImageError {
+	#[error("{0} already exists")]
+	AlreadyExists(String),
+
+	#[error("qemu-img {command} failed: {message}")]
+	Failed {
+		command: String,
+		message: String
+	},
+
+	#[error("couldn't parse qemu-img's output")]
+	InvalidOutput(#[from] serde_json::Error),
+
+	#[error(transparent)]
+	IoError(#[from] std::io::Error)
+}
+
+#[derive(Error, Debug)]
+pub enum VMQmpCommandError {
+	/// QEMU didn't respond to the command in time (it's probably wedged).
+	#[error("QMP command timed out after {0:?}")]
+	QmpTimeout(std::time::Duration),
+
+	/// QEMU ran the command, but it failed.
+	#[error("QMP command failed: {0}")]
+	QemuError(String),
+
+	#[error(transparent)]
+	IoError(#[from] std::io::Error)
+}
+
+#[derive(Error, Debug)]
+pub enum VMGuestAgentError {
+	/// The guest agent didn't respond in time (it's probably not running in the guest).
+	#[error("guest agent command timed out after {0:?}")]
+	Timeout(std::time::Duration),
+
+	/// The guest agent ran the command, but it failed.
+	#[error("guest agent command failed: {0}")]
+	AgentError(String),
+
+	#[error(transparent)]
+	IoError(#[from] std::io::Error)
+}
+
 #[derive(Error, Debug)]
 pub enum VMDbusConnectionError {
+	/// QEMU wouldn't take its end of the connection.
+	#[error("failure handing the D-Bus connection to QEMU")]
+	QmpCommandFailed(#[from] VMQmpCommandError),
+
+	#[error(transparent)]
+	ZbusError(#[from] zbus::Error),
+
 	#[error(transparent)]
 	IoError(#[from] std::io::Error)
 }
@@ -48,9 +369,106 @@ pub enum VMStartError {
 	QmpHandshakeFailure(#[from] VMQmpHandshakeError),
 
 	/// There was an error starting the p2p D-Bus session between QEMU and Sunlight.
-	/// (ditto, but with zbus errors? or would it be more worth it to box a value here?)
 	#[error("failure initiating p2p D-Bus connection")]
-	DbusConnectionFailure(#[from] VMDbusConnectionError)
+	DbusConnectionFailure(#[from] VMDbusConnectionError),
+
+	/// A QMP command needed to finish setting the VM up failed.
+	#[error("failure executing QMP command")]
+	QmpCommandFailure(#[from] VMQmpCommandError),
+
+	/// The VM was already started (or is busy starting or stopping).
+	#[error("the VM can't be started while it's {0:?}")]
+	InvalidState(VMState)
+}
+
+/// Errors from controlling an already started VM (shutting it down, pausing it, ...).
+#[derive(Error, Debug)]
+pub enum VMControlError {
+	#[error("the VM isn't running")]
+	NotRunning,
+
+	/// The VM can't do that from the state it's in (e.g: shutting down a VM which is still starting).
+	#[error("the VM is {0:?}")]
+	InvalidState(VMState),
+
+	#[error("failure executing QMP command")]
+	QmpCommandFailure(#[from] VMQmpCommandError),
+
+	/// The VM's display isn't VNC with a password.
+	#[error("the VM has no password protected VNC server")]
+	NoVncPassword,
+
+	/// The VM doesn't have a [crate::qemu::vm::GuestAgent] device.
+	#[error("the VM has no guest agent channel")]
+	NoGuestAgent,
+
+	#[error("failure talking to the guest agent")]
+	GuestAgentFailure(#[from] VMGuestAgentError),
+
+	/// The VM doesn't have a [crate::qemu::vm::SerialConsole] device.
+	#[error("the VM has no serial console")]
+	NoSerialConsole,
+
+	/// The VM doesn't have a [crate::qemu::vm::Balloon] device.
+	#[error("the VM has no memory balloon")]
+	NoBalloon,
+
+	/// The VM doesn't have a [crate::qemu::vm::Vsock] device.
+	#[error("the VM has no vsock device")]
+	NoVsock,
+
+	#[error("the VM has no virtio-serial channel named \"{0}\"")]
+	NoSuchChannel(String),
+
+	/// The serial console is wired to QEMU's (and so Sunlight's) own standard input and output.
+	#[error("the serial console (or channel) is on standard input and output")]
+	ConsoleOnStdio,
+
+	#[error("block job {job} failed: {reason}")]
+	BlockJobFailed {
+		job: String,
+		reason: String
+	},
+
+	#[error("memory dump failed: {0}")]
+	DumpFailed(String),
+
+	#[error("failure creating an overlay image")]
+	ImageFailure(#[from] VMImageError),
+
+	#[error("device failed validation")]
+	InvalidDevice(#[from] OptionValidationError),
+
+	#[error("{0} can't be hotplugged")]
+	NotHotpluggable(String),
+
+	/// On Q35, PCI devices can only be hotplugged into ports reserved with
+	/// [crate::qemu::vm::VirtualMachine::reserve_hotplug_port].
+	#[error("no free hotplug port")]
+	NoFreeHotplugPort,
+
+	#[error("{0} isn't a reserved hotplug port")]
+	NotAHotplugPort(String),
+
+	#[error("hotplug port {0} is already in use")]
+	HotplugPortInUse(String),
+
+	#[error("device {0} wasn't hotplugged")]
+	NotHotplugged(String),
+
+	/// The guest didn't let go of the device. It may still do so later.
+	#[error("timed out waiting for the guest to release device {0}")]
+	UnplugTimeout(String),
+
+	#[error("monitor command failed: {0}")]
+	MonitorCommandFailed(String),
+
+	/// The VM was shut down for [crate::qemu::vm::VirtualMachine::restart], but didn't start again.
+	#[error("the VM stopped, but couldn't be started again")]
+	RestartFailed(#[source] VMStartError),
+
+	#[error(transparent)]
+	IoError(#[from] std::io::Error)
 }
 
 #[derive(Error, Debug)]
@@ -59,10 +477,51 @@ pub enum VMCreateError {
 	#[error("invalid name characters pressent")]
 	InvalidName,
 
+	#[error("a device in the source VM can't be cloned")]
+	UncloneableDevice,
+
+	/// A vsock's context id has to be unique on the host, so a clone can't share it.
+	#[error("vsock {0}'s context id can't be shared with a clone")]
+	VsockCid(String),
+
+	/// Encrypted images can't have a backing image, so a linked clone can't have an overlay on one.
+	#[error("drive {0} is encrypted, so it can't be shared with a linked clone")]
+	EncryptedDrive(String),
+
+	/// Creating a linked clone's disk overlays failed.
+	#[error("failure creating an overlay image")]
+	ImageFailure(#[from] VMImageError),
+
+	#[error(transparent)]
+	IoError(#[from] std::io::Error)
+
+}
+
+/// Errors from loading or saving a VM definition.
+#[derive(Error, Debug)]
+pub enum VMConfigError {
+	#[error("failure parsing VM definition")]
+	ParseError(#[from] toml::de::Error),
+
+	#[error("failure writing VM definition")]
+	SerializeError(#[from] toml::ser::Error),
+
+	#[error("invalid VM definition")]
+	CreateError(#[from] VMCreateError),
+
+	/// The VM has a device which can't be described in a definition (e.g: a custom [crate::qemu::vm::QemuOption]).
+	#[error("device {0} can't be saved in a VM definition")]
+	UndescribableDevice(String),
+
+	#[error("no QEMU machine type specified")]
+	NoMachineType,
+
+	#[error(transparent)]
+	IoError(#[from] std::io::Error)
 }
 
 /// Current VM state.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VMState {
 	/// The VM is not running.
 	Stopped,
@@ -74,6 +533,31 @@ pub enum VMState {
 	Started,
 
 	/// The VM is atttempting to stop.
-	Stopping
+	Stopping,
+
+	/// The guest panicked (see [crate::qemu::vm::Pvpanic]). QEMU is still running, with the vCPUs paused.
+	Crashed
+}
+
+/// Why a VM's QEMU process exited, from [crate::qemu::vm::VirtualMachine::exit_reason].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+	/// The guest powered itself off.
+	GuestPoweroff,
+
+	/// The guest reset itself, and QEMU was set to exit instead of rebooting it (e.g: to break a reset loop).
+	GuestReset,
+
+	/// Something on the host stopped it, e.g: [crate::qemu::vm::VirtualMachine::shutdown], a signal, or closing the display.
+	Host,
+
+	/// The guest's watchdog fired, and was set to stop the VM.
+	Watchdog,
+
+	/// The guest panicked.
+	Panicked,
+
+	/// QEMU exited without saying why (e.g: it crashed, or was killed), with this status.
+	Crashed(std::process::ExitStatus)
 }
 
@@ -0,0 +1,218 @@
+//! QEMU guest agent client. The guest agent runs inside the guest, and talks to
+//! Sunlight over a virtio-serial port (see [crate::qemu::vm::GuestAgent]).
+
+use super::enums::*;
+use super::qmp;
+use qapi::futures::{QapiService, QgaStreamTokio};
+use std::time::Duration;
+use tokio::io::WriteHalf;
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+
+pub use qapi::qga;
+
+/// How long commands get to complete, unless changed with [GuestAgentClient::set_timeout].
+/// If the guest agent isn't running in the guest, this is how long it takes to find out.
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether a command started with [GuestAgentClient::guest_exec] has exited.
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the guest agent socket for a VM should live, next to its QMP socket (under [qmp::runtime_dir]).
+pub fn socket_path(vm_name: &str) -> String {
+	qmp::runtime_dir()
+		.join(format!("sunlight_{vm_name}.qga"))
+		.to_string_lossy()
+		.into_owned()
+}
+
+/// A connection to the guest agent running in a VM.
+pub struct GuestAgentClient {
+	service: QapiService<QgaStreamTokio<WriteHalf<UnixStream>>>,
+
+	/// Reads responses from the guest agent. Commands can't complete without it running.
+	events: JoinHandle<()>,
+
+	timeout: Duration
+}
+
+impl GuestAgentClient {
+	/// Connect to the guest agent socket at `path`, and sync with the guest agent.
+	pub async fn connect(path: &str) -> Result<GuestAgentClient, VMGuestAgentError> {
+		let stream = QgaStreamTokio::open_uds(path).await?;
+		let (service, events) = stream.spawn_tokio();
+
+		let client = GuestAgentClient {
+			service,
+			events,
+			timeout: DEFAULT_COMMAND_TIMEOUT
+		};
+
+		client.sync().await?;
+		Ok(client)
+	}
+
+	/// The guest agent protocol has no handshake, and the channel may still have a response
+	/// to a previous connection's command in it. Syncing with a unique id skips past that.
+	async fn sync(&self) -> Result<(), VMGuestAgentError> {
+		let id = std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.map(|time| time.subsec_nanos() as i32 & i32::MAX)
+			.unwrap_or(1);
+
+		match tokio::time::timeout(self.timeout, self.service.guest_sync(id)).await {
+			Ok(Ok(())) => Ok(()),
+			Ok(Err(qapi::ExecuteError::Qapi(err))) => Err(VMGuestAgentError::AgentError(err.desc)),
+			Ok(Err(qapi::ExecuteError::Io(err))) => Err(VMGuestAgentError::IoError(err)),
+			Err(..) => Err(VMGuestAgentError::Timeout(self.timeout))
+		}
+	}
+
+	/// Set how long commands are given to complete before giving up on them.
+	/// The default is [DEFAULT_COMMAND_TIMEOUT].
+	pub fn set_timeout(&mut self, timeout: Duration) -> &mut GuestAgentClient {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Execute a guest agent command, and return its typed result.
+	///
+	/// Commands and their arguments are the structures in [qga] (e.g: `qga::guest_info {}`).
+	pub async fn execute<C: qga::QgaCommand>(&self, command: C) -> Result<C::Ok, VMGuestAgentError> {
+		match tokio::time::timeout(self.timeout, self.service.execute(command)).await {
+			Ok(Ok(result)) => Ok(result),
+			Ok(Err(qapi::ExecuteError::Qapi(err))) => Err(VMGuestAgentError::AgentError(err.desc)),
+			Ok(Err(qapi::ExecuteError::Io(err))) => Err(VMGuestAgentError::IoError(err)),
+			Err(..) => Err(VMGuestAgentError::Timeout(self.timeout))
+		}
+	}
+
+	/// Check that the guest agent is alive.
+	pub async fn guest_ping(&self) -> Result<(), VMGuestAgentError> {
+		self.execute(qga::guest_ping {}).await?;
+		Ok(())
+	}
+
+	/// Run a program in the guest, and wait for it to exit. If `capture_output` is set,
+	/// its stdout and stderr are returned in the status (up to a limit set by the guest agent).
+	pub async fn guest_exec(&self, path: &str, args: &[&str], capture_output: bool) -> Result<qga::GuestExecStatus, VMGuestAgentError> {
+		let exec = self.execute(qga::guest_exec {
+			path: String::from(path),
+			arg: Some(args.iter().map(|arg| String::from(*arg)).collect()),
+			env: None,
+			input_data: None,
+			capture_output: Some(capture_output)
+		}).await?;
+
+		loop {
+			let status = self.execute(qga::guest_exec_status { pid: exec.pid }).await?;
+			if status.exited {
+				return Ok(status);
+			}
+
+			tokio::time::sleep(EXEC_POLL_INTERVAL).await;
+		}
+	}
+
+	/// Get the guest's network interfaces, and the IP addresses assigned to them.
+	pub async fn guest_get_ip_addresses(&self) -> Result<Vec<qga::GuestNetworkInterface>, VMGuestAgentError> {
+		self.execute(qga::guest_network_get_interfaces {}).await
+	}
+
+	/// Freeze all of the guest's (freezable) filesystems, so a consistent snapshot of its
+	/// disks can be taken. Returns how many filesystems were frozen.
+	///
+	/// The guest can't write to its disks until [GuestAgentClient::fsthaw] is called.
+	pub async fn fsfreeze(&self) -> Result<i64, VMGuestAgentError> {
+		self.execute(qga::guest_fsfreeze_freeze {}).await
+	}
+
+	/// Thaw filesystems frozen with [GuestAgentClient::fsfreeze]. Returns how many filesystems were thawed.
+	pub async fn fsthaw(&self) -> Result<i64, VMGuestAgentError> {
+		self.execute(qga::guest_fsfreeze_thaw {}).await
+	}
+}
+
+impl Drop for GuestAgentClient {
+	fn drop(&mut self) {
+		self.events.abort();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::{json, Value};
+	use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+	use tokio::net::UnixListener;
+
+	/// A stand-in guest agent. It syncs, answers a few commands, and ignores `guest-info`.
+	fn fake_agent(name: &str) -> (String, JoinHandle<()>) {
+		let path = std::env::temp_dir()
+			.join(format!("sunlight-test-{}-{name}.qga", std::process::id()))
+			.to_string_lossy()
+			.into_owned();
+		let _ = std::fs::remove_file(&path);
+		let listener = UnixListener::bind(&path).unwrap();
+
+		let server = tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let (read, mut write) = stream.into_split();
+			let mut lines = BufReader::new(read).lines();
+
+			while let Ok(Some(line)) = lines.next_line().await {
+				let command: Value = serde_json::from_str(&line).unwrap();
+				let reply = match command["execute"].as_str().unwrap() {
+					"guest-sync" => json!({ "return": command["arguments"]["id"] }),
+					"guest-ping" => json!({ "return": {} }),
+					"guest-exec" => json!({ "return": { "pid": 42 } }),
+					"guest-exec-status" => json!({ "return": { "exited": true, "exitcode": 0, "out-data": "aGk=" } }),
+					"guest-fsfreeze-freeze" => json!({ "return": 2 }),
+					"guest-info" => continue,
+					_ => json!({ "error": { "class": "GenericError", "desc": "Command not supported" } })
+				};
+
+				write.write_all(format!("{reply}\n").as_bytes()).await.unwrap();
+			}
+		});
+
+		(path, server)
+	}
+
+	#[test]
+	fn socket_is_next_to_qmp() {
+		assert!(socket_path("test").ends_with("sunlight_test.qga"));
+	}
+
+	#[tokio::test]
+	async fn commands_are_answered_by_the_agent() {
+		let (path, server) = fake_agent("commands");
+		let client = GuestAgentClient::connect(&path).await.unwrap();
+
+		client.guest_ping().await.unwrap();
+		assert_eq!(client.fsfreeze().await.unwrap(), 2);
+
+		let status = client.guest_exec("/bin/echo", &["hi"], true).await.unwrap();
+		assert_eq!(status.exitcode, Some(0));
+		assert!(status.out_data.is_some());
+
+		assert!(matches!(client.fsthaw().await, Err(VMGuestAgentError::AgentError(desc)) if desc == "Command not supported"));
+
+		drop(client);
+		server.abort();
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn unanswered_commands_time_out() {
+		let (path, server) = fake_agent("wedged");
+		let mut client = GuestAgentClient::connect(&path).await.unwrap();
+		client.set_timeout(Duration::from_millis(50));
+
+		assert!(matches!(client.execute(qga::guest_info {}).await, Err(VMGuestAgentError::Timeout(timeout)) if timeout == Duration::from_millis(50)));
+
+		drop(client);
+		server.abort();
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,256 @@
+//! Disk images, managed with qemu-img. This covers creating the images drives (e.g: [crate::qemu::vm::DiskDrive::HdDrive])
+//! refer to, and looking after them afterwards, without having to do it out of band.
+
+use super::enums::*;
+use super::vm::{ByteSize, DiskFormat};
+use serde::Deserialize;
+use tokio::process::Command;
+
+const QEMU_IMG: &str = "qemu-img";
+
+/// What `qemu-img info` says about an image.
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+	pub filename: String,
+
+	/// The format name (e.g: `qcow2`).
+	pub format: String,
+
+	/// The size of the disk the guest sees.
+	pub virtual_size: ByteSize,
+
+	/// How much space the image takes up on the host, if qemu-img could tell.
+	pub actual_size: Option<ByteSize>,
+
+	/// The image this one is an overlay on top of, if any.
+	pub backing_file: Option<String>,
+	pub backing_format: Option<String>,
+
+	/// Whether the image wasn't closed cleanly (qcow2 with lazy refcounts only).
+	pub dirty: bool
+}
+
+/// `qemu-img info --output=json`, as qemu-img spells it.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RawImageInfo {
+	filename: String,
+	format: String,
+	virtual_size: u64,
+	actual_size: Option<u64>,
+	full_backing_filename: Option<String>,
+	backing_filename: Option<String>,
+	backing_filename_format: Option<String>,
+	#[serde(default)]
+	dirty_flag: bool
+}
+
+impl From<RawImageInfo> for ImageInfo {
+	fn from(raw: RawImageInfo) -> ImageInfo {
+		ImageInfo {
+			filename: raw.filename,
+			format: raw.format,
+			virtual_size: ByteSize::bytes(raw.virtual_size),
+			actual_size: raw.actual_size.map(ByteSize::bytes),
+			// The full name is resolved relative to the overlay, so it can be opened from anywhere
+			backing_file: raw.full_backing_filename.or(raw.backing_filename),
+			backing_format: raw.backing_filename_format,
+			dirty: raw.dirty_flag
+		}
+	}
+}
+
+/// A disk image on the host.
+pub struct Image {
+	path: String
+}
+
+impl Image {
+	/// An image which already exists. Nothing is checked until it's used.
+	pub fn open(path: &str) -> Image {
+		Image {
+			path: String::from(path)
+		}
+	}
+
+	/// Create a new (empty) image of `size`. Refuses to overwrite an existing file.
+	pub async fn create(path: &str, size: ByteSize, format: DiskFormat) -> Result<Image, VMImageError> {
+		check_free(path)?;
+		qemu_img(&["create", "-q", "-f", format.name(), path, size.as_bytes().to_string().as_str()]).await?;
+
+		Ok(Image::open(path))
+	}
+
+	/// Create a qcow2 overlay on top of this image, which starts out reading everything from this one,
+	/// and keeps its own writes. This image shouldn't be written to while the overlay is in use.
+	pub async fn create_overlay(&self, path: &str) -> Result<Image, VMImageError> {
+		check_free(path)?;
+
+		let backing = self.absolute_path()?;
+		let backing_format = self.info().await?.format;
+		qemu_img(&["create", "-q", "-f", "qcow2", "-b", backing.as_str(), "-F", backing_format.as_str(), path]).await?;
+
+		Ok(Image::open(path))
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	/// This image's path, made absolute. qemu-img resolves a relative backing path
+	/// against the overlay's directory, not the current one, so overlays need this.
+	fn absolute_path(&self) -> Result<String, VMImageError> {
+		Ok(std::fs::canonicalize(&self.path)?.to_string_lossy().into_owned())
+	}
+
+	pub async fn info(&self) -> Result<ImageInfo, VMImageError> {
+		let output = qemu_img(&["info", "--output=json", self.path.as_str()]).await?;
+		Ok(serde_json::from_str::<RawImageInfo>(&output)?.into())
+	}
+
+	/// This image and everything it's backed by, starting with this image.
+	pub async fn backing_chain(&self) -> Result<Vec<ImageInfo>, VMImageError> {
+		let output = qemu_img(&["info", "--output=json", "--backing-chain", self.path.as_str()]).await?;
+
+		Ok(serde_json::from_str::<Vec<RawImageInfo>>(&output)?
+			.into_iter()
+			.map(ImageInfo::from)
+			.collect())
+	}
+
+	/// Change the size of the disk the guest sees. Don't do this to an image a running VM is using
+	/// (resize it through QMP instead). qemu-img refuses to shrink images, since data would be lost.
+	pub async fn resize(&self, size: ByteSize) -> Result<(), VMImageError> {
+		qemu_img(&["resize", "-q", self.path.as_str(), size.as_bytes().to_string().as_str()]).await?;
+		Ok(())
+	}
+
+	/// Copy this image (and anything it's backed by, flattened into one image) to `path` in `format`.
+	/// Refuses to overwrite an existing file.
+	pub async fn convert(&self, path: &str, format: DiskFormat) -> Result<Image, VMImageError> {
+		check_free(path)?;
+		qemu_img(&["convert", "-q", "-O", format.name(), self.path.as_str(), path]).await?;
+
+		Ok(Image::open(path))
+	}
+}
+
+/// qemu-img happily overwrites whatever's there, which is never what's wanted.
+fn check_free(path: &str) -> Result<(), VMImageError> {
+	if std::path::Path::new(path).exists() {
+		return Err(VMImageError::AlreadyExists(String::from(path)));
+	}
+
+	Ok(())
+}
+
+/// Run qemu-img with `args`, returning what it printed.
+async fn qemu_img(args: &[&str]) -> Result<String, VMImageError> {
+	let output = Command::new(QEMU_IMG)
+		.args(args)
+		.stdin(std::process::Stdio::null())
+		.output()
+		.await?;
+
+	if !output.status.success() {
+		return Err(VMImageError::Failed {
+			command: args[0].to_string(),
+			message: String::from_utf8_lossy(&output.stderr).trim().to_string()
+		});
+	}
+
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// `qemu-img info --output=json --backing-chain` on a qcow2 overlay of a raw image.
+	const BACKING_CHAIN: &str = r#"[
+    {
+        "children": [],
+        "virtual-size": 10737418240,
+        "filename": "overlay.qcow2",
+        "cluster-size": 65536,
+        "format": "qcow2",
+        "actual-size": 200704,
+        "format-specific": {
+            "type": "qcow2",
+            "data": {
+                "compat": "1.1",
+                "compression-type": "zlib",
+                "lazy-refcounts": true,
+                "refcount-bits": 16,
+                "corrupt": false,
+                "extended-l2": false
+            }
+        },
+        "full-backing-filename": "/var/lib/sunlight/images/base.raw",
+        "backing-filename": "images/base.raw",
+        "backing-filename-format": "raw",
+        "dirty-flag": true
+    },
+    {
+        "children": [],
+        "virtual-size": 10737418240,
+        "filename": "/var/lib/sunlight/images/base.raw",
+        "format": "raw",
+        "actual-size": 4096
+    }
+]"#;
+
+	fn parse(json: &str) -> ImageInfo {
+		serde_json::from_str::<RawImageInfo>(json).unwrap().into()
+	}
+
+	#[test]
+	fn backing_paths_are_absolute() {
+		// Tests run from the crate's directory
+		let path = Image::open("Cargo.toml").absolute_path().unwrap();
+		assert!(path.starts_with('/'));
+		assert!(path.ends_with("/vm/Cargo.toml"), "{path}");
+
+		assert!(matches!(Image::open("missing.qcow2").absolute_path(), Err(VMImageError::IoError(..))));
+	}
+
+	#[test]
+	fn full_backing_paths_are_preferred() {
+		let chain = serde_json::from_str::<Vec<serde_json::Value>>(BACKING_CHAIN).unwrap();
+		let overlay = parse(&chain[0].to_string());
+		assert_eq!(overlay.backing_file.as_deref(), Some("/var/lib/sunlight/images/base.raw"));
+		assert_eq!(overlay.backing_format.as_deref(), Some("raw"));
+
+		// Older qemu-img only gives the name as it was written in the overlay
+		let mut relative = chain[0].clone();
+		relative.as_object_mut().unwrap().remove("full-backing-filename");
+		assert_eq!(parse(&relative.to_string()).backing_file.as_deref(), Some("images/base.raw"));
+	}
+
+	#[test]
+	fn images_are_clean_unless_flagged() {
+		let chain = serde_json::from_str::<Vec<serde_json::Value>>(BACKING_CHAIN).unwrap();
+		assert!(parse(&chain[0].to_string()).dirty);
+
+		// Only qcow2 with lazy refcounts has the flag at all
+		let base = parse(&chain[1].to_string());
+		assert!(!base.dirty);
+		assert_eq!(base.backing_file, None);
+		assert_eq!(base.backing_format, None);
+	}
+
+	#[test]
+	fn backing_chains_are_listed_from_the_top() {
+		let chain = serde_json::from_str::<Vec<RawImageInfo>>(BACKING_CHAIN).unwrap()
+			.into_iter()
+			.map(ImageInfo::from)
+			.collect::<Vec<ImageInfo>>();
+
+		let formats = chain.iter().map(|image| image.format.as_str()).collect::<Vec<&str>>();
+		assert_eq!(formats, ["qcow2", "raw"]);
+		assert_eq!(chain[0].filename, "overlay.qcow2");
+		assert_eq!(chain[0].virtual_size, ByteSize::gib(10));
+		assert_eq!(chain[0].actual_size, Some(ByteSize::bytes(200704)));
+		assert_eq!(chain[0].backing_file.as_deref(), Some(chain[1].filename.as_str()));
+	}
+}
@@ -0,0 +1,182 @@
+//! A stand-in for a VM's QMP server, so code talking to QEMU can be tested without it.
+
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, UnixAddr};
+use serde_json::{json, Value};
+use std::io::IoSliceMut;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWriteExt, Interest};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const GREETING: &str = r#"{"QMP": {"version": {"qemu": {"major": 8, "minor": 2, "micro": 0}, "package": ""}, "capabilities": []}}"#;
+
+/// A command the server received.
+#[derive(Debug, Clone)]
+pub(crate) struct Received {
+	pub execute: String,
+	pub arguments: Value,
+
+	/// Whether a file descriptor was passed along with the command.
+	pub fd: bool
+}
+
+/// Answers a command (by name, with its arguments) with its return value, or an error description.
+pub(crate) type Responder = dyn Fn(&str, &Value) -> Result<Value, String> + Send + Sync;
+
+pub(crate) struct MockQmp {
+	path: String,
+	received: Arc<Mutex<Vec<Received>>>,
+	events: mpsc::UnboundedSender<Value>,
+	server: JoinHandle<()>
+}
+
+impl MockQmp {
+	/// Listen on a fresh socket named after `name`. Commands are answered by `respond`, and
+	/// the server keeps accepting connections (one at a time) until it's dropped.
+	pub fn start(name: &str, respond: impl Fn(&str, &Value) -> Result<Value, String> + Send + Sync + 'static) -> MockQmp {
+		let path = std::env::temp_dir()
+			.join(format!("sunlight-test-{}-{name}.qmp", std::process::id()))
+			.to_string_lossy()
+			.into_owned();
+
+		Self::start_at(&path, respond)
+	}
+
+	/// Like [MockQmp::start], but listening on `path` (e.g: where a VM expects its QMP socket).
+	pub fn start_at(path: &str, respond: impl Fn(&str, &Value) -> Result<Value, String> + Send + Sync + 'static) -> MockQmp {
+		let path = String::from(path);
+		let _ = std::fs::remove_file(&path);
+		let listener = UnixListener::bind(&path).unwrap();
+		let received = Arc::new(Mutex::new(Vec::new()));
+		let (events, mut event_receiver) = mpsc::unbounded_channel();
+		let respond: Arc<Responder> = Arc::new(respond);
+
+		let server = tokio::spawn({
+			let received = received.clone();
+			async move {
+				while let Ok((stream, _)) = listener.accept().await {
+					serve(stream, &received, respond.as_ref(), &mut event_receiver).await;
+				}
+			}
+		});
+
+		MockQmp { path, received, events, server }
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	/// Every command received so far, other than the capabilities handshake.
+	pub fn received(&self) -> Vec<Received> {
+		self.received.lock().unwrap().clone()
+	}
+
+	/// The names of every command received so far, other than the capabilities handshake.
+	pub fn commands(&self) -> Vec<String> {
+		self.received().into_iter().map(|command| command.execute).collect()
+	}
+
+	/// Send an event to whoever's connected.
+	pub fn event(&self, name: &str, data: Value) {
+		let _ = self.events.send(json!({
+			"event": name,
+			"data": data,
+			"timestamp": { "seconds": 0, "microseconds": 0 }
+		}));
+	}
+}
+
+impl Drop for MockQmp {
+	fn drop(&mut self) {
+		self.server.abort();
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+/// Talk to one client, until it disconnects.
+async fn serve(mut stream: UnixStream, received: &Mutex<Vec<Received>>, respond: &Responder, events: &mut mpsc::UnboundedReceiver<Value>) {
+	if stream.write_all(format!("{GREETING}\n").as_bytes()).await.is_err() {
+		return;
+	}
+
+	let mut buffer = Vec::new();
+	loop {
+		let (bytes, fd) = tokio::select! {
+			read = read_with_fd(&stream) => match read {
+				Ok((bytes, fd)) if !bytes.is_empty() => (bytes, fd),
+				_ => return
+			},
+			Some(event) = events.recv() => {
+				if stream.write_all(format!("{event}\n").as_bytes()).await.is_err() {
+					return;
+				}
+				continue;
+			}
+		};
+
+		buffer.extend(bytes);
+		while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+			let line = buffer.drain(..=end).collect::<Vec<u8>>();
+			let command: Value = serde_json::from_slice(&line).unwrap();
+			let execute = command["execute"].as_str().unwrap_or_default().to_owned();
+			let arguments = command.get("arguments").cloned().unwrap_or(json!({}));
+
+			let result = match execute.as_str() {
+				"qmp_capabilities" => Ok(json!({})),
+				_ => {
+					received.lock().unwrap().push(Received { execute: execute.clone(), arguments: arguments.clone(), fd });
+					respond(&execute, &arguments)
+				}
+			};
+
+			let mut reply = match result {
+				Ok(value) => json!({ "return": value }),
+				Err(desc) => json!({ "error": { "class": "GenericError", "desc": desc } })
+			};
+			if let Some(id) = command.get("id") {
+				reply["id"] = id.clone();
+			}
+
+			if stream.write_all(format!("{reply}\n").as_bytes()).await.is_err() {
+				return;
+			}
+		}
+	}
+}
+
+/// Read what's available, and whether a file descriptor came with it (which is closed straight away).
+async fn read_with_fd(stream: &UnixStream) -> std::io::Result<(Vec<u8>, bool)> {
+	loop {
+		stream.readable().await?;
+
+		let read = stream.try_io(Interest::READABLE, || {
+			let mut buf = [0u8; 4096];
+			let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+			let mut iov = [IoSliceMut::new(&mut buf)];
+
+			let msg = recvmsg::<UnixAddr>(stream.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::empty())
+				.map_err(std::io::Error::from)?;
+
+			let mut fd = false;
+			for cmsg in msg.cmsgs() {
+				if let ControlMessageOwned::ScmRights(fds) = cmsg {
+					fd = true;
+					for received in fds {
+						let _ = nix::unistd::close(received);
+					}
+				}
+			}
+
+			let len = msg.bytes;
+			Ok((buf[..len].to_vec(), fd))
+		});
+
+		match read {
+			Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+			read => return read
+		}
+	}
+}
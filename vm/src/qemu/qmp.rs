@@ -0,0 +1,731 @@
+//! QMP (QEMU Machine Protocol) client. Each VM gets its own QMP socket,
+//! which Sunlight connects to once QEMU has been spawned.
+
+use super::enums::*;
+use futures::stream::{BoxStream, StreamExt};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags, UnixAddr};
+use qapi::futures::{QapiService, QmpStreamTokio};
+use std::io::IoSlice;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, Interest};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixStream;
+use tokio::process::Child;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+pub use qapi::qmp;
+
+/// How long commands get to complete, unless changed with [QmpClient::set_timeout].
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long QEMU gets to create its QMP socket after being spawned.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to retry connecting while waiting for the socket.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often to check on a running [BlockJob].
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How many events are kept for subscribers which haven't caught up yet.
+/// Subscribers which fall further behind than this miss the oldest events.
+const EVENT_BUFFER: usize = 64;
+
+/// Where a VM's sockets live: `$XDG_RUNTIME_DIR` if it's set, since
+/// that's private to the user, and the system temporary directory otherwise.
+pub fn runtime_dir() -> std::path::PathBuf {
+	match std::env::var_os("XDG_RUNTIME_DIR") {
+		Some(dir) => std::path::PathBuf::from(dir),
+		None => std::env::temp_dir()
+	}
+}
+
+/// Where the QMP socket for a VM lives, under [runtime_dir].
+pub fn socket_path(vm_name: &str) -> String {
+	runtime_dir()
+		.join(format!("sunlight_{vm_name}.qmp"))
+		.to_string_lossy()
+		.into_owned()
+}
+
+/// An asynchronous event from QEMU. The events Sunlight cares about are broken out,
+/// and everything else is passed along as is.
+#[derive(Debug, Clone)]
+pub enum QmpEvent {
+	/// QEMU is shutting down. `guest` is whether the guest asked for it (e.g: it powered itself off).
+	Shutdown {
+		guest: bool,
+		reason: qmp::ShutdownCause
+	},
+
+	/// The VM was reset. `guest` is whether the guest asked for it (e.g: it rebooted).
+	Reset {
+		guest: bool,
+		reason: qmp::ShutdownCause
+	},
+
+	/// The vCPUs were paused.
+	Stop,
+
+	/// The vCPUs were resumed.
+	Resume,
+
+	/// A block job finished. `error` is set if it failed.
+	BlockJobCompleted {
+		device: String,
+		error: Option<String>
+	},
+
+	/// A device was unplugged, and the guest let go of it. `device` is the device id, if it had one.
+	DeviceDeleted {
+		device: Option<String>,
+		path: String
+	},
+
+	/// The guest's watchdog fired (it's probably hung), and QEMU is about to do `action` about it.
+	Watchdog {
+		action: qmp::WatchdogAction
+	},
+
+	/// The guest panicked, and QEMU did `action` about it.
+	GuestPanicked {
+		action: qmp::GuestPanicAction
+	},
+
+	/// A guest memory dump finished. `error` is set if it failed.
+	DumpCompleted {
+		error: Option<String>
+	},
+
+	/// Any other event.
+	Other(qmp::Event)
+}
+
+impl From<qmp::Event> for QmpEvent {
+	fn from(event: qmp::Event) -> QmpEvent {
+		match event {
+			qmp::Event::SHUTDOWN { data, .. } => QmpEvent::Shutdown { guest: data.guest, reason: data.reason },
+			qmp::Event::RESET { data, .. } => QmpEvent::Reset { guest: data.guest, reason: data.reason },
+			qmp::Event::STOP { .. } => QmpEvent::Stop,
+			qmp::Event::RESUME { .. } => QmpEvent::Resume,
+			qmp::Event::BLOCK_JOB_COMPLETED { data, .. } => QmpEvent::BlockJobCompleted { device: data.device, error: data.error },
+			qmp::Event::DEVICE_DELETED { data, .. } => QmpEvent::DeviceDeleted { device: data.device, path: data.path },
+			qmp::Event::WATCHDOG { data, .. } => QmpEvent::Watchdog { action: data.action },
+			qmp::Event::GUEST_PANICKED { data, .. } => QmpEvent::GuestPanicked { action: data.action },
+			qmp::Event::DUMP_COMPLETED { data, .. } => QmpEvent::DumpCompleted { error: data.error },
+			event => QmpEvent::Other(event)
+		}
+	}
+}
+
+/// Keys of the options Sunlight hotplugs which QMP wants as booleans (`on` and `off` on the command line).
+const BOOL_KEYS: &[&str] = &[
+	// -netdev
+	"vhost",
+
+	// -device
+	"failover", "mq", "multifunction", "share-rw", "write-cache",
+	"csum", "guest_csum", "gso", "guest_tso4", "guest_tso6", "guest_ecn", "guest_ufo",
+	"host_tso4", "host_tso6", "host_ecn", "host_ufo", "mrg_rxbuf"
+];
+
+/// Keys of the options Sunlight hotplugs which QMP wants as numbers.
+const NUMBER_KEYS: &[&str] = &[
+	// -netdev
+	"queues",
+
+	// -device
+	"host_mtu", "logical_block_size", "num-queues", "physical_block_size", "rombar", "rotation_rate", "vectors"
+];
+
+/// Parses the value of a QEMU command line option (`type,key=value,...`) into QMP arguments,
+/// with the leading type stored under `type_key` (e.g: `driver` for `-device`).
+///
+/// QMP wants typed values, which the command line doesn't have, so the keys in [BOOL_KEYS] and
+/// [NUMBER_KEYS] are converted. Everything else (ids, paths, serial numbers, ...) stays a string,
+/// even if it looks like a number.
+pub(crate) fn keyval_to_arguments(type_key: &str, keyval: &str) -> qapi::Dictionary {
+	let mut parts = vec![String::new()];
+	let mut chars = keyval.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			// A doubled comma is an escaped one (see qemu_escape)
+			',' if chars.peek() == Some(&',') => {
+				chars.next();
+				parts.last_mut().unwrap().push(',');
+			},
+			',' => parts.push(String::new()),
+			_ => parts.last_mut().unwrap().push(c)
+		}
+	}
+
+	let mut parts = parts.into_iter();
+	let mut arguments = qapi::Dictionary::new();
+	if let Some(kind) = parts.next() {
+		arguments.insert(String::from(type_key), qapi::Any::from(kind));
+	}
+
+	for part in parts {
+		let (key, value) = part.split_once('=').unwrap_or((part.as_str(), "on"));
+		let value = match value {
+			"on" if BOOL_KEYS.contains(&key) => qapi::Any::from(true),
+			"off" if BOOL_KEYS.contains(&key) => qapi::Any::from(false),
+			_ if NUMBER_KEYS.contains(&key) => match value.parse::<u64>() {
+				Ok(number) => qapi::Any::from(number),
+				Err(..) => qapi::Any::from(value)
+			},
+			_ => qapi::Any::from(value)
+		};
+
+		arguments.insert(String::from(key), value);
+	}
+
+	arguments
+}
+
+/// Builds a `device_add` from the value of a `-device` option.
+pub(crate) fn device_add_from_keyval(keyval: &str) -> qmp::device_add {
+	let mut arguments = keyval_to_arguments("driver", keyval);
+	let mut take = |key: &str| match arguments.remove(key) {
+		Some(qapi::Any::String(value)) => Some(value),
+		_ => None
+	};
+
+	let driver = take("driver").unwrap_or_default();
+	let id = take("id");
+	let bus = take("bus");
+
+	qmp::device_add {
+		driver,
+		id,
+		bus,
+		arguments
+	}
+}
+
+/// A descriptor waiting to go out with the command which takes it (see [QmpClient::send_fd]).
+struct PendingFd {
+	fd: OwnedFd,
+
+	/// The command, exactly as it's written to the socket.
+	command: Vec<u8>
+}
+
+/// The write half of the QMP socket, which can also pass a file descriptor along with a `getfd` command.
+/// QEMU holds on to descriptors it receives until the command they came with takes them.
+struct QmpWriter {
+	write: OwnedWriteHalf,
+	pending_fd: Arc<Mutex<Option<PendingFd>>>
+}
+
+impl AsyncWrite for QmpWriter {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+		let this = self.get_mut();
+		let mut pending_fd = this.pending_fd.lock().unwrap();
+
+		// Only the getfd it was queued for takes the descriptor, so another command being sent first
+		// mustn't carry it off. Each command is written out on its own, starting at the first write
+		let fd = match pending_fd.as_ref() {
+			Some(pending) if buf.starts_with(&pending.command) => pending.fd.as_raw_fd(),
+			_ => {
+				drop(pending_fd);
+				return Pin::new(&mut this.write).poll_write(cx, buf);
+			}
+		};
+
+		let socket: &UnixStream = this.write.as_ref();
+		loop {
+			ready!(socket.poll_write_ready(cx))?;
+
+			let sent = socket.try_io(Interest::WRITABLE, || {
+				sendmsg::<UnixAddr>(socket.as_raw_fd(), &[IoSlice::new(buf)], &[ControlMessage::ScmRights(&[fd])], MsgFlags::MSG_NOSIGNAL, None)
+					.map_err(std::io::Error::from)
+			});
+
+			match sent {
+				Ok(written) => {
+					*pending_fd = None;
+					return Poll::Ready(Ok(written));
+				},
+				Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+				Err(err) => return Poll::Ready(Err(err))
+			}
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+	}
+}
+
+/// A connection to a VM's QMP server, after the capabilities handshake.
+pub struct QmpClient {
+	service: QapiService<QmpStreamTokio<QmpWriter>>,
+
+	/// A descriptor waiting to go out with its `getfd` (see [QmpClient::send_fd]).
+	pending_fd: Arc<Mutex<Option<PendingFd>>>,
+
+	/// Held while sending a descriptor, since only one can be waiting at a time.
+	sending_fd: tokio::sync::Mutex<()>,
+
+	/// Reads responses (and events) from QEMU. Commands can't complete without it running.
+	events: JoinHandle<()>,
+
+	/// Only kept around to subscribe more receivers from; the sender lives in the reader task,
+	/// so subscribers see the channel close once QEMU closes the connection.
+	event_receiver: broadcast::Receiver<QmpEvent>,
+
+	timeout: Duration
+}
+
+impl QmpClient {
+	/// Connect to the QMP socket at `path`, and perform the capabilities handshake.
+	pub async fn connect(path: &str) -> Result<QmpClient, VMStartError> {
+		let (read, write) = UnixStream::connect(path)
+			.await
+			.map_err(VMQmpConnectionError::IoError)?
+			.into_split();
+
+		let pending_fd = Arc::new(Mutex::new(None));
+		let writer = QmpWriter {
+			write,
+			pending_fd: pending_fd.clone()
+		};
+
+		let negotiation = QmpStreamTokio::open_split(read, writer)
+			.await
+			.map_err(VMQmpConnectionError::IoError)?;
+
+		let stream = negotiation.negotiate()
+			.await
+			.map_err(VMQmpHandshakeError::IoError)?;
+
+		let (service, mut stream) = stream.into_parts();
+
+		// Tells the stream to end once the service is dropped (like spawn_tokio does)
+		let _ = stream.release();
+
+		let (sender, event_receiver) = broadcast::channel(EVENT_BUFFER);
+		let events = tokio::spawn(async move {
+			// Reading events is also what completes commands, so this keeps going with no subscribers
+			while let Some(Ok(event)) = stream.next().await {
+				let _ = sender.send(QmpEvent::from(event));
+			}
+		});
+
+		Ok(QmpClient {
+			service,
+			pending_fd,
+			sending_fd: tokio::sync::Mutex::new(()),
+			events,
+			event_receiver,
+			timeout: DEFAULT_COMMAND_TIMEOUT
+		})
+	}
+
+	/// Connect to a freshly spawned QEMU process. QEMU creates the socket some time after
+	/// it starts, so this keeps retrying until it shows up, QEMU exits, or it gives up.
+	pub(crate) async fn connect_spawned(path: &str, process: &mut Child) -> Result<QmpClient, VMStartError> {
+		let deadline = tokio::time::Instant::now() + CONNECT_TIMEOUT;
+
+		loop {
+			if let Some(status) = process.try_wait().map_err(VMQmpConnectionError::IoError)? {
+				return Err(VMQmpConnectionError::QemuExited(status).into());
+			}
+
+			if std::path::Path::new(path).exists() {
+				return Self::connect(path).await;
+			}
+
+			if tokio::time::Instant::now() >= deadline {
+				return Err(VMQmpConnectionError::SocketTimeout.into());
+			}
+
+			tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+		}
+	}
+
+	/// Set how long commands are given to complete before giving up on them
+	/// (e.g: if QEMU is wedged). The default is [DEFAULT_COMMAND_TIMEOUT].
+	pub fn set_timeout(&mut self, timeout: Duration) -> &mut QmpClient {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Execute a QMP command, and return its typed result.
+	///
+	/// Commands and their arguments are the structures in [qmp] (e.g: `qmp::query_status {}`).
+	pub async fn execute<C: qmp::QmpCommand>(&self, command: C) -> Result<C::Ok, VMQmpCommandError> {
+		self.execute_with_timeout(command, self.timeout).await
+	}
+
+	/// Like [QmpClient::execute], but giving the command `timeout` to complete instead
+	/// of the client's timeout (e.g: for a command which is known to take a while).
+	pub async fn execute_with_timeout<C: qmp::QmpCommand>(&self, command: C, timeout: Duration) -> Result<C::Ok, VMQmpCommandError> {
+		match tokio::time::timeout(timeout, self.service.execute(command)).await {
+			Ok(Ok(result)) => Ok(result),
+			Ok(Err(qapi::ExecuteError::Qapi(err))) => Err(VMQmpCommandError::QemuError(err.desc)),
+			Ok(Err(qapi::ExecuteError::Io(err))) => Err(VMQmpCommandError::IoError(err)),
+			Err(..) => Err(VMQmpCommandError::QmpTimeout(timeout))
+		}
+	}
+
+	/// Pass a file descriptor to QEMU, which commands taking an `fdname` (e.g: `add_client`)
+	/// can then refer to as `name`. QEMU keeps its own copy, so `fd` is closed once it's sent.
+	pub async fn send_fd(&self, name: &str, fd: OwnedFd) -> Result<(), VMQmpCommandError> {
+		let _sending = self.sending_fd.lock().await;
+		let getfd = qmp::getfd { fdname: String::from(name) };
+
+		// The same as what the service writes (commands only carry ids when out-of-band execution is on, which it isn't)
+		let mut command = serde_json::to_vec(&qapi::Execute::<_, u32>::new(getfd.clone(), None)).map_err(std::io::Error::from)?;
+		command.push(b'\n');
+		*self.pending_fd.lock().unwrap() = Some(PendingFd { fd, command });
+
+		let result = self.execute(getfd).await;
+
+		// Don't let a failed send hand the descriptor to a later command
+		*self.pending_fd.lock().unwrap() = None;
+		result.map(|_| ())
+	}
+
+	/// Subscribe to QEMU's events. Only events sent after subscribing are seen, and the
+	/// stream ends once QEMU closes the connection (e.g: because it exited).
+	///
+	/// A subscriber which falls too far behind misses the oldest events, rather than holding everyone else up.
+	pub fn events(&self) -> BoxStream<'static, QmpEvent> {
+		futures::stream::unfold(self.event_receiver.resubscribe(), |mut receiver| async move {
+			loop {
+				match receiver.recv().await {
+					Ok(event) => return Some((event, receiver)),
+					Err(broadcast::error::RecvError::Lagged(..)) => continue,
+					Err(broadcast::error::RecvError::Closed) => return None
+				}
+			}
+		}).boxed()
+	}
+}
+
+impl Drop for QmpClient {
+	fn drop(&mut self) {
+		self.events.abort();
+	}
+}
+
+/// A running block job (e.g: from [crate::qemu::vm::VirtualMachine::block_commit]).
+///
+/// Jobs are started with `auto-dismiss` off, so QEMU keeps them around once they finish
+/// until [BlockJob::wait] (or [BlockJob::cancel]) has seen how they went.
+pub struct BlockJob<'a> {
+	qmp: &'a QmpClient,
+	id: String,
+
+	/// Whether the job has to be told to complete once it's ready
+	/// (like an active commit, which otherwise keeps mirroring writes forever).
+	complete_when_ready: bool
+}
+
+impl<'a> BlockJob<'a> {
+	pub(crate) fn new(qmp: &'a QmpClient, id: String, complete_when_ready: bool) -> BlockJob<'a> {
+		BlockJob {
+			qmp,
+			id,
+			complete_when_ready
+		}
+	}
+
+	/// The QEMU job id.
+	pub fn id(&self) -> &str {
+		&self.id
+	}
+
+	/// Wait for the job to finish.
+	pub async fn wait(self) -> Result<(), VMControlError> {
+		let mut completing = false;
+
+		loop {
+			let job = self.status().await?;
+
+			match job.status {
+				qmp::JobStatus::ready if self.complete_when_ready && !completing => {
+					self.qmp.execute(qmp::job_complete { id: self.id.clone() }).await?;
+					completing = true;
+				},
+
+				qmp::JobStatus::concluded => {
+					self.qmp.execute(qmp::job_dismiss { id: self.id.clone() }).await?;
+
+					return match job.error {
+						Some(reason) => Err(VMControlError::BlockJobFailed { job: self.id, reason }),
+						None => Ok(())
+					};
+				},
+
+				_ => {}
+			}
+
+			tokio::time::sleep(JOB_POLL_INTERVAL).await;
+		}
+	}
+
+	/// Cancel the job, and wait for QEMU to finish cancelling it. For an active commit which
+	/// is already ready, this leaves the guest writing to the overlay, like it was before.
+	pub async fn cancel(self) -> Result<(), VMControlError> {
+		self.qmp.execute(qmp::job_cancel { id: self.id.clone() }).await?;
+
+		loop {
+			// A cancelled job concludes with an error, which is the point
+			if let qmp::JobStatus::concluded = self.status().await?.status {
+				self.qmp.execute(qmp::job_dismiss { id: self.id.clone() }).await?;
+				return Ok(());
+			}
+
+			tokio::time::sleep(JOB_POLL_INTERVAL).await;
+		}
+	}
+
+	async fn status(&self) -> Result<qmp::JobInfo, VMControlError> {
+		self.qmp.execute(qmp::query_jobs {})
+			.await?
+			.into_iter()
+			.find(|job| job.id == self.id)
+			.ok_or_else(|| VMControlError::BlockJobFailed { job: self.id.clone(), reason: String::from("the job disappeared") })
+	}
+}
+
+/// How far along a [MemoryDump] is, in bytes of guest memory.
+#[derive(Clone, Copy, Debug)]
+pub struct DumpProgress {
+	pub completed: u64,
+	pub total: u64
+}
+
+/// A guest memory dump QEMU is writing in the background
+/// (from [crate::qemu::vm::VirtualMachine::dump_memory]).
+pub struct MemoryDump<'a> {
+	qmp: &'a QmpClient,
+
+	/// Subscribed to before the dump was started, so the completion event can't be missed.
+	events: BoxStream<'static, QmpEvent>
+}
+
+impl<'a> MemoryDump<'a> {
+	pub(crate) fn new(qmp: &'a QmpClient, events: BoxStream<'static, QmpEvent>) -> MemoryDump<'a> {
+		MemoryDump {
+			qmp,
+			events
+		}
+	}
+
+	/// How much of the guest's memory has been written so far.
+	pub async fn progress(&self) -> Result<DumpProgress, VMControlError> {
+		let result = self.qmp.execute(qmp::query_dump {}).await?;
+
+		Ok(DumpProgress {
+			completed: result.completed.max(0) as u64,
+			total: result.total.max(0) as u64
+		})
+	}
+
+	/// Wait for the dump to finish.
+	pub async fn wait(mut self) -> Result<(), VMControlError> {
+		while let Some(event) = self.events.next().await {
+			if let QmpEvent::DumpCompleted { error } = event {
+				return match error {
+					Some(reason) => Err(VMControlError::DumpFailed(reason)),
+					None => Ok(())
+				};
+			}
+		}
+
+		// QEMU went away before it finished
+		Err(VMControlError::NotRunning)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::qemu::mock_qmp::MockQmp;
+	use serde_json::json;
+
+	#[test]
+	fn keyvals_are_typed_by_key() {
+		let device = keyval_to_arguments("driver", "virtio-blk-pci,id=vm.disk0,drive=vm.disk0.drive,serial=0042,share-rw=on,num-queues=4,logical_block_size=4096,bus=vm.port0,addr=0x0");
+		assert_eq!(serde_json::Value::Object(device), json!({
+			"driver": "virtio-blk-pci",
+			"id": "vm.disk0",
+			"drive": "vm.disk0.drive",
+			"serial": "0042",
+			"share-rw": true,
+			"num-queues": 4,
+			"logical_block_size": 4096,
+			"bus": "vm.port0",
+			"addr": "0x0"
+		}));
+
+		let netdev = keyval_to_arguments("type", "tap,vhost=on,script=no,downscript=no,ifname=tap0,id=vm.lan,queues=2");
+		assert_eq!(netdev["vhost"], json!(true));
+		assert_eq!(netdev["script"], json!("no"));
+		assert_eq!(netdev["queues"], json!(2));
+
+		// Escaped commas stay in the value, and a key on its own is turned on
+		let bridge = keyval_to_arguments("type", "bridge,br=br0,helper=/opt/a,,b,id=vm.1");
+		assert_eq!(bridge["helper"], json!("/opt/a,b"));
+		assert_eq!(bridge["id"], json!("vm.1"));
+		assert_eq!(keyval_to_arguments("driver", "virtio-net-pci,mq")["mq"], json!(true));
+	}
+
+	#[test]
+	fn devices_are_added_from_keyvals() {
+		let device = device_add_from_keyval("virtio-net-pci,id=vm.net0,netdev=vm.netdev0,mac=52:54:00:12:34:56,bus=vm.port0,addr=0x0,failover=on");
+		assert_eq!(device.driver, "virtio-net-pci");
+		assert_eq!(device.id.as_deref(), Some("vm.net0"));
+		assert_eq!(device.bus.as_deref(), Some("vm.port0"));
+		assert_eq!(device.arguments["mac"], json!("52:54:00:12:34:56"));
+		assert_eq!(device.arguments["failover"], json!(true));
+		assert!(!device.arguments.contains_key("driver"));
+	}
+
+	fn null_fd() -> OwnedFd {
+		OwnedFd::from(std::fs::File::open("/dev/null").unwrap())
+	}
+
+	#[tokio::test]
+	async fn descriptors_only_go_with_their_getfd() {
+		let server = MockQmp::start("send-fd", |_, _| Ok(json!({})));
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+
+		qmp.send_fd("vnc", null_fd()).await.unwrap();
+		qmp.execute(qmp::stop {}).await.unwrap();
+
+		// A descriptor queued for one getfd doesn't go out with another
+		let other = qmp::getfd { fdname: String::from("other") };
+		let mut command = serde_json::to_vec(&qapi::Execute::<_, u32>::new(other, None)).unwrap();
+		command.push(b'\n');
+		*qmp.pending_fd.lock().unwrap() = Some(PendingFd { fd: null_fd(), command });
+		qmp.execute(qmp::getfd { fdname: String::from("vnc") }).await.unwrap();
+
+		let received = server.received();
+		assert_eq!(server.commands(), ["getfd", "stop", "getfd"]);
+		assert_eq!(received[0].arguments, json!({ "fdname": "vnc" }));
+		assert_eq!(received.iter().map(|command| command.fd).collect::<Vec<bool>>(), [true, false, false]);
+	}
+
+	#[tokio::test]
+	async fn events_reach_subscribers() {
+		let server = MockQmp::start("events", |_, _| Ok(json!({})));
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+		let mut events = qmp.events();
+
+		server.event("GUEST_PANICKED", json!({ "action": "pause" }));
+		server.event("STOP", json!({}));
+
+		assert!(matches!(events.next().await, Some(QmpEvent::GuestPanicked { action: qmp::GuestPanicAction::pause })));
+		assert!(matches!(events.next().await, Some(QmpEvent::Stop)));
+	}
+
+	#[tokio::test]
+	async fn events_are_decoded() {
+		let server = MockQmp::start("decoded-events", |_, _| Ok(json!({})));
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+		let mut events = qmp.events();
+
+		server.event("SHUTDOWN", json!({ "guest": true, "reason": "guest-shutdown" }));
+		server.event("DEVICE_DELETED", json!({ "device": "vm.disk1", "path": "/machine/peripheral/vm.disk1" }));
+		server.event("BLOCK_JOB_COMPLETED", json!({ "type": "commit", "device": "vm.commit0", "len": 0, "offset": 0, "speed": 0, "error": "No space left on device" }));
+		server.event("POWERDOWN", json!({}));
+
+		assert!(matches!(events.next().await, Some(QmpEvent::Shutdown { guest: true, reason: qmp::ShutdownCause::guest_shutdown })));
+		assert!(matches!(events.next().await, Some(QmpEvent::DeviceDeleted { device: Some(device), path })
+			if device == "vm.disk1" && path == "/machine/peripheral/vm.disk1"));
+		assert!(matches!(events.next().await, Some(QmpEvent::BlockJobCompleted { device, error: Some(error) })
+			if device == "vm.commit0" && error == "No space left on device"));
+		// Anything Sunlight doesn't break out still comes through
+		assert!(matches!(events.next().await, Some(QmpEvent::Other(qmp::Event::POWERDOWN { .. }))));
+	}
+
+	#[tokio::test]
+	async fn every_subscriber_gets_every_event() {
+		let server = MockQmp::start("subscribers", |_, _| Ok(json!({})));
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+		let mut first = qmp.events();
+		let mut second = qmp.events();
+
+		server.event("STOP", json!({}));
+		server.event("RESUME", json!({}));
+
+		for events in [&mut first, &mut second] {
+			assert!(matches!(events.next().await, Some(QmpEvent::Stop)));
+			assert!(matches!(events.next().await, Some(QmpEvent::Resume)));
+		}
+
+		// A late subscriber only sees what comes after it subscribed
+		let mut late = qmp.events();
+		server.event("STOP", json!({}));
+		assert!(matches!(late.next().await, Some(QmpEvent::Stop)));
+		assert!(matches!(first.next().await, Some(QmpEvent::Stop)));
+	}
+
+	#[tokio::test]
+	async fn events_end_when_qemu_goes_away() {
+		let server = MockQmp::start("events-closed", |_, _| Ok(json!({})));
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+		let mut events = qmp.events();
+
+		server.event("STOP", json!({}));
+		assert!(matches!(events.next().await, Some(QmpEvent::Stop)));
+
+		drop(server);
+		let end = tokio::time::timeout(Duration::from_secs(5), events.next()).await;
+		assert!(matches!(end, Ok(None)));
+	}
+
+	#[tokio::test]
+	async fn failed_commands_report_qemus_error() {
+		let server = MockQmp::start("error", |_, _| Err(String::from("Device 'vm.disk9' not found")));
+		let qmp = QmpClient::connect(server.path()).await.unwrap();
+
+		match qmp.execute(qmp::stop {}).await {
+			Err(VMQmpCommandError::QemuError(desc)) => assert_eq!(desc, "Device 'vm.disk9' not found"),
+			other => panic!("unexpected result: {other:?}")
+		}
+	}
+
+	#[tokio::test]
+	async fn unanswered_commands_time_out() {
+		use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+		// Finishes the handshake, then never answers anything else
+		let path = std::env::temp_dir().join(format!("sunlight-test-{}-wedged.qmp", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let listener = tokio::net::UnixListener::bind(&path).unwrap();
+		let server = tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let (read, mut write) = stream.into_split();
+			let mut lines = BufReader::new(read).lines();
+
+			write.write_all(b"{\"QMP\": {\"version\": {\"qemu\": {\"major\": 8, \"minor\": 2, \"micro\": 0}, \"package\": \"\"}, \"capabilities\": []}}\n").await.unwrap();
+			lines.next_line().await.unwrap();
+			write.write_all(b"{\"return\": {}}\n").await.unwrap();
+			while let Ok(Some(..)) = lines.next_line().await {}
+		});
+
+		let mut qmp = QmpClient::connect(path.to_str().unwrap()).await.unwrap();
+		qmp.set_timeout(Duration::from_millis(50));
+
+		assert!(matches!(qmp.execute(qmp::stop {}).await, Err(VMQmpCommandError::QmpTimeout(timeout)) if timeout == Duration::from_millis(50)));
+		assert!(matches!(qmp.execute_with_timeout(qmp::cont {}, Duration::from_millis(10)).await, Err(VMQmpCommandError::QmpTimeout(timeout)) if timeout == Duration::from_millis(10)));
+
+		server.abort();
+		std::fs::remove_file(&path).unwrap();
+	}
+}
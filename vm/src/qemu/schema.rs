@@ -0,0 +1,1741 @@
+//! A JSON Schema for [VmConfig], so frontends can build forms for VM definitions and check them
+//! before handing them over. See [VmConfig::json_schema].
+//!
+//! The schema is written out by hand, so adding a field to a config type means adding it here too.
+
+use super::config::*;
+use super::vm::*;
+use serde_json::{json, Map, Value};
+
+/// Something which appears in a [VmConfig], and so has a schema.
+pub(crate) trait JsonSchema {
+	/// Whether the value can be left out (i.e: it's an [Option]).
+	const OPTIONAL: bool = false;
+
+	fn schema() -> Value;
+}
+
+/// A property of an object (or of an enum variant with fields).
+struct Property {
+	name: &'static str,
+	schema: Value,
+	required: bool
+}
+
+/// A field, which has to be given unless it's an [Option].
+fn field<T: JsonSchema>(name: &'static str, description: &str) -> Property {
+	Property { name, schema: describe(T::schema(), description), required: !T::OPTIONAL }
+}
+
+/// A field marked `#[serde(default)]`, which can always be left out.
+fn defaulted<T: JsonSchema>(name: &'static str, description: &str) -> Property {
+	Property { required: false, ..field::<T>(name, description) }
+}
+
+/// Add a description to a schema, unless there isn't one (the field has no doc comment).
+fn describe(mut schema: Value, description: &str) -> Value {
+	if !description.is_empty() {
+		schema["description"] = Value::from(description);
+	}
+	schema
+}
+
+fn object(description: &str, properties: Vec<Property>) -> Value {
+	let required = properties.iter()
+		.filter(|property| property.required)
+		.map(|property| property.name)
+		.collect::<Vec<&str>>();
+
+	let properties = properties.into_iter()
+		.map(|property| (String::from(property.name), property.schema))
+		.collect::<Map<String, Value>>();
+
+	describe(json!({ "type": "object", "properties": properties, "required": required }), description)
+}
+
+/// A variant of an internally tagged enum (see [tagged]).
+struct Variant {
+	name: &'static str,
+	description: &'static str,
+	properties: Vec<Property>
+}
+
+fn variant(name: &'static str, description: &'static str, properties: Vec<Property>) -> Variant {
+	Variant { name, description, properties }
+}
+
+/// An enum marked `#[serde(tag = "...")]`, whose variants are objects with `tag` saying which variant it is.
+fn tagged(tag: &'static str, description: &str, variants: Vec<Variant>) -> Value {
+	let variants = variants.into_iter()
+		.map(|variant| {
+			let mut properties = variant.properties;
+			properties.insert(0, Property { name: tag, schema: json!({ "const": variant.name }), required: true });
+			object(variant.description, properties)
+		})
+		.collect::<Vec<Value>>();
+
+	describe(json!({ "oneOf": variants }), description)
+}
+
+/// An enum which is written as one of a set of strings, e.g: a plain enum with only unit variants.
+fn strings(description: &str, variants: &[(&str, &str)]) -> Value {
+	let variants = variants.iter()
+		.map(|(name, description)| describe(json!({ "const": name }), description))
+		.collect::<Vec<Value>>();
+
+	describe(json!({ "type": "string", "oneOf": variants }), description)
+}
+
+/// A [DeviceConfig] variant: the device's own schema, with `device` naming it.
+fn device<T: JsonSchema>(name: &str) -> Value {
+	json!({
+		"allOf": [
+			{ "type": "object", "properties": { "device": { "const": name } }, "required": ["device"] },
+			T::schema()
+		]
+	})
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+	const OPTIONAL: bool = true;
+
+	fn schema() -> Value {
+		T::schema()
+	}
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+	fn schema() -> Value {
+		json!({ "type": "array", "items": T::schema() })
+	}
+}
+
+impl JsonSchema for String {
+	fn schema() -> Value {
+		json!({ "type": "string" })
+	}
+}
+
+impl JsonSchema for bool {
+	fn schema() -> Value {
+		json!({ "type": "boolean" })
+	}
+}
+
+macro_rules! integer_schema {
+	($($int:ty),*) => {
+		$(impl JsonSchema for $int {
+			fn schema() -> Value {
+				json!({ "type": "integer", "minimum": <$int>::MIN, "maximum": <$int>::MAX })
+			}
+		})*
+	};
+}
+
+integer_schema!(u8, u16, u32, u64, i8, i32);
+
+impl JsonSchema for VmConfig {
+	fn schema() -> Value {
+		object("A complete VM definition. This mirrors what can be set through the VirtualMachine builder.", vec![
+			field::<String>("name", ""),
+			field::<Option<String>>("uuid", ""),
+			field::<Option<Architecture>>("arch", "Defaults to x86_64."),
+			field::<Option<Accelerator>>("accel", "Defaults to KVM."),
+			field::<MachineType>("machine", ""),
+			field::<Option<DisplayBackend>>("display", ""),
+			field::<Option<Sandbox>>("sandbox", ""),
+			field::<Option<QemuLog>>("log", ""),
+			field::<Option<Audio>>("audio", ""),
+			field::<Option<AudioBackend>>("audio_backend", "Defaults to AudioBackend::None."),
+			field::<Option<NumaTopology>>("numa", ""),
+			defaulted::<bool>("serial_bios", ""),
+			field::<Option<u32>>("virtio_serial_max_ports", "Sized to fit the VM's virtio-serial ports if unset."),
+			field::<Option<Firmware>>("firmware", ""),
+			field::<Option<BootConfig>>("boot", ""),
+			field::<Option<String>>("qemu_binary", "Defaults to the architecture's QEMU binary on `PATH`."),
+			field::<Option<String>>("state_dir", "Defaults to default_state_dir."),
+			defaulted::<Vec<String>>("hotplug_ports", ""),
+			defaulted::<Vec<String>>("root_ports", ""),
+			defaulted::<Vec<ThrottleGroup>>("throttle_groups", ""),
+			defaulted::<Vec<Secret>>("secrets", ""),
+			defaulted::<Vec<DeviceConfig>>("devices", ""),
+			defaulted::<Vec<DeviceConfig>>("drives", "")
+		])
+	}
+}
+
+impl JsonSchema for Architecture {
+	fn schema() -> Value {
+		strings("The guest architecture, which decides the QEMU binary to run.", &[
+			("X86_64", ""),
+			("S390x", "IBM Z. Devices live on channel subsystems instead of PCI.")
+		])
+	}
+}
+
+impl JsonSchema for Accelerator {
+	fn schema() -> Value {
+		tagged("type", "The accelerator QEMU will use to run the guest.", vec![
+			variant("Kvm", "Linux KVM. Requires `/dev/kvm` to be present and accessible.", Vec::new()),
+			variant("Tcg", "QEMU's Tiny Code Generator (software emulation). Slow, but works everywhere.", vec![
+				field::<Option<bool>>("multithreaded", "Whether to translate with one thread per vCPU (`multi`), or a single thread for all of them (`single`). QEMU picks a default based on the guest if None."),
+				field::<Option<u32>>("tb_size_mb", "The size of the translation block cache, in megabytes.")
+			]),
+			variant("Hvf", "macOS's Hypervisor.framework.", Vec::new()),
+			variant("Whpx", "The Windows Hypervisor Platform.", Vec::new()),
+			variant("Auto", "Whichever hardware accelerator this host has (see Accelerator::resolve), falling back to TCG if it has none (e.g: in a container without `/dev/kvm`). The host is only looked at when the VM starts; generated command lines let QEMU try each accelerator in turn instead.", Vec::new())
+		])
+	}
+}
+
+impl JsonSchema for MachineType {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("Pc", "PC machine type. Uses a i440fx chipset.", vec![
+				field::<bool>("acpi", ""),
+				field::<bool>("usb", "")
+			]),
+			variant("Q35", "Q35 machine type. Preferrable for VMs running more modern guests, and required for vGPU support.", vec![
+				field::<bool>("acpi", ""),
+				field::<bool>("usb", ""),
+				field::<bool>("hmat", ""),
+				field::<bool>("legacy_root_port", "Use the legacy Intel `ioh3420` root port instead of the generic `pcie-root-port`. Only useful for compatibility with old QEMU versions.")
+			]),
+			variant("S390CcwVirtio", "The s390x virtio machine. Requires Architecture::S390x.", Vec::new())
+		])
+	}
+}
+
+impl JsonSchema for DisplayBackend {
+	fn schema() -> Value {
+		tagged("type", "How the guest's display is shown.", vec![
+			variant("None", "No display at all.", Vec::new()),
+			variant("EglHeadless", "Headless OpenGL rendering, for server-side GL. Requires a GL-capable graphics adapter.", vec![
+				field::<Option<String>>("rendernode", "The DRM render node to render on (e.g: `/dev/dri/renderD128`). QEMU picks one if None.")
+			]),
+			variant("Gtk", "A local GTK window. Needs a host display (X11 or Wayland).", vec![
+				defaulted::<bool>("gl", "Render with OpenGL. Requires a GL-capable graphics adapter.")
+			]),
+			variant("Sdl", "A local SDL window. Needs a host display (X11 or Wayland).", vec![
+				defaulted::<bool>("gl", "Render with OpenGL. Requires a GL-capable graphics adapter.")
+			]),
+			variant("DbusP2p", "Export the display over a peer-to-peer D-Bus connection, which Sunlight sets up once QEMU starts (see VirtualMachine::dbus_display).", vec![
+				defaulted::<bool>("gl", "Share GL scanouts with the client. Requires a GL-capable graphics adapter.")
+			]),
+			variant("Spice", "A SPICE server, which SPICE clients (e.g: remote-viewer) can connect to. It listens on either a TCP port or a UNIX socket.", vec![
+				field::<Option<u16>>("port", ""),
+				field::<Option<String>>("addr", "The address to listen on with `port`. Defaults to all addresses."),
+				field::<Option<String>>("unix_socket", ""),
+				field::<Option<SpiceTls>>("tls", ""),
+				defaulted::<bool>("disable_ticketing", "Let clients connect without a password. Otherwise, `password_file` has to be set."),
+				field::<Option<String>>("password_file", "A file holding the password clients have to give."),
+				defaulted::<bool>("agent", "Add a channel for the SPICE guest agent (spice-vdagent), which handles things like clipboard sharing and resizing the guest display to fit the client."),
+				defaulted::<u8>("usb_redirect", "How many USB devices clients can redirect to the guest at once."),
+				defaulted::<bool>("gl", "Render with OpenGL. Requires a GL-capable graphics adapter, and only works over a UNIX socket, since GL frames are passed as dma-bufs."),
+				field::<Option<String>>("rendernode", "The DRM render node to render on. QEMU picks one if None.")
+			]),
+			variant("Vnc", "A VNC server.", vec![
+				field::<Option<String>>("listen", "The address to listen on. Defaults to all addresses."),
+				field::<u16>("port", "The TCP port to listen on. VNC ports start at 5900."),
+				field::<Option<String>>("password_secret", "A file holding the password clients have to give. Without this, anyone can connect. The password can be changed later on with VirtualMachine::set_vnc_password."),
+				field::<Option<u16>>("websocket", "Also accept VNC over WebSockets (e.g: for noVNC) on this port.")
+			])
+		])
+	}
+}
+
+impl JsonSchema for SpiceTls {
+	fn schema() -> Value {
+		object("TLS for a SPICE server.", vec![
+			field::<u16>("port", ""),
+			field::<String>("x509_dir", "Directory holding `ca-cert.pem`, `server-cert.pem` and `server-key.pem`.")
+		])
+	}
+}
+
+impl JsonSchema for Sandbox {
+	fn schema() -> Value {
+		object("QEMU's seccomp sandbox (`-sandbox on`). Each flag denies the given class of syscalls.", vec![
+			field::<bool>("deny_obsolete", ""),
+			field::<bool>("deny_elevate_privileges", ""),
+			field::<bool>("deny_spawn", "Deny fork()/exec(). Incompatible with devices that need helper processes."),
+			field::<bool>("deny_resource_control", "")
+		])
+	}
+}
+
+impl JsonSchema for QemuLog {
+	fn schema() -> Value {
+		object("QEMU's own debug logging (`-d` and `-D`), independent of tracing.", vec![
+			field::<Vec<LogCategory>>("categories", ""),
+			field::<String>("file", "The file the log is written to.")
+		])
+	}
+}
+
+impl JsonSchema for LogCategory {
+	fn schema() -> Value {
+		strings("A QEMU log item (`-d`). See `qemu-system-x86_64 -d help` for what each one logs.", &[
+			("OutAsm", ""),
+			("InAsm", ""),
+			("Op", ""),
+			("OpOpt", ""),
+			("OpInd", ""),
+			("Int", ""),
+			("Exec", ""),
+			("Cpu", ""),
+			("Fpu", ""),
+			("Mmu", ""),
+			("Pcall", ""),
+			("CpuReset", ""),
+			("Unimp", ""),
+			("GuestErrors", ""),
+			("Page", ""),
+			("Nochain", "")
+		])
+	}
+}
+
+impl JsonSchema for Audio {
+	fn schema() -> Value {
+		tagged("type", "The guest's sound card. Its output goes to the VM's AudioBackend.", vec![
+			variant("IntelHda", "Intel HD Audio, with a duplex (line in/out) codec.", vec![
+				field::<Option<HdaController>>("controller", "The controller to use. If None, it's picked based on the machine type.")
+			]),
+			variant("Ac97", "Intel 82801AA AC97. For older guests without HDA drivers.", Vec::new()),
+			variant("VirtioSound", "VirtIO sound. Needs QEMU 8.2 or newer.", Vec::new())
+		])
+	}
+}
+
+impl JsonSchema for HdaController {
+	fn schema() -> Value {
+		strings("Which Intel HD Audio controller model to use.", &[
+			("Ich9", "`ich9-intel-hda`. The one Q35 machines actually have."),
+			("Ich6", "`intel-hda` (ICH6). Matches the older PC (i440fx) machine type.")
+		])
+	}
+}
+
+impl JsonSchema for AudioBackend {
+	fn schema() -> Value {
+		tagged("type", "Where the host side of the guest's audio goes (`-audiodev`).", vec![
+			variant("None", "Discard output, and record silence.", Vec::new()),
+			variant("Pipewire", "Needs QEMU 8.1 or newer.", Vec::new()),
+			variant("PulseAudio", "", vec![
+				field::<Option<String>>("server", "The server to connect to. Defaults to the user's server.")
+			]),
+			variant("Alsa", "", vec![
+				field::<Option<String>>("device", "The ALSA device to play to and record from. Defaults to `default`.")
+			])
+		])
+	}
+}
+
+impl JsonSchema for NumaTopology {
+	fn schema() -> Value {
+		object("The VM's NUMA layout. Nodes are numbered in the order they're listed.", vec![
+			field::<Vec<NumaNode>>("nodes", ""),
+			defaulted::<Vec<HmatLatencyBandwidth>>("hmat", "Requires a MachineType::Q35 with `hmat` enabled.")
+		])
+	}
+}
+
+impl JsonSchema for NumaNode {
+	fn schema() -> Value {
+		object("A guest NUMA node.", vec![
+			field::<ByteSize>("size", "How much of the VM's memory is on this node. Sizes of all nodes have to add up to Memory's size."),
+			field::<Option<MemoryBackend>>("backend", "Defaults to anonymous memory (MemoryBackend::Ram)."),
+			defaulted::<bool>("prealloc", ""),
+			defaulted::<bool>("legacy_mem", "Give the node its memory with the legacy mem= form instead of a memdev. Only older machine types take it, and QEMU refuses a mix, so it's all nodes or none."),
+			defaulted::<Vec<u32>>("cpus", "The vCPUs (by index) on this node. Nodes without any are memory-only."),
+			field::<Option<u32>>("initiator", "The node whose vCPUs have the best access to this node's memory. HMAT needs this on every node; a node with vCPUs is usually its own initiator.")
+		])
+	}
+}
+
+impl JsonSchema for ByteSize {
+	fn schema() -> Value {
+		json!({
+			"type": "string",
+			"description": "A size in bytes, written the way QEMU takes sizes, e.g: `4G` or `512M`. `B` is bytes, and no suffix at all means megabytes, like `-m`.",
+			"pattern": "^[0-9]+[BbKkMmGgTt]?$"
+		})
+	}
+}
+
+impl JsonSchema for MemoryBackend {
+	fn schema() -> Value {
+		tagged("type", "Where guest RAM comes from.", vec![
+			variant("Ram", "Anonymous memory. This is what QEMU uses if no backend is given.", vec![
+				defaulted::<bool>("share", "")
+			]),
+			variant("Memfd", "A memfd, which other processes (e.g: vhost-user backends like virtiofsd) can map.", vec![
+				defaulted::<bool>("share", ""),
+				field::<Option<ByteSize>>("hugepage_size", "Back the memfd with huge pages of this size (e.g: `2M` or `1G`).")
+			]),
+			variant("File", "A file, usually on a hugetlbfs mount.", vec![
+				field::<String>("mem_path", "The file (or directory, which QEMU creates a file in) to back memory with."),
+				defaulted::<bool>("share", ""),
+				field::<Option<ByteSize>>("hugepage_size", "If set, `mem_path` has to be on a hugetlbfs mount with this page size (e.g: `2M` or `1G`).")
+			])
+		])
+	}
+}
+
+impl JsonSchema for HmatLatencyBandwidth {
+	fn schema() -> Value {
+		object("An HMAT System Locality Latency and Bandwidth entry, describing how fast the `initiator` node's vCPUs can reach the `target` node's memory.", vec![
+			field::<u32>("initiator", ""),
+			field::<u32>("target", ""),
+			field::<HmatDataType>("data_type", ""),
+			field::<Option<u64>>("latency_ns", "In nanoseconds. Set for latency data types."),
+			field::<Option<ByteSize>>("bandwidth", "Per second (e.g: `10G`). Set for bandwidth data types.")
+		])
+	}
+}
+
+impl JsonSchema for HmatDataType {
+	fn schema() -> Value {
+		strings("What an HmatLatencyBandwidth entry describes.", &[
+			("AccessLatency", ""),
+			("ReadLatency", ""),
+			("WriteLatency", ""),
+			("AccessBandwidth", ""),
+			("ReadBandwidth", ""),
+			("WriteBandwidth", "")
+		])
+	}
+}
+
+impl JsonSchema for Firmware {
+	fn schema() -> Value {
+		tagged("type", "The firmware the VM boots with.", vec![
+			variant("Bios", "SeaBIOS. This is QEMU's default, so nothing extra is generated for it.", Vec::new()),
+			variant("Uefi", "UEFI (OVMF), loaded from a pair of pflash images.", vec![
+				field::<String>("code_path", "The firmware code image (e.g: `/usr/share/OVMF/OVMF_CODE.fd`). This is mapped read-only."),
+				field::<String>("vars_template", "The template for the UEFI variable store (e.g: `/usr/share/OVMF/OVMF_VARS.fd`). This is copied into the VM's state directory on first boot, so each VM gets its own writable copy.")
+			])
+		])
+	}
+}
+
+impl JsonSchema for BootConfig {
+	fn schema() -> Value {
+		object("What the VM boots from, and the firmware's boot menu.", vec![
+			defaulted::<Vec<BootDevice>>("order", "The devices to try booting from, in order. Devices which aren't listed are tried afterwards (unless `strict`), in whatever order the firmware likes."),
+			defaulted::<bool>("menu", "Show the firmware's boot menu."),
+			field::<Option<u32>>("menu_timeout_ms", "How long the boot menu waits for a key press. The firmware picks if None."),
+			field::<Option<String>>("splash", "A picture (JPEG or BMP) shown along with the boot menu."),
+			defaulted::<bool>("strict", "Only boot from the devices in `order`.")
+		])
+	}
+}
+
+impl JsonSchema for BootDevice {
+	fn schema() -> Value {
+		// Externally tagged, e.g: `{ Drive = "disk0" }`
+		let by_id = |variant: &str, description: &str| json!({
+			"type": "object",
+			"description": description,
+			"properties": { variant: { "type": "string" } },
+			"required": [variant],
+			"additionalProperties": false
+		});
+
+		json!({
+			"description": "A device the VM can boot from, by id.",
+			"oneOf": [
+				by_id("Drive", "A DiskDrive (other than a pflash drive)."),
+				by_id("Network", "Network boot (PXE) from a NetworkAdapter.")
+			]
+		})
+	}
+}
+
+impl JsonSchema for ThrottleGroup {
+	fn schema() -> Value {
+		object("A named set of I/O limits which every drive referencing it shares, i.e: the combined I/O of all the drives in the group is limited, instead of each drive on its own.", vec![
+			field::<String>("id", ""),
+			field::<IoLimits>("limits", "")
+		])
+	}
+}
+
+impl JsonSchema for IoLimits {
+	fn schema() -> Value {
+		object("I/O limits for a drive, or a group of drives. Anything left as None is unlimited.", vec![
+			field::<Option<u64>>("iops_total", ""),
+			field::<Option<u64>>("iops_read", ""),
+			field::<Option<u64>>("iops_write", ""),
+			field::<Option<u64>>("bps_total", "Bytes per second."),
+			field::<Option<u64>>("bps_read", ""),
+			field::<Option<u64>>("bps_write", "")
+		])
+	}
+}
+
+impl JsonSchema for Secret {
+	fn schema() -> Value {
+		object("A secret (a passphrase, or a key) which other options refer to by id, so it doesn't have to be passed to each of them directly.", vec![
+			field::<String>("id", ""),
+			field::<SecretSource>("source", "")
+		])
+	}
+}
+
+impl JsonSchema for SecretSource {
+	fn schema() -> Value {
+		tagged("type", "Where a Secret's value comes from.", vec![
+			variant("File", "Read from a file when QEMU starts. The file shouldn't have a trailing newline, since it's taken as part of the secret.", vec![
+				field::<String>("path", "")
+			]),
+			variant("Inline", "Given directly. This ends up on the QEMU command line (where anyone who can list processes can see it), and in the saved config, so prefer the other sources.", vec![
+				field::<String>("data", "")
+			]),
+			variant("Keyring", "A key in the Linux kernel keyring, by its serial number (e.g: from `keyctl add`).", vec![
+				field::<i32>("serial", "")
+			])
+		])
+	}
+}
+
+impl JsonSchema for DeviceConfig {
+	fn schema() -> Value {
+		#[allow(unused_mut)]
+		let mut variants = vec![
+			device::<Cpu>("Cpu"),
+			device::<Memory>("Memory"),
+			device::<DiskController>("DiskController"),
+			device::<UsbController>("UsbController"),
+			device::<UsbDevice>("UsbDevice"),
+			device::<InputDevice>("InputDevice"),
+			device::<DiskDrive>("DiskDrive"),
+			device::<NvmeSubsystem>("NvmeSubsystem"),
+			device::<GraphicsAdapter>("GraphicsAdapter"),
+			device::<Network>("Network"),
+			device::<NetworkAdapter>("NetworkAdapter"),
+			device::<Tpm>("Tpm"),
+			device::<VirtioInputHost>("VirtioInputHost"),
+			device::<VirtioCrypto>("VirtioCrypto"),
+			device::<Vsock>("Vsock"),
+			device::<Rng>("Rng"),
+			device::<Balloon>("Balloon"),
+			device::<NinePShare>("NinePShare"),
+			device::<SmartCard>("SmartCard"),
+			device::<IsaDebugCon>("IsaDebugCon"),
+			device::<Watchdog>("Watchdog"),
+			device::<Pvpanic>("Pvpanic"),
+			device::<PciPassthrough>("PciPassthrough"),
+			device::<GuestAgent>("GuestAgent"),
+			device::<SerialConsole>("SerialConsole"),
+			device::<VirtioSerialChannel>("VirtioSerialChannel")
+		];
+
+		#[cfg(feature = "testing")]
+		variants.push(device::<EduDevice>("EduDevice"));
+
+		json!({
+			"description": "Any device which can be put in a VmConfig. The `device` key says which one it is, and devices with multiple variants (e.g: GraphicsAdapter) are picked with `type`.",
+			"oneOf": variants
+		})
+	}
+}
+
+impl JsonSchema for Cpu {
+	fn schema() -> Value {
+		object("", vec![
+			field::<String>("model", "The CPU model."),
+			defaulted::<Vec<String>>("features", "CPU features. Later on, these can be typed/exclusions. For now, I don't care."),
+			field::<i8>("core_count", "Cores per die."),
+			field::<Option<i8>>("dies", "How many dies each socket is split into. Each die gets its own L3 cache in the guest's view."),
+			field::<Option<i8>>("sockets", "Defaults to 1."),
+			field::<Option<i8>>("threads", "Threads per core. Defaults to 1."),
+			field::<Option<u32>>("vcpus", "How many vCPUs the VM boots with. Defaults to the whole topology; anything less leaves the rest to be hotplugged later."),
+			field::<Option<u32>>("max_vcpus", "The most vCPUs the VM can have. If set, the topology has to add up to this instead of `vcpus`, so there's room to hotplug more vCPUs up to it."),
+			defaulted::<bool>("host_cache_info", "Pass the host's cache topology through to the guest. Only works with the `host` model.")
+		])
+	}
+}
+
+impl JsonSchema for Memory {
+	fn schema() -> Value {
+		object("", vec![
+			field::<ByteSize>("size", ""),
+			field::<bool>("prealloc", "Allocate all of guest RAM up front, instead of as the guest touches it."),
+			field::<Option<MemoryBackend>>("backend", "Defaults to anonymous memory (MemoryBackend::Ram)."),
+			field::<Option<ByteSize>>("max_size", "The maximum size memory can be hotplugged up to. Must be set along with `slots`."),
+			field::<Option<u8>>("slots", "How many DIMM slots are available for hotplugging memory. Must be set along with `max_size`.")
+		])
+	}
+}
+
+impl JsonSchema for DiskController {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("VirtioScsi", "", vec![
+				field::<String>("id", "")
+			])
+		])
+	}
+}
+
+impl JsonSchema for UsbController {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("Xhci", "QEMU's generic xHCI (USB 3) controller.", vec![
+				field::<String>("id", ""),
+				field::<Option<u8>>("usb2_ports", "How many USB 2 ports to provide (up to 15). QEMU's default is 4."),
+				field::<Option<u8>>("usb3_ports", "How many USB 3 ports to provide (up to 15). QEMU's default is 4.")
+			]),
+			variant("NecXhci", "The NEC uPD720200 xHCI controller. Some older guests only have drivers for this one.", vec![
+				field::<String>("id", ""),
+				field::<Option<u8>>("usb2_ports", "How many USB 2 ports to provide (up to 15). QEMU's default is 4."),
+				field::<Option<u8>>("usb3_ports", "How many USB 3 ports to provide (up to 15). QEMU's default is 4.")
+			]),
+			variant("Uhci", "The PIIX3 UHCI (USB 1.1) controller. For very old guests.", vec![
+				field::<String>("id", "")
+			])
+		])
+	}
+}
+
+impl JsonSchema for UsbDevice {
+	fn schema() -> Value {
+		tagged("type", "A USB device (other than input devices, which are InputDevices).", vec![
+			variant("Storage", "A USB mass storage device (e.g: a flash drive), backed by a disk image.", vec![
+				field::<String>("id", ""),
+				field::<String>("image_path", ""),
+				field::<DiskFormat>("format", ""),
+				field::<bool>("readonly", "")
+			]),
+			variant("HostById", "Pass a host USB device through, picked by its vendor and product id. Since this doesn't depend on where the device is plugged in, it keeps working if the device is replugged.", vec![
+				field::<String>("id", ""),
+				field::<u16>("vendor_id", ""),
+				field::<u16>("product_id", "")
+			]),
+			variant("HostByPort", "Pass through whatever is plugged into a host USB port.", vec![
+				field::<String>("id", ""),
+				field::<u8>("bus", "The host USB bus number."),
+				field::<String>("port", "The port path on that bus (e.g: `2` or `1.4` for a port on a hub).")
+			])
+		])
+	}
+}
+
+impl JsonSchema for DiskFormat {
+	fn schema() -> Value {
+		strings("A disk image format.", &[
+			(DiskFormat::Qcow2.name(), "QEMU's own format. Sparse, with backing images, snapshots and compression."),
+			(DiskFormat::Raw.name(), "A plain image, byte for byte what the guest sees."),
+			(DiskFormat::Vmdk.name(), "VMware."),
+			(DiskFormat::Vdi.name(), "VirtualBox."),
+			(DiskFormat::Vhdx.name(), "Hyper-V."),
+			(DiskFormat::Vpc.name(), "Virtual PC (VHD).")
+		])
+	}
+}
+
+impl JsonSchema for InputDevice {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("UsbMouse", "A USB HID mouse. For older guests which don't know about tablets or virtio input.", Vec::new()),
+			variant("UsbKeyboard", "A USB HID keyboard.", Vec::new()),
+			variant("UsbTablet", "A USB tablet. Uses absolute coordinates, so the guest cursor follows the client's without grabbing it.", Vec::new())
+		])
+	}
+}
+
+impl JsonSchema for DiskDrive {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("CdDrive", "", vec![
+				field::<DiskInterface>("interface", ""),
+				field::<String>("id", ""),
+				field::<Option<String>>("iso_path", "The ISO inserted at boot. Left empty if None; see VirtualMachine::change_media.")
+			]),
+			variant("HdDrive", "", vec![
+				field::<String>("id", ""),
+				field::<DiskInterface>("interface", ""),
+				field::<String>("image_path", ""),
+				field::<bool>("readonly", ""),
+				field::<DiskFormat>("format", ""),
+				field::<bool>("ssd", ""),
+				field::<Option<CacheMode>>("cache", ""),
+				field::<Option<AioMode>>("aio", ""),
+				field::<Option<String>>("throttle_group", "The id of a ThrottleGroup this drive shares its I/O limits with."),
+				defaulted::<Option<IoLimits>>("throttle", "I/O limits for this drive alone. These apply on top of any throttle group's. See VirtualMachine::set_io_limits for changing them while the VM is running."),
+				defaulted::<bool>("share_rw", "Don't take an exclusive lock on the image, so other VMs can write to it too. This WILL corrupt the image unless the guests use a cluster-aware filesystem."),
+				field::<Option<u32>>("logical_block_size", "The block sizes reported to the guest, in bytes (e.g: 4096 for both to emulate a 4Kn disk)."),
+				field::<Option<u32>>("physical_block_size", ""),
+				defaulted::<bool>("discard", "Pass discard (TRIM) requests from the guest through to the image, so it can shrink."),
+				field::<Option<DetectZeroes>>("detect_zeroes", "Detect writes of all zeroes, and turn them into zero writes or discards."),
+				defaulted::<bool>("iothread", "Give the drive an I/O thread of its own. Virtio only."),
+				field::<Option<u16>>("num_queues", "How many request queues the drive has. Virtio only. QEMU's default is one per vCPU."),
+				defaulted::<Option<String>>("backing", "The image `image_path` is a qcow2 overlay on top of. If the overlay doesn't exist yet, it's created (empty) when the VM starts. The backing image mustn't be written to after that."),
+				defaulted::<Option<LuksConfig>>("encryption", "Unlock a LUKS encrypted qcow2 image with a Secret.")
+			]),
+			variant("Nvme", "An NVMe drive: a controller of its own, with the image as namespace 1. For namespaces shared between controllers, use an NvmeSubsystem.", vec![
+				field::<String>("id", ""),
+				field::<String>("image_path", ""),
+				field::<DiskFormat>("format", ""),
+				field::<Option<String>>("serial", "The controller serial number reported to the guest. Defaults to the drive id."),
+				defaulted::<Vec<NvmeNamespace>>("namespaces", "More namespaces to put on the controller, after the image. Their ids have to start from 2.")
+			]),
+			variant("Pflash", "A pflash drive. There are no configurable interface types.", vec![
+				field::<String>("id", ""),
+				field::<String>("image_path", ""),
+				field::<bool>("readonly", ""),
+				field::<DiskFormat>("format", "")
+			])
+		])
+	}
+}
+
+impl JsonSchema for DiskInterface {
+	fn schema() -> Value {
+		strings("", &[
+			("Ide", "IDE (or SATA if using the q35 machine type.)"),
+			("Scsi", "SCSI (incl. VirtIO SCSI)."),
+			("Virtio", "virtio-blk. Each drive is its own PCI device, so there's no controller to add. Hard drives only.")
+		])
+	}
+}
+
+impl JsonSchema for CacheMode {
+	fn schema() -> Value {
+		strings("How a drive uses the host's page cache.", &[
+			(CacheMode::Writeback.name(), "Writes go through the page cache, and are flushed when the guest asks. QEMU's default."),
+			(CacheMode::Writethrough.name(), "Every write is flushed before it completes, and the guest is told there's no write cache."),
+			(CacheMode::None.name(), "Bypass the page cache (O_DIRECT), flushing when the guest asks."),
+			(CacheMode::Unsafe.name(), "Never flush. Fast, but a host crash can lose (or corrupt) anything written."),
+			(CacheMode::DirectSync.name(), "Bypass the page cache, and flush every write.")
+		])
+	}
+}
+
+impl JsonSchema for AioMode {
+	fn schema() -> Value {
+		strings("How QEMU does a drive's I/O.", &[
+			(AioMode::Threads.name(), "A pool of threads doing blocking I/O. QEMU's default."),
+			(AioMode::Native.name(), "Linux native AIO. Needs a cache mode which bypasses the page cache."),
+			(AioMode::IoUring.name(), "io_uring.")
+		])
+	}
+}
+
+impl JsonSchema for DetectZeroes {
+	fn schema() -> Value {
+		strings("What a drive does with writes of all zeroes.", &[
+			(DetectZeroes::Off.name(), "Write them out like anything else. QEMU's default."),
+			(DetectZeroes::On.name(), "Turn them into zero writes, which formats like qcow2 can do without storing the zeroes."),
+			(DetectZeroes::Unmap.name(), "Turn them into discards. Needs the drive to have `discard` enabled.")
+		])
+	}
+}
+
+impl JsonSchema for LuksConfig {
+	fn schema() -> Value {
+		object("LUKS encryption for a qcow2 image. The image has to have been created encrypted (e.g: `qemu-img create -f qcow2 -o encrypt.format=luks,encrypt.key-secret=...`).", vec![
+			field::<String>("secret", "The id of the Secret holding the passphrase.")
+		])
+	}
+}
+
+impl JsonSchema for NvmeNamespace {
+	fn schema() -> Value {
+		object("A namespace on an NvmeSubsystem.", vec![
+			field::<u32>("nsid", "The namespace id. Must be unique within the subsystem, and start from 1."),
+			field::<String>("image_path", ""),
+			field::<DiskFormat>("format", "")
+		])
+	}
+}
+
+impl JsonSchema for NvmeSubsystem {
+	fn schema() -> Value {
+		object("An NVMe subsystem with a single controller, and one or more namespaces. Mostly useful for testing how guests deal with multiple namespaces.", vec![
+			field::<String>("id", ""),
+			field::<String>("nqn", "The NVMe Qualified Name of the subsystem (e.g: `nqn.2019-08.org.qemu:sunlight`)."),
+			field::<Vec<NvmeNamespace>>("namespaces", "The namespaces are kept on the subsystem, since they can't exist without it.")
+		])
+	}
+}
+
+impl JsonSchema for GraphicsAdapter {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("StdVga", "Standard VGA adapter.", vec![
+				field::<ByteSize>("vgamem", "Between 1M and 512M. QEMU rounds this up to a power of two.")
+			]),
+			variant("CirrusVga", "Cirrus Logic GD5446.", vec![
+				field::<ByteSize>("vgamem", "4M, 8M or 16M.")
+			]),
+			variant("QxlVga", "Red Hat QXL.", Vec::new()),
+			variant("VirtioGpu", "VirtIO GPU.", vec![
+				field::<bool>("blob", "Enable blob resources, for zero-copy buffer sharing with the host. Requires a shared (memfd) memory backend."),
+				field::<Option<ByteSize>>("hostmem", "Size of the host-visible memory region."),
+				defaulted::<bool>("vga", "Use the VGA-compatible variant (virtio-vga), so there's output before the guest's driver loads."),
+				defaulted::<bool>("virgl", "OpenGL acceleration through virglrenderer. Requires a display which renders with GL."),
+				defaulted::<bool>("venus", "Vulkan acceleration through virglrenderer's Venus. Requires virgl, blob and hostmem.")
+			]),
+			variant("VgpuVga", "A Mediated Device (MDEV) vGPU device, provided by supported GPU devices.  Currently this means/supports: - Intel GVT-g - NVIDIA vGPU", vec![
+				field::<String>("uuid", "The MDEV UUID. This *must* match the VM's UUID, at least for NVIDIA. I don't know if a similar requirement is true for Intel, but I assume it is."),
+				field::<bool>("use_ramfb", "Use the QEMU ramfb device to provide pre-boot (pre-graphics driver initalization) video."),
+				field::<Option<String>>("pci_vendor_id", ""),
+				field::<Option<String>>("pci_device_id", ""),
+				field::<Option<String>>("pci_sub_vendor_id", ""),
+				field::<Option<String>>("pci_sub_device_id", ""),
+				field::<Option<bool>>("rombar", ""),
+				field::<Option<String>>("romfile", ""),
+				field::<Option<String>>("root_port", "The named root port to place the vGPU on. One is allocated if None.")
+			])
+		])
+	}
+}
+
+impl JsonSchema for Network {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("User", "", vec![
+				field::<String>("id", ""),
+				defaulted::<Vec<PortForward>>("host_forwards", "Ports forwarded from the host to the guest (e.g: for SSH). More can be added while the VM is running with VirtualMachine::add_port_forward.")
+			]),
+			variant("Tap", "", vec![
+				field::<String>("id", ""),
+				field::<String>("dev", ""),
+				field::<Option<ManagedTap>>("managed", "Have Sunlight create the tap device when the VM starts, and delete it once the VM stops. If None, the tap has to exist already."),
+				field::<Option<u8>>("queues", "How many queue pairs to open the tap with. An unmanaged tap has to have been created with `multi_queue` for more than one. The adapter's `queues` should match.")
+			]),
+			variant("Bridge", "Connect to an existing host bridge (e.g: `br0`), through qemu-bridge-helper. The helper is setuid, and only attaches to bridges allowed in its ACL (`/etc/qemu/bridge.conf`).", vec![
+				field::<String>("id", ""),
+				field::<String>("bridge", ""),
+				field::<Option<String>>("helper", "The helper to use, if not QEMU's default path for qemu-bridge-helper.")
+			]),
+			variant("VhostUser", "Connect to a vhost-user backend (e.g: an OVS-DPDK `dpdkvhostuser` port), which handles packets itself by mapping guest memory. The VM's memory needs a backend with `share` on, and the adapter has to be virtio.", vec![
+				field::<String>("id", ""),
+				field::<String>("socket_path", "The backend's socket. QEMU connects to it as a client."),
+				field::<Option<u8>>("queues", "How many queue pairs to use. The adapter's `queues` should match.")
+			])
+		])
+	}
+}
+
+impl JsonSchema for PortForward {
+	fn schema() -> Value {
+		object("Forwards a port on the host to a port in the guest, for user networking (`hostfwd`).", vec![
+			field::<ForwardProtocol>("proto", ""),
+			field::<Option<String>>("host_addr", "The host address to listen on. Listens on all addresses if None."),
+			field::<u16>("host_port", ""),
+			field::<Option<String>>("guest_addr", "The guest address to forward to. Defaults to the first address handed out by the built-in DHCP server."),
+			field::<u16>("guest_port", "")
+		])
+	}
+}
+
+impl JsonSchema for ForwardProtocol {
+	fn schema() -> Value {
+		strings("Which protocol a PortForward forwards.", &[
+			("Tcp", ""),
+			("Udp", "")
+		])
+	}
+}
+
+impl JsonSchema for ManagedTap {
+	fn schema() -> Value {
+		object("How Sunlight sets up a tap device it creates for a Network::Tap.", vec![
+			field::<Option<String>>("bridge", "A bridge to attach the tap to.")
+		])
+	}
+}
+
+impl JsonSchema for NetworkAdapter {
+	fn schema() -> Value {
+		tagged("type", "", vec![
+			variant("Virtio", "", vec![
+				field::<String>("id", ""),
+				field::<String>("netdev", ""),
+				field::<Option<MacAddr>>("mac", ""),
+				field::<Option<bool>>("rombar", "Expose (or hide) the option ROM BAR. Omitted if None."),
+				field::<Option<String>>("romfile", "Use a custom option ROM instead of QEMU's."),
+				defaulted::<bool>("failover", "Make this adapter the failover standby for a passthrough NIC. The guest pairs the two by MAC address, so a MAC must be set when this is on."),
+				field::<Option<u16>>("host_mtu", "The MTU advertised to the guest (e.g: 9000 for jumbo frames). The guest driver has to support VIRTIO_NET_F_MTU (Linux 4.10+, or a recent virtio-win) to pick it up."),
+				defaulted::<VirtioNetOffloads>("offloads", ""),
+				field::<Option<u8>>("queues", "Enable multiqueue with this many queue pairs. The netdev needs to be opened with (at least) as many queues for this to do anything.")
+			]),
+			variant("Rtl8139", "", vec![
+				field::<String>("id", ""),
+				field::<String>("netdev", ""),
+				field::<Option<MacAddr>>("mac", ""),
+				field::<Option<bool>>("rombar", ""),
+				field::<Option<String>>("romfile", "")
+			]),
+			variant("E1000", "Intel 82540EM (PCI). Nearly every OS has a driver for this one.", vec![
+				field::<String>("id", ""),
+				field::<String>("netdev", ""),
+				field::<Option<MacAddr>>("mac", ""),
+				field::<Option<bool>>("rombar", ""),
+				field::<Option<String>>("romfile", "")
+			]),
+			variant("E1000e", "Intel 82574L (PCIe). Only supported on Q35.", vec![
+				field::<String>("id", ""),
+				field::<String>("netdev", ""),
+				field::<Option<MacAddr>>("mac", ""),
+				field::<Option<bool>>("rombar", ""),
+				field::<Option<String>>("romfile", "")
+			]),
+			variant("Vmxnet3", "VMware's paravirtual adapter. For guests imported from VMware.", vec![
+				field::<String>("id", ""),
+				field::<String>("netdev", ""),
+				field::<Option<MacAddr>>("mac", ""),
+				field::<Option<bool>>("rombar", ""),
+				field::<Option<String>>("romfile", "")
+			])
+		])
+	}
+}
+
+impl JsonSchema for MacAddr {
+	fn schema() -> Value {
+		json!({
+			"type": "string",
+			"description": "A unicast MAC address, written the usual way (e.g: `52:54:00:12:34:56`).",
+			"pattern": "^[0-9A-Fa-f]{2}(:[0-9A-Fa-f]{2}){5}$"
+		})
+	}
+}
+
+impl JsonSchema for VirtioNetOffloads {
+	fn schema() -> Value {
+		object("virtio-net offload features. Anything left as None keeps QEMU's default (which is on). Mostly useful for turning offloads off while debugging checksum/segmentation issues.", vec![
+			field::<Option<bool>>("csum", ""),
+			field::<Option<bool>>("guest_csum", ""),
+			field::<Option<bool>>("gso", ""),
+			field::<Option<bool>>("guest_tso4", ""),
+			field::<Option<bool>>("guest_tso6", ""),
+			field::<Option<bool>>("guest_ecn", ""),
+			field::<Option<bool>>("guest_ufo", ""),
+			field::<Option<bool>>("host_tso4", ""),
+			field::<Option<bool>>("host_tso6", ""),
+			field::<Option<bool>>("host_ecn", ""),
+			field::<Option<bool>>("host_ufo", ""),
+			field::<Option<bool>>("mrg_rxbuf", "")
+		])
+	}
+}
+
+impl JsonSchema for Tpm {
+	fn schema() -> Value {
+		object("A TPM 2.0 device, backed by a swtpm instance listening on a UNIX socket.", vec![
+			field::<TpmInterface>("interface", ""),
+			field::<String>("socket_path", "The swtpm control socket."),
+			defaulted::<bool>("managed", "Have Sunlight run swtpm while the VM is running, keeping the TPM's state in the VM's state directory (under `tpm`). Otherwise swtpm has to already be listening on `socket_path`.")
+		])
+	}
+}
+
+impl JsonSchema for TpmInterface {
+	fn schema() -> Value {
+		strings("The interface a TPM is exposed to the guest with.", &[
+			("Tis", "TPM Interface Specification. What most x86 guests expect."),
+			("Crb", "Command Response Buffer. Used by aarch64 and newer setups. Discovered through ACPI.")
+		])
+	}
+}
+
+impl JsonSchema for VirtioInputHost {
+	fn schema() -> Value {
+		object("Passes a host input device (keyboard, mouse, ...) straight through to the guest.", vec![
+			field::<String>("id", ""),
+			field::<String>("evdev_path", "The evdev node of the host device (e.g: `/dev/input/event3`).")
+		])
+	}
+}
+
+#[cfg(feature = "testing")]
+impl JsonSchema for EduDevice {
+	fn schema() -> Value {
+		object("QEMU's educational PCI device. It doesn't do anything useful, but it's a known device which is handy to look for when testing Sunlight itself.", Vec::new())
+	}
+}
+
+impl JsonSchema for VirtioCrypto {
+	fn schema() -> Value {
+		object("A virtio-crypto device, backed by QEMU's builtin (software) crypto backend.", vec![
+			field::<String>("id", "")
+		])
+	}
+}
+
+impl JsonSchema for Vsock {
+	fn schema() -> Value {
+		object("A vhost-vsock device, which gives the guest an address for sockets between it and the host. Needs `/dev/vhost-vsock` on the host. See VirtualMachine::forward_vsock.", vec![
+			defaulted::<String>("id", ""),
+			field::<u32>("guest_cid", "The guest's context id (its address). It has to be unique on the host.")
+		])
+	}
+}
+
+impl JsonSchema for Rng {
+	fn schema() -> Value {
+		tagged("type", "A hardware random number generator for the guest, so it doesn't have to wait for entropy at boot.", vec![
+			variant("Virtio", "virtio-rng.", vec![
+				field::<String>("id", ""),
+				field::<Option<String>>("source", "A host file to read entropy from (e.g: `/dev/urandom`). If None, QEMU uses its builtin backend, which gets entropy the same way QEMU does."),
+				field::<Option<u64>>("max_bytes", "Limit the guest to `max_bytes` of entropy every `period_ms` (so a guest can't drain the host's entropy). Unlimited if None."),
+				field::<Option<u32>>("period_ms", "The period `max_bytes` applies to, in milliseconds. QEMU defaults to 1000.")
+			])
+		])
+	}
+}
+
+impl JsonSchema for Balloon {
+	fn schema() -> Value {
+		object("A memory balloon (virtio-balloon), which lets the host take memory back from an idle guest (see VirtualMachine::set_balloon). A VM can only have one.", vec![
+			field::<String>("id", ""),
+			defaulted::<bool>("free_page_reporting", "Let the guest hand pages it isn't using back to the host on its own, without waiting for the balloon to be inflated."),
+			defaulted::<bool>("deflate_on_oom", "Let the guest deflate the balloon when it's about to run out of memory."),
+			field::<Option<u32>>("stats_interval_secs", "How often the guest reports its memory usage, in seconds (see VirtualMachine::balloon_stats). If None, it doesn't, and only the balloon's own size is known.")
+		])
+	}
+}
+
+impl JsonSchema for NinePShare {
+	fn schema() -> Value {
+		object("A host directory shared with the guest over 9p (virtio-9p).", vec![
+			field::<String>("id", ""),
+			field::<String>("path", "The host directory to share."),
+			field::<String>("mount_tag", "The tag the guest mounts the share by."),
+			field::<NinePSecurityModel>("security_model", ""),
+			defaulted::<bool>("legacy_virtfs", "Use the (deprecated) `-virtfs` shorthand instead of `-fsdev` + `-device`.")
+		])
+	}
+}
+
+impl JsonSchema for NinePSecurityModel {
+	fn schema() -> Value {
+		strings("How a NinePShare maps guest file ownership/permissions onto the host.", &[
+			("Passthrough", "Files are created with the guest's credentials. QEMU must run as root for this to be useful."),
+			("Mapped", "Guest credentials are stored in extended attributes."),
+			("MappedFile", "Guest credentials are stored in hidden files."),
+			("None", "Like passthrough, but failures to set credentials are ignored.")
+		])
+	}
+}
+
+impl JsonSchema for SmartCard {
+	fn schema() -> Value {
+		object("A USB CCID smartcard reader.", vec![
+			defaulted::<String>("id", "Left empty by definitions from before readers had ids, which get one assigned."),
+			field::<SmartCardBackend>("backend", "")
+		])
+	}
+}
+
+impl JsonSchema for SmartCardBackend {
+	fn schema() -> Value {
+		strings("Where a SmartCard reader gets its card from.", &[
+			("Passthrough", "Pass a card from the SPICE client through to the guest. Requires SPICE."),
+			("Emulated", "Emulate a card with certificates from the host's NSS database.")
+		])
+	}
+}
+
+impl JsonSchema for IsaDebugCon {
+	fn schema() -> Value {
+		object("The ISA debug console. OVMF and SeaBIOS write their debug output to this port, which QEMU then logs to the given file.", vec![
+			field::<u16>("iobase", "The I/O port the debug console lives on. 0x402 is what OVMF uses by default."),
+			field::<String>("file_path", "The file debug output will be written to.")
+		])
+	}
+}
+
+impl JsonSchema for Watchdog {
+	fn schema() -> Value {
+		object("A watchdog timer, to notice (and recover from) the guest hanging. QEMU sends a crate::qemu::qmp::QmpEvent::Watchdog event whenever it fires. A VM can only have one.", vec![
+			field::<WatchdogModel>("model", ""),
+			field::<WatchdogAction>("action", "")
+		])
+	}
+}
+
+impl JsonSchema for WatchdogModel {
+	fn schema() -> Value {
+		strings("The watchdog timer hardware the guest sees.", &[
+			("I6300esb", "Intel 6300ESB, a PCI device. Works on any machine type with PCI."),
+			("Itco", "The TCO watchdog built into the Q35 machine's ICH9 chipset.")
+		])
+	}
+}
+
+impl JsonSchema for WatchdogAction {
+	fn schema() -> Value {
+		strings("What QEMU does when the guest stops petting its watchdog.", &[
+			("Reset", ""),
+			("Poweroff", ""),
+			("Pause", "Pause the vCPUs, leaving the VM as it was for inspection."),
+			("Debug", "Only log it, and let the guest carry on.")
+		])
+	}
+}
+
+impl JsonSchema for Pvpanic {
+	fn schema() -> Value {
+		object("A pvpanic device, which the guest kernel uses to tell QEMU it has panicked. The VM is then paused, and goes into VMState::Crashed until it's stopped.", vec![
+			field::<Option<String>>("crash_dump", "Write the guest's memory (as an ELF core) here when it panics, for debugging the crash later.")
+		])
+	}
+}
+
+impl JsonSchema for PciPassthrough {
+	fn schema() -> Value {
+		object("Passes a whole host PCI device (e.g: a GPU, or an NVMe drive) through to the guest with VFIO. Unlike GraphicsAdapter::VgpuVga, the host loses access to the device while the VM is running.", vec![
+			field::<String>("id", ""),
+			field::<String>("host_address", "The host PCI address of the device (e.g: `0000:01:00.0`)."),
+			field::<Option<bool>>("rombar", ""),
+			field::<Option<String>>("romfile", ""),
+			defaulted::<bool>("multifunction", "Expose the device as multifunction, so other functions of the same card (e.g: a GPU's audio function) can sit next to it."),
+			defaulted::<bool>("rebind_driver", "Bind the device to vfio-pci when the VM starts (unbinding the host driver), and give it back to the host driver once the VM stops. If this is off, the device has to already be bound to vfio-pci."),
+			field::<Option<String>>("failover_pair_id", "Make this device the failover primary for the virtio-net adapter with this id (which needs `failover` enabled). Only useful for network cards."),
+			field::<Option<String>>("root_port", "The named root port to place the device on. On Q35, one is allocated if None.")
+		])
+	}
+}
+
+impl JsonSchema for GuestAgent {
+	fn schema() -> Value {
+		object("A virtio-serial channel for the QEMU guest agent, which Sunlight can talk to through VirtualMachine::guest_agent. The guest has to be running qemu-ga.", vec![
+			field::<String>("id", ""),
+			field::<String>("socket_path", "The socket QEMU will listen on for the host side of the channel.")
+		])
+	}
+}
+
+impl JsonSchema for SerialConsole {
+	fn schema() -> Value {
+		object("A serial port (e.g: COM1 or ttyS0), which VirtualMachine::console can connect to.", vec![
+			field::<String>("id", ""),
+			field::<SerialBackend>("backend", "")
+		])
+	}
+}
+
+impl JsonSchema for SerialBackend {
+	fn schema() -> Value {
+		tagged("type", "Where a SerialConsole's output goes (and its input comes from).", vec![
+			variant("Stdio", "QEMU's own standard input and output, i.e: the terminal Sunlight was started from. Only one console can use this.", Vec::new()),
+			variant("Pty", "A pseudo-terminal, which QEMU creates.", Vec::new()),
+			variant("UnixSocket", "A UNIX socket QEMU listens on.", vec![
+				field::<String>("path", "")
+			]),
+			variant("LogFile", "A file output is appended to. Nothing can be sent to the guest.", vec![
+				field::<String>("path", "")
+			])
+		])
+	}
+}
+
+impl JsonSchema for VirtioSerialChannel {
+	fn schema() -> Value {
+		object("A named virtio-serial port, for custom host <-> guest channels (e.g: a home-grown guest agent). The guest sees it as `/dev/virtio-ports/{name}`, and the host connects with VirtualMachine::channel.", vec![
+			field::<String>("id", ""),
+			field::<String>("name", "The port name the guest sees (e.g: `org.example.agent.0`)."),
+			field::<SerialBackend>("backend", "")
+		])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::BTreeMap;
+
+	/// The names of a string enum's variants, or a tagged enum's.
+	fn variant_names(schema: &Value, tag: Option<&str>) -> Vec<String> {
+		schema["oneOf"].as_array().unwrap().iter()
+			.map(|variant| match tag {
+				Some(tag) => &variant["properties"][tag]["const"],
+				None => &variant["const"]
+			})
+			.map(|name| String::from(name.as_str().unwrap()))
+			.collect()
+	}
+
+	#[test]
+	fn schema_includes_enum_variants() {
+		let schema = VmConfig::json_schema();
+		let machine = &schema["properties"]["machine"];
+		assert_eq!(variant_names(machine, Some("type")), ["Pc", "Q35", "S390CcwVirtio"]);
+		assert!(schema["required"].as_array().unwrap().contains(&json!("machine")));
+
+		let drives = DeviceConfig::schema();
+		let drive = drives["oneOf"].as_array().unwrap().iter()
+			.find(|device| device["allOf"][0]["properties"]["device"]["const"] == "DiskDrive")
+			.unwrap();
+		let hd = drive["allOf"][1]["oneOf"].as_array().unwrap().iter()
+			.find(|variant| variant["properties"]["type"]["const"] == "HdDrive")
+			.unwrap();
+		assert_eq!(variant_names(&hd["properties"]["format"], None), ["qcow2", "raw", "vmdk", "vdi", "vhdx", "vpc"]);
+		assert!(!hd["properties"]["num_queues"]["description"].as_str().unwrap().is_empty());
+	}
+
+	/// Whether `value` matches `schema`, for the keywords this module uses. Patterns aren't checked.
+	fn conforms(value: &Value, schema: &Value) -> bool {
+		let type_matches = match schema["type"].as_str() {
+			Some("object") => value.is_object(),
+			Some("array") => value.is_array(),
+			Some("string") => value.is_string(),
+			Some("boolean") => value.is_boolean(),
+			Some("integer") => value.is_i64() || value.is_u64(),
+			_ => true
+		};
+
+		if !type_matches || schema.get("const").is_some_and(|constant| value != constant) {
+			return false;
+		}
+
+		if let Some(number) = value.as_f64() {
+			let below = schema["minimum"].as_f64().is_some_and(|minimum| number < minimum);
+			let above = schema["maximum"].as_f64().is_some_and(|maximum| number > maximum);
+			if below || above {
+				return false;
+			}
+		}
+
+		if let Some(object) = value.as_object() {
+			let properties = schema["properties"].as_object();
+			let property = |key: &str| properties.and_then(|properties| properties.get(key));
+
+			let missing = schema["required"].as_array().is_some_and(|required| {
+				required.iter().any(|name| !object.contains_key(name.as_str().unwrap()))
+			});
+			let unknown = schema["additionalProperties"] == json!(false) && object.keys().any(|key| property(key).is_none());
+			let invalid = object.iter().any(|(key, field)| property(key).is_some_and(|property| !conforms(field, property)));
+
+			if missing || unknown || invalid {
+				return false;
+			}
+		}
+
+		if let Some(items) = value.as_array() {
+			if items.iter().any(|item| !conforms(item, &schema["items"])) {
+				return false;
+			}
+		}
+
+		let all = schema["allOf"].as_array().cloned().unwrap_or_default();
+		let one = schema["oneOf"].as_array().cloned().unwrap_or_default();
+		all.iter().all(|part| conforms(value, part))
+			&& (one.is_empty() || one.iter().filter(|part| conforms(value, part)).count() == 1)
+	}
+
+	/// Breaks a VM definition in some way.
+	type Breakage = fn(&mut Value);
+
+	/// A saved VM definition with a disk, as JSON.
+	fn saved_definition() -> Value {
+		let mut vm = VirtualMachine::modern_linux_defaults("schema").unwrap();
+		vm.add_drive(DiskDrive::Nvme {
+			id: String::from("root"),
+			image_path: String::from("/tmp/root.qcow2"),
+			format: DiskFormat::Qcow2,
+			serial: None,
+			namespaces: Vec::new()
+		});
+
+		toml::from_str(&vm.to_config().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn saved_definitions_match_the_schema() {
+		let schema = VmConfig::json_schema();
+		let definition = saved_definition();
+
+		assert!(conforms(&definition, &schema));
+		assert!(serde_json::from_value::<VmConfig>(definition).is_ok());
+	}
+
+	#[test]
+	fn malformed_definitions_are_rejected() {
+		let schema = VmConfig::json_schema();
+
+		// Each of these is broken in a way loading it would also trip over
+		let broken: Vec<(&str, Breakage)> = vec![
+			("no machine", |config| { config.as_object_mut().unwrap().remove("machine"); }),
+			("unknown machine type", |config| config["machine"]["type"] = json!("Isapc")),
+			("machine field missing", |config| { config["machine"].as_object_mut().unwrap().remove("acpi"); }),
+			("boolean as a string", |config| config["serial_bios"] = json!("yes")),
+			("unknown device", |config| config["devices"][0]["device"] = json!("Floppy")),
+			("integer out of range", |config| config["devices"][0]["core_count"] = json!(1000)),
+			("unknown disk format", |config| config["drives"][0]["format"] = json!("qcow3")),
+			("devices not a list", |config| config["devices"] = json!({}))
+		];
+
+		for (problem, breaking) in broken {
+			let mut definition = saved_definition();
+			breaking(&mut definition);
+
+			assert!(!conforms(&definition, &schema), "the schema allows a definition with {problem}");
+			assert!(serde_json::from_value::<VmConfig>(definition).is_err(), "a definition with {problem} loads");
+		}
+	}
+
+	#[test]
+	fn optional_fields_arent_required() {
+		let schema = Cpu::schema();
+		let required = schema["required"].as_array().unwrap();
+		assert!(required.contains(&json!("model")));
+		assert!(!required.contains(&json!("dies")));
+		assert_eq!(schema["properties"]["dies"]["type"], "integer");
+	}
+
+	/// A VM with every device, and every variant of the enums devices use. Options are all set,
+	/// so every field gets saved.
+	fn every_device() -> VirtualMachine {
+		let string = |value: &str| Some(String::from(value));
+		let everything = IoLimits {
+			iops_total: Some(500), iops_read: Some(400), iops_write: Some(300),
+			bps_total: Some(5 << 20), bps_read: Some(4 << 20), bps_write: Some(3 << 20)
+		};
+		let serial_backends = [
+			SerialBackend::Stdio,
+			SerialBackend::Pty,
+			SerialBackend::UnixSocket { path: String::from("/tmp/serial.sock") },
+			SerialBackend::LogFile { path: String::from("/tmp/serial.log") }
+		];
+
+		let mut vm = VirtualMachine::new("schema").unwrap();
+		vm.set_uuid("00000000-0000-0000-0000-000000000003")
+			.set_sandbox(Sandbox { deny_obsolete: true, deny_elevate_privileges: true, deny_spawn: true, deny_resource_control: true })
+			.set_qemu_log(vec![
+				LogCategory::OutAsm, LogCategory::InAsm, LogCategory::Op, LogCategory::OpOpt,
+				LogCategory::OpInd, LogCategory::Int, LogCategory::Exec, LogCategory::Cpu,
+				LogCategory::Fpu, LogCategory::Mmu, LogCategory::Pcall, LogCategory::CpuReset,
+				LogCategory::Unimp, LogCategory::GuestErrors, LogCategory::Page, LogCategory::Nochain
+			], "/tmp/qemu.log")
+			.set_numa_topology(NumaTopology {
+				nodes: vec![
+					NumaNode { size: ByteSize::gib(2), backend: Some(MemoryBackend::Ram { share: true }), prealloc: true, legacy_mem: false, cpus: vec![0, 1], initiator: Some(0) },
+					NumaNode { size: ByteSize::gib(2), backend: Some(MemoryBackend::Memfd { share: true, hugepage_size: Some(ByteSize::mib(2)) }), prealloc: false, legacy_mem: true, cpus: Vec::new(), initiator: Some(0) }
+				],
+				hmat: [
+					HmatDataType::AccessLatency, HmatDataType::ReadLatency, HmatDataType::WriteLatency,
+					HmatDataType::AccessBandwidth, HmatDataType::ReadBandwidth, HmatDataType::WriteBandwidth
+				].into_iter()
+					.map(|data_type| HmatLatencyBandwidth { initiator: 0, target: 1, data_type, latency_ns: Some(10), bandwidth: Some(ByteSize::gib(10)) })
+					.collect()
+			})
+			.enable_serial_bios()
+			.set_virtio_serial_max_ports(16)
+			.set_boot(BootConfig {
+				order: vec![BootDevice::Drive(String::from("hd0")), BootDevice::Network(String::from("nic0"))],
+				menu: true,
+				menu_timeout_ms: Some(3000),
+				splash: string("/tmp/splash.bmp"),
+				strict: true
+			})
+			.set_qemu_binary("/usr/bin/qemu-system-x86_64")
+			.set_state_dir("/tmp/state")
+			.reserve_hotplug_port("hotplug0")
+			.add_root_port("port0")
+			.add_throttle_group(ThrottleGroup { id: String::from("disks"), limits: everything.clone() })
+			.add_secret(Secret { id: String::from("luks"), source: SecretSource::File { path: String::from("/tmp/luks") } })
+			.add_secret(Secret { id: String::from("vnc"), source: SecretSource::Inline { data: String::from("hunter2") } })
+			.add_secret(Secret { id: String::from("keyring"), source: SecretSource::Keyring { serial: 42 } });
+
+		vm.add_device(Cpu {
+			model: String::from("host"),
+			features: vec![String::from("+avx2")],
+			core_count: 2,
+			dies: Some(1),
+			sockets: Some(1),
+			threads: Some(1),
+			vcpus: Some(2),
+			max_vcpus: Some(4),
+			host_cache_info: true
+		})
+			.add_device(Memory {
+				size: ByteSize::gib(4),
+				prealloc: true,
+				backend: Some(MemoryBackend::File { mem_path: String::from("/dev/hugepages"), share: true, hugepage_size: Some(ByteSize::gib(1)) }),
+				max_size: Some(ByteSize::gib(8)),
+				slots: Some(2)
+			})
+			.add_device(DiskController::VirtioScsi { id: String::from("scsi0") })
+			.add_device(UsbController::Xhci { id: String::from("xhci0"), usb2_ports: Some(4), usb3_ports: Some(4) })
+			.add_device(UsbController::NecXhci { id: String::from("xhci1"), usb2_ports: Some(4), usb3_ports: Some(4) })
+			.add_device(UsbController::Uhci { id: String::from("uhci0") })
+			.add_device(UsbDevice::Storage { id: String::from("stick0"), image_path: String::from("/tmp/stick.img"), format: DiskFormat::Raw, readonly: true })
+			.add_device(UsbDevice::HostById { id: String::from("host0"), vendor_id: 0x1234, product_id: 0x5678 })
+			.add_device(UsbDevice::HostByPort { id: String::from("host1"), bus: 1, port: String::from("2.1") })
+			.add_device(InputDevice::UsbMouse)
+			.add_device(InputDevice::UsbKeyboard)
+			.add_device(InputDevice::UsbTablet)
+			.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(16) })
+			.add_device(GraphicsAdapter::CirrusVga { vgamem: ByteSize::mib(4) })
+			.add_device(GraphicsAdapter::QxlVga {})
+			.add_device(GraphicsAdapter::VirtioGpu { blob: true, hostmem: Some(ByteSize::mib(256)), vga: true, virgl: true, venus: true })
+			.add_device(GraphicsAdapter::VgpuVga {
+				uuid: String::from("00000000-0000-0000-0000-000000000004"),
+				use_ramfb: true,
+				pci_vendor_id: string("0x10de"),
+				pci_device_id: string("0x1eb8"),
+				pci_sub_vendor_id: string("0x10de"),
+				pci_sub_device_id: string("0x12a2"),
+				rombar: Some(false),
+				romfile: string("/tmp/vgpu.rom"),
+				root_port: string("port0")
+			})
+			.add_device(Network::User {
+				id: String::from("user0"),
+				host_forwards: vec![
+					PortForward { proto: ForwardProtocol::Tcp, host_addr: string("127.0.0.1"), host_port: 2222, guest_addr: string("10.0.2.15"), guest_port: 22 },
+					PortForward { proto: ForwardProtocol::Udp, host_addr: None, host_port: 5353, guest_addr: None, guest_port: 53 }
+				]
+			})
+			.add_device(Network::Tap { id: String::from("tap0"), dev: String::from("tap0"), managed: Some(ManagedTap { bridge: string("br0") }), queues: Some(2) })
+			.add_device(Network::Bridge { id: String::from("bridge0"), bridge: String::from("br0"), helper: string("/usr/lib/qemu/qemu-bridge-helper") })
+			.add_device(Network::VhostUser { id: String::from("vhost0"), socket_path: String::from("/tmp/vhost.sock"), queues: Some(2) })
+			.add_device(NetworkAdapter::Virtio {
+				id: String::from("nic0"),
+				netdev: String::from("user0"),
+				mac: Some("52:54:00:12:34:56".parse().unwrap()),
+				rombar: Some(true),
+				romfile: string("/tmp/efi-virtio.rom"),
+				failover: true,
+				host_mtu: Some(9000),
+				offloads: VirtioNetOffloads {
+					csum: Some(true), guest_csum: Some(true), gso: Some(true),
+					guest_tso4: Some(true), guest_tso6: Some(true), guest_ecn: Some(true), guest_ufo: Some(true),
+					host_tso4: Some(true), host_tso6: Some(true), host_ecn: Some(true), host_ufo: Some(true),
+					mrg_rxbuf: Some(true)
+				},
+				queues: Some(2)
+			})
+			.add_device(NetworkAdapter::Rtl8139 { id: String::from("nic1"), netdev: String::from("tap0"), mac: Some(MacAddr::derive("00000000-0000-0000-0000-000000000003", "nic1")), rombar: Some(false), romfile: string("/tmp/nic.rom") })
+			.add_device(NetworkAdapter::E1000 { id: String::from("nic2"), netdev: String::from("tap0"), mac: Some(MacAddr::derive("00000000-0000-0000-0000-000000000003", "nic2")), rombar: Some(false), romfile: string("/tmp/nic.rom") })
+			.add_device(NetworkAdapter::E1000e { id: String::from("nic3"), netdev: String::from("tap0"), mac: Some(MacAddr::derive("00000000-0000-0000-0000-000000000003", "nic3")), rombar: Some(false), romfile: string("/tmp/nic.rom") })
+			.add_device(NetworkAdapter::Vmxnet3 { id: String::from("nic4"), netdev: String::from("tap0"), mac: Some(MacAddr::derive("00000000-0000-0000-0000-000000000003", "nic4")), rombar: Some(false), romfile: string("/tmp/nic.rom") })
+			.add_device(Tpm::managed(TpmInterface::Tis, "schema"))
+			.add_device(Tpm { interface: TpmInterface::Crb, socket_path: String::from("/tmp/swtpm.sock"), managed: false })
+			.add_device(VirtioInputHost { id: String::from("input0"), evdev_path: String::from("/dev/input/event3") })
+			.add_device(VirtioCrypto { id: String::from("crypto0") })
+			.add_device(Vsock { id: String::from("vsock0"), guest_cid: 3 })
+			.add_device(Rng::Virtio { id: String::from("rng0"), source: string("/dev/urandom"), max_bytes: Some(1024), period_ms: Some(1000) })
+			.add_device(Balloon { id: String::from("balloon0"), free_page_reporting: true, deflate_on_oom: true, stats_interval_secs: Some(5) })
+			.add_device(IsaDebugCon::new("/tmp/debugcon.log"))
+			.add_device(Pvpanic { crash_dump: string("/tmp/crash") })
+			.add_device(PciPassthrough {
+				id: String::from("gpu0"),
+				host_address: String::from("0000:01:00.0"),
+				rombar: Some(true),
+				romfile: string("/tmp/gpu.rom"),
+				multifunction: true,
+				rebind_driver: true,
+				failover_pair_id: string("nic0"),
+				root_port: string("port0")
+			})
+			.add_device(GuestAgent { id: String::from("ga0"), socket_path: String::from("/tmp/ga.sock") })
+			.add_device(VirtioSerialChannel { id: String::from("channel0"), name: String::from("org.example.agent.0"), backend: SerialBackend::Pty });
+
+		let security_models = [NinePSecurityModel::Passthrough, NinePSecurityModel::Mapped, NinePSecurityModel::MappedFile, NinePSecurityModel::None];
+		for (i, security_model) in security_models.into_iter().enumerate() {
+			vm.add_device(NinePShare { id: format!("share{i}"), path: String::from("/tmp/share"), mount_tag: format!("share{i}"), security_model, legacy_virtfs: i == 0 });
+		}
+
+		for (i, backend) in [SmartCardBackend::Passthrough, SmartCardBackend::Emulated].into_iter().enumerate() {
+			vm.add_device(SmartCard { id: format!("ccid{i}"), backend });
+		}
+
+		let actions = [WatchdogAction::Reset, WatchdogAction::Poweroff, WatchdogAction::Pause, WatchdogAction::Debug];
+		for (i, action) in actions.into_iter().enumerate() {
+			let model = if i % 2 == 0 { WatchdogModel::I6300esb } else { WatchdogModel::Itco };
+			vm.add_device(Watchdog { model, action });
+		}
+
+		for (i, backend) in serial_backends.into_iter().enumerate() {
+			vm.add_device(SerialConsole { id: format!("serial{i}"), backend });
+		}
+
+		#[cfg(feature = "testing")]
+		vm.add_device(EduDevice);
+
+		let formats = [DiskFormat::Qcow2, DiskFormat::Raw, DiskFormat::Vmdk, DiskFormat::Vdi, DiskFormat::Vhdx, DiskFormat::Vpc];
+		let caches = [CacheMode::Writeback, CacheMode::Writethrough, CacheMode::None, CacheMode::Unsafe, CacheMode::DirectSync];
+		let aios = [AioMode::Threads, AioMode::Native, AioMode::IoUring];
+		let interfaces = [DiskInterface::Ide, DiskInterface::Scsi, DiskInterface::Virtio];
+		let detect_zeroes = [DetectZeroes::Off, DetectZeroes::On, DetectZeroes::Unmap];
+		for (i, format) in formats.into_iter().enumerate() {
+			vm.add_drive(DiskDrive::HdDrive {
+				id: format!("hd{i}"),
+				interface: interfaces[i % interfaces.len()].clone(),
+				image_path: format!("/tmp/hd{i}.img"),
+				readonly: true,
+				format,
+				ssd: true,
+				cache: Some(caches[i % caches.len()]),
+				aio: Some(aios[i % aios.len()]),
+				throttle_group: string("disks"),
+				throttle: Some(everything.clone()),
+				share_rw: true,
+				logical_block_size: Some(512),
+				physical_block_size: Some(4096),
+				discard: true,
+				detect_zeroes: Some(detect_zeroes[i % detect_zeroes.len()]),
+				iothread: true,
+				num_queues: Some(4),
+				backing: string("/tmp/base.qcow2"),
+				encryption: Some(LuksConfig { secret: String::from("luks") })
+			});
+		}
+
+		vm.add_drive(DiskDrive::CdDrive { interface: DiskInterface::Ide, id: String::from("cd0"), iso_path: string("/tmp/install.iso") })
+			.add_drive(DiskDrive::Nvme {
+				id: String::from("nvme0"),
+				image_path: String::from("/tmp/nvme.qcow2"),
+				format: DiskFormat::Qcow2,
+				serial: string("deadbeef"),
+				namespaces: vec![NvmeNamespace { nsid: 2, image_path: String::from("/tmp/ns2.raw"), format: DiskFormat::Raw }]
+			})
+			.add_drive(DiskDrive::Pflash { id: String::from("pflash0"), image_path: String::from("/tmp/vars.fd"), readonly: false, format: DiskFormat::Raw })
+			.add_drive(NvmeSubsystem {
+				id: String::from("subsys0"),
+				nqn: String::from("nqn.2019-08.org.qemu:sunlight"),
+				namespaces: vec![NvmeNamespace { nsid: 1, image_path: String::from("/tmp/ns1.qcow2"), format: DiskFormat::Qcow2 }]
+			});
+
+		vm
+	}
+
+	/// Saved definitions of [every_device], plus VMs which between them use every variant of the
+	/// VM-wide enums (machine types, display backends, ...), as JSON.
+	fn every_definition() -> Vec<Value> {
+		let string = |value: &str| Some(String::from(value));
+		let archs = [Architecture::X86_64, Architecture::S390x];
+		let accels = [
+			Accelerator::Kvm,
+			Accelerator::Tcg { multithreaded: Some(true), tb_size_mb: Some(256) },
+			Accelerator::Hvf,
+			Accelerator::Whpx,
+			Accelerator::Auto
+		];
+		let machines = [
+			MachineType::Pc { acpi: true, usb: true },
+			MachineType::Q35 { acpi: true, usb: true, hmat: true, legacy_root_port: true },
+			MachineType::S390CcwVirtio
+		];
+		let displays = [
+			DisplayBackend::None,
+			DisplayBackend::EglHeadless { rendernode: string("/dev/dri/renderD128") },
+			DisplayBackend::Gtk { gl: true },
+			DisplayBackend::Sdl { gl: true },
+			DisplayBackend::DbusP2p { gl: true },
+			DisplayBackend::Spice {
+				port: Some(5930),
+				addr: string("127.0.0.1"),
+				unix_socket: string("/tmp/spice.sock"),
+				tls: Some(SpiceTls { port: 5931, x509_dir: String::from("/tmp/pki") }),
+				disable_ticketing: true,
+				password_file: string("/tmp/spice.pass"),
+				agent: true,
+				usb_redirect: 2,
+				gl: true,
+				rendernode: string("/dev/dri/renderD128")
+			},
+			DisplayBackend::Vnc { listen: string("127.0.0.1"), port: 5900, password_secret: string("/tmp/vnc.pass"), websocket: Some(5700) }
+		];
+		let audios = [
+			Audio::IntelHda { controller: Some(HdaController::Ich9) },
+			Audio::IntelHda { controller: Some(HdaController::Ich6) },
+			Audio::Ac97,
+			Audio::VirtioSound
+		];
+		let audio_backends = [
+			AudioBackend::None,
+			AudioBackend::Pipewire,
+			AudioBackend::PulseAudio { server: string("unix:/run/pulse/native") },
+			AudioBackend::Alsa { device: string("hw:0") }
+		];
+		let firmwares = [
+			Firmware::Bios,
+			Firmware::Uefi { code_path: String::from("/tmp/OVMF_CODE.fd"), vars_template: String::from("/tmp/OVMF_VARS.fd") }
+		];
+
+		(0..displays.len())
+			.map(|i| {
+				let mut vm = every_device();
+				vm.set_architecture(archs[i % archs.len()].clone())
+					.set_accelerator(accels[i % accels.len()].clone())
+					.set_machine_type(machines[i % machines.len()].clone())
+					.set_display_backend(displays[i].clone())
+					.set_audio(audios[i % audios.len()].clone())
+					.set_audio_backend(audio_backends[i % audio_backends.len()].clone())
+					.set_firmware(firmwares[i % firmwares.len()].clone());
+
+				toml::from_str(&vm.to_config().unwrap()).unwrap()
+			})
+			.collect()
+	}
+
+	/// What a oneOf alternative is called in [uncovered]'s report: the constant it's picked by.
+	fn alternative_name(alternative: &Value) -> String {
+		if let Some(name) = alternative["const"].as_str() {
+			return String::from(name);
+		}
+
+		let properties = alternative["properties"].as_object().into_iter().flatten();
+		let tag = properties.filter_map(|(_, property)| property["const"].as_str()).next();
+		let key = alternative["required"][0].as_str();
+		match (tag, key) {
+			(Some(name), _) | (None, Some(name)) => String::from(name),
+			(None, None) => alternative_name(&alternative["allOf"][0])
+		}
+	}
+
+	/// Whether `schema` describes `key` for `value`, in its own properties or those of
+	/// the allOf parts and oneOf alternatives `value` matches.
+	fn describes(schema: &Value, value: &Value, key: &str) -> bool {
+		let parts = schema["allOf"].as_array().into_iter().flatten()
+			.chain(schema["oneOf"].as_array().into_iter().flatten().filter(|part| conforms(value, part)));
+
+		schema["properties"].get(key).is_some() || parts.into_iter().any(|part| describes(part, value, key))
+	}
+
+	/// A copy of `schema` without its descriptions. Fields of the same type are described
+	/// differently (e.g: a DiskFormat on two devices), but they're still the same part.
+	fn without_descriptions(schema: &Value) -> Value {
+		match schema {
+			Value::Object(object) => object.iter()
+				.filter(|(key, _)| *key != "description")
+				.map(|(key, value)| (key.clone(), without_descriptions(value)))
+				.collect(),
+			Value::Array(items) => items.iter().map(without_descriptions).collect(),
+			other => other.clone()
+		}
+	}
+
+	/// A part of a schema, where it was first seen, and the values which were checked against it.
+	type Exercised<'a> = BTreeMap<String, (String, &'a Value, Vec<&'a Value>)>;
+
+	/// Walk `value` through `schema`, collecting which values each part of the schema saw into `parts`.
+	/// Keys the schema doesn't describe go in `gaps`. `whole` is false for allOf parts and oneOf
+	/// alternatives, which only describe some of a value's keys.
+	fn exercise<'a>(value: &'a Value, schema: &'a Value, path: &str, whole: bool, parts: &mut Exercised<'a>, gaps: &mut Vec<String>) {
+		parts.entry(without_descriptions(schema).to_string())
+			.or_insert_with(|| (String::from(path), schema, Vec::new()))
+			.2.push(value);
+
+		for (key, field) in value.as_object().into_iter().flatten() {
+			if whole && !describes(schema, value, key) {
+				gaps.push(format!("{path}.{key} (not in the schema)"));
+			}
+
+			if let Some(property) = schema["properties"].get(key) {
+				exercise(field, property, &format!("{path}.{key}"), true, parts, gaps);
+			}
+		}
+
+		for item in value.as_array().into_iter().flatten() {
+			exercise(item, &schema["items"], &format!("{path}[]"), true, parts, gaps);
+		}
+
+		for part in schema["allOf"].as_array().into_iter().flatten() {
+			exercise(value, part, path, false, parts, gaps);
+		}
+
+		for alternative in schema["oneOf"].as_array().into_iter().flatten().filter(|alternative| conforms(value, alternative)) {
+			exercise(value, alternative, &format!("{path}:{}", alternative_name(alternative)), false, parts, gaps);
+		}
+	}
+
+	/// Every part of `schema` which none of `values` exercises (oneOf alternatives nothing matches,
+	/// properties which are never given, lists which are always empty), and every key in `values`
+	/// which the schema doesn't describe.
+	fn uncovered(values: &[Value], schema: &Value) -> Vec<String> {
+		let mut parts = Exercised::new();
+		let mut gaps = Vec::new();
+		for value in values {
+			exercise(value, schema, "VmConfig", true, &mut parts, &mut gaps);
+		}
+
+		for (path, schema, values) in parts.values() {
+			for alternative in schema["oneOf"].as_array().into_iter().flatten() {
+				if !values.iter().any(|value| conforms(value, alternative)) {
+					gaps.push(format!("{path}:{}", alternative_name(alternative)));
+				}
+			}
+
+			for key in schema["properties"].as_object().into_iter().flat_map(|properties| properties.keys()) {
+				if !values.iter().any(|value| value.get(key).is_some()) {
+					gaps.push(format!("{path}.{key}"));
+				}
+			}
+
+			if schema.get("items").is_some() && !values.iter().any(|value| value.as_array().is_some_and(|items| !items.is_empty())) {
+				gaps.push(format!("{path}[]"));
+			}
+		}
+
+		gaps.sort();
+		gaps.dedup();
+		gaps
+	}
+
+	#[test]
+	fn every_device_and_variant_matches_the_schema() {
+		let schema = VmConfig::json_schema();
+		let definitions = every_definition();
+
+		for definition in &definitions {
+			assert!(conforms(definition, &schema), "{definition:#}");
+			assert!(serde_json::from_value::<VmConfig>(definition.clone()).is_ok());
+		}
+
+		let gaps = uncovered(&definitions, &schema);
+		assert!(gaps.is_empty(), "not covered by every_definition: {gaps:#?}");
+
+		// A device missing from both the schema and every_device wouldn't be noticed above
+		let saved = serde_json::from_value::<VmConfig>(definitions[0].clone()).unwrap();
+		let mut devices = saved.devices.iter().chain(&saved.drives)
+			.map(|device| match device {
+				DeviceConfig::Cpu(..) => "Cpu",
+				DeviceConfig::Memory(..) => "Memory",
+				DeviceConfig::DiskController(..) => "DiskController",
+				DeviceConfig::UsbController(..) => "UsbController",
+				DeviceConfig::UsbDevice(..) => "UsbDevice",
+				DeviceConfig::InputDevice(..) => "InputDevice",
+				DeviceConfig::DiskDrive(..) => "DiskDrive",
+				DeviceConfig::NvmeSubsystem(..) => "NvmeSubsystem",
+				DeviceConfig::GraphicsAdapter(..) => "GraphicsAdapter",
+				DeviceConfig::Network(..) => "Network",
+				DeviceConfig::NetworkAdapter(..) => "NetworkAdapter",
+				DeviceConfig::Tpm(..) => "Tpm",
+				DeviceConfig::VirtioInputHost(..) => "VirtioInputHost",
+				#[cfg(feature = "testing")]
+				DeviceConfig::EduDevice(..) => "EduDevice",
+				DeviceConfig::VirtioCrypto(..) => "VirtioCrypto",
+				DeviceConfig::Vsock(..) => "Vsock",
+				DeviceConfig::Rng(..) => "Rng",
+				DeviceConfig::Balloon(..) => "Balloon",
+				DeviceConfig::NinePShare(..) => "NinePShare",
+				DeviceConfig::SmartCard(..) => "SmartCard",
+				DeviceConfig::IsaDebugCon(..) => "IsaDebugCon",
+				DeviceConfig::Watchdog(..) => "Watchdog",
+				DeviceConfig::Pvpanic(..) => "Pvpanic",
+				DeviceConfig::PciPassthrough(..) => "PciPassthrough",
+				DeviceConfig::GuestAgent(..) => "GuestAgent",
+				DeviceConfig::SerialConsole(..) => "SerialConsole",
+				DeviceConfig::VirtioSerialChannel(..) => "VirtioSerialChannel"
+			})
+			.collect::<Vec<&str>>();
+		devices.sort_unstable();
+		devices.dedup();
+
+		let mut described = DeviceConfig::schema()["oneOf"].as_array().unwrap().iter()
+			.map(alternative_name)
+			.collect::<Vec<String>>();
+		described.sort_unstable();
+		assert_eq!(described, devices);
+	}
+}
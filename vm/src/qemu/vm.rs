@@ -1,481 +1,10966 @@
+use super::binary::QemuBinary;
+use super::config::*;
+use super::dbus::DbusDisplay;
 use super::enums::*;
+use super::ga::{self, GuestAgentClient};
+use super::image::Image;
+use super::qmp::{self, BlockJob, MemoryDump, QmpClient, QmpEvent};
+use futures::stream::{BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::process::*;
 
 pub(crate) fn bool_to_qemu(val: bool) -> String {
 	if val {
 		return String::from("on");
 	}
-	return String::from("off");
+	String::from("off")
+}
+
+/// Escapes a value for QEMU's option parser, which uses commas as separators (so a literal comma is written `,,`).
+/// Anything user-provided which ends up inside a `key=value,...` list (like paths) should go through this.
+pub(crate) fn qemu_escape(value: &str) -> String {
+	value.replace(',', ",,")
+}
+
+/// Builds a QEMU flag and its value, as separate arguments.
+pub(crate) fn qemu_arg(flag: &str, value: impl Into<String>) -> Vec<String> {
+	vec![String::from(flag), value.into()]
+}
+
+/// How long [VirtualMachine::shutdown] waits at each step before escalating, unless
+/// changed with [VirtualMachine::set_shutdown_timeout].
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long [VirtualMachine::hot_unplug] waits for the guest to let go of a device.
+pub const HOT_UNPLUG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// [QemuOption::order_key] for options which other devices depend on.
+pub const ORDER_EARLY: u32 = 10;
+
+/// [QemuOption::order_key] for everything else.
+pub const ORDER_DEFAULT: u32 = 50;
+
+/// Lets a boxed [QemuOption] be turned back into the concrete device it is
+/// (see [VirtualMachine::devices_of]). Implemented for everything.
+pub trait AsAny: Any {
+	fn as_any(&self) -> &dyn Any;
+	fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn as_any_mut(&mut self) -> &mut dyn Any {
+		self
+	}
 }
 
 /// trait that objects which want to convert to QEMU options implement
-pub trait QemuOption {
+pub trait QemuOption: AsAny {
 
-	/// Returns QEMU arguments 
-	fn as_options(&self) -> String;
+	/// Returns QEMU arguments. Each flag and its value are separate entries,
+	/// so these can be passed to the process API as-is.
+	fn as_options(&self) -> Vec<String>;
 
 	/// Validate that the options generated will actually work. The base implementation
 	/// provided inside the trait definition is good enough for most cases, but anything
 	/// more complex will need more complex validation logic.
-	fn validate(&self, _machine: &VirtualMachine) -> bool {
-		true
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		Ok(())
 	}
 
-}
+	/// Anything about these options which will work, but probably isn't what was wanted
+	/// (or is dangerous). These don't stop the VM from starting.
+	fn warnings(&self, _machine: &VirtualMachine) -> Vec<ValidationWarning> {
+		Vec::new()
+	}
 
-pub enum MachineType {
-	/// PC machine type. Uses a i440fx chipset.
-	Pc {
-		acpi: bool,
-		usb: bool
-	},
+	/// Where this option goes relative to the others. Options with a lower key are emitted first,
+	/// so things other devices refer to (like controllers and netdevs) should use [ORDER_EARLY].
+	fn order_key(&self) -> u32 {
+		ORDER_DEFAULT
+	}
 
-	/// Q35 machine type. Preferrable for VMs running more modern guests, and required for vGPU support.
-	Q35 {
-		acpi: bool,
-		usb: bool,
-		hmat: bool
+	/// A human-readable label for this option (e.g: `GraphicsAdapter::StdVga`), used to annotate
+	/// the generated command line. Enums should override this to include the variant.
+	fn label(&self) -> String {
+		let name = std::any::type_name::<Self>();
+		String::from(name.rsplit("::").next().unwrap_or(name))
 	}
-}
 
-pub struct Cpu {
-	/// The CPU model.
-	pub model : String,
+	/// Make a boxed copy of this option, used when cloning a whole VM. Options
+	/// which can't be copied return None (which is the default).
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		None
+	}
 
-	/// CPU features. Later on, these can be typed/exclusions.
-	/// For now, I don't care.
-	pub features : Vec<String>,
-	
-	pub core_count: i8
-}
+	/// Describe this device for a [VmConfig], used when saving a VM definition.
+	/// Devices which can't be described return None (which is the default).
+	fn to_config(&self) -> Option<DeviceConfig> {
+		None
+	}
 
-pub struct Memory {
-	pub size: String,
-	pub prealloc: bool
-}
+	/// The prefix used when automatically assigning this device an id (e.g: `net` for `net0`).
+	/// Devices which don't carry an id return None.
+	fn id_prefix(&self) -> Option<&'static str> {
+		None
+	}
 
-pub enum Snapshot {
-	NoSnapshots,
+	/// Give this device the automatically assigned id `{prefix}{index}`, if it was left empty.
+	fn assign_id(&mut self, _index: usize) {}
 
-	/// This uses the QEMU `-snapshot` option. This creates a temporary
-	/// backing file in /var/tmp, where all data differences will be stored 
-	/// (and deleted when the VM shuts down.)
-	HdSnapshot,
+	/// The id this device was given (or assigned). Devices which don't carry an id return None.
+	fn device_id(&self) -> Option<&str> {
+		None
+	}
 
-}
+	/// Whether this device sits on a PCI bus, which some architectures don't have.
+	fn requires_pci(&self) -> bool {
+		false
+	}
 
-pub enum DiskInterface {
-	/// IDE (or SATA if using the q35 machine type.)
-	Ide, 
+	/// Whether this device should get a PCIe root port of its own on Q35 (e.g: passthrough
+	/// devices, which need a PCIe slot for the guest to see their PCIe capabilities).
+	fn wants_root_port(&self) -> bool {
+		false
+	}
 
-	/// SCSI (incl. VirtIO SCSI).
-	Scsi
+	/// The named root port (see [VirtualMachine::add_root_port]) this device asked to be placed on, if any.
+	/// Devices which want a root port and don't ask for one get a root port allocated for them.
+	fn requested_root_port(&self) -> Option<&str> {
+		None
+	}
+
+	/// Like [QemuOption::as_options], but for the device placed on the given PCIe root port.
+	/// Only used for devices which want a root port.
+	fn as_options_on_port(&self, _port: &str) -> Vec<String> {
+		self.as_options()
+	}
+
+	/// Get the host ready for this device, right before QEMU is started (e.g: binding a device to vfio-pci).
+	fn prepare_host(&self) -> Result<(), std::io::Error> {
+		Ok(())
+	}
+
+	/// Undo [QemuOption::prepare_host], once QEMU has exited.
+	fn release_host(&self) -> Result<(), std::io::Error> {
+		Ok(())
+	}
+
+	/// How [BootConfig::order] refers to this device, if it can be booted from.
+	fn boot_device(&self) -> Option<BootDevice> {
+		None
+	}
+
+	/// How many ports this device takes on the VM's shared virtio-serial controller,
+	/// which is only added if something needs it.
+	fn virtio_serial_ports(&self) -> u32 {
+		0
+	}
 }
 
+/// The accelerator QEMU will use to run the guest.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Accelerator {
+	/// Linux KVM. Requires `/dev/kvm` to be present and accessible.
+	Kvm,
 
-pub enum DiskDrive {
-	CdDrive {
-		interface: DiskInterface,
-		id: String
-	},
+	/// QEMU's Tiny Code Generator (software emulation). Slow, but works everywhere.
+	Tcg {
+		/// Whether to translate with one thread per vCPU (`multi`), or a single thread
+		/// for all of them (`single`). QEMU picks a default based on the guest if None.
+		multithreaded: Option<bool>,
 
-	HdDrive {
-		id: String,
-		interface: DiskInterface,
-		image_path: String,
-		readonly: bool,
-		format: String,
-		ssd: bool,
-		cache: Option<String>, // will be omitted if None
-		aio: Option<String>
+		/// The size of the translation block cache, in megabytes.
+		tb_size_mb: Option<u32>
 	},
 
-	/// A pflash drive. There are no configurable interface types.
-	Pflash {
-		id: String,
-		image_path: String,
-		readonly: bool,
-		format: String
-	}
+	/// macOS's Hypervisor.framework.
+	Hvf,
 
+	/// The Windows Hypervisor Platform.
+	Whpx,
+
+	/// Whichever hardware accelerator this host has (see [Accelerator::resolve]), falling back
+	/// to TCG if it has none (e.g: in a container without `/dev/kvm`). The host is only looked at
+	/// when the VM starts; generated command lines let QEMU try each accelerator in turn instead.
+	Auto
 }
 
-pub enum DiskController {
-	VirtioScsi {
-		id: String
+impl Accelerator {
+	/// The accelerator to actually use for [Accelerator::Auto]: the first available of KVM, HVF and WHPX,
+	/// or TCG (with QEMU's defaults) if none of them are. Any other accelerator is returned as is.
+	pub fn resolve(&self) -> Accelerator {
+		match self {
+			Self::Auto => Self::hardware()
+				.find(accel_available)
+				.unwrap_or(Self::Tcg { multithreaded: None, tb_size_mb: None }),
+			accel => accel.clone()
+		}
+	}
+
+	/// The hardware accelerators which can exist on this OS, in order of preference.
+	fn hardware() -> impl Iterator<Item = Accelerator> {
+		[Self::Kvm, Self::Hvf, Self::Whpx].into_iter().filter(|accel| match accel {
+			Self::Kvm => cfg!(target_os = "linux"),
+			Self::Hvf => cfg!(target_os = "macos"),
+			Self::Whpx => cfg!(target_os = "windows"),
+			_ => false
+		})
 	}
 }
 
-pub enum GraphicsAdapter {
-	/// Standard VGA adapter.
-	StdVga {
-		ram_size_mb: i16
+/// How the guest's display is shown.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DisplayBackend {
+	/// No display at all.
+	None,
+
+	/// Headless OpenGL rendering, for server-side GL. Requires a GL-capable graphics adapter.
+	EglHeadless {
+		/// The DRM render node to render on (e.g: `/dev/dri/renderD128`). QEMU picks one if None.
+		rendernode: Option<String>
 	},
 
-	/// Cirrus Logic GD5446.
-	CirrusVga {
-		ram_size_mb: i16
+	/// A local GTK window. Needs a host display (X11 or Wayland).
+	Gtk {
+		/// Render with OpenGL. Requires a GL-capable graphics adapter.
+		#[serde(default)]
+		gl: bool
 	},
 
-	/// Red Hat QXL.
-	QxlVga {},
+	/// A local SDL window. Needs a host display (X11 or Wayland).
+	Sdl {
+		/// Render with OpenGL. Requires a GL-capable graphics adapter.
+		#[serde(default)]
+		gl: bool
+	},
 
-	/// A Mediated Device (MDEV) vGPU device, provided by supported GPU devices. 
-	/// 
-	/// Currently this means/supports:
-	/// - Intel GVT-g
-	/// - NVIDIA vGPU
-	VgpuVga {
-		/// The MDEV UUID. This *must* match the VM's UUID, at least for NVIDIA. 
-		/// I don't know if a similar requirement is true for Intel, but I assume it is.
-		uuid: String,
+	/// Export the display over a peer-to-peer D-Bus connection, which
+	/// Sunlight sets up once QEMU starts (see [VirtualMachine::dbus_display]).
+	DbusP2p {
+		/// Share GL scanouts with the client. Requires a GL-capable graphics adapter.
+		#[serde(default)]
+		gl: bool
+	},
 
-		/// Use the QEMU ramfb device to provide pre-boot (pre-graphics driver initalization) video.
-		use_ramfb: bool,
+	/// A SPICE server, which SPICE clients (e.g: remote-viewer) can connect to.
+	/// It listens on either a TCP port or a UNIX socket.
+	Spice {
+		port: Option<u16>,
 
-		// these are used for bypassing vgpu driver restrictions
-		
-		pci_vendor_id: Option<String>,
-		pci_device_id: Option<String>,
-		pci_sub_vendor_id: Option<String>,
-		pci_sub_device_id: Option<String>		
-	}
+		/// The address to listen on with `port`. Defaults to all addresses.
+		addr: Option<String>,
 
-}
+		unix_socket: Option<String>,
 
-pub enum Network {
-	User {
-		id: String
+		tls: Option<SpiceTls>,
+
+		/// Let clients connect without a password. Otherwise, `password_file` has to be set.
+		#[serde(default)]
+		disable_ticketing: bool,
+
+		/// A file holding the password clients have to give.
+		password_file: Option<String>,
+
+		/// Add a channel for the SPICE guest agent (spice-vdagent), which handles
+		/// things like clipboard sharing and resizing the guest display to fit the client.
+		#[serde(default)]
+		agent: bool,
+
+		/// How many USB devices clients can redirect to the guest at once.
+		#[serde(default)]
+		usb_redirect: u8,
+
+		/// Render with OpenGL. Requires a GL-capable graphics adapter, and
+		/// only works over a UNIX socket, since GL frames are passed as dma-bufs.
+		#[serde(default)]
+		gl: bool,
+
+		/// The DRM render node to render on. QEMU picks one if None.
+		rendernode: Option<String>
 	},
 
-	Tap {
-		id: String,
-		dev: String
+	/// A VNC server.
+	Vnc {
+		/// The address to listen on. Defaults to all addresses.
+		listen: Option<String>,
+
+		/// The TCP port to listen on. VNC ports start at 5900.
+		port: u16,
+
+		/// A file holding the password clients have to give. Without this, anyone can connect.
+		/// The password can be changed later on with [VirtualMachine::set_vnc_password].
+		password_secret: Option<String>,
+
+		/// Also accept VNC over WebSockets (e.g: for noVNC) on this port.
+		websocket: Option<u16>
 	}
 }
 
-pub enum NetworkAdapter {
-	Virtio {
-		id: String,
-		netdev: String,
-		mac: Option<String>
-	},
-	
-	Rtl8139 {
-		id: String,
-		netdev: String,
-		mac: Option<String>
-	},
+/// TLS for a SPICE server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SpiceTls {
+	pub port: u16,
 
-	// more variants?
+	/// Directory holding `ca-cert.pem`, `server-cert.pem` and `server-key.pem`.
+	pub x509_dir: String
+}
 
+/// QEMU's seccomp sandbox (`-sandbox on`). Each flag denies the given class of syscalls.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sandbox {
+	pub deny_obsolete: bool,
+	pub deny_elevate_privileges: bool,
+
+	/// Deny fork()/exec(). Incompatible with devices that need helper processes.
+	pub deny_spawn: bool,
+
+	pub deny_resource_control: bool
 }
 
-impl QemuOption for MachineType {
-	fn as_options(&self) -> String {
+/// A QEMU log item (`-d`). See `qemu-system-x86_64 -d help` for what each one logs.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum LogCategory {
+	OutAsm,
+	InAsm,
+	Op,
+	OpOpt,
+	OpInd,
+	Int,
+	Exec,
+	Cpu,
+	Fpu,
+	Mmu,
+	Pcall,
+	CpuReset,
+	Unimp,
+	GuestErrors,
+	Page,
+	Nochain
+}
+
+impl LogCategory {
+	/// The name QEMU knows this category by.
+	pub fn name(&self) -> &'static str {
 		match self {
-			Self::Pc { acpi, usb } => format!("-machine pc,acpi={},usb={}", bool_to_qemu(*acpi), bool_to_qemu(*usb)),
-			Self::Q35 { acpi, usb, hmat } => format!("-machine q35,acpi={},usb={},hmat={} -device ioh3420,id=vm.pcie_root,slot=0,bus=pcie.0", bool_to_qemu(*acpi), bool_to_qemu(*usb), bool_to_qemu(*hmat)),
-			//_ => panic!("Unhandled machine type in MachineType::as_options()")
+			Self::OutAsm => "out_asm",
+			Self::InAsm => "in_asm",
+			Self::Op => "op",
+			Self::OpOpt => "op_opt",
+			Self::OpInd => "op_ind",
+			Self::Int => "int",
+			Self::Exec => "exec",
+			Self::Cpu => "cpu",
+			Self::Fpu => "fpu",
+			Self::Mmu => "mmu",
+			Self::Pcall => "pcall",
+			Self::CpuReset => "cpu_reset",
+			Self::Unimp => "unimp",
+			Self::GuestErrors => "guest_errors",
+			Self::Page => "page",
+			Self::Nochain => "nochain"
 		}
 	}
 }
 
-impl QemuOption for Cpu {
-	fn as_options(&self) -> String {
-		// Make sure there are features for us to append
-		if self.features.is_empty() {
-			format!("-cpu {} -smp cores={}", self.model, self.core_count)
-		} else {
-			format!("-cpu {},{} -smp cores={}", self.model, self.features.join(","), self.core_count)
+impl std::str::FromStr for LogCategory {
+	type Err = UnknownLogCategory;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"out_asm" => Ok(Self::OutAsm),
+			"in_asm" => Ok(Self::InAsm),
+			"op" => Ok(Self::Op),
+			"op_opt" => Ok(Self::OpOpt),
+			"op_ind" => Ok(Self::OpInd),
+			"int" => Ok(Self::Int),
+			"exec" => Ok(Self::Exec),
+			"cpu" => Ok(Self::Cpu),
+			"fpu" => Ok(Self::Fpu),
+			"mmu" => Ok(Self::Mmu),
+			"pcall" => Ok(Self::Pcall),
+			"cpu_reset" => Ok(Self::CpuReset),
+			"unimp" => Ok(Self::Unimp),
+			"guest_errors" => Ok(Self::GuestErrors),
+			"page" => Ok(Self::Page),
+			"nochain" => Ok(Self::Nochain),
+			_ => Err(UnknownLogCategory(String::from(s)))
 		}
 	}
+}
 
-	fn validate(&self, _machine: &VirtualMachine) -> bool {
-		// should probably also check features, but it IS ok for that to be empty
-		// we explicitly check for it when doing as_options() at least
-		!self.model.is_empty()
-	}
+/// QEMU's own debug logging (`-d` and `-D`), independent of tracing.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QemuLog {
+	pub categories: Vec<LogCategory>,
+
+	/// The file the log is written to.
+	pub file: String
 }
 
-impl QemuOption for Memory {
-	fn as_options(&self) -> String {
-		// TODO: we should allow memory backends, because -mem-prealloc is self-deprecated
-		if self.prealloc {
-			return format!("-m {} -mem-prealloc", self.size);
-		}
+/// The guest architecture, which decides the QEMU binary to run.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Architecture {
+	X86_64,
 
-		return format!("-m {}", self.size);
-	}
+	/// IBM Z. Devices live on channel subsystems instead of PCI.
+	S390x
 }
 
+impl Architecture {
+	/// The QEMU system emulator binary for this architecture.
+	pub fn qemu_binary(&self) -> &'static str {
+		match self {
+			Self::X86_64 => "qemu-system-x86_64",
+			Self::S390x => "qemu-system-s390x"
+		}
+	}
 
-impl QemuOption for DiskController {
-	fn as_options(&self) -> String {
+	/// Whether this architecture has a PCI(e) bus for devices to go on.
+	pub fn has_pci(&self) -> bool {
 		match self {
-			Self::VirtioScsi { id } => format!("-object iothread,id=vm.{id}.block_thread -device virtio-scsi-pci,num_queues=6,iothread=vm.{id}.block_thread,id=vm.{id}")
+			Self::X86_64 => true,
+			Self::S390x => false
 		}
 	}
 }
 
-impl QemuOption for DiskDrive {
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MachineType {
+	/// PC machine type. Uses a i440fx chipset.
+	Pc {
+		acpi: bool,
+		usb: bool
+	},
 
-	fn as_options(&self) -> String {
-		match self {
-			Self::CdDrive { interface, id } => {
-				match interface {
-					DiskInterface::Ide => {
-						format!("-drive if=none,media=cdrom,aio=io_uring,id={id} -device ide-cd,drive={id},id={id}.drive")
-					},
-					DiskInterface::Scsi => {
-						format!("-drive if=none,media=cdrom,aio=io_uring,id={id} -device scsi-cd,drive={id},id={id}.drive")
-					}
-				}
-			},
-			Self::HdDrive { id, interface, image_path, readonly, format, ssd, cache, aio } => {
-				let mut drive_str = format!("-drive if=none,file={image_path},format={format},id=vm.{id}.drive,readonly={}", bool_to_qemu(*readonly));
+	/// Q35 machine type. Preferrable for VMs running more modern guests, and required for vGPU support.
+	Q35 {
+		acpi: bool,
+		usb: bool,
+		hmat: bool,
 
+		/// Use the legacy Intel `ioh3420` root port instead of the generic `pcie-root-port`.
+		/// Only useful for compatibility with old QEMU versions.
+		legacy_root_port: bool
+	},
 
-				match cache {
-					Some(str) => drive_str.push_str(format!(",cache={str}").as_str()),
-					_ => {}
-				}
+	/// The s390x virtio machine. Requires [Architecture::S390x].
+	S390CcwVirtio
+}
 
-				match aio {
-					Some(str) => drive_str.push_str(format!(",aio={str}").as_str()),
-					_ => {}
-				}
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Cpu {
+	/// The CPU model.
+	pub model : String,
 
-				let mut opts_str = format!("id=vm.{id},drive=vm.{id}.drive");
+	/// CPU features. Later on, these can be typed/exclusions.
+	/// For now, I don't care.
+	#[serde(default)]
+	pub features : Vec<String>,
+	
+	/// Cores per die.
+	pub core_count: i8,
 
-				// if on an ssd
-				if *ssd {
-					opts_str.push_str(",rotation_rate=1");
-				}
+	/// How many dies each socket is split into. Each die gets its own L3 cache in the guest's view.
+	pub dies: Option<i8>,
 
-				match interface {
-					DiskInterface::Ide => format!("{drive_str} -device ide-hd,{opts_str}"),
-					DiskInterface::Scsi => format!("{drive_str} -device scsi-hd,{opts_str}")
-				}
-			}
+	/// Defaults to 1.
+	pub sockets: Option<i8>,
 
-			_ => panic!("a certified stupid flower moment probably")
-		}
+	/// Threads per core. Defaults to 1.
+	pub threads: Option<i8>,
 
-	}
+	/// How many vCPUs the VM boots with. Defaults to the whole topology; anything
+	/// less leaves the rest to be hotplugged later.
+	pub vcpus: Option<u32>,
+
+	/// The most vCPUs the VM can have. If set, the topology has to add up to this
+	/// instead of `vcpus`, so there's room to hotplug more vCPUs up to it.
+	pub max_vcpus: Option<u32>,
+
+	/// Pass the host's cache topology through to the guest. Only works with the `host` model.
+	#[serde(default)]
+	pub host_cache_info: bool
 }
 
-impl QemuOption for GraphicsAdapter {
-	fn as_options(&self) -> String {
-		match self {
-			Self::StdVga { ram_size_mb } => format!("-device VGA,vgamem_mb={},id=vm.vga", ram_size_mb),
-			Self::CirrusVga { ram_size_mb } => format!("-device cirrus-vga,vgamem_mb={},id=vm.vga", ram_size_mb),
-			Self::QxlVga {  } => format!("-device qxl-vga,id=vm.vga"),
-			Self::VgpuVga { uuid, use_ramfb, pci_vendor_id, pci_device_id, pci_sub_vendor_id, pci_sub_device_id } => {
-				let path = format!("/sys/bus/mdev/devices/{uuid}");
-				if pci_vendor_id.is_some() {
-					let vid = pci_device_id.as_deref().unwrap();
-					let pid = pci_vendor_id.as_deref().unwrap();
-					let subvid = pci_sub_vendor_id.as_deref().unwrap();
-					let subpid = pci_sub_device_id.as_deref().unwrap();
-					return format!("-device vfio-pci-nohotplug,sysfsdev={path},display=on,ramfb={},id=vm.vgpu,bus=vm.pcie_root,addr=0x0,x-pci-vendor-id={vid},x-pci-device-id={pid},x-pci-sub-vendor-id={subvid},x-pci-sub-device-id={subpid}", bool_to_qemu(*use_ramfb));
-				}
-				return format!("-device vfio-pci-nohotplug,sysfsdev={path},display=on,ramfb={},id=vm.vgpu,bus=vm.pcie_root,addr=0x0", bool_to_qemu(*use_ramfb));
-			}
-		}
+/// Where guest RAM comes from.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MemoryBackend {
+	/// Anonymous memory. This is what QEMU uses if no backend is given.
+	Ram {
+		#[serde(default)]
+		share: bool
+	},
+
+	/// A memfd, which other processes (e.g: vhost-user backends like virtiofsd) can map.
+	Memfd {
+		#[serde(default)]
+		share: bool,
+
+		/// Back the memfd with huge pages of this size (e.g: `2M` or `1G`).
+		hugepage_size: Option<ByteSize>
+	},
+
+	/// A file, usually on a hugetlbfs mount.
+	File {
+		/// The file (or directory, which QEMU creates a file in) to back memory with.
+		mem_path: String,
+
+		#[serde(default)]
+		share: bool,
+
+		/// If set, `mem_path` has to be on a hugetlbfs mount with this page size (e.g: `2M` or `1G`).
+		hugepage_size: Option<ByteSize>
 	}
+}
 
-	fn validate(&self, machine: &VirtualMachine) -> bool {
-		match self {
-			Self::VgpuVga { uuid, .. } => { 
-				// if the machine doesn't even *have* a uuid, 
-				// it's probably not configured properly
-				if machine.uuid.is_none() {
-					return false;
-				}
+/// A guest NUMA node.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NumaNode {
+	/// How much of the VM's memory is on this node. Sizes of all nodes have to add up to [Memory]'s size.
+	pub size: ByteSize,
 
-				match machine.machine {
-					Some(MachineType::Q35 { .. }) => true,
+	/// Defaults to anonymous memory ([MemoryBackend::Ram]).
+	pub backend: Option<MemoryBackend>,
 
-					// vGPU can't be used in a PC configuration or an invalid one
-					Some(MachineType::Pc { .. }) => false,
-					None => false
-				};
+	#[serde(default)]
+	pub prealloc: bool,
 
-				// likewise, if we don't have one, then we're
-				// the misconfigured one, not the VM
-				if uuid.is_empty() {
-					return false
-				}
+	/// Give the node its memory with the legacy `mem=` form instead of a memdev. Only older
+	/// machine types take it, and QEMU refuses a mix, so it's all nodes or none.
+	#[serde(default)]
+	pub legacy_mem: bool,
 
-				return machine.uuid.as_deref().unwrap() == uuid;
-			}
+	/// The vCPUs (by index) on this node. Nodes without any are memory-only.
+	#[serde(default)]
+	pub cpus: Vec<u32>,
 
-			_ => true // no special cases
-		}
-	}
+	/// The node whose vCPUs have the best access to this node's memory. HMAT needs
+	/// this on every node; a node with vCPUs is usually its own initiator.
+	pub initiator: Option<u32>
 }
 
-impl QemuOption for Network {
-	fn as_options(&self) -> String {	
-		match self {
-			Self::User { id } => format!("-netdev user,id=vm.{id}"),
-			Self::Tap { id, dev } => format!("-netdev tap,vhost=on,script=no,downscript=no,ifname={dev},id=vm.{id}")
-		}
-	}
+/// What an [HmatLatencyBandwidth] entry describes.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum HmatDataType {
+	AccessLatency,
+	ReadLatency,
+	WriteLatency,
+	AccessBandwidth,
+	ReadBandwidth,
+	WriteBandwidth
 }
 
-impl QemuOption for NetworkAdapter {
-	fn as_options(&self) -> String {
-		match self {
-			Self::Virtio { id, netdev, mac } => {
-				let mut base = format!("-device virtio-net-pci,id=vm.{id},netdev=vm.{netdev}");
-				match mac {
-					Some(addr) => base.push_str(format!(",mac={addr}").as_str()),
-					_ => {}
-				}
-				base
-			},
+impl HmatDataType {
+	fn is_latency(&self) -> bool {
+		matches!(self, Self::AccessLatency | Self::ReadLatency | Self::WriteLatency)
+	}
 
-			Self::Rtl8139 { id, netdev, mac } => {
-				let mut base = format!("-device rtl8139,id=vm.{id},netdev=vm.{netdev}");
-				match mac {
-					Some(addr) => base.push_str(format!(",mac={addr}").as_str()),
-					_ => {}
-				}
-				base
-			}
+	fn as_qemu(&self) -> &'static str {
+		match self {
+			Self::AccessLatency => "access-latency",
+			Self::ReadLatency => "read-latency",
+			Self::WriteLatency => "write-latency",
+			Self::AccessBandwidth => "access-bandwidth",
+			Self::ReadBandwidth => "read-bandwidth",
+			Self::WriteBandwidth => "write-bandwidth"
 		}
 	}
 }
 
-fn join_options<'a>(vec: &'a Vec<Box<dyn QemuOption + 'a>>, machine: &VirtualMachine) -> Vec<String> {
-	// this is occursed. it also doesn't join properly for the process API,
-	// but we handle that later
-	vec.iter()
-		.map(|o| {
-			if o.validate(machine) {
-				o.as_options()
-			} else {
-				// This really is not a good error handling strategy,
-				// but it's honestly the best I've got, short of causing a panic!
-				String::from("uh oh system fuck")
+/// An HMAT System Locality Latency and Bandwidth entry, describing
+/// how fast the `initiator` node's vCPUs can reach the `target` node's memory.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HmatLatencyBandwidth {
+	pub initiator: u32,
+	pub target: u32,
+	pub data_type: HmatDataType,
+
+	/// In nanoseconds. Set for latency data types.
+	pub latency_ns: Option<u64>,
+
+	/// Per second (e.g: `10G`). Set for bandwidth data types.
+	pub bandwidth: Option<ByteSize>
+}
+
+/// The VM's NUMA layout. Nodes are numbered in the order they're listed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NumaTopology {
+	pub nodes: Vec<NumaNode>,
+
+	/// Requires a [MachineType::Q35] with `hmat` enabled.
+	#[serde(default)]
+	pub hmat: Vec<HmatLatencyBandwidth>
+}
+
+impl MemoryBackend {
+	/// Whether other processes (e.g: vhost-user backends) can map this memory.
+	pub(crate) fn is_shared(&self) -> bool {
+		match self {
+			Self::Ram { share } | Self::Memfd { share, .. } | Self::File { share, .. } => *share
+		}
+	}
+
+	/// The `-object` for this backend, with the given id and size.
+	pub(crate) fn object(&self, id: &str, size: ByteSize, prealloc: bool) -> String {
+		let mut object = match self {
+			Self::Ram { share } => format!("memory-backend-ram,id={id},size={size},share={}", bool_to_qemu(*share)),
+			Self::Memfd { share, hugepage_size } => {
+				let mut object = format!("memory-backend-memfd,id={id},size={size},share={}", bool_to_qemu(*share));
+				if let Some(hugepage_size) = hugepage_size {
+					object.push_str(format!(",hugetlb=on,hugetlbsize={hugepage_size}").as_str());
+				}
+				object
+			},
+			Self::File { mem_path, share, .. } => format!("memory-backend-file,id={id},size={size},mem-path={},share={}", qemu_escape(mem_path), bool_to_qemu(*share))
+		};
+
+		if prealloc {
+			object.push_str(",prealloc=on");
+		}
+
+		object
+	}
+
+	pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+		match self {
+			Self::Memfd { hugepage_size: Some(hugepage_size), .. } if hugepage_size.as_bytes() == 0 => {
+				return Err(ValidationError::InvalidField { field: "hugepage_size", reason: "must be greater than 0" });
+			},
+
+			Self::File { mem_path, hugepage_size, .. } => {
+				if mem_path.is_empty() {
+					return Err(ValidationError::EmptyField { field: "mem_path" });
+				}
+
+				if let Some(hugepage_size) = hugepage_size {
+					let (mount_point, pagesize) = match hugetlbfs_mount(mem_path) {
+						Some(mount) => mount,
+						None => return Err(ValidationError::NoHugepageMount { path: mem_path.clone(), size: hugepage_size.to_string() })
+					};
+
+					// The mount may not say, in which case the default huge page size is all we can go by
+					if let Some(pagesize) = pagesize {
+						if pagesize.parse::<ByteSize>().ok() != Some(*hugepage_size) {
+							return Err(ValidationError::NoHugepageMount { path: mount_point, size: hugepage_size.to_string() });
+						}
+					}
+				}
+
+				// QEMU creates the file if it doesn't exist, but not any directories leading up to it
+				let path = std::path::Path::new(mem_path);
+				if !path.exists() && !containing_dir(mem_path).is_some_and(|dir| dir.is_dir()) {
+					return Err(ValidationError::PathNotFound { path: mem_path.clone() });
+				}
+			},
+
+			_ => {}
+		}
+
+		Ok(())
+	}
+}
+
+/// The hugetlbfs mount `path` is on, and its page size. A mount without an explicit
+/// `pagesize` uses the default huge page size, which is given as `None`.
+fn hugetlbfs_mount(path: &str) -> Option<(String, Option<String>)> {
+	let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+
+	mounts.lines()
+		.filter_map(|line| {
+			let fields = line.split_whitespace().collect::<Vec<&str>>();
+			match fields[..] {
+				[_, mount_point, "hugetlbfs", options, ..] => Some((mount_point, options)),
+				_ => None
+			}
+		})
+		.filter(|(mount_point, _)| std::path::Path::new(path).starts_with(mount_point))
+		// The most specific mount is the one which applies
+		.max_by_key(|(mount_point, _)| mount_point.len())
+		.map(|(mount_point, options)| {
+			let pagesize = options.split(',')
+				.find_map(|option| option.strip_prefix("pagesize="))
+				.map(String::from);
+			(String::from(mount_point), pagesize)
+		})
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Memory {
+	pub size: ByteSize,
+
+	/// Allocate all of guest RAM up front, instead of as the guest touches it.
+	pub prealloc: bool,
+
+	/// Defaults to anonymous memory ([MemoryBackend::Ram]).
+	pub backend: Option<MemoryBackend>,
+
+	/// The maximum size memory can be hotplugged up to. Must be set along with `slots`.
+	pub max_size: Option<ByteSize>,
+
+	/// How many DIMM slots are available for hotplugging memory. Must be set along with `max_size`.
+	pub slots: Option<u8>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Snapshot {
+	NoSnapshots,
+
+	/// This uses the QEMU `-snapshot` option. This creates a temporary
+	/// backing file in /var/tmp, where all data differences will be stored 
+	/// (and deleted when the VM shuts down.)
+	///
+	/// For snapshots which stick around, see [VirtualMachine::snapshot_disk].
+	HdSnapshot,
+
+}
+
+/// A disk image format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DiskFormat {
+	/// QEMU's own format. Sparse, with backing images, snapshots and compression.
+	Qcow2,
+
+	/// A plain image, byte for byte what the guest sees.
+	Raw,
+
+	/// VMware.
+	Vmdk,
+
+	/// VirtualBox.
+	Vdi,
+
+	/// Hyper-V.
+	Vhdx,
+
+	/// Virtual PC (VHD).
+	Vpc
+}
+
+/// Gives a disk option enum `name()` (what QEMU calls each variant), and the string conversions
+/// serde and VM definitions use, which all go through it.
+macro_rules! disk_option_names {
+	($option:ident, $kind:literal, { $($variant:ident => $name:literal),* $(,)? }) => {
+		impl $option {
+			/// The name QEMU (and qemu-img) uses for this.
+			pub fn name(&self) -> &'static str {
+				match self {
+					$(Self::$variant => $name),*
+				}
+			}
+		}
+
+		impl std::str::FromStr for $option {
+			type Err = InvalidDiskOption;
+
+			fn from_str(s: &str) -> Result<Self, Self::Err> {
+				match s {
+					$($name => Ok(Self::$variant),)*
+					_ => Err(InvalidDiskOption { kind: $kind, value: String::from(s) })
+				}
+			}
+		}
+
+		impl TryFrom<String> for $option {
+			type Error = InvalidDiskOption;
+
+			fn try_from(s: String) -> Result<Self, Self::Error> {
+				s.parse()
+			}
+		}
+
+		impl From<$option> for String {
+			fn from(option: $option) -> String {
+				String::from(option.name())
+			}
+		}
+
+		impl std::fmt::Display for $option {
+			fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+				f.write_str(self.name())
+			}
+		}
+	};
+}
+
+disk_option_names!(DiskFormat, "disk format", {
+	Qcow2 => "qcow2",
+	Raw => "raw",
+	Vmdk => "vmdk",
+	Vdi => "vdi",
+	Vhdx => "vhdx",
+	Vpc => "vpc"
+});
+
+/// How a drive uses the host's page cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CacheMode {
+	/// Writes go through the page cache, and are flushed when the guest asks. QEMU's default.
+	Writeback,
+
+	/// Every write is flushed before it completes, and the guest is told there's no write cache.
+	Writethrough,
+
+	/// Bypass the page cache (O_DIRECT), flushing when the guest asks.
+	None,
+
+	/// Never flush. Fast, but a host crash can lose (or corrupt) anything written.
+	Unsafe,
+
+	/// Bypass the page cache, and flush every write.
+	DirectSync
+}
+
+impl CacheMode {
+	/// Whether the page cache is bypassed (`cache.direct`).
+	pub fn direct(&self) -> bool {
+		matches!(self, Self::None | Self::DirectSync)
+	}
+}
+
+disk_option_names!(CacheMode, "cache mode", {
+	Writeback => "writeback",
+	Writethrough => "writethrough",
+	None => "none",
+	Unsafe => "unsafe",
+	DirectSync => "directsync"
+});
+
+/// How QEMU does a drive's I/O.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum AioMode {
+	/// A pool of threads doing blocking I/O. QEMU's default.
+	Threads,
+
+	/// Linux native AIO. Needs a cache mode which bypasses the page cache.
+	Native,
+
+	/// io_uring.
+	IoUring
+}
+
+disk_option_names!(AioMode, "aio mode", {
+	Threads => "threads",
+	Native => "native",
+	IoUring => "io_uring"
+});
+
+/// What a drive does with writes of all zeroes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DetectZeroes {
+	/// Write them out like anything else. QEMU's default.
+	Off,
+
+	/// Turn them into zero writes, which formats like qcow2 can do without storing the zeroes.
+	On,
+
+	/// Turn them into discards. Needs the drive to have `discard` enabled.
+	Unmap
+}
+
+disk_option_names!(DetectZeroes, "detect-zeroes mode", {
+	Off => "off",
+	On => "on",
+	Unmap => "unmap"
+});
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum DiskInterface {
+	/// IDE (or SATA if using the q35 machine type.)
+	Ide, 
+
+	/// SCSI (incl. VirtIO SCSI).
+	Scsi,
+
+	/// virtio-blk. Each drive is its own PCI device, so there's no controller to add.
+	/// Hard drives only.
+	Virtio
+}
+
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DiskDrive {
+	CdDrive {
+		interface: DiskInterface,
+		id: String,
+
+		/// The ISO inserted at boot. Left empty if None; see [VirtualMachine::change_media].
+		iso_path: Option<String>
+	},
+
+	HdDrive {
+		id: String,
+		interface: DiskInterface,
+		image_path: String,
+		readonly: bool,
+		format: DiskFormat,
+		ssd: bool,
+		cache: Option<CacheMode>, // will be omitted if None
+		aio: Option<AioMode>,
+
+		/// The id of a [ThrottleGroup] this drive shares its I/O limits with.
+		throttle_group: Option<String>,
+
+		/// I/O limits for this drive alone. These apply on top of any throttle group's.
+		/// See [VirtualMachine::set_io_limits] for changing them while the VM is running.
+		#[serde(default)]
+		throttle: Option<IoLimits>,
+
+		/// Don't take an exclusive lock on the image, so other VMs can write to it too.
+		/// This WILL corrupt the image unless the guests use a cluster-aware filesystem.
+		#[serde(default)]
+		share_rw: bool,
+
+		/// The block sizes reported to the guest, in bytes (e.g: 4096 for both to emulate a 4Kn disk).
+		logical_block_size: Option<u32>,
+		physical_block_size: Option<u32>,
+
+		/// Pass discard (TRIM) requests from the guest through to the image, so it can shrink.
+		#[serde(default)]
+		discard: bool,
+
+		/// Detect writes of all zeroes, and turn them into zero writes or discards.
+		detect_zeroes: Option<DetectZeroes>,
+
+		/// Give the drive an I/O thread of its own. Virtio only.
+		#[serde(default)]
+		iothread: bool,
+
+		/// How many request queues the drive has. Virtio only. QEMU's default is one per vCPU.
+		num_queues: Option<u16>,
+
+		/// The image `image_path` is a qcow2 overlay on top of. If the overlay doesn't exist yet,
+		/// it's created (empty) when the VM starts. The backing image mustn't be written to after that.
+		#[serde(default)]
+		backing: Option<String>,
+
+		/// Unlock a LUKS encrypted qcow2 image with a [Secret].
+		#[serde(default)]
+		encryption: Option<LuksConfig>
+	},
+
+	/// An NVMe drive: a controller of its own, with the image as namespace 1.
+	/// For namespaces shared between controllers, use an [NvmeSubsystem].
+	Nvme {
+		id: String,
+		image_path: String,
+		format: DiskFormat,
+
+		/// The controller serial number reported to the guest. Defaults to the drive id.
+		serial: Option<String>,
+
+		/// More namespaces to put on the controller, after the image. Their ids have to start from 2.
+		#[serde(default)]
+		namespaces: Vec<NvmeNamespace>
+	},
+
+	/// A pflash drive. There are no configurable interface types.
+	Pflash {
+		id: String,
+		image_path: String,
+		readonly: bool,
+		format: DiskFormat
+	}
+
+}
+
+/// A namespace on an [NvmeSubsystem].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NvmeNamespace {
+	/// The namespace id. Must be unique within the subsystem, and start from 1.
+	pub nsid: u32,
+
+	pub image_path: String,
+	pub format: DiskFormat
+}
+
+/// An NVMe subsystem with a single controller, and one or more namespaces. Mostly
+/// useful for testing how guests deal with multiple namespaces.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NvmeSubsystem {
+	pub id: String,
+
+	/// The NVMe Qualified Name of the subsystem (e.g: `nqn.2019-08.org.qemu:sunlight`).
+	pub nqn: String,
+
+	/// The namespaces are kept on the subsystem, since they can't exist without it.
+	pub namespaces: Vec<NvmeNamespace>
+}
+
+/// The largest limit QEMU accepts for anything.
+const IO_LIMIT_MAX: u64 = 1_000_000_000_000_000;
+
+/// I/O limits for a drive, or a group of drives. Anything left as None is unlimited.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct IoLimits {
+	pub iops_total: Option<u64>,
+	pub iops_read: Option<u64>,
+	pub iops_write: Option<u64>,
+
+	/// Bytes per second.
+	pub bps_total: Option<u64>,
+	pub bps_read: Option<u64>,
+	pub bps_write: Option<u64>
+}
+
+impl IoLimits {
+	/// Generates the limits as options, with each property name given a prefix
+	/// (since -object throttle-group and -drive name them differently).
+	pub(crate) fn as_options(&self, prefix: &str) -> String {
+		let limits = [
+			("iops-total", self.iops_total),
+			("iops-read", self.iops_read),
+			("iops-write", self.iops_write),
+			("bps-total", self.bps_total),
+			("bps-read", self.bps_read),
+			("bps-write", self.bps_write)
+		];
+
+		limits.iter()
+			.filter_map(|(name, limit)| limit.map(|limit| format!(",{prefix}{name}={limit}")))
+			.collect()
+	}
+
+	/// QEMU refuses a total limit alongside separate read and write ones, and anything over [IO_LIMIT_MAX].
+	pub(crate) fn check(&self) -> Result<(), ValidationError> {
+		if self.iops_total.is_some() && (self.iops_read.is_some() || self.iops_write.is_some()) {
+			return Err(ValidationError::InvalidField { field: "iops_total", reason: "can't be combined with iops_read or iops_write" });
+		}
+
+		if self.bps_total.is_some() && (self.bps_read.is_some() || self.bps_write.is_some()) {
+			return Err(ValidationError::InvalidField { field: "bps_total", reason: "can't be combined with bps_read or bps_write" });
+		}
+
+		let limits = [self.iops_total, self.iops_read, self.iops_write, self.bps_total, self.bps_read, self.bps_write];
+		if limits.iter().flatten().any(|limit| *limit > IO_LIMIT_MAX) {
+			return Err(ValidationError::InvalidField { field: "limits", reason: "can't be more than 10^15" });
+		}
+
+		Ok(())
+	}
+
+	/// The `block_set_io_throttle` command applying these limits to the drive with `id`.
+	/// Limits of 0 are unlimited, so this also removes any which are None.
+	#[allow(deprecated)]
+	pub(crate) fn block_set_io_throttle(&self, id: String) -> qmp::qmp::block_set_io_throttle {
+		// check() keeps everything well within an i64
+		let limit = |limit: Option<u64>| limit.unwrap_or(0) as i64;
+
+		qmp::qmp::block_set_io_throttle(qmp::qmp::BlockIOThrottle {
+			id: Some(id),
+			device: None,
+			iops: limit(self.iops_total),
+			iops_rd: limit(self.iops_read),
+			iops_wr: limit(self.iops_write),
+			bps: limit(self.bps_total),
+			bps_rd: limit(self.bps_read),
+			bps_wr: limit(self.bps_write),
+			bps_max: None,
+			bps_max_length: None,
+			bps_rd_max: None,
+			bps_rd_max_length: None,
+			bps_wr_max: None,
+			bps_wr_max_length: None,
+			iops_max: None,
+			iops_max_length: None,
+			iops_rd_max: None,
+			iops_rd_max_length: None,
+			iops_wr_max: None,
+			iops_wr_max_length: None,
+			iops_size: None,
+			group: None
+		})
+	}
+}
+
+/// A named set of I/O limits which every drive referencing it shares, i.e: the combined
+/// I/O of all the drives in the group is limited, instead of each drive on its own.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ThrottleGroup {
+	pub id: String,
+	pub limits: IoLimits
+}
+
+/// Where a [Secret]'s value comes from.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SecretSource {
+	/// Read from a file when QEMU starts. The file shouldn't have a trailing newline,
+	/// since it's taken as part of the secret.
+	File {
+		path: String
+	},
+
+	/// Given directly. This ends up on the QEMU command line (where anyone who can list
+	/// processes can see it), and in the saved config, so prefer the other sources.
+	Inline {
+		data: String
+	},
+
+	/// A key in the Linux kernel keyring, by its serial number (e.g: from `keyctl add`).
+	Keyring {
+		serial: i32
+	}
+}
+
+/// A secret (a passphrase, or a key) which other options refer to by id, so it doesn't
+/// have to be passed to each of them directly.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Secret {
+	pub id: String,
+	pub source: SecretSource
+}
+
+/// LUKS encryption for a qcow2 image. The image has to have been created encrypted
+/// (e.g: `qemu-img create -f qcow2 -o encrypt.format=luks,encrypt.key-secret=...`).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LuksConfig {
+	/// The id of the [Secret] holding the passphrase.
+	pub secret: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DiskController {
+	VirtioScsi {
+		id: String
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum UsbController {
+	/// QEMU's generic xHCI (USB 3) controller.
+	Xhci {
+		id: String,
+
+		/// How many USB 2 ports to provide (up to 15). QEMU's default is 4.
+		usb2_ports: Option<u8>,
+
+		/// How many USB 3 ports to provide (up to 15). QEMU's default is 4.
+		usb3_ports: Option<u8>
+	},
+
+	/// The NEC uPD720200 xHCI controller. Some older guests only have drivers for this one.
+	NecXhci {
+		id: String,
+
+		/// How many USB 2 ports to provide (up to 15). QEMU's default is 4.
+		usb2_ports: Option<u8>,
+
+		/// How many USB 3 ports to provide (up to 15). QEMU's default is 4.
+		usb3_ports: Option<u8>
+	},
+
+	/// The PIIX3 UHCI (USB 1.1) controller. For very old guests.
+	Uhci {
+		id: String
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum InputDevice {
+	/// A USB HID mouse. For older guests which don't know about tablets or virtio input.
+	UsbMouse,
+
+	/// A USB HID keyboard.
+	UsbKeyboard,
+
+	/// A USB tablet. Uses absolute coordinates, so the guest cursor follows the client's without grabbing it.
+	UsbTablet
+}
+
+/// A USB device (other than input devices, which are [InputDevice]s).
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum UsbDevice {
+	/// A USB mass storage device (e.g: a flash drive), backed by a disk image.
+	Storage {
+		id: String,
+		image_path: String,
+		format: DiskFormat,
+		readonly: bool
+	},
+
+	/// Pass a host USB device through, picked by its vendor and product id. Since this doesn't
+	/// depend on where the device is plugged in, it keeps working if the device is replugged.
+	HostById {
+		id: String,
+		vendor_id: u16,
+		product_id: u16
+	},
+
+	/// Pass through whatever is plugged into a host USB port.
+	HostByPort {
+		id: String,
+
+		/// The host USB bus number.
+		bus: u8,
+
+		/// The port path on that bus (e.g: `2` or `1.4` for a port on a hub).
+		port: String
+	}
+}
+
+impl UsbDevice {
+	/// The id of a device passed through from the host.
+	fn passthrough_id(&self) -> Option<&str> {
+		match self {
+			Self::HostById { id, .. } | Self::HostByPort { id, .. } => Some(id),
+			Self::Storage { .. } => None
+		}
+	}
+}
+
+/// A USB device passed through from the host, from [VirtualMachine::usb_devices].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UsbPassthrough {
+	pub id: String,
+
+	/// Whether the host device is there for the guest to use. Passthrough devices stay in the VM
+	/// while the host device is unplugged, and pick it up again if it's plugged back in.
+	pub attached: bool
+}
+
+/// A device on the guest's PCI buses, from [VirtualMachine::pci_devices].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PciDevice {
+	pub bus: u8,
+	pub slot: u8,
+	pub function: u8,
+
+	/// The PCI class code (e.g: `0x0200` for an ethernet controller), and QEMU's name for it.
+	pub class: u16,
+	pub class_description: Option<String>,
+
+	pub vendor_id: u16,
+	pub device_id: u16,
+
+	/// The device's id, if it has one. Devices Sunlight adds have the same ids as
+	/// their options (e.g: [DiskDrive]'s `id`); QEMU's own devices mostly have none.
+	pub id: Option<String>
+}
+
+impl PciDevice {
+	/// Flatten `query-pci`'s answer, including the devices behind any bridges (e.g: root ports).
+	fn from_qmp(buses: Vec<qmp::qmp::PciInfo>) -> Vec<PciDevice> {
+		fn add(devices: &mut Vec<PciDevice>, infos: Vec<qmp::qmp::PciDeviceInfo>) {
+			for info in infos {
+				let id = match info.qdev_id.strip_prefix("vm.") {
+					Some(id) => Some(String::from(id)),
+					None if info.qdev_id.is_empty() => None,
+					None => Some(info.qdev_id)
+				};
+
+				devices.push(PciDevice {
+					bus: info.bus as u8,
+					slot: info.slot as u8,
+					function: info.function as u8,
+					class: info.class_info.class as u16,
+					class_description: info.class_info.desc,
+					vendor_id: info.id.vendor as u16,
+					device_id: info.id.device as u16,
+					id
+				});
+
+				if let Some(bridged) = info.pci_bridge.and_then(|bridge| bridge.devices) {
+					add(devices, bridged);
+				}
 			}
-		}).collect::<Vec<String>>()
+		}
+
+		let mut devices = Vec::new();
+		for bus in buses {
+			add(&mut devices, bus.devices);
+		}
+		devices
+	}
 }
 
-/// A QEMU virtual machine.
-pub struct VirtualMachine<'a> {
-	// process
-	process: Option<Command>,
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum GraphicsAdapter {
+	/// Standard VGA adapter.
+	StdVga {
+		/// Between 1M and 512M. QEMU rounds this up to a power of two.
+		vgamem: ByteSize
+	},
+
+	/// Cirrus Logic GD5446.
+	CirrusVga {
+		/// 4M, 8M or 16M.
+		vgamem: ByteSize
+	},
+
+	/// Red Hat QXL.
+	QxlVga {},
+
+	/// VirtIO GPU.
+	VirtioGpu {
+		/// Enable blob resources, for zero-copy buffer sharing with the host.
+		/// Requires a shared (memfd) memory backend.
+		blob: bool,
+
+		/// Size of the host-visible memory region.
+		hostmem: Option<ByteSize>,
+
+		/// Use the VGA-compatible variant (virtio-vga), so there's output before the guest's driver loads.
+		#[serde(default)]
+		vga: bool,
+
+		/// OpenGL acceleration through virglrenderer. Requires a display which renders with GL.
+		#[serde(default)]
+		virgl: bool,
+
+		/// Vulkan acceleration through virglrenderer's Venus. Requires virgl, blob and hostmem.
+		#[serde(default)]
+		venus: bool
+	},
+
+	/// A Mediated Device (MDEV) vGPU device, provided by supported GPU devices. 
+	/// 
+	/// Currently this means/supports:
+	/// - Intel GVT-g
+	/// - NVIDIA vGPU
+	VgpuVga {
+		/// The MDEV UUID. This *must* match the VM's UUID, at least for NVIDIA. 
+		/// I don't know if a similar requirement is true for Intel, but I assume it is.
+		uuid: String,
+
+		/// Use the QEMU ramfb device to provide pre-boot (pre-graphics driver initalization) video.
+		use_ramfb: bool,
+
+		// these are used for bypassing vgpu driver restrictions
+		
+		pci_vendor_id: Option<String>,
+		pci_device_id: Option<String>,
+		pci_sub_vendor_id: Option<String>,
+		pci_sub_device_id: Option<String>,
+
+		rombar: Option<bool>,
+		romfile: Option<String>,
+
+		/// The named root port to place the vGPU on. One is allocated if None.
+		root_port: Option<String>
+	}
+
+}
+
+/// How Sunlight sets up a tap device it creates for a [Network::Tap].
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ManagedTap {
+	/// A bridge to attach the tap to.
+	pub bridge: Option<String>
+}
+
+/// Which protocol a [PortForward] forwards.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ForwardProtocol {
+	Tcp,
+	Udp
+}
+
+impl ForwardProtocol {
+	pub(crate) fn name(&self) -> &'static str {
+		match self {
+			Self::Tcp => "tcp",
+			Self::Udp => "udp"
+		}
+	}
+}
+
+/// Forwards a port on the host to a port in the guest, for user networking (`hostfwd`).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PortForward {
+	pub proto: ForwardProtocol,
+
+	/// The host address to listen on. Listens on all addresses if None.
+	pub host_addr: Option<String>,
+	pub host_port: u16,
+
+	/// The guest address to forward to. Defaults to the first address handed out by the built-in DHCP server.
+	pub guest_addr: Option<String>,
+	pub guest_port: u16
+}
+
+impl PortForward {
+	/// The host side of the rule (`proto:addr:port`), which is also how hostfwd_remove identifies it.
+	fn host_side(&self) -> String {
+		format!("{}:{}:{}", self.proto.name(), self.host_addr.as_deref().unwrap_or(""), self.host_port)
+	}
+
+	/// The rule, in QEMU's hostfwd syntax (e.g: `tcp::2222-:22`).
+	pub(crate) fn rule(&self) -> String {
+		format!("{}-{}:{}", self.host_side(), self.guest_addr.as_deref().unwrap_or(""), self.guest_port)
+	}
+
+	/// Whether both forwards would try to listen on the same host socket.
+	fn clashes_with(&self, other: &PortForward) -> bool {
+		let addrs_overlap = match (&self.host_addr, &other.host_addr) {
+			(Some(addr), Some(other)) => addr == other,
+			// Listening on all addresses overlaps with anything
+			_ => true
+		};
+
+		self.proto == other.proto && self.host_port == other.host_port && addrs_overlap
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Network {
+	User {
+		id: String,
+
+		/// Ports forwarded from the host to the guest (e.g: for SSH). More can be added while the VM
+		/// is running with [VirtualMachine::add_port_forward].
+		#[serde(default)]
+		host_forwards: Vec<PortForward>
+	},
+
+	Tap {
+		id: String,
+		dev: String,
+
+		/// Have Sunlight create the tap device when the VM starts, and delete it once the VM stops.
+		/// If None, the tap has to exist already.
+		managed: Option<ManagedTap>,
+
+		/// How many queue pairs to open the tap with. An unmanaged tap has to have been created with
+		/// `multi_queue` for more than one. The adapter's `queues` should match.
+		queues: Option<u8>
+	},
+
+	/// Connect to an existing host bridge (e.g: `br0`), through qemu-bridge-helper. The helper is
+	/// setuid, and only attaches to bridges allowed in its ACL (`/etc/qemu/bridge.conf`).
+	Bridge {
+		id: String,
+		bridge: String,
+
+		/// The helper to use, if not QEMU's default path for qemu-bridge-helper.
+		helper: Option<String>
+	},
+
+	/// Connect to a vhost-user backend (e.g: an OVS-DPDK `dpdkvhostuser` port), which handles packets itself by
+	/// mapping guest memory. The VM's memory needs a backend with `share` on, and the adapter has to be virtio.
+	VhostUser {
+		id: String,
+
+		/// The backend's socket. QEMU connects to it as a client.
+		socket_path: String,
+
+		/// How many queue pairs to use. The adapter's `queues` should match.
+		queues: Option<u8>
+	}
+}
+
+/// virtio-net offload features. Anything left as None keeps QEMU's default (which is on).
+/// Mostly useful for turning offloads off while debugging checksum/segmentation issues.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct VirtioNetOffloads {
+	pub csum: Option<bool>,
+	pub guest_csum: Option<bool>,
+	pub gso: Option<bool>,
+	pub guest_tso4: Option<bool>,
+	pub guest_tso6: Option<bool>,
+	pub guest_ecn: Option<bool>,
+	pub guest_ufo: Option<bool>,
+	pub host_tso4: Option<bool>,
+	pub host_tso6: Option<bool>,
+	pub host_ecn: Option<bool>,
+	pub host_ufo: Option<bool>,
+	pub mrg_rxbuf: Option<bool>
+}
+
+impl VirtioNetOffloads {
+	pub(crate) fn as_options(&self) -> String {
+		let features = [
+			("csum", self.csum),
+			("guest_csum", self.guest_csum),
+			("gso", self.gso),
+			("guest_tso4", self.guest_tso4),
+			("guest_tso6", self.guest_tso6),
+			("guest_ecn", self.guest_ecn),
+			("guest_ufo", self.guest_ufo),
+			("host_tso4", self.host_tso4),
+			("host_tso6", self.host_tso6),
+			("host_ecn", self.host_ecn),
+			("host_ufo", self.host_ufo),
+			("mrg_rxbuf", self.mrg_rxbuf)
+		];
+
+		features.iter()
+			.filter_map(|(name, val)| val.map(|val| format!(",{name}={}", bool_to_qemu(val))))
+			.collect()
+	}
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum NetworkAdapter {
+	Virtio {
+		id: String,
+		netdev: String,
+		mac: Option<MacAddr>,
+
+		/// Expose (or hide) the option ROM BAR. Omitted if None.
+		rombar: Option<bool>,
+
+		/// Use a custom option ROM instead of QEMU's.
+		romfile: Option<String>,
+
+		/// Make this adapter the failover standby for a passthrough NIC. The guest pairs
+		/// the two by MAC address, so a MAC must be set when this is on.
+		#[serde(default)]
+		failover: bool,
+
+		/// The MTU advertised to the guest (e.g: 9000 for jumbo frames). The guest driver
+		/// has to support VIRTIO_NET_F_MTU (Linux 4.10+, or a recent virtio-win) to pick it up.
+		host_mtu: Option<u16>,
+
+		#[serde(default)]
+		offloads: VirtioNetOffloads,
+
+		/// Enable multiqueue with this many queue pairs. The netdev needs to be
+		/// opened with (at least) as many queues for this to do anything.
+		queues: Option<u8>
+	},
+	
+	Rtl8139 {
+		id: String,
+		netdev: String,
+		mac: Option<MacAddr>,
+		rombar: Option<bool>,
+		romfile: Option<String>
+	},
+
+	/// Intel 82540EM (PCI). Nearly every OS has a driver for this one.
+	E1000 {
+		id: String,
+		netdev: String,
+		mac: Option<MacAddr>,
+		rombar: Option<bool>,
+		romfile: Option<String>
+	},
+
+	/// Intel 82574L (PCIe). Only supported on Q35.
+	E1000e {
+		id: String,
+		netdev: String,
+		mac: Option<MacAddr>,
+		rombar: Option<bool>,
+		romfile: Option<String>
+	},
+
+	/// VMware's paravirtual adapter. For guests imported from VMware.
+	Vmxnet3 {
+		id: String,
+		netdev: String,
+		mac: Option<MacAddr>,
+		rombar: Option<bool>,
+		romfile: Option<String>
+	},
+
+	// more variants?
+
+}
+
+/// Which Intel HD Audio controller model to use.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum HdaController {
+	/// `ich9-intel-hda`. The one Q35 machines actually have.
+	Ich9,
+
+	/// `intel-hda` (ICH6). Matches the older PC (i440fx) machine type.
+	Ich6
+}
+
+/// The guest's sound card. Its output goes to the VM's [AudioBackend].
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Audio {
+	/// Intel HD Audio, with a duplex (line in/out) codec.
+	IntelHda {
+		/// The controller to use. If None, it's picked based on the machine type.
+		controller: Option<HdaController>
+	},
+
+	/// Intel 82801AA AC97. For older guests without HDA drivers.
+	Ac97,
+
+	/// VirtIO sound. Needs QEMU 8.2 or newer.
+	VirtioSound
+}
+
+/// Where the host side of the guest's audio goes (`-audiodev`).
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AudioBackend {
+	/// Discard output, and record silence.
+	None,
+
+	/// Needs QEMU 8.1 or newer.
+	Pipewire,
+
+	PulseAudio {
+		/// The server to connect to. Defaults to the user's server.
+		server: Option<String>
+	},
+
+	Alsa {
+		/// The ALSA device to play to and record from. Defaults to `default`.
+		device: Option<String>
+	}
+}
+
+impl Audio {
+	/// Fills in any device model left up to the machine type.
+	pub(crate) fn for_machine(&self, machine: &MachineType) -> Audio {
+		match self {
+			Self::IntelHda { controller: None } => Self::IntelHda {
+				controller: Some(match machine {
+					MachineType::Q35 { .. } => HdaController::Ich9,
+					_ => HdaController::Ich6
+				})
+			},
+
+			_ => self.clone()
+		}
+	}
+}
+
+/// The interface a TPM is exposed to the guest with.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TpmInterface {
+	/// TPM Interface Specification. What most x86 guests expect.
+	Tis,
+
+	/// Command Response Buffer. Used by aarch64 and newer setups. Discovered through ACPI.
+	Crb
+}
+
+/// A TPM 2.0 device, backed by a swtpm instance listening on a UNIX socket.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Tpm {
+	pub interface: TpmInterface,
+
+	/// The swtpm control socket.
+	pub socket_path: String,
+
+	/// Have Sunlight run swtpm while the VM is running, keeping the TPM's state in the VM's
+	/// state directory (under `tpm`). Otherwise swtpm has to already be listening on `socket_path`.
+	#[serde(default)]
+	pub managed: bool
+}
+
+impl Tpm {
+	/// The control socket of the swtpm instance Sunlight should run for this TPM, if any.
+	fn swtpm_socket(&self) -> Option<&str> {
+		self.managed.then_some(self.socket_path.as_str())
+	}
+
+	/// Create a TPM with an swtpm instance Sunlight manages, with its socket next to
+	/// the QMP socket of the VM named `vm_name` (under [qmp::runtime_dir]).
+	pub fn managed(interface: TpmInterface, vm_name: &str) -> Tpm {
+		Tpm {
+			interface,
+			socket_path: qmp::runtime_dir().join(format!("sunlight_{vm_name}.swtpm")).to_string_lossy().into_owned(),
+			managed: true
+		}
+	}
+}
+
+/// How long a managed swtpm gets to create its control socket.
+const SWTPM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The swtpm command line for a managed [Tpm], keeping its state in `tpm_state`.
+fn swtpm_command(tpm_state: &std::path::Path, socket_path: &str) -> Command {
+	let mut command = Command::new("swtpm");
+	command.args(["socket", "--tpm2", "--terminate"])
+		.arg("--tpmstate").arg(format!("dir={}", tpm_state.to_string_lossy()))
+		.arg("--ctrl").arg(format!("type=unixio,path={socket_path}"))
+		.stdin(std::process::Stdio::null())
+		.kill_on_drop(true);
+	command
+}
+
+/// Wait up to `timeout` for `child` (swtpm) to create the socket at `socket_path`.
+async fn wait_for_socket(child: &mut Child, socket_path: &str, timeout: Duration) -> Result<(), VMQemuProcessStartError> {
+	let deadline = tokio::time::Instant::now() + timeout;
+	while !std::path::Path::new(socket_path).exists() {
+		if let Some(status) = child.try_wait()? {
+			return Err(VMQemuProcessStartError::SwtpmExited(status));
+		}
+
+		if tokio::time::Instant::now() >= deadline {
+			return Err(VMQemuProcessStartError::SwtpmTimeout);
+		}
+
+		tokio::time::sleep(Duration::from_millis(50)).await;
+	}
+
+	Ok(())
+}
+
+/// The firmware the VM boots with.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Firmware {
+	/// SeaBIOS. This is QEMU's default, so nothing extra is generated for it.
+	Bios,
+
+	/// UEFI (OVMF), loaded from a pair of pflash images.
+	Uefi {
+		/// The firmware code image (e.g: `/usr/share/OVMF/OVMF_CODE.fd`). This is mapped read-only.
+		code_path: String,
+
+		/// The template for the UEFI variable store (e.g: `/usr/share/OVMF/OVMF_VARS.fd`). This is copied
+		/// into the VM's state directory on first boot, so each VM gets its own writable copy.
+		vars_template: String
+	}
+}
+
+impl Firmware {
+	/// Where this VM's copy of the UEFI variable store lives.
+	pub fn vars_path(state_dir: &str) -> String {
+		std::path::Path::new(state_dir)
+			.join("OVMF_VARS.fd")
+			.to_string_lossy()
+			.into_owned()
+	}
+
+	/// Copy the variable store template into the state directory, if it hasn't been already.
+	pub(crate) fn prepare(&self, state_dir: &str) -> Result<(), std::io::Error> {
+		if let Self::Uefi { vars_template, .. } = self {
+			let vars_path = Firmware::vars_path(state_dir);
+			if !std::path::Path::new(&vars_path).exists() {
+				std::fs::create_dir_all(state_dir)?;
+				std::fs::copy(vars_template, vars_path)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The pflash drives this firmware is loaded from. The code has to come first (unit 0).
+	pub(crate) fn drives(&self, state_dir: &str) -> Vec<DiskDrive> {
+		match self {
+			Self::Bios => vec![],
+			Self::Uefi { code_path, .. } => vec![
+				DiskDrive::Pflash {
+					id: String::from("firmware.code"),
+					image_path: code_path.clone(),
+					readonly: true,
+					format: DiskFormat::Raw
+				},
+				DiskDrive::Pflash {
+					id: String::from("firmware.vars"),
+					image_path: Firmware::vars_path(state_dir),
+					readonly: false,
+					format: DiskFormat::Raw
+				}
+			]
+		}
+	}
+
+	pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+		if let Self::Uefi { code_path, vars_template } = self {
+			for path in [code_path, vars_template] {
+				if !std::path::Path::new(path).is_file() {
+					return Err(ValidationError::PathNotFound { path: path.clone() });
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	pub(crate) fn label(&self) -> String {
+		let variant = match self {
+			Self::Bios => "Bios",
+			Self::Uefi { .. } => "Uefi"
+		};
+
+		format!("Firmware::{variant}")
+	}
+}
+
+/// A device the VM can boot from, by id.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BootDevice {
+	/// A [DiskDrive] (other than a pflash drive).
+	Drive(String),
+
+	/// Network boot (PXE) from a [NetworkAdapter].
+	Network(String)
+}
+
+/// What the VM boots from, and the firmware's boot menu.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct BootConfig {
+	/// The devices to try booting from, in order. Devices which aren't listed
+	/// are tried afterwards (unless `strict`), in whatever order the firmware likes.
+	#[serde(default)]
+	pub order: Vec<BootDevice>,
+
+	/// Show the firmware's boot menu.
+	#[serde(default)]
+	pub menu: bool,
+
+	/// How long the boot menu waits for a key press. The firmware picks if None.
+	pub menu_timeout_ms: Option<u32>,
+
+	/// A picture (JPEG or BMP) shown along with the boot menu.
+	pub splash: Option<String>,
+
+	/// Only boot from the devices in `order`.
+	#[serde(default)]
+	pub strict: bool
+}
+
+impl QemuOption for BootConfig {
+	fn as_options(&self) -> Vec<String> {
+		// The order itself is given to each device as its bootindex
+		let mut opts = Vec::new();
+
+		if self.menu {
+			opts.push(String::from("menu=on"));
+		}
+		if let Some(splash) = &self.splash {
+			opts.push(format!("splash={}", qemu_escape(splash)));
+		}
+		// SeaBIOS uses the splash time as the menu's timeout
+		if let Some(timeout) = self.menu_timeout_ms {
+			opts.push(format!("splash-time={timeout}"));
+		}
+		if self.strict {
+			opts.push(String::from("strict=on"));
+		}
+
+		if opts.is_empty() {
+			return vec![];
+		}
+
+		qemu_arg("-boot", opts.join(","))
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		let bootable = machine.devices.iter()
+			.chain(machine.drives.iter())
+			.filter_map(|dev| dev.boot_device())
+			.collect::<Vec<BootDevice>>();
+
+		for (index, device) in self.order.iter().enumerate() {
+			if !bootable.contains(device) {
+				return Err(ValidationError::InvalidField { field: "order", reason: "refers to a device which doesn't exist (or can't be booted from)" });
+			}
+
+			if self.order[..index].contains(device) {
+				return Err(ValidationError::InvalidField { field: "order", reason: "lists a device more than once" });
+			}
+		}
+
+		if self.strict && self.order.is_empty() {
+			return Err(ValidationError::InvalidField { field: "strict", reason: "nothing could be booted with an empty order" });
+		}
+
+		// The firmware only shows these along with the menu
+		if !self.menu {
+			if self.menu_timeout_ms.is_some() {
+				return Err(ValidationError::InvalidField { field: "menu_timeout_ms", reason: "only used with the boot menu" });
+			}
+
+			if self.splash.is_some() {
+				return Err(ValidationError::InvalidField { field: "splash", reason: "only shown with the boot menu" });
+			}
+		}
+
+		if let Some(splash) = &self.splash {
+			if !std::path::Path::new(splash).is_file() {
+				return Err(ValidationError::PathNotFound { path: splash.clone() });
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Where a VM keeps its persistent state (e.g: UEFI variables), unless set with
+/// [VirtualMachine::set_state_dir]. This is `$XDG_STATE_HOME/sunlight/{name}`,
+/// falling back to `~/.local/state/sunlight/{name}`.
+pub fn default_state_dir(vm_name: &str) -> String {
+	let base = match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+		(Some(state), _) => std::path::PathBuf::from(state),
+		(None, Some(home)) => std::path::Path::new(&home).join(".local/state"),
+		(None, None) => std::env::temp_dir()
+	};
+
+	base.join("sunlight")
+		.join(vm_name)
+		.to_string_lossy()
+		.into_owned()
+}
+
+/// Generates the `rombar`/`romfile` options shared by PCI devices.
+pub(crate) fn rom_options(rombar: &Option<bool>, romfile: &Option<String>) -> String {
+	let mut opts = String::new();
+
+	if let Some(bar) = rombar {
+		opts.push_str(format!(",rombar={}", *bar as u8).as_str());
+	}
+
+	if let Some(path) = romfile {
+		opts.push_str(format!(",romfile={}", qemu_escape(path)).as_str());
+	}
+
+	opts
+}
+
+/// Checks that a custom option ROM (if any) actually exists.
+pub(crate) fn check_romfile(romfile: &Option<String>) -> Result<(), ValidationError> {
+	match romfile {
+		Some(path) if !std::path::Path::new(path).is_file() => Err(ValidationError::PathNotFound { path: path.clone() }),
+		_ => Ok(())
+	}
+}
+
+/// Passes a host input device (keyboard, mouse, ...) straight through to the guest.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioInputHost {
+	pub id: String,
+
+	/// The evdev node of the host device (e.g: `/dev/input/event3`).
+	pub evdev_path: String
+}
+
+/// QEMU's educational PCI device. It doesn't do anything useful, but it's
+/// a known device which is handy to look for when testing Sunlight itself.
+#[cfg(feature = "testing")]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EduDevice;
+
+/// A virtio-crypto device, backed by QEMU's builtin (software) crypto backend.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioCrypto {
+	pub id: String
+}
+
+/// A vhost-vsock device, which gives the guest an address for sockets between it and the host.
+/// Needs `/dev/vhost-vsock` on the host. See [VirtualMachine::forward_vsock].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Vsock {
+	#[serde(default)]
+	pub id: String,
+
+	/// The guest's context id (its address). It has to be unique on the host.
+	pub guest_cid: u32
+}
+
+/// A unix socket relaying connections to a port of the guest's [Vsock], from [VirtualMachine::forward_vsock].
+/// Dropping it stops accepting connections (and removes the socket), but leaves open ones be.
+pub struct VsockForward {
+	path: String,
+	relay: JoinHandle<()>
+}
+
+impl Drop for VsockForward {
+	fn drop(&mut self) {
+		self.relay.abort();
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+/// A connected vsock stream socket. Tokio has no vsock type, so this drives the socket itself through [AsyncFd].
+struct VsockStream {
+	fd: AsyncFd<OwnedFd>
+}
+
+impl VsockStream {
+	/// Connect to a vsock port. Sockets belonging to the host are reached through CID 2.
+	async fn connect(cid: u32, port: u32) -> std::io::Result<VsockStream> {
+		use nix::fcntl::{fcntl, FcntlArg, OFlag};
+		use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+		use std::os::fd::FromRawFd;
+
+		let fd = tokio::task::spawn_blocking(move || {
+			let fd = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+
+			// SAFETY: socket() just created the descriptor, so nothing else owns it
+			let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+			connect(fd.as_raw_fd(), &VsockAddr::new(cid, port))?;
+			fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+			Ok::<_, std::io::Error>(fd)
+		}).await.map_err(std::io::Error::other)??;
+
+		Ok(VsockStream { fd: AsyncFd::new(fd)? })
+	}
+}
+
+impl AsyncRead for VsockStream {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+		loop {
+			let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+			let unfilled = buf.initialize_unfilled();
+
+			match guard.try_io(|fd| nix::unistd::read(fd.as_raw_fd(), unfilled).map_err(std::io::Error::from)) {
+				Ok(read) => {
+					buf.advance(read?);
+					return Poll::Ready(Ok(()));
+				},
+				Err(_would_block) => continue
+			}
+		}
+	}
+}
+
+impl AsyncWrite for VsockStream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+		loop {
+			let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+
+			match guard.try_io(|fd| nix::unistd::write(fd.as_raw_fd(), buf).map_err(std::io::Error::from)) {
+				Ok(written) => return Poll::Ready(written),
+				Err(_would_block) => continue
+			}
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		let shutdown = nix::sys::socket::shutdown(self.fd.as_raw_fd(), nix::sys::socket::Shutdown::Write);
+		Poll::Ready(shutdown.map_err(std::io::Error::from))
+	}
+}
+
+/// Relay each connection accepted on `listener` to a connection made with `connect`, until the listener fails.
+async fn relay_connections<S, F>(listener: tokio::net::UnixListener, connect: impl Fn() -> F)
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	F: std::future::Future<Output = std::io::Result<S>> + Send + 'static
+{
+	while let Ok((mut client, _)) = listener.accept().await {
+		let target = connect();
+		tokio::spawn(async move {
+			match target.await {
+				Ok(mut target) => {
+					let _ = tokio::io::copy_bidirectional(&mut client, &mut target).await;
+				},
+				Err(err) => log::warn!("couldn't connect a forwarded vsock connection: {err}")
+			}
+		});
+	}
+}
+
+/// A hardware random number generator for the guest, so it doesn't have to wait for entropy at boot.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Rng {
+	/// virtio-rng.
+	Virtio {
+		id: String,
+
+		/// A host file to read entropy from (e.g: `/dev/urandom`). If None, QEMU
+		/// uses its builtin backend, which gets entropy the same way QEMU does.
+		source: Option<String>,
+
+		/// Limit the guest to `max_bytes` of entropy every `period_ms`
+		/// (so a guest can't drain the host's entropy). Unlimited if None.
+		max_bytes: Option<u64>,
+
+		/// The period `max_bytes` applies to, in milliseconds. QEMU defaults to 1000.
+		period_ms: Option<u32>
+	}
+}
+
+/// A memory balloon (virtio-balloon), which lets the host take memory back from an idle guest
+/// (see [VirtualMachine::set_balloon]). A VM can only have one.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Balloon {
+	pub id: String,
+
+	/// Let the guest hand pages it isn't using back to the host on its own,
+	/// without waiting for the balloon to be inflated.
+	#[serde(default)]
+	pub free_page_reporting: bool,
+
+	/// Let the guest deflate the balloon when it's about to run out of memory.
+	#[serde(default)]
+	pub deflate_on_oom: bool,
+
+	/// How often the guest reports its memory usage, in seconds (see [VirtualMachine::balloon_stats]).
+	/// If None, it doesn't, and only the balloon's own size is known.
+	pub stats_interval_secs: Option<u32>
+}
+
+impl Balloon {
+	/// Where the device's properties (like its guest stats) live in the QOM tree.
+	fn qom_path(&self) -> String {
+		format!("/machine/peripheral/vm.{}", self.id)
+	}
+}
+
+/// Memory usage of a guest with a [Balloon], from [VirtualMachine::balloon_stats].
+/// The guest's own figures are None if it hasn't reported them (or doesn't know them).
+#[derive(Clone, Debug)]
+pub struct BalloonStats {
+	/// How much memory the guest has left, once the balloon's taken its share.
+	pub actual: ByteSize,
+
+	/// When the guest last reported its figures, in seconds since the Unix epoch.
+	pub last_update: Option<u64>,
+
+	pub total_memory: Option<ByteSize>,
+	pub free_memory: Option<ByteSize>,
+
+	/// Memory the guest could use without swapping, including reclaimable caches.
+	pub available_memory: Option<ByteSize>,
+	pub disk_caches: Option<ByteSize>,
+	pub swapped_in: Option<ByteSize>,
+	pub swapped_out: Option<ByteSize>
+}
+
+impl BalloonStats {
+	/// Put the balloon's size together with the `guest-stats` QOM property (which is null if not polled).
+	fn new(actual: i64, guest: &serde_json::Value) -> BalloonStats {
+		// Figures the guest hasn't reported are -1 (as an unsigned 64-bit number)
+		let stat = |name: &str| guest["stats"][name].as_u64()
+			.filter(|value| *value != u64::MAX)
+			.map(ByteSize::bytes);
+
+		BalloonStats {
+			actual: ByteSize::bytes(actual.max(0) as u64),
+			last_update: guest["last-update"].as_u64().filter(|time| *time != 0),
+			total_memory: stat("stat-total-memory"),
+			free_memory: stat("stat-free-memory"),
+			available_memory: stat("stat-available-memory"),
+			disk_caches: stat("stat-disk-caches"),
+			swapped_in: stat("stat-swap-in"),
+			swapped_out: stat("stat-swap-out")
+		}
+	}
+}
+
+/// How a [NinePShare] maps guest file ownership/permissions onto the host.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum NinePSecurityModel {
+	/// Files are created with the guest's credentials. QEMU must run as root for this to be useful.
+	Passthrough,
+
+	/// Guest credentials are stored in extended attributes.
+	Mapped,
+
+	/// Guest credentials are stored in hidden files.
+	MappedFile,
+
+	/// Like passthrough, but failures to set credentials are ignored.
+	None
+}
+
+/// A host directory shared with the guest over 9p (virtio-9p).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NinePShare {
+	pub id: String,
+
+	/// The host directory to share.
+	pub path: String,
+
+	/// The tag the guest mounts the share by.
+	pub mount_tag: String,
+
+	pub security_model: NinePSecurityModel,
+
+	/// Use the (deprecated) `-virtfs` shorthand instead of `-fsdev` + `-device`.
+	#[serde(default)]
+	pub legacy_virtfs: bool
+}
+
+/// Where a [SmartCard] reader gets its card from.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum SmartCardBackend {
+	/// Pass a card from the SPICE client through to the guest. Requires SPICE.
+	Passthrough,
+
+	/// Emulate a card with certificates from the host's NSS database.
+	Emulated
+}
+
+/// A USB CCID smartcard reader.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SmartCard {
+	/// Left empty by definitions from before readers had ids, which get one assigned.
+	#[serde(default)]
+	pub id: String,
+
+	pub backend: SmartCardBackend
+}
+
+/// Passes a whole host PCI device (e.g: a GPU, or an NVMe drive) through to the guest with VFIO.
+/// Unlike [GraphicsAdapter::VgpuVga], the host loses access to the device while the VM is running.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PciPassthrough {
+	pub id: String,
+
+	/// The host PCI address of the device (e.g: `0000:01:00.0`).
+	pub host_address: String,
+
+	pub rombar: Option<bool>,
+	pub romfile: Option<String>,
+
+	/// Expose the device as multifunction, so other functions of the same card
+	/// (e.g: a GPU's audio function) can sit next to it.
+	#[serde(default)]
+	pub multifunction: bool,
+
+	/// Bind the device to vfio-pci when the VM starts (unbinding the host driver),
+	/// and give it back to the host driver once the VM stops. If this is off, the
+	/// device has to already be bound to vfio-pci.
+	#[serde(default)]
+	pub rebind_driver: bool,
+
+	/// Make this device the failover primary for the virtio-net adapter with this id
+	/// (which needs `failover` enabled). Only useful for network cards.
+	pub failover_pair_id: Option<String>,
+
+	/// The named root port to place the device on. On Q35, one is allocated if None.
+	pub root_port: Option<String>
+}
+
+/// The sysfs directory of a host PCI device.
+fn pci_sysfs_path(address: &str) -> std::path::PathBuf {
+	std::path::Path::new("/sys/bus/pci/devices").join(address)
+}
+
+/// The driver a host PCI device is bound to, if any.
+fn pci_driver(address: &str) -> Option<String> {
+	std::fs::read_link(pci_sysfs_path(address).join("driver"))
+		.ok()
+		.and_then(|driver| driver.file_name().map(|name| name.to_string_lossy().into_owned()))
+}
+
+/// A virtio-serial channel for the QEMU guest agent, which Sunlight can talk
+/// to through [VirtualMachine::guest_agent]. The guest has to be running qemu-ga.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuestAgent {
+	pub id: String,
+
+	/// The socket QEMU will listen on for the host side of the channel.
+	pub socket_path: String
+}
+
+impl GuestAgent {
+	/// Create a guest agent channel with its socket at [ga::socket_path] for the VM named `vm_name`.
+	pub fn new(vm_name: &str) -> GuestAgent {
+		GuestAgent {
+			id: String::new(),
+			socket_path: ga::socket_path(vm_name)
+		}
+	}
+}
+
+/// Where a [SerialConsole]'s output goes (and its input comes from).
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SerialBackend {
+	/// QEMU's own standard input and output, i.e: the terminal Sunlight was started from.
+	/// Only one console can use this.
+	Stdio,
+
+	/// A pseudo-terminal, which QEMU creates.
+	Pty,
+
+	/// A UNIX socket QEMU listens on.
+	UnixSocket {
+		path: String
+	},
+
+	/// A file output is appended to. Nothing can be sent to the guest.
+	LogFile {
+		path: String
+	}
+}
+
+/// A serial port (e.g: COM1 or ttyS0), which [VirtualMachine::console] can connect to.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SerialConsole {
+	pub id: String,
+	pub backend: SerialBackend
+}
+
+/// A named virtio-serial port, for custom host <-> guest channels (e.g: a home-grown guest agent).
+/// The guest sees it as `/dev/virtio-ports/{name}`, and the host connects with [VirtualMachine::channel].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioSerialChannel {
+	pub id: String,
+
+	/// The port name the guest sees (e.g: `org.example.agent.0`).
+	pub name: String,
+
+	pub backend: SerialBackend
+}
+
+/// A connection to a [SerialConsole] or [VirtioSerialChannel], from [VirtualMachine::console] or [VirtualMachine::channel].
+pub struct SerialConsoleIo {
+	pub reader: Box<dyn AsyncRead + Send + Unpin>,
+
+	/// None if nothing can be sent to the guest (with [SerialBackend::LogFile]).
+	pub writer: Option<Box<dyn AsyncWrite + Send + Unpin>>
+}
+
+/// The ISA debug console. OVMF and SeaBIOS write their debug output to this port,
+/// which QEMU then logs to the given file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IsaDebugCon {
+	/// The I/O port the debug console lives on. 0x402 is what OVMF uses by default.
+	pub iobase: u16,
+
+	/// The file debug output will be written to.
+	pub file_path: String
+}
+
+impl IsaDebugCon {
+	/// Create a debug console at OVMF's default port (0x402).
+	pub fn new(file_path: &str) -> IsaDebugCon {
+		IsaDebugCon {
+			iobase: 0x402,
+			file_path: String::from(file_path)
+		}
+	}
+}
+
+/// The watchdog timer hardware the guest sees.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum WatchdogModel {
+	/// Intel 6300ESB, a PCI device. Works on any machine type with PCI.
+	I6300esb,
+
+	/// The TCO watchdog built into the Q35 machine's ICH9 chipset.
+	Itco
+}
+
+/// What QEMU does when the guest stops petting its watchdog.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum WatchdogAction {
+	Reset,
+	Poweroff,
+
+	/// Pause the vCPUs, leaving the VM as it was for inspection.
+	Pause,
+
+	/// Only log it, and let the guest carry on.
+	Debug
+}
+
+/// A watchdog timer, to notice (and recover from) the guest hanging. QEMU sends a
+/// [crate::qemu::qmp::QmpEvent::Watchdog] event whenever it fires. A VM can only have one.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Watchdog {
+	pub model: WatchdogModel,
+	pub action: WatchdogAction
+}
+
+/// A pvpanic device, which the guest kernel uses to tell QEMU it has panicked.
+/// The VM is then paused, and goes into [VMState::Crashed] until it's stopped.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Pvpanic {
+	/// Write the guest's memory (as an ELF core) here when it panics, for debugging the crash later.
+	pub crash_dump: Option<String>
+}
+
+/// The file format of a guest memory dump (see [VirtualMachine::dump_memory]).
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum DumpFormat {
+	/// An ELF core, which gdb (and the crash utility) can read.
+	Elf,
+
+	/// kdump's compressed format, as written by makedumpfile. The compressed variants
+	/// other than zlib are only there if QEMU was built with lzo or snappy.
+	KdumpZlib,
+	KdumpLzo,
+	KdumpSnappy,
+
+	/// A Windows crash dump. Needs a Windows guest with the vmcoreinfo device.
+	WinDmp
+}
+
+impl DumpFormat {
+	fn to_qmp(self) -> qmp::qmp::DumpGuestMemoryFormat {
+		match self {
+			Self::Elf => qmp::qmp::DumpGuestMemoryFormat::elf,
+			Self::KdumpZlib => qmp::qmp::DumpGuestMemoryFormat::kdump_zlib,
+			Self::KdumpLzo => qmp::qmp::DumpGuestMemoryFormat::kdump_lzo,
+			Self::KdumpSnappy => qmp::qmp::DumpGuestMemoryFormat::kdump_snappy,
+			Self::WinDmp => qmp::qmp::DumpGuestMemoryFormat::win_dmp
+		}
+	}
+}
+
+/// Checks if the given accelerator can actually be used on this host.
+pub fn accel_available(accel: &Accelerator) -> bool {
+	match accel {
+		Accelerator::Kvm => kvm_usable(KVM_DEVICE),
+
+		Accelerator::Hvf => cfg!(target_os = "macos"),
+		Accelerator::Whpx => cfg!(target_os = "windows"),
+		Accelerator::Tcg { .. } | Accelerator::Auto => true
+	}
+}
+
+/// Where KVM lives.
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// Opening the KVM device read/write covers both the module not being loaded
+/// and the user not having permission (i.e: not in the kvm group).
+fn kvm_usable(device: &str) -> bool {
+	std::fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.open(device)
+		.is_ok()
+}
+
+impl QemuOption for Accelerator {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::Kvm => qemu_arg("-accel", "kvm"),
+			Self::Tcg { multithreaded, tb_size_mb } => {
+				let mut base = String::from("tcg");
+				if let Some(multi) = multithreaded {
+					base.push_str(if *multi { ",thread=multi" } else { ",thread=single" });
+				}
+				if let Some(size) = tb_size_mb {
+					base.push_str(format!(",tb-size={size}").as_str());
+				}
+				qemu_arg("-accel", base)
+			},
+			Self::Hvf => qemu_arg("-accel", "hvf"),
+			Self::Whpx => qemu_arg("-accel", "whpx"),
+			// QEMU uses the first of these which works
+			Self::Auto => Self::hardware()
+				.chain(std::iter::once(Self::Tcg { multithreaded: None, tb_size_mb: None }))
+				.flat_map(|accel| accel.as_options())
+				.collect()
+		}
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::Tcg { tb_size_mb: Some(0), .. } => Err(ValidationError::InvalidField { field: "tb_size_mb", reason: "must be greater than 0" }),
+			_ => Ok(())
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::Kvm => "Kvm",
+			Self::Tcg { .. } => "Tcg",
+			Self::Hvf => "Hvf",
+			Self::Whpx => "Whpx",
+			Self::Auto => "Auto"
+		};
+
+		format!("Accelerator::{variant}")
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+impl DisplayBackend {
+	/// Whether this display renders with OpenGL, which 3D-accelerated adapters need.
+	pub fn renders_with_gl(&self) -> bool {
+		matches!(self, Self::EglHeadless { .. }
+			| Self::Spice { gl: true, .. }
+			| Self::Gtk { gl: true }
+			| Self::Sdl { gl: true }
+			| Self::DbusP2p { gl: true })
+	}
+}
+
+impl QemuOption for DisplayBackend {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::None => qemu_arg("-display", "none"),
+			Self::EglHeadless { rendernode } => {
+				match rendernode {
+					Some(node) => qemu_arg("-display", format!("egl-headless,rendernode={}", qemu_escape(node))),
+					None => qemu_arg("-display", "egl-headless")
+				}
+			},
+			Self::Gtk { gl } => qemu_arg("-display", format!("gtk,gl={}", bool_to_qemu(*gl))),
+			Self::Sdl { gl } => qemu_arg("-display", format!("sdl,gl={}", bool_to_qemu(*gl))),
+			Self::DbusP2p { gl } => qemu_arg("-display", format!("dbus,p2p=on,gl={}", bool_to_qemu(*gl))),
+			Self::Spice { port, addr, unix_socket, tls, disable_ticketing, password_file, agent, usb_redirect, gl, rendernode } => {
+				let mut opts = Vec::new();
+				let mut spice = Vec::new();
+
+				if let Some(port) = port {
+					spice.push(format!("port={port}"));
+				}
+
+				if let Some(unix_socket) = unix_socket {
+					spice.push(format!("unix=on,addr={}", qemu_escape(unix_socket)));
+				} else if let Some(addr) = addr {
+					spice.push(format!("addr={}", qemu_escape(addr)));
+				}
+
+				if let Some(tls) = tls {
+					spice.push(format!("tls-port={},x509-dir={}", tls.port, qemu_escape(&tls.x509_dir)));
+				}
+
+				if *disable_ticketing {
+					spice.push(String::from("disable-ticketing=on"));
+				}
+
+				// The password is read from a file, so it doesn't show up in the command line
+				if let Some(password_file) = password_file {
+					opts.append(&mut qemu_arg("-object", format!("secret,id=vm.spice.password,file={}", qemu_escape(password_file))));
+					spice.push(String::from("password-secret=vm.spice.password"));
+				}
+
+				if *gl {
+					spice.push(String::from("gl=on"));
+
+					if let Some(node) = rendernode {
+						spice.push(format!("rendernode={}", qemu_escape(node)));
+					}
+				}
+
+				opts.append(&mut qemu_arg("-spice", spice.join(",")));
+
+				if *agent {
+					opts.append(&mut [
+						qemu_arg("-chardev", "spicevmc,id=vm.spice.vdagent,name=vdagent"),
+						qemu_arg("-device", format!("virtserialport,bus={VIRTIO_SERIAL_ID}.0,chardev=vm.spice.vdagent,name=com.redhat.spice.0"))
+					].concat());
+				}
+
+				for index in 0..*usb_redirect {
+					opts.append(&mut [
+						qemu_arg("-chardev", format!("spicevmc,id=vm.spice.usbredir{index},name=usbredir")),
+						qemu_arg("-device", format!("usb-redir,chardev=vm.spice.usbredir{index},id=vm.spice.usbredir{index}.dev"))
+					].concat());
+				}
+
+				// Otherwise QEMU opens a local window as well
+				opts.append(&mut qemu_arg("-display", "none"));
+				opts
+			},
+			Self::Vnc { listen, port, password_secret, websocket } => {
+				let mut opts = Vec::new();
+
+				// QEMU takes a display number, which is the port minus 5900
+				let mut vnc = format!("{}:{}", listen.as_deref().unwrap_or(""), port.saturating_sub(5900));
+
+				if let Some(password_secret) = password_secret {
+					opts.append(&mut qemu_arg("-object", format!("secret,id=vm.vnc.password,file={}", qemu_escape(password_secret))));
+					vnc.push_str(",password-secret=vm.vnc.password");
+				}
+
+				if let Some(websocket) = websocket {
+					vnc.push_str(format!(",websocket={websocket}").as_str());
+				}
+
+				opts.append(&mut qemu_arg("-vnc", vnc));
+				opts
+			}
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::None => Ok(()),
+			Self::EglHeadless { rendernode } => {
+				if let Some(node) = rendernode {
+					if !std::path::Path::new(node).exists() {
+						return Err(ValidationError::PathNotFound { path: node.clone() });
+					}
+				}
+
+				// egl-headless has nothing to render without a GL-capable adapter
+				if !machine.devices_of::<GraphicsAdapter>().any(GraphicsAdapter::provides_gl) {
+					return Err(ValidationError::NoGlAdapter);
+				}
+
+				Ok(())
+			},
+			Self::Gtk { gl } | Self::Sdl { gl } => {
+				if std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none() {
+					return Err(ValidationError::NoHostDisplay);
+				}
+
+				if *gl && !machine.devices_of::<GraphicsAdapter>().any(GraphicsAdapter::provides_gl) {
+					return Err(ValidationError::NoGlAdapter);
+				}
+
+				Ok(())
+			},
+			Self::DbusP2p { gl } => {
+				if *gl && !machine.devices_of::<GraphicsAdapter>().any(GraphicsAdapter::provides_gl) {
+					return Err(ValidationError::NoGlAdapter);
+				}
+
+				Ok(())
+			},
+			Self::Spice { port, addr, unix_socket, tls, disable_ticketing, password_file, usb_redirect, gl, rendernode, .. } => {
+				if port.is_none() && unix_socket.is_none() && tls.is_none() {
+					return Err(ValidationError::InvalidField { field: "port", reason: "SPICE needs a port, a TLS port or a UNIX socket to listen on" });
+				}
+
+				if unix_socket.is_some() && (port.is_some() || addr.is_some() || tls.is_some()) {
+					return Err(ValidationError::InvalidField { field: "unix_socket", reason: "can't be combined with port, addr or tls" });
+				}
+
+				if let Some(tls) = tls {
+					for file in ["ca-cert.pem", "server-cert.pem", "server-key.pem"] {
+						let path = std::path::Path::new(&tls.x509_dir).join(file);
+						if !path.exists() {
+							return Err(ValidationError::PathNotFound { path: path.to_string_lossy().into_owned() });
+						}
+					}
+				}
+
+				match (disable_ticketing, password_file) {
+					(true, Some(..)) => return Err(ValidationError::InvalidField { field: "password_file", reason: "can't be set along with disable_ticketing" }),
+					(false, None) => return Err(ValidationError::InvalidField { field: "disable_ticketing", reason: "either disable ticketing, or set password_file" }),
+					(false, Some(password_file)) if !std::path::Path::new(password_file).exists() => {
+						return Err(ValidationError::PathNotFound { path: password_file.clone() });
+					},
+					_ => {}
+				}
+
+				if *usb_redirect > 0 {
+					check_usb_bus(machine)?;
+				}
+
+				if *gl {
+					if unix_socket.is_none() {
+						return Err(ValidationError::InvalidField { field: "gl", reason: "only works over a UNIX socket" });
+					}
+
+					if !machine.devices_of::<GraphicsAdapter>().any(GraphicsAdapter::provides_gl) {
+						return Err(ValidationError::NoGlAdapter);
+					}
+				}
+
+				if let Some(node) = rendernode {
+					if !*gl {
+						return Err(ValidationError::InvalidField { field: "rendernode", reason: "only used with gl" });
+					}
+
+					if !std::path::Path::new(node).exists() {
+						return Err(ValidationError::PathNotFound { path: node.clone() });
+					}
+				}
+
+				Ok(())
+			},
+			Self::Vnc { port, password_secret, websocket, .. } => {
+				if *port < 5900 {
+					return Err(ValidationError::InvalidField { field: "port", reason: "VNC ports start at 5900" });
+				}
+
+				if *websocket == Some(*port) {
+					return Err(ValidationError::InvalidField { field: "websocket", reason: "can't be the same as the VNC port" });
+				}
+
+				if let Some(password_secret) = password_secret {
+					if !std::path::Path::new(password_secret).exists() {
+						return Err(ValidationError::PathNotFound { path: password_secret.clone() });
+					}
+				}
+
+				Ok(())
+			}
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::None => "None",
+			Self::EglHeadless { .. } => "EglHeadless",
+			Self::Gtk { .. } => "Gtk",
+			Self::Sdl { .. } => "Sdl",
+			Self::DbusP2p { .. } => "DbusP2p",
+			Self::Spice { .. } => "Spice",
+			Self::Vnc { .. } => "Vnc"
+		};
+
+		format!("DisplayBackend::{variant}")
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn virtio_serial_ports(&self) -> u32 {
+		match self {
+			Self::Spice { agent: true, .. } => 1,
+			_ => 0
+		}
+	}
+}
+
+impl QemuOption for Sandbox {
+	fn as_options(&self) -> Vec<String> {
+		let mut opts = String::from("on");
+
+		if self.deny_obsolete {
+			opts.push_str(",obsolete=deny");
+		}
+
+		if self.deny_elevate_privileges {
+			opts.push_str(",elevateprivileges=deny");
+		}
+
+		if self.deny_spawn {
+			opts.push_str(",spawn=deny");
+		}
+
+		if self.deny_resource_control {
+			opts.push_str(",resourcecontrol=deny");
+		}
+
+		qemu_arg("-sandbox", opts)
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if !self.deny_spawn {
+			return Ok(());
+		}
+
+		// QEMU would only fail when it tries to spawn the helper, which
+		// makes for a very confusing error, so catch it here instead
+		if machine.devices_of::<Network>().any(Network::spawns_helper) {
+			return Err(ValidationError::SandboxForbidsHelper);
+		}
+
+		Ok(())
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+/// Hands out PCIe root ports on the Q35 root complex. Every root port needs a unique
+/// chassis/slot pair, so each port gets its own chassis (starting from 1).
+pub(crate) struct RootPortAllocator {
+	legacy: bool,
+	chassis: u8,
+
+	/// How many ports have been allocated for devices which didn't ask for a specific one.
+	allocated: usize
+}
+
+impl RootPortAllocator {
+	pub(crate) fn new(legacy: bool) -> RootPortAllocator {
+		RootPortAllocator {
+			legacy,
+			chassis: 0,
+			allocated: 0
+		}
+	}
+
+	/// The `-device` value for a new root port with the given (full) id.
+	pub(crate) fn port(&mut self, id: &str, hotplug: bool) -> Result<String, VMQemuProcessStartError> {
+		self.chassis = self.chassis.checked_add(1).ok_or(VMQemuProcessStartError::TooManyRootPorts)?;
+
+		let device = if self.legacy { "ioh3420" } else { "pcie-root-port" };
+		let mut port = format!("{device},id={id},chassis={},slot=0,bus=pcie.0", self.chassis);
+
+		if hotplug {
+			port.push_str(",hotplug=on");
+		}
+
+		Ok(port)
+	}
+
+	/// Allocate a new port for a device. Returns the port's id, and its `-device` value.
+	pub(crate) fn allocate(&mut self) -> Result<(String, String), VMQemuProcessStartError> {
+		let id = format!("vm.rp{}", self.allocated);
+		self.allocated += 1;
+
+		let port = self.port(&id, false)?;
+		Ok((id, port))
+	}
+}
+
+/// Sets a device's place in the boot order, by adding `bootindex` to its (first) `-device` argument.
+pub(crate) fn set_boot_index(mut options: Vec<String>, index: usize) -> Vec<String> {
+	if let Some(position) = options.iter().position(|opt| opt == "-device") {
+		if let Some(device) = options.get_mut(position + 1) {
+			device.push_str(format!(",bootindex={index}").as_str());
+		}
+	}
+
+	options
+}
+
+/// Swaps the `host` CPU model, which only exists under hardware acceleration, for `max` (the closest TCG has).
+/// Under KVM, `max` is everything the host supports too, so this is safe when it isn't known which will be used.
+pub(crate) fn emulate_host_cpu(mut options: Vec<String>) -> Vec<String> {
+	// Not every flag takes a value (e.g: -nodefaults), so look for -cpu rather than going in pairs
+	for index in 1..options.len() {
+		if options[index - 1] != "-cpu" {
+			continue;
+		}
+
+		let cpu = &mut options[index];
+		if cpu == "host" || cpu.starts_with("host,") {
+			cpu.replace_range(..4, "max");
+		}
+	}
+
+	options
+}
+
+/// Puts a device on a root port, by adding `bus` and `addr` to its (first) `-device` argument.
+/// A root port has a single slot, so the device always goes at address 0.
+pub(crate) fn place_on_port(mut options: Vec<String>, port: &str) -> Vec<String> {
+	if let Some(index) = options.iter().position(|opt| opt == "-device") {
+		if let Some(device) = options.get_mut(index + 1) {
+			device.push_str(format!(",bus={port},addr=0x0").as_str());
+		}
+	}
+
+	options
+}
+
+/// Whether we (and so QEMU, which runs as us) can create files in `dir`. The permission
+/// bits alone don't say, since which of them apply depends on who owns the directory.
+pub(crate) fn dir_writable(dir: &std::path::Path) -> bool {
+	dir.is_dir() && nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK).is_ok()
+}
+
+/// The directory a file at `path` would be created in (the current directory for a bare file name),
+/// or None if `path` can't name a file (e.g: it's empty).
+fn containing_dir(path: &str) -> Option<&std::path::Path> {
+	match std::path::Path::new(path).parent() {
+		Some(dir) if dir.as_os_str().is_empty() => Some(std::path::Path::new(".")),
+		dir => dir
+	}
+}
+
+impl QemuOption for QemuLog {
+	fn as_options(&self) -> Vec<String> {
+		let categories = self.categories.iter()
+			.map(|cat| cat.name())
+			.collect::<Vec<&str>>()
+			.join(",");
+
+		[qemu_arg("-d", categories), qemu_arg("-D", self.file.clone())].concat()
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.categories.is_empty() {
+			return Err(ValidationError::EmptyField { field: "categories" });
+		}
+
+		// QEMU creates the log file itself, so the directory it's going in needs to be writable
+		let Some(dir) = containing_dir(&self.file) else {
+			return Err(ValidationError::EmptyField { field: "file" });
+		};
+
+		if !dir_writable(dir) {
+			return Err(ValidationError::DirectoryNotWritable { path: dir.to_string_lossy().into_owned() });
+		}
+
+		Ok(())
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+impl QemuOption for MachineType {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::Pc { acpi, usb } => qemu_arg("-machine", format!("pc,acpi={},usb={}", bool_to_qemu(*acpi), bool_to_qemu(*usb))),
+			// Root ports are created by the VM, as devices need them
+			Self::Q35 { acpi, usb, hmat, .. } => qemu_arg("-machine", format!("q35,acpi={},usb={},hmat={}", bool_to_qemu(*acpi), bool_to_qemu(*usb), bool_to_qemu(*hmat))),
+			Self::S390CcwVirtio => qemu_arg("-machine", "s390-ccw-virtio"),
+			//_ => panic!("Unhandled machine type in MachineType::as_options()")
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		let matches = match self {
+			Self::Pc { .. } | Self::Q35 { .. } => matches!(machine.arch, Architecture::X86_64),
+			Self::S390CcwVirtio => matches!(machine.arch, Architecture::S390x)
+		};
+
+		if !matches {
+			return Err(ValidationError::ArchitectureMismatch);
+		}
+
+		Ok(())
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::Pc { .. } => "Pc",
+			Self::Q35 { .. } => "Q35",
+			Self::S390CcwVirtio => "S390CcwVirtio"
+		};
+
+		format!("MachineType::{variant}")
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+impl Cpu {
+	/// How many vCPUs the topology describes.
+	fn topology_size(&self) -> u32 {
+		[self.sockets, self.dies, Some(self.core_count), self.threads].iter()
+			.map(|count| count.unwrap_or(1).max(0) as u32)
+			.product()
+	}
+
+	/// How many vCPUs the VM boots with.
+	fn boot_vcpus(&self) -> u32 {
+		self.vcpus.unwrap_or(self.topology_size())
+	}
+
+	/// The most vCPUs the VM can have, including hotpluggable ones.
+	fn max_vcpu_count(&self) -> u32 {
+		self.max_vcpus.unwrap_or(self.boot_vcpus())
+	}
+}
+
+impl QemuOption for Cpu {
+	fn as_options(&self) -> Vec<String> {
+		let mut cpu = self.model.clone();
+
+		if self.host_cache_info {
+			cpu.push_str(",host-cache-info=on");
+		}
+
+		// Make sure there are features for us to append
+		if !self.features.is_empty() {
+			cpu.push_str(format!(",{}", self.features.join(",")).as_str());
+		}
+
+		let mut smp = format!("{},sockets={}", self.boot_vcpus(), self.sockets.unwrap_or(1));
+
+		// Only x86 machines know about dies (see validate)
+		if let Some(dies) = self.dies {
+			smp.push_str(format!(",dies={dies}").as_str());
+		}
+
+		smp.push_str(format!(",cores={},threads={},maxcpus={}", self.core_count, self.threads.unwrap_or(1), self.max_vcpus.unwrap_or(self.boot_vcpus())).as_str());
+
+		[qemu_arg("-cpu", cpu), qemu_arg("-smp", smp)].concat()
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// Auto swaps it for max when it has to fall back to TCG
+		if self.model == "host" && matches!(machine.accel, Accelerator::Tcg { .. }) {
+			return Err(ValidationError::InvalidField { field: "model", reason: "the host CPU model needs hardware acceleration; use max under TCG" });
+		}
+
+		if self.core_count < 1 {
+			return Err(ValidationError::InvalidField { field: "core_count", reason: "must be at least 1" });
+		}
+
+		if self.dies.is_some_and(|dies| dies < 1) {
+			return Err(ValidationError::InvalidField { field: "dies", reason: "must be at least 1" });
+		}
+
+		// Only x86 machines know about dies
+		if self.dies.is_some() && !matches!(machine.arch, Architecture::X86_64) {
+			return Err(ValidationError::InvalidField { field: "dies", reason: "only x86 machines have dies" });
+		}
+
+		if self.sockets.is_some_and(|sockets| sockets < 1) {
+			return Err(ValidationError::InvalidField { field: "sockets", reason: "must be at least 1" });
+		}
+
+		if self.threads.is_some_and(|threads| threads < 1) {
+			return Err(ValidationError::InvalidField { field: "threads", reason: "must be at least 1" });
+		}
+
+		if self.vcpus == Some(0) {
+			return Err(ValidationError::InvalidField { field: "vcpus", reason: "must be at least 1" });
+		}
+
+		// QEMU wants the topology to describe every possible vCPU, hotpluggable ones included
+		match (self.vcpus, self.max_vcpus) {
+			(_, Some(max_vcpus)) if max_vcpus != self.topology_size() => {
+				return Err(ValidationError::InvalidField { field: "max_vcpus", reason: "has to equal sockets * dies * cores * threads" });
+			},
+			(Some(vcpus), Some(max_vcpus)) if vcpus > max_vcpus => {
+				return Err(ValidationError::InvalidField { field: "vcpus", reason: "can't be more than max_vcpus" });
+			},
+			(Some(vcpus), None) if vcpus != self.topology_size() => {
+				return Err(ValidationError::InvalidField { field: "vcpus", reason: "has to equal sockets * dies * cores * threads (set max_vcpus to leave room for hotplug)" });
+			},
+			_ => {}
+		}
+
+		if self.host_cache_info && self.model != "host" {
+			return Err(ValidationError::InvalidField { field: "host_cache_info", reason: "only works with the host CPU model" });
+		}
+
+		// should probably also check features, but it IS ok for that to be empty
+		// we explicitly check for it when doing as_options() at least
+		if self.model.is_empty() {
+			return Err(ValidationError::EmptyField { field: "model" });
+		}
+
+		Ok(())
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Cpu(self.clone()))
+	}
+}
+
+impl NumaTopology {
+	/// Whether `node` exists, and has vCPUs (so it can be an initiator).
+	fn has_cpus(&self, node: u32) -> bool {
+		self.nodes.get(node as usize).is_some_and(|node| !node.cpus.is_empty())
+	}
+}
+
+impl QemuOption for NumaTopology {
+	fn as_options(&self) -> Vec<String> {
+		let mut opts = Vec::new();
+
+		for (index, node) in self.nodes.iter().enumerate() {
+			let mut numa = if node.legacy_mem {
+				format!("node,nodeid={index},mem={}", node.size)
+			} else {
+				let memdev = format!("vm.numa{index}.ram");
+				let object = node.backend.as_ref()
+					.unwrap_or(&MemoryBackend::Ram { share: false })
+					.object(&memdev, node.size, node.prealloc);
+
+				opts.append(&mut qemu_arg("-object", object));
+				format!("node,nodeid={index},memdev={memdev}")
+			};
+
+			for cpu in &node.cpus {
+				numa.push_str(format!(",cpus={cpu}").as_str());
+			}
+
+			if let Some(initiator) = node.initiator {
+				numa.push_str(format!(",initiator={initiator}").as_str());
+			}
+
+			opts.append(&mut qemu_arg("-numa", numa));
+		}
+
+		for entry in &self.hmat {
+			let mut hmat = format!("hmat-lb,initiator={},target={},hierarchy=memory,data-type={}", entry.initiator, entry.target, entry.data_type.as_qemu());
+
+			if let Some(latency) = entry.latency_ns {
+				hmat.push_str(format!(",latency={latency}").as_str());
+			}
+
+			if let Some(bandwidth) = &entry.bandwidth {
+				hmat.push_str(format!(",bandwidth={bandwidth}").as_str());
+			}
+
+			opts.append(&mut qemu_arg("-numa", hmat));
+		}
+
+		opts
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.nodes.is_empty() {
+			return Err(ValidationError::EmptyField { field: "nodes" });
+		}
+
+		// QEMU refuses a mix of memdev and the legacy mem= form
+		if self.nodes.iter().any(|node| node.legacy_mem) && !self.nodes.iter().all(|node| node.legacy_mem) {
+			return Err(ValidationError::MixedNumaMemory);
+		}
+
+		let memory = match machine.devices_of::<Memory>().next() {
+			Some(memory) => memory,
+			None => return Err(ValidationError::NoMemory)
+		};
+
+		// Node memory comes from the nodes' own backends
+		if memory.backend.is_some() || memory.prealloc {
+			return Err(ValidationError::InvalidField { field: "backend", reason: "memory backends and prealloc have to be set on each NUMA node instead of Memory" });
+		}
+
+		let max_vcpus = match machine.devices_of::<Cpu>().next().map(Cpu::max_vcpu_count) {
+			Some(max_vcpus) => max_vcpus,
+			None => return Err(ValidationError::NoCpu)
+		};
+
+		let mut assigned_cpus = Vec::new();
+
+		for node in &self.nodes {
+			if node.size.as_bytes() == 0 {
+				return Err(ValidationError::InvalidField { field: "size", reason: "must be greater than 0" });
+			}
+
+			if node.legacy_mem && (node.backend.is_some() || node.prealloc) {
+				return Err(ValidationError::InvalidField { field: "backend", reason: "nodes using the legacy mem= form can't have a backend or prealloc" });
+			}
+
+			if let Some(backend) = &node.backend {
+				backend.validate()?;
+			}
+
+			for cpu in &node.cpus {
+				if *cpu >= max_vcpus {
+					return Err(ValidationError::InvalidField { field: "cpus", reason: "refers to a vCPU the CPU topology doesn't have" });
+				}
+
+				if assigned_cpus.contains(cpu) {
+					return Err(ValidationError::InvalidField { field: "cpus", reason: "a vCPU can only be on one node" });
+				}
+
+				assigned_cpus.push(*cpu);
+			}
+
+			if let Some(initiator) = node.initiator {
+				if !self.has_cpus(initiator) {
+					return Err(ValidationError::InvalidField { field: "initiator", reason: "has to be a node with vCPUs" });
+				}
+			}
+		}
+
+		let total = self.nodes.iter().try_fold(ByteSize::bytes(0), |total, node| total.checked_add(node.size));
+		if total != Some(memory.size) {
+			return Err(ValidationError::InvalidField { field: "size", reason: "the sizes of all NUMA nodes have to add up to the VM's memory size" });
+		}
+
+		let hmat = matches!(machine.machine, Some(MachineType::Q35 { hmat: true, .. }));
+
+		if hmat && self.nodes.iter().any(|node| node.initiator.is_none()) {
+			return Err(ValidationError::EmptyField { field: "initiator" });
+		}
+
+		if !self.hmat.is_empty() && !hmat {
+			return Err(ValidationError::UnsupportedMachineType { requires: "Q35 with hmat enabled" });
+		}
+
+		for entry in &self.hmat {
+			if !self.has_cpus(entry.initiator) {
+				return Err(ValidationError::InvalidField { field: "initiator", reason: "has to be a node with vCPUs" });
+			}
+
+			if entry.target as usize >= self.nodes.len() {
+				return Err(ValidationError::InvalidField { field: "target", reason: "isn't a NUMA node" });
+			}
+
+			match (entry.data_type.is_latency(), entry.latency_ns, &entry.bandwidth) {
+				(true, Some(..), None) => {},
+				(false, None, Some(..)) => {},
+				(true, ..) => return Err(ValidationError::InvalidField { field: "latency_ns", reason: "latency data types need latency_ns (and no bandwidth)" }),
+				(false, ..) => return Err(ValidationError::InvalidField { field: "bandwidth", reason: "bandwidth data types need bandwidth (and no latency_ns)" })
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// A size in bytes. In VM definitions (and through [std::str::FromStr]) it's written the way QEMU
+/// takes sizes, e.g: `4G` or `512M`. `B` is bytes, and no suffix at all means megabytes, like `-m`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+	pub const fn bytes(bytes: u64) -> ByteSize {
+		ByteSize(bytes)
+	}
+
+	pub const fn kib(kib: u64) -> ByteSize {
+		ByteSize(kib.saturating_mul(1 << 10))
+	}
+
+	pub const fn mib(mib: u64) -> ByteSize {
+		ByteSize(mib.saturating_mul(1 << 20))
+	}
+
+	pub const fn gib(gib: u64) -> ByteSize {
+		ByteSize(gib.saturating_mul(1 << 30))
+	}
+
+	pub const fn as_bytes(&self) -> u64 {
+		self.0
+	}
+
+	/// Whether this is a whole number of megabytes, which is what most QEMU sizes are rounded to.
+	pub const fn is_whole_mib(&self) -> bool {
+		self.0 % (1 << 20) == 0
+	}
+
+	pub fn checked_add(self, other: ByteSize) -> Option<ByteSize> {
+		self.0.checked_add(other.0).map(ByteSize)
+	}
+
+	pub fn checked_sub(self, other: ByteSize) -> Option<ByteSize> {
+		self.0.checked_sub(other.0).map(ByteSize)
+	}
+}
+
+// Sizes come from VM definitions, so the operators saturate rather than panicking (or wrapping)
+// on something silly. Use checked_add and checked_sub where going out of range has to be caught
+
+impl std::ops::Add for ByteSize {
+	type Output = ByteSize;
+
+	fn add(self, other: ByteSize) -> ByteSize {
+		ByteSize(self.0.saturating_add(other.0))
+	}
+}
+
+impl std::ops::Sub for ByteSize {
+	type Output = ByteSize;
+
+	fn sub(self, other: ByteSize) -> ByteSize {
+		ByteSize(self.0.saturating_sub(other.0))
+	}
+}
+
+impl std::ops::Mul<u64> for ByteSize {
+	type Output = ByteSize;
+
+	fn mul(self, count: u64) -> ByteSize {
+		ByteSize(self.0.saturating_mul(count))
+	}
+}
+
+impl std::iter::Sum for ByteSize {
+	fn sum<I: Iterator<Item = ByteSize>>(iter: I) -> ByteSize {
+		iter.fold(ByteSize(0), |total, size| total + size)
+	}
+}
+
+impl std::str::FromStr for ByteSize {
+	type Err = InvalidByteSize;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (number, shift) = match s.chars().last() {
+			Some('B' | 'b') => (&s[..s.len() - 1], 0),
+			Some('K' | 'k') => (&s[..s.len() - 1], 10),
+			Some('M' | 'm') => (&s[..s.len() - 1], 20),
+			Some('G' | 'g') => (&s[..s.len() - 1], 30),
+			Some('T' | 't') => (&s[..s.len() - 1], 40),
+			_ => (s, 20)
+		};
+
+		number.parse::<u64>()
+			.ok()
+			.and_then(|number| number.checked_mul(1 << shift))
+			.map(ByteSize)
+			.ok_or_else(|| InvalidByteSize(String::from(s)))
+	}
+}
+
+impl TryFrom<String> for ByteSize {
+	type Error = InvalidByteSize;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl From<ByteSize> for String {
+	fn from(size: ByteSize) -> String {
+		size.to_string()
+	}
+}
+
+impl std::fmt::Display for ByteSize {
+	/// Writes the size with the largest suffix it's a whole multiple of, so QEMU reads it back exactly.
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let (shift, suffix) = [(40, 'T'), (30, 'G'), (20, 'M'), (10, 'K')].into_iter()
+			.find(|(shift, _)| self.0 != 0 && self.0 % (1 << shift) == 0)
+			.unwrap_or((0, 'B'));
+
+		write!(f, "{}{suffix}", self.0 >> shift)
+	}
+}
+
+/// A unicast MAC address, written the usual way (e.g: `52:54:00:12:34:56`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddr([u8; 6]);
+
+impl MacAddr {
+	/// QEMU's own locally administered prefix, which it also uses for the addresses it picks.
+	const PREFIX: [u8; 3] = [0x52, 0x54, 0x00];
+
+	pub fn new(octets: [u8; 6]) -> Result<MacAddr, InvalidMacAddr> {
+		// Multicast addresses can't be assigned to an interface
+		if octets[0] & 1 != 0 {
+			return Err(InvalidMacAddr { addr: MacAddr(octets).to_string(), reason: "the multicast bit is set" });
+		}
+
+		Ok(MacAddr(octets))
+	}
+
+	/// Derive an address from a VM's UUID and a NIC's id, so a NIC without a MAC
+	/// keeps the same one across restarts (instead of QEMU picking one by position).
+	pub fn derive(vm_uuid: &str, nic_id: &str) -> MacAddr {
+		// FNV-1a, since std's hashers aren't guaranteed to stay the same between Rust releases
+		let hash = vm_uuid.bytes()
+			.chain(std::iter::once(0))
+			.chain(nic_id.bytes())
+			.fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+
+		let [a, b, c, ..] = hash.to_be_bytes();
+		let [x, y, z] = Self::PREFIX;
+		MacAddr([x, y, z, a, b, c])
+	}
+
+	pub const fn octets(&self) -> [u8; 6] {
+		self.0
+	}
+}
+
+impl std::str::FromStr for MacAddr {
+	type Err = InvalidMacAddr;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || InvalidMacAddr { addr: String::from(s), reason: "expected six hex octets separated by colons" };
+
+		let mut octets = [0u8; 6];
+		let mut parts = s.split(':');
+
+		for octet in octets.iter_mut() {
+			// from_str_radix would take a sign too
+			let part = parts.next()
+				.filter(|part| part.len() == 2 && part.bytes().all(|byte| byte.is_ascii_hexdigit()))
+				.ok_or_else(invalid)?;
+			*octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+		}
+
+		if parts.next().is_some() {
+			return Err(invalid());
+		}
+
+		MacAddr::new(octets)
+	}
+}
+
+impl TryFrom<String> for MacAddr {
+	type Error = InvalidMacAddr;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl From<MacAddr> for String {
+	fn from(addr: MacAddr) -> String {
+		addr.to_string()
+	}
+}
+
+impl std::fmt::Display for MacAddr {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let [a, b, c, d, e, g] = self.0;
+		write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+	}
+}
+
+impl QemuOption for Memory {
+	fn as_options(&self) -> Vec<String> {
+		let mut size = self.size.to_string();
+
+		if let (Some(max_size), Some(slots)) = (&self.max_size, self.slots) {
+			size.push_str(format!(",slots={slots},maxmem={max_size}").as_str());
+		}
+
+		// Plain anonymous memory doesn't need an explicit backend
+		if self.backend.is_none() && !self.prealloc {
+			return qemu_arg("-m", size);
+		}
+
+		let backend = self.backend.as_ref()
+			.unwrap_or(&MemoryBackend::Ram { share: false })
+			.object("vm.ram", self.size, self.prealloc);
+
+		[
+			qemu_arg("-m", size),
+			qemu_arg("-object", backend),
+			qemu_arg("-machine", "memory-backend=vm.ram")
+		].concat()
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// -m is in megabytes
+		if self.size < ByteSize::mib(1) || !self.size.is_whole_mib() {
+			return Err(ValidationError::InvalidField { field: "size", reason: "has to be a whole number of megabytes, and at least 1M" });
+		}
+
+		if let Some(backend) = &self.backend {
+			backend.validate()?;
+		}
+
+		match (&self.max_size, self.slots) {
+			(None, None) => Ok(()),
+
+			(Some(max_size), Some(slots)) => {
+				if slots == 0 {
+					return Err(ValidationError::InvalidField { field: "slots", reason: "must be at least 1" });
+				}
+
+				if *max_size < self.size {
+					return Err(ValidationError::InvalidField { field: "max_size", reason: "can't be smaller than size" });
+				}
+
+				Ok(())
+			},
+
+			// QEMU needs both of these to do memory hotplug
+			_ => Err(ValidationError::InvalidField { field: "max_size", reason: "max_size and slots have to be set together" })
+		}
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Memory(self.clone()))
+	}
+}
+
+
+impl QemuOption for DiskController {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::VirtioScsi { id } => [
+				qemu_arg("-object", format!("iothread,id=vm.{id}.block_thread")),
+				qemu_arg("-device", format!("virtio-scsi-pci,num_queues=6,iothread=vm.{id}.block_thread,id=vm.{id}"))
+			].concat()
+		}
+	}
+
+	fn order_key(&self) -> u32 {
+		ORDER_EARLY
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		match self {
+			Self::VirtioScsi { .. } => Some("scsi")
+		}
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		match self {
+			Self::VirtioScsi { id } => assign_if_empty(id, "scsi", index)
+		}
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		match self {
+			Self::VirtioScsi { id } => Some(id)
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::VirtioScsi { .. } => "VirtioScsi"
+		};
+
+		format!("DiskController::{variant}")
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::DiskController(self.clone()))
+	}
+}
+
+impl QemuOption for UsbController {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::Xhci { id, usb2_ports, usb3_ports } | Self::NecXhci { id, usb2_ports, usb3_ports } => {
+				let device = match self {
+					Self::NecXhci { .. } => "nec-usb-xhci",
+					_ => "qemu-xhci"
+				};
+
+				let mut base = format!("{device},id=vm.{id}");
+				if let Some(ports) = usb2_ports {
+					base.push_str(format!(",p2={ports}").as_str());
+				}
+				if let Some(ports) = usb3_ports {
+					base.push_str(format!(",p3={ports}").as_str());
+				}
+				qemu_arg("-device", base)
+			},
+			Self::Uhci { id } => qemu_arg("-device", format!("piix3-usb-uhci,id=vm.{id}"))
+		}
+	}
+
+	fn order_key(&self) -> u32 {
+		ORDER_EARLY
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			// QEMU's xHCI emulation supports at most 15 ports of each kind
+			Self::Xhci { usb2_ports, usb3_ports, .. } | Self::NecXhci { usb2_ports, usb3_ports, .. } => {
+				for (field, ports) in [("usb2_ports", usb2_ports), ("usb3_ports", usb3_ports)] {
+					if ports.is_some_and(|ports| !(1..=15).contains(&ports)) {
+						return Err(ValidationError::InvalidField { field, reason: "must be between 1 and 15" });
+					}
+				}
+
+				Ok(())
+			},
+			Self::Uhci { .. } => Ok(())
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::Xhci { .. } => "Xhci",
+			Self::NecXhci { .. } => "NecXhci",
+			Self::Uhci { .. } => "Uhci"
+		};
+
+		format!("UsbController::{variant}")
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("usb")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		match self {
+			Self::Xhci { id, .. } | Self::NecXhci { id, .. } | Self::Uhci { id } => assign_if_empty(id, "usb", index)
+		}
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		match self {
+			Self::Xhci { id, .. } | Self::NecXhci { id, .. } | Self::Uhci { id } => Some(id)
+		}
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::UsbController(self.clone()))
+	}
+}
+
+/// Checks that the VM has somewhere to plug USB devices into.
+pub(crate) fn check_usb_bus(machine: &VirtualMachine) -> Result<(), ValidationError> {
+	let machine_usb = match machine.machine {
+		Some(MachineType::Pc { usb, .. }) => usb,
+		Some(MachineType::Q35 { usb, .. }) => usb,
+		Some(MachineType::S390CcwVirtio) => false,
+		None => false
+	};
+
+	if !machine_usb && machine.devices_of::<UsbController>().next().is_none() {
+		return Err(ValidationError::NoUsbBus);
+	}
+
+	Ok(())
+}
+
+impl QemuOption for InputDevice {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::UsbMouse => qemu_arg("-device", "usb-mouse"),
+			Self::UsbKeyboard => qemu_arg("-device", "usb-kbd"),
+			Self::UsbTablet => qemu_arg("-device", "usb-tablet")
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		check_usb_bus(machine)
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::UsbMouse => "UsbMouse",
+			Self::UsbKeyboard => "UsbKeyboard",
+			Self::UsbTablet => "UsbTablet"
+		};
+
+		format!("InputDevice::{variant}")
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::InputDevice(self.clone()))
+	}
+}
+
+/// Whether a host USB device matching `matches` (given the device's sysfs directory) is plugged in.
+fn host_usb_device_exists(matches: impl Fn(&std::path::Path) -> bool) -> bool {
+	match std::fs::read_dir("/sys/bus/usb/devices") {
+		Ok(devices) => devices.flatten().any(|dev| matches(&dev.path())),
+		Err(..) => false
+	}
+}
+
+/// Reads a sysfs attribute holding a hex id (e.g: `idVendor`).
+fn read_usb_id(dev: &std::path::Path, attribute: &str) -> Option<u16> {
+	let id = std::fs::read_to_string(dev.join(attribute)).ok()?;
+	u16::from_str_radix(id.trim(), 16).ok()
+}
+
+impl QemuOption for UsbDevice {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::Storage { id, image_path, format, readonly } => [
+				qemu_arg("-drive", format!("if=none,file={},format={format},id=vm.{id}.drive,readonly={}", qemu_escape(image_path), bool_to_qemu(*readonly))),
+				qemu_arg("-device", format!("usb-storage,drive=vm.{id}.drive,id=vm.{id}"))
+			].concat(),
+			Self::HostById { id, vendor_id, product_id } => qemu_arg("-device", format!("usb-host,vendorid={vendor_id:#06x},productid={product_id:#06x},id=vm.{id}")),
+			Self::HostByPort { id, bus, port } => qemu_arg("-device", format!("usb-host,hostbus={bus},hostport={port},id=vm.{id}"))
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		check_usb_bus(machine)?;
+
+		match self {
+			Self::Storage { image_path, .. } => {
+				if !std::path::Path::new(image_path).is_file() {
+					return Err(ValidationError::PathNotFound { path: image_path.clone() });
+				}
+
+				Ok(())
+			},
+			Self::HostById { vendor_id, product_id, .. } => {
+				let found = host_usb_device_exists(|dev| {
+					read_usb_id(dev, "idVendor") == Some(*vendor_id) && read_usb_id(dev, "idProduct") == Some(*product_id)
+				});
+
+				if !found {
+					return Err(ValidationError::UsbDeviceNotFound { device: format!("{vendor_id:04x}:{product_id:04x}") });
+				}
+
+				Ok(())
+			},
+			Self::HostByPort { bus, port, .. } => {
+				if port.is_empty() {
+					return Err(ValidationError::EmptyField { field: "port" });
+				}
+
+				// sysfs names devices {bus}-{port}
+				let name = format!("{bus}-{port}");
+				if !host_usb_device_exists(|dev| dev.file_name().is_some_and(|file| file.to_string_lossy() == name)) {
+					return Err(ValidationError::UsbDeviceNotFound { device: name });
+				}
+
+				Ok(())
+			}
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::Storage { .. } => "Storage",
+			Self::HostById { .. } => "HostById",
+			Self::HostByPort { .. } => "HostByPort"
+		};
+
+		format!("UsbDevice::{variant}")
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("usbdev")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		match self {
+			Self::Storage { id, .. } | Self::HostById { id, .. } | Self::HostByPort { id, .. } => assign_if_empty(id, "usbdev", index)
+		}
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		match self {
+			Self::Storage { id, .. } | Self::HostById { id, .. } | Self::HostByPort { id, .. } => Some(id)
+		}
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::UsbDevice(self.clone()))
+	}
+}
+
+impl DiskDrive {
+	/// The overlay this drive uses, and the image it's on top of, if it has a backing image.
+	pub fn overlay(&self) -> Option<(&str, &str)> {
+		match self {
+			Self::HdDrive { image_path, backing: Some(backing), .. } => Some((image_path, backing)),
+			_ => None
+		}
+	}
+
+	/// Make this drive use a new qcow2 overlay in `dir` on top of the image it used (see
+	/// [VirtualMachine::clone_from]). Returns the overlay and its backing image, or None if
+	/// this isn't a drive which should be (read-only drives can just share the image).
+	/// Encrypted drives can't have a backing image, so they can't be linked at all.
+	fn link_overlay(&mut self, dir: &str) -> Result<Option<(String, String)>, VMCreateError> {
+		let Self::HdDrive { id, image_path, format, readonly: false, backing, encryption, .. } = self else {
+			return Ok(None);
+		};
+
+		if encryption.is_some() {
+			return Err(VMCreateError::EncryptedDrive(id.clone()));
+		}
+
+		let overlay = std::path::Path::new(dir)
+			.join(format!("{id}.qcow2"))
+			.to_string_lossy()
+			.into_owned();
+
+		*backing = Some(std::mem::replace(image_path, overlay.clone()));
+		*format = DiskFormat::Qcow2;
+		Ok(backing.clone().map(|backing| (overlay, backing)))
+	}
+
+	/// The `blockdev-add` equivalent of this drive's `-drive` option, for hotplugging it.
+	/// Only hard drives can be hotplugged.
+	pub(crate) fn blockdev_options(&self) -> Option<serde_json::Result<qmp::qmp::BlockdevOptions>> {
+		let Self::HdDrive { id, image_path, readonly, format, cache, aio, throttle_group, discard, detect_zeroes, encryption, .. } = self else {
+			return None;
+		};
+
+		let mut file = serde_json::json!({ "driver": "file", "filename": image_path });
+		if let Some(aio) = aio {
+			file["aio"] = serde_json::json!(aio.name());
+		}
+
+		let mut node = serde_json::json!({ "driver": format.name(), "read-only": readonly, "file": file });
+
+		// Cache modes are shorthand for these (plus the device's write cache, which the device sets)
+		node["cache"] = match cache {
+			Some(CacheMode::None | CacheMode::DirectSync) => serde_json::json!({ "direct": true }),
+			Some(CacheMode::Unsafe) => serde_json::json!({ "no-flush": true }),
+			_ => serde_json::json!({})
+		};
+
+		if *discard {
+			node["discard"] = serde_json::json!("unmap");
+		}
+
+		if let Some(detect_zeroes) = detect_zeroes {
+			node["detect-zeroes"] = serde_json::json!(detect_zeroes.name());
+		}
+
+		if let Some(luks) = encryption {
+			node["encrypt"] = serde_json::json!({ "format": "luks", "key-secret": format!("vm.{}.secret", luks.secret) });
+		}
+
+		// Like with -drive, the throttle filter sits on top of the image
+		let mut top = match throttle_group {
+			Some(group) => serde_json::json!({ "driver": "throttle", "throttle-group": format!("vm.{group}.tg"), "file": node }),
+			None => node
+		};
+		top["node-name"] = serde_json::json!(format!("vm.{id}.drive"));
+
+		Some(serde_json::from_value(top))
+	}
+
+	/// Whether the guest has to be told the device has no write cache, which
+	/// -drive takes care of itself for the cache modes which imply it.
+	pub(crate) fn write_through(&self) -> bool {
+		matches!(self, Self::HdDrive { cache: Some(CacheMode::Writethrough | CacheMode::DirectSync), .. })
+	}
+}
+
+impl QemuOption for DiskDrive {
+
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::CdDrive { interface, id, iso_path } => {
+				// There's no virtio CD drive; validation rejects that
+				let device = match interface {
+					DiskInterface::Ide => "ide-cd",
+					DiskInterface::Scsi => "scsi-cd",
+					DiskInterface::Virtio => "virtio-blk-pci"
+				};
+
+				let mut drive_str = format!("if=none,media=cdrom,aio=io_uring,id=vm.{id}.drive");
+				if let Some(iso) = iso_path {
+					drive_str.push_str(format!(",file={},format=raw", qemu_escape(iso)).as_str());
+				}
+
+				[
+					qemu_arg("-drive", drive_str),
+					qemu_arg("-device", format!("{device},drive=vm.{id}.drive,id=vm.{id}"))
+				].concat()
+			},
+			Self::HdDrive { id, interface, image_path, readonly, format, ssd, cache, aio, throttle_group, throttle, share_rw, logical_block_size, physical_block_size, discard, detect_zeroes, iothread, num_queues, encryption, .. } => {
+				// Throttle groups can only be joined through a throttle filter node,
+				// so the image itself ends up one level down
+				let image_path = qemu_escape(image_path);
+				let (mut drive_str, format_prefix, file_prefix) = match throttle_group {
+					Some(group) => (format!("if=none,driver=throttle,throttle-group=vm.{group}.tg,file.driver={format},file.file.filename={image_path},id=vm.{id}.drive,readonly={}", bool_to_qemu(*readonly)), "file.", "file.file."),
+					None => (format!("if=none,file={image_path},format={format},id=vm.{id}.drive,readonly={}", bool_to_qemu(*readonly)), "", "")
+				};
+
+				if let Some(luks) = encryption {
+					drive_str.push_str(format!(",{format_prefix}encrypt.format=luks,{format_prefix}encrypt.key-secret=vm.{}.secret", luks.secret).as_str());
+				}
+
+				if let Some(str) = cache {
+					drive_str.push_str(format!(",cache={str}").as_str());
+				}
+
+				if let Some(str) = aio {
+					drive_str.push_str(format!(",{file_prefix}aio={str}").as_str());
+				}
+
+				if *discard {
+					drive_str.push_str(",discard=unmap");
+				}
+
+				if let Some(limits) = throttle {
+					drive_str.push_str(limits.as_options("throttling.").as_str());
+				}
+
+				if let Some(detect_zeroes) = detect_zeroes {
+					drive_str.push_str(format!(",detect-zeroes={detect_zeroes}").as_str());
+				}
+
+				let mut opts_str = format!("id=vm.{id},drive=vm.{id}.drive");
+
+				if *iothread {
+					opts_str.push_str(format!(",iothread=vm.{id}.iothread").as_str());
+				}
+
+				if let Some(queues) = num_queues {
+					opts_str.push_str(format!(",num-queues={queues}").as_str());
+				}
+
+				// if on an ssd
+				if *ssd {
+					opts_str.push_str(",rotation_rate=1");
+				}
+
+				if *share_rw {
+					opts_str.push_str(",share-rw=on");
+				}
+
+				if let Some(size) = logical_block_size {
+					opts_str.push_str(format!(",logical_block_size={size}").as_str());
+				}
+
+				if let Some(size) = physical_block_size {
+					opts_str.push_str(format!(",physical_block_size={size}").as_str());
+				}
+
+				let device = match interface {
+					DiskInterface::Ide => "ide-hd",
+					DiskInterface::Scsi => "scsi-hd",
+					DiskInterface::Virtio => "virtio-blk-pci"
+				};
+
+				let drive = [qemu_arg("-drive", drive_str), qemu_arg("-device", format!("{device},{opts_str}"))].concat();
+				if *iothread {
+					[qemu_arg("-object", format!("iothread,id=vm.{id}.iothread")), drive].concat()
+				} else {
+					drive
+				}
+			}
+
+			Self::Nvme { id, image_path, format, serial, namespaces } => {
+				let serial = serial.as_ref().unwrap_or(id);
+				let mut opts = [
+					qemu_arg("-drive", format!("if=none,file={},format={format},id=vm.{id}.drive", qemu_escape(image_path))),
+					qemu_arg("-device", format!("nvme,id=vm.{id},serial={serial}")),
+					qemu_arg("-device", format!("nvme-ns,drive=vm.{id}.drive,nsid=1,bus=vm.{id}"))
+				].concat();
+
+				for ns in namespaces {
+					let nsid = ns.nsid;
+					opts.append(&mut qemu_arg("-drive", format!("if=none,file={},format={},id=vm.{id}.ns{nsid}.drive", qemu_escape(&ns.image_path), ns.format)));
+					opts.append(&mut qemu_arg("-device", format!("nvme-ns,drive=vm.{id}.ns{nsid}.drive,nsid={nsid},bus=vm.{id}")));
+				}
+
+				opts
+			},
+
+			Self::Pflash { id, image_path, readonly, format } => {
+				qemu_arg("-drive", format!("if=pflash,file={},format={format},id=vm.{id}.drive,readonly={}", qemu_escape(image_path), bool_to_qemu(*readonly)))
+			}
+		}
+
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::CdDrive { interface: DiskInterface::Virtio, .. } => {
+				Err(ValidationError::InvalidField { field: "interface", reason: "virtio-blk can't emulate a CD drive" })
+			},
+
+			Self::CdDrive { iso_path: Some(iso), .. } if !std::path::Path::new(iso).is_file() => {
+				Err(ValidationError::PathNotFound { path: iso.clone() })
+			},
+
+			Self::Nvme { serial, namespaces, .. } => {
+				if serial.as_ref().is_some_and(|serial| serial.is_empty()) {
+					return Err(ValidationError::EmptyField { field: "serial" });
+				}
+
+				// The image is always namespace 1
+				check_nvme_nsids(std::iter::once(1).chain(namespaces.iter().map(|ns| ns.nsid)))
+			},
+
+			Self::HdDrive { interface, image_path, format, cache, aio, throttle_group, throttle, logical_block_size, physical_block_size, ssd, discard, detect_zeroes, iothread, num_queues, backing, encryption, .. } => {
+				let virtio = matches!(interface, DiskInterface::Virtio);
+
+				// QEMU refuses native AIO through the page cache
+				if *aio == Some(AioMode::Native) && !cache.is_some_and(|cache| cache.direct()) {
+					return Err(ValidationError::InvalidField { field: "aio", reason: "native AIO needs a cache mode which bypasses the page cache (none or directsync)" });
+				}
+
+				if let Some(backing) = backing {
+					if *format != DiskFormat::Qcow2 {
+						return Err(ValidationError::InvalidField { field: "format", reason: "overlays on a backing image have to be qcow2" });
+					}
+
+					if backing == image_path {
+						return Err(ValidationError::InvalidField { field: "backing", reason: "an image can't be its own backing image" });
+					}
+
+					if !std::path::Path::new(backing).is_file() {
+						return Err(ValidationError::PathNotFound { path: backing.clone() });
+					}
+				}
+
+				if let Some(luks) = encryption {
+					if *format != DiskFormat::Qcow2 {
+						return Err(ValidationError::InvalidField { field: "format", reason: "only qcow2 images can be LUKS encrypted" });
+					}
+
+					// The overlay would be created without knowing how to open the backing image
+					if backing.is_some() {
+						return Err(ValidationError::InvalidField { field: "encryption", reason: "encrypted drives can't have a backing image" });
+					}
+
+					if !machine.secrets.iter().any(|secret| secret.id == luks.secret) {
+						return Err(ValidationError::UnknownSecret { secret: luks.secret.clone() });
+					}
+				}
+
+				if let Some(limits) = throttle {
+					limits.check()?;
+				}
+
+				if *iothread && !virtio {
+					return Err(ValidationError::InvalidField { field: "iothread", reason: "only virtio drives can have their own I/O thread" });
+				}
+
+				match num_queues {
+					Some(..) if !virtio => return Err(ValidationError::InvalidField { field: "num_queues", reason: "only virtio drives have multiple queues" }),
+					Some(0) => return Err(ValidationError::InvalidField { field: "num_queues", reason: "must be at least 1" }),
+					_ => {}
+				}
+
+				// virtio-blk doesn't report a rotation rate
+				if *ssd && virtio {
+					return Err(ValidationError::InvalidField { field: "ssd", reason: "only applies to IDE and SCSI drives" });
+				}
+
+				if *detect_zeroes == Some(DetectZeroes::Unmap) && !*discard {
+					return Err(ValidationError::InvalidField { field: "detect_zeroes", reason: "\"unmap\" needs discard to be enabled" });
+				}
+
+				// Block sizes have to be powers of two, of at least a sector
+				for (field, size) in [("logical_block_size", logical_block_size), ("physical_block_size", physical_block_size)] {
+					if size.is_some_and(|size| size < 512 || !size.is_power_of_two()) {
+						return Err(ValidationError::InvalidField { field, reason: "must be a power of two, and at least 512" });
+					}
+				}
+
+				// A physical block can't be smaller than a logical one (QEMU's default logical size is 512)
+				if physical_block_size.unwrap_or(512) < logical_block_size.unwrap_or(512) {
+					return Err(ValidationError::InvalidField { field: "physical_block_size", reason: "can't be smaller than the logical block size" });
+				}
+
+				match throttle_group {
+					Some(group) if !machine.throttle_groups.iter().any(|tg| tg.id == *group) => {
+						Err(ValidationError::UnknownThrottleGroup { group: group.clone() })
+					},
+					_ => Ok(())
+				}
+			},
+
+			_ => Ok(())
+		}
+	}
+
+	fn warnings(&self, _machine: &VirtualMachine) -> Vec<ValidationWarning> {
+		match self {
+			Self::HdDrive { share_rw: true, .. } => vec![ValidationWarning::SharedWritableImage],
+			_ => Vec::new()
+		}
+	}
+
+	fn requires_pci(&self) -> bool {
+		matches!(self, Self::HdDrive { interface: DiskInterface::Virtio, .. } | Self::Nvme { .. })
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		match self {
+			Self::CdDrive { .. } => Some("cd"),
+			Self::HdDrive { .. } => Some("disk"),
+			Self::Nvme { .. } => Some("nvme"),
+			Self::Pflash { .. } => Some("pflash")
+		}
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		let prefix = self.id_prefix().unwrap();
+		match self {
+			Self::CdDrive { id, .. } => assign_if_empty(id, prefix, index),
+			Self::HdDrive { id, .. } => assign_if_empty(id, prefix, index),
+			Self::Nvme { id, .. } => assign_if_empty(id, prefix, index),
+			Self::Pflash { id, .. } => assign_if_empty(id, prefix, index)
+		}
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		match self {
+			Self::CdDrive { id, .. } | Self::HdDrive { id, .. } | Self::Nvme { id, .. } | Self::Pflash { id, .. } => Some(id)
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::CdDrive { .. } => "CdDrive",
+			Self::HdDrive { .. } => "HdDrive",
+			Self::Nvme { .. } => "Nvme",
+			Self::Pflash { .. } => "Pflash"
+		};
+
+		format!("DiskDrive::{variant}")
+	}
+
+	fn boot_device(&self) -> Option<BootDevice> {
+		match self {
+			Self::CdDrive { id, .. } | Self::HdDrive { id, .. } | Self::Nvme { id, .. } => Some(BootDevice::Drive(id.clone())),
+			// Firmware lives on these, rather than anything to boot
+			Self::Pflash { .. } => None
+		}
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::DiskDrive(self.clone()))
+	}
+}
+
+impl GraphicsAdapter {
+	/// The UUID the VM must have for this adapter to work, if any (NVIDIA vGPUs
+	/// require the VM UUID to match the mdev UUID).
+	pub fn required_vm_uuid(&self) -> Option<&str> {
+		match self {
+			Self::VgpuVga { uuid, .. } => Some(uuid.as_str()),
+			_ => None
+		}
+	}
+
+	/// Whether this adapter can provide GL-accelerated scanout
+	/// (needed by GL display backends like egl-headless).
+	pub fn provides_gl(&self) -> bool {
+		// vGPUs export their display over dma-buf, which GL displays can pick up
+		matches!(self, Self::VgpuVga { .. } | Self::VirtioGpu { virgl: true, .. })
+	}
+}
+
+impl QemuOption for GraphicsAdapter {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::StdVga { vgamem } => qemu_arg("-device", format!("VGA,vgamem_mb={},id=vm.vga", vgamem.as_bytes() >> 20)),
+			Self::CirrusVga { vgamem } => qemu_arg("-device", format!("cirrus-vga,vgamem_mb={},id=vm.vga", vgamem.as_bytes() >> 20)),
+			Self::QxlVga {  } => qemu_arg("-device", "qxl-vga,id=vm.vga"),
+			Self::VirtioGpu { blob, hostmem, vga, virgl, venus } => {
+				let device = match (vga, virgl) {
+					(false, false) => "virtio-gpu-pci",
+					(true, false) => "virtio-vga",
+					(false, true) => "virtio-gpu-gl-pci",
+					(true, true) => "virtio-vga-gl"
+				};
+
+				let mut base = format!("{device},id=vm.vga");
+				if *venus {
+					base.push_str(",venus=on");
+				}
+				if *blob {
+					base.push_str(",blob=on");
+				}
+				if let Some(size) = hostmem {
+					base.push_str(format!(",hostmem={size}").as_str());
+				}
+				qemu_arg("-device", base)
+			},
+			Self::VgpuVga { uuid, use_ramfb, pci_vendor_id, pci_device_id, pci_sub_vendor_id, pci_sub_device_id, rombar, romfile, .. } => {
+				let path = format!("/sys/bus/mdev/devices/{uuid}");
+				let rom = rom_options(rombar, romfile);
+				// validate makes sure these are either all set or all unset
+				if let (Some(vid), Some(pid), Some(subvid), Some(subpid)) = (pci_vendor_id, pci_device_id, pci_sub_vendor_id, pci_sub_device_id) {
+					return qemu_arg("-device", format!("vfio-pci-nohotplug,sysfsdev={path},display=on,ramfb={},id=vm.vgpu,x-pci-vendor-id={vid},x-pci-device-id={pid},x-pci-sub-vendor-id={subvid},x-pci-sub-device-id={subpid}{rom}", bool_to_qemu(*use_ramfb)));
+				}
+				qemu_arg("-device", format!("vfio-pci-nohotplug,sysfsdev={path},display=on,ramfb={},id=vm.vgpu{rom}", bool_to_qemu(*use_ramfb)))
+			}
+		}
+	}
+
+	fn wants_root_port(&self) -> bool {
+		matches!(self, Self::VgpuVga { .. })
+	}
+
+	fn requested_root_port(&self) -> Option<&str> {
+		match self {
+			Self::VgpuVga { root_port, .. } => root_port.as_deref(),
+			_ => None
+		}
+	}
+
+	fn as_options_on_port(&self, port: &str) -> Vec<String> {
+		place_on_port(self.as_options(), port)
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::VgpuVga { uuid, use_ramfb, pci_vendor_id, pci_device_id, pci_sub_vendor_id, pci_sub_device_id, romfile, .. } => { 
+				// if the machine doesn't even *have* a uuid, 
+				// it's probably not configured properly
+				let vm_uuid = match &machine.uuid {
+					Some(vm_uuid) => vm_uuid,
+					None => return Err(ValidationError::MissingUuid)
+				};
+
+				// ramfb is only useful if there's a display to show it on
+				if *use_ramfb {
+					if let Some(DisplayBackend::None) = machine.display {
+						return Err(ValidationError::InvalidField { field: "use_ramfb", reason: "there's no display to show it on" });
+					}
+				}
+
+				check_romfile(romfile)?;
+
+				// QEMU needs the whole set to spoof the device
+				let ids = [pci_vendor_id, pci_device_id, pci_sub_vendor_id, pci_sub_device_id];
+				if ids.iter().any(|id| id.is_some()) && !ids.iter().all(|id| id.is_some()) {
+					return Err(ValidationError::InvalidField { field: "pci_vendor_id", reason: "all four pci ids have to be set together" });
+				}
+
+				match machine.machine {
+					Some(MachineType::Q35 { .. }) => {},
+
+					// vGPU can't be used in a PC configuration or an invalid one
+					Some(MachineType::Pc { .. }) => return Err(ValidationError::UnsupportedMachineType { requires: "the Q35 machine type" }),
+					Some(MachineType::S390CcwVirtio) => return Err(ValidationError::UnsupportedMachineType { requires: "the Q35 machine type" }),
+					None => return Err(ValidationError::MissingMachineType)
+				};
+
+				// likewise, if we don't have one, then we're
+				// the misconfigured one, not the VM
+				if uuid.is_empty() {
+					return Err(ValidationError::EmptyField { field: "uuid" });
+				}
+
+				if vm_uuid != uuid {
+					return Err(ValidationError::UuidMismatch { vm: vm_uuid.clone(), device: uuid.clone() });
+				}
+
+				Ok(())
+			}
+
+			Self::VirtioGpu { blob, hostmem, virgl, venus, .. } => {
+				if (*blob || hostmem.is_some()) && !matches!(machine.machine, Some(MachineType::Q35 { .. })) {
+					return Err(ValidationError::UnsupportedMachineType { requires: "the Q35 machine type" });
+				}
+
+				// Blob resources are handed to the host renderer straight out of guest memory
+				if *blob && !machine.guest_memory_shared() {
+					return Err(ValidationError::MemoryNotShared);
+				}
+
+				if *venus {
+					if !*virgl {
+						return Err(ValidationError::InvalidField { field: "venus", reason: "needs virgl" });
+					}
+
+					if !*blob || hostmem.is_none() {
+						return Err(ValidationError::InvalidField { field: "venus", reason: "needs blob and hostmem" });
+					}
+				}
+
+				// virglrenderer needs a GL context, which only GL displays set up
+				if *virgl && !machine.display.as_ref().is_some_and(DisplayBackend::renders_with_gl) {
+					return Err(ValidationError::NoGlDisplay);
+				}
+
+				Ok(())
+			}
+
+			Self::StdVga { vgamem } => {
+				if *vgamem < ByteSize::mib(1) || *vgamem > ByteSize::mib(512) || !vgamem.is_whole_mib() {
+					return Err(ValidationError::InvalidField { field: "vgamem", reason: "has to be a whole number of megabytes, between 1M and 512M" });
+				}
+
+				Ok(())
+			}
+
+			Self::CirrusVga { vgamem } => {
+				if ![ByteSize::mib(4), ByteSize::mib(8), ByteSize::mib(16)].contains(vgamem) {
+					return Err(ValidationError::InvalidField { field: "vgamem", reason: "has to be 4M, 8M or 16M" });
+				}
+
+				Ok(())
+			}
+
+			_ => Ok(()) // no special cases
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::StdVga { .. } => "StdVga",
+			Self::CirrusVga { .. } => "CirrusVga",
+			Self::QxlVga { .. } => "QxlVga",
+			Self::VirtioGpu { .. } => "VirtioGpu",
+			Self::VgpuVga { .. } => "VgpuVga"
+		};
+
+		format!("GraphicsAdapter::{variant}")
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::GraphicsAdapter(self.clone()))
+	}
+}
+
+/// Checks that a bridge exists. Only bridges have a bridge directory.
+fn check_bridge(bridge: &str) -> Result<(), ValidationError> {
+	if !std::path::Path::new(format!("/sys/class/net/{bridge}/bridge").as_str()).is_dir() {
+		return Err(ValidationError::NoSuchBridge { bridge: String::from(bridge) });
+	}
+
+	Ok(())
+}
+
+/// Runs `ip` (from iproute2), e.g: to create a tap device.
+fn run_ip(args: &[&str]) -> Result<(), std::io::Error> {
+	let output = std::process::Command::new("ip").args(args).output()?;
+	if !output.status.success() {
+		let error = String::from_utf8_lossy(&output.stderr);
+		return Err(std::io::Error::other(format!("ip {}: {}", args.join(" "), error.trim())));
+	}
+
+	Ok(())
+}
+
+/// Deletes a host network interface, if it still exists.
+fn delete_interface(name: &str) -> Result<(), std::io::Error> {
+	if !std::path::Path::new("/sys/class/net").join(name).exists() {
+		return Ok(());
+	}
+
+	run_ip(&["link", "delete", "dev", name])
+}
+
+/// The file in a VM's state directory which lists the host interfaces created for it (see [QemuOption::host_interface]).
+const HOST_INTERFACES_FILE: &str = "host_interfaces";
+
+impl Network {
+	/// The id adapters refer to this network by (as their `netdev`).
+	pub fn id(&self) -> &str {
+		match self {
+			Self::User { id, .. } | Self::Tap { id, .. } | Self::Bridge { id, .. } | Self::VhostUser { id, .. } => id
+		}
+	}
+
+	/// How many queue pairs this network is opened with, or None if it can only have one.
+	fn max_queues(&self) -> Option<u8> {
+		match self {
+			Self::Tap { queues, .. } | Self::VhostUser { queues, .. } => Some(queues.unwrap_or(1)),
+			Self::User { .. } | Self::Bridge { .. } => None
+		}
+	}
+
+	/// Ports this network forwards from the host to the guest. These are checked for clashes across the whole VM.
+	pub fn host_forwards(&self) -> &[PortForward] {
+		match self {
+			Self::User { host_forwards, .. } => host_forwards,
+			_ => &[]
+		}
+	}
+
+	/// Whether QEMU needs to spawn a helper process for this network
+	/// (qemu-bridge-helper), which `-sandbox spawn=deny` forbids.
+	fn spawns_helper(&self) -> bool {
+		matches!(self, Self::Bridge { .. })
+	}
+
+	/// The host network interface [QemuOption::prepare_host] creates, if any. These are recorded in
+	/// the VM's state directory, so they still get deleted if Sunlight goes away without doing it.
+	pub fn host_interface(&self) -> Option<&str> {
+		match self {
+			Self::Tap { dev, managed: Some(..), .. } => Some(dev),
+			_ => None
+		}
+	}
+
+	/// The `netdev_add` equivalent of this network's `-netdev` option, for hotplugging it.
+	pub(crate) fn netdev(&self) -> serde_json::Result<qmp::qmp::Netdev> {
+		let options = self.as_options();
+		let mut arguments = option_values(&options, "-netdev")
+			.next()
+			.map(|netdev| qmp::keyval_to_arguments("type", netdev))
+			.unwrap_or_default();
+
+		// hostfwd can be given more than once, so QMP takes a list (of QAPI String structs)
+		if let Self::User { host_forwards, .. } = self {
+			if !host_forwards.is_empty() {
+				let rules = host_forwards.iter()
+					.map(|forward| serde_json::json!({ "str": forward.rule() }))
+					.collect::<Vec<serde_json::Value>>();
+				arguments.insert(String::from("hostfwd"), serde_json::Value::from(rules));
+			}
+		}
+
+		serde_json::from_value(serde_json::Value::Object(arguments))
+	}
+}
+
+impl QemuOption for Network {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::User { id, host_forwards } => {
+				let forwards: String = host_forwards.iter()
+					.map(|forward| format!(",hostfwd={}", qemu_escape(&forward.rule())))
+					.collect();
+				qemu_arg("-netdev", format!("user,id=vm.{id}{forwards}"))
+			},
+			Self::Tap { id, dev, queues, .. } => {
+				let mut netdev = format!("tap,vhost=on,script=no,downscript=no,ifname={},id=vm.{id}", qemu_escape(dev));
+				if let Some(queues) = queues {
+					netdev.push_str(format!(",queues={queues}").as_str());
+				}
+				qemu_arg("-netdev", netdev)
+			},
+			Self::Bridge { id, bridge, helper } => {
+				let mut base = format!("bridge,br={},id=vm.{id}", qemu_escape(bridge));
+				if let Some(helper) = helper {
+					base.push_str(format!(",helper={}", qemu_escape(helper)).as_str());
+				}
+				qemu_arg("-netdev", base)
+			},
+			Self::VhostUser { id, socket_path, queues } => {
+				let mut netdev = format!("vhost-user,id=vm.{id},chardev=vm.{id}.chardev");
+				if let Some(queues) = queues {
+					netdev.push_str(format!(",queues={queues}").as_str());
+				}
+
+				[
+					qemu_arg("-chardev", format!("socket,id=vm.{id}.chardev,path={}", qemu_escape(socket_path))),
+					qemu_arg("-netdev", netdev)
+				].concat()
+			}
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::VhostUser { socket_path, queues, .. } => {
+				if queues.is_some_and(|queues| queues == 0) {
+					return Err(ValidationError::InvalidField { field: "queues", reason: "must be at least 1" });
+				}
+
+				if !std::path::Path::new(socket_path).exists() {
+					return Err(ValidationError::PathNotFound { path: socket_path.clone() });
+				}
+
+				// The backend can't get at packets in guest memory otherwise
+				if !machine.guest_memory_shared() {
+					return Err(ValidationError::MemoryNotShared);
+				}
+
+				Ok(())
+			},
+
+			Self::Tap { queues: Some(0), .. } => Err(ValidationError::InvalidField { field: "queues", reason: "must be at least 1" }),
+
+			Self::Tap { dev, managed: Some(managed), .. } => {
+				// IFNAMSIZ, less the terminator
+				if dev.is_empty() || dev.len() > 15 {
+					return Err(ValidationError::InvalidField { field: "dev", reason: "must be between 1 and 15 characters" });
+				}
+
+				match &managed.bridge {
+					Some(bridge) => check_bridge(bridge),
+					None => Ok(())
+				}
+			},
+
+			Self::Bridge { bridge, helper, .. } => {
+				check_bridge(bridge)?;
+
+				match helper {
+					Some(helper) if !std::path::Path::new(helper).is_file() => Err(ValidationError::PathNotFound { path: helper.clone() }),
+					_ => Ok(())
+				}
+			},
+
+			_ => Ok(())
+		}
+	}
+
+	fn order_key(&self) -> u32 {
+		ORDER_EARLY
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("netdev")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		match self {
+			Self::User { id, .. } => assign_if_empty(id, "netdev", index),
+			Self::Tap { id, .. } => assign_if_empty(id, "netdev", index),
+			Self::Bridge { id, .. } => assign_if_empty(id, "netdev", index),
+			Self::VhostUser { id, .. } => assign_if_empty(id, "netdev", index)
+		}
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(self.id())
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::User { .. } => "User",
+			Self::Tap { .. } => "Tap",
+			Self::Bridge { .. } => "Bridge",
+			Self::VhostUser { .. } => "VhostUser"
+		};
+
+		format!("Network::{variant}")
+	}
+
+	fn prepare_host(&self) -> Result<(), std::io::Error> {
+		let Self::Tap { dev, managed: Some(managed), queues, .. } = self else {
+			return Ok(());
+		};
+
+		// Each queue is a separate fd on the same tap, which has to allow that
+		match queues {
+			Some(queues) if *queues > 1 => run_ip(&["tuntap", "add", "dev", dev, "mode", "tap", "multi_queue"])?,
+			_ => run_ip(&["tuntap", "add", "dev", dev, "mode", "tap"])?
+		}
+		if let Some(bridge) = &managed.bridge {
+			run_ip(&["link", "set", "dev", dev, "master", bridge])?;
+		}
+		run_ip(&["link", "set", "dev", dev, "up"])
+	}
+
+	fn release_host(&self) -> Result<(), std::io::Error> {
+		match self.host_interface() {
+			Some(dev) => delete_interface(dev),
+			None => Ok(())
+		}
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Network(self.clone()))
+	}
+}
+
+impl QemuOption for NetworkAdapter {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::Virtio { id, netdev, mac, rombar, romfile, failover, host_mtu, offloads, queues } => {
+				let mut base = format!("virtio-net-pci,id=vm.{id},netdev=vm.{netdev}");
+				if let Some(addr) = mac {
+					base.push_str(format!(",mac={addr}").as_str());
+				}
+				base.push_str(rom_options(rombar, romfile).as_str());
+				if *failover {
+					base.push_str(",failover=on");
+				}
+				if let Some(mtu) = host_mtu {
+					base.push_str(format!(",host_mtu={mtu}").as_str());
+				}
+				base.push_str(offloads.as_options().as_str());
+				if let Some(queues) = queues {
+					// One vector per queue (rx + tx), plus config and control
+					base.push_str(format!(",mq=on,vectors={}", 2 * (*queues as u32) + 2).as_str());
+				}
+				qemu_arg("-device", base)
+			},
+
+			Self::Rtl8139 { id, netdev, mac, rombar, romfile }
+			| Self::E1000 { id, netdev, mac, rombar, romfile }
+			| Self::E1000e { id, netdev, mac, rombar, romfile }
+			| Self::Vmxnet3 { id, netdev, mac, rombar, romfile } => {
+				let device = match self {
+					Self::E1000 { .. } => "e1000",
+					Self::E1000e { .. } => "e1000e",
+					Self::Vmxnet3 { .. } => "vmxnet3",
+					_ => "rtl8139"
+				};
+
+				let mut base = format!("{device},id=vm.{id},netdev=vm.{netdev}");
+				if let Some(addr) = mac {
+					base.push_str(format!(",mac={addr}").as_str());
+				}
+				base.push_str(rom_options(rombar, romfile).as_str());
+				qemu_arg("-device", base)
+			}
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::Virtio { id, netdev, mac, romfile, failover, host_mtu, queues, .. } => {
+				if *failover {
+					// The derived address would do, as long as there's a UUID to derive it from
+					if mac.is_none() && machine.uuid.is_none() {
+						return Err(ValidationError::EmptyField { field: "mac" });
+					}
+
+					// Failover does nothing without a primary device to pair with
+					if !machine.devices_of::<PciPassthrough>().any(|dev| dev.failover_pair_id.as_ref() == Some(id)) {
+						return Err(ValidationError::InvalidField { field: "failover", reason: "no passthrough device has this adapter as its failover_pair_id" });
+					}
+				}
+
+				// 576 is the minimum MTU IPv4 requires hosts to handle
+				if let Some(mtu) = host_mtu {
+					if *mtu < 576 {
+						return Err(ValidationError::InvalidField { field: "host_mtu", reason: "must be at least 576" });
+					}
+				}
+
+				if let Some(queues) = queues {
+					if *queues == 0 {
+						return Err(ValidationError::InvalidField { field: "queues", reason: "must be at least 1" });
+					}
+
+					if machine.devices_of::<Cpu>().next().is_none() {
+						return Err(ValidationError::NoCpu);
+					}
+
+					// The extra queues have nothing behind them otherwise
+					match machine.devices_of::<Network>().find(|network| network.id() == netdev).map(Network::max_queues) {
+						Some(None) => return Err(ValidationError::InvalidField { field: "queues", reason: "the netdev doesn't support multiqueue" }),
+						Some(Some(max)) if *queues > max => return Err(ValidationError::InvalidField { field: "queues", reason: "the netdev has fewer queues than the adapter" }),
+						_ => {}
+					}
+				}
+
+				check_romfile(romfile)
+			},
+			// The 82574L is a PCIe device, which the PC machine type has nowhere to put
+			Self::E1000e { romfile, .. } => {
+				if !matches!(machine.machine, Some(MachineType::Q35 { .. })) {
+					return Err(ValidationError::UnsupportedMachineType { requires: "the Q35 machine type" });
+				}
+
+				check_romfile(romfile)
+			},
+			Self::Rtl8139 { romfile, .. } | Self::E1000 { romfile, .. } | Self::Vmxnet3 { romfile, .. } => check_romfile(romfile)
+		}
+	}
+
+	fn warnings(&self, machine: &VirtualMachine) -> Vec<ValidationWarning> {
+		let Self::Virtio { queues: Some(queues), .. } = self else {
+			return Vec::new();
+		};
+
+		// The extra queues just waste resources
+		match machine.devices_of::<Cpu>().next().map(Cpu::boot_vcpus) {
+			Some(vcpus) if *queues as u32 > vcpus => vec![ValidationWarning::MoreQueuesThanVcpus { queues: *queues, vcpus }],
+			_ => Vec::new()
+		}
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("net")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		// If the netdev was left empty too, pair with the netdev
+		// which was auto-assigned the same index (net0 <-> netdev0)
+		match self {
+			Self::Virtio { id, netdev, .. } => {
+				assign_if_empty(id, "net", index);
+				assign_if_empty(netdev, "netdev", index);
+			},
+			Self::Rtl8139 { id, netdev, .. }
+			| Self::E1000 { id, netdev, .. }
+			| Self::E1000e { id, netdev, .. }
+			| Self::Vmxnet3 { id, netdev, .. } => {
+				assign_if_empty(id, "net", index);
+				assign_if_empty(netdev, "netdev", index);
+			}
+		}
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		match self {
+			Self::Virtio { id, .. }
+			| Self::Rtl8139 { id, .. }
+			| Self::E1000 { id, .. }
+			| Self::E1000e { id, .. }
+			| Self::Vmxnet3 { id, .. } => Some(id)
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::Virtio { .. } => "Virtio",
+			Self::Rtl8139 { .. } => "Rtl8139",
+			Self::E1000 { .. } => "E1000",
+			Self::E1000e { .. } => "E1000e",
+			Self::Vmxnet3 { .. } => "Vmxnet3"
+		};
+
+		format!("NetworkAdapter::{variant}")
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn boot_device(&self) -> Option<BootDevice> {
+		match self {
+			Self::Virtio { id, .. }
+			| Self::Rtl8139 { id, .. }
+			| Self::E1000 { id, .. }
+			| Self::E1000e { id, .. }
+			| Self::Vmxnet3 { id, .. } => Some(BootDevice::Network(id.clone()))
+		}
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::NetworkAdapter(self.clone()))
+	}
+}
+
+impl NetworkAdapter {
+	/// Give this adapter a MAC address derived from the VM's UUID and its id, if it doesn't have one.
+	/// Returns whether it did.
+	fn derive_mac(&mut self, vm_uuid: &str) -> bool {
+		match self {
+			Self::Virtio { id, mac: mac @ None, .. }
+			| Self::Rtl8139 { id, mac: mac @ None, .. }
+			| Self::E1000 { id, mac: mac @ None, .. }
+			| Self::E1000e { id, mac: mac @ None, .. }
+			| Self::Vmxnet3 { id, mac: mac @ None, .. } => {
+				*mac = Some(MacAddr::derive(vm_uuid, id));
+				true
+			},
+			_ => false
+		}
+	}
+}
+
+impl QemuOption for Audio {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::IntelHda { controller } => {
+				let device = match controller {
+					Some(HdaController::Ich6) => "intel-hda",
+					_ => "ich9-intel-hda"
+				};
+
+				[qemu_arg("-device", format!("{device},id=vm.sound")), qemu_arg("-device", "hda-duplex,bus=vm.sound.0,audiodev=vm.audio")].concat()
+			},
+			Self::Ac97 => qemu_arg("-device", "AC97,id=vm.sound,audiodev=vm.audio"),
+			Self::VirtioSound => qemu_arg("-device", "virtio-sound-pci,id=vm.sound,audiodev=vm.audio")
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// We can't pick a controller without knowing the machine
+		if machine.machine.is_none() {
+			return Err(ValidationError::MissingMachineType);
+		}
+
+		Ok(())
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::IntelHda { .. } => "IntelHda",
+			Self::Ac97 => "Ac97",
+			Self::VirtioSound => "VirtioSound"
+		};
+
+		format!("Audio::{variant}")
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+impl QemuOption for AudioBackend {
+	fn as_options(&self) -> Vec<String> {
+		let audiodev = match self {
+			Self::None => String::from("none,id=vm.audio"),
+			Self::Pipewire => String::from("pipewire,id=vm.audio"),
+			Self::PulseAudio { server: Some(server) } => format!("pa,id=vm.audio,server={}", qemu_escape(server)),
+			Self::PulseAudio { server: None } => String::from("pa,id=vm.audio"),
+			Self::Alsa { device: Some(device) } => format!("alsa,id=vm.audio,in.dev={0},out.dev={0}", qemu_escape(device)),
+			Self::Alsa { device: None } => String::from("alsa,id=vm.audio")
+		};
+
+		qemu_arg("-audiodev", audiodev)
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if machine.audio.is_none() {
+			return Err(ValidationError::InvalidField { field: "audio_backend", reason: "there's no sound card for it to be used by" });
+		}
+
+		match self {
+			Self::PulseAudio { server: Some(server) } if server.is_empty() => Err(ValidationError::EmptyField { field: "server" }),
+			Self::Alsa { device: Some(device) } if device.is_empty() => Err(ValidationError::EmptyField { field: "device" }),
+			_ => Ok(())
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::None => "None",
+			Self::Pipewire => "Pipewire",
+			Self::PulseAudio { .. } => "PulseAudio",
+			Self::Alsa { .. } => "Alsa"
+		};
+
+		format!("AudioBackend::{variant}")
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+/// Namespace ids start from 1, and can't be reused within a subsystem (or controller).
+fn check_nvme_nsids(nsids: impl IntoIterator<Item = u32>) -> Result<(), ValidationError> {
+	let mut seen = std::collections::HashSet::new();
+	if !nsids.into_iter().all(|nsid| nsid != 0 && seen.insert(nsid)) {
+		return Err(ValidationError::InvalidField { field: "nsid", reason: "must be unique, and start from 1" });
+	}
+
+	Ok(())
+}
+
+impl QemuOption for NvmeSubsystem {
+	fn as_options(&self) -> Vec<String> {
+		let id = &self.id;
+		let mut opts = [
+			qemu_arg("-device", format!("nvme-subsys,id=vm.{id},nqn={}", self.nqn)),
+			qemu_arg("-device", format!("nvme,id=vm.{id}.ctrl,serial={id},subsys=vm.{id}"))
+		].concat();
+
+		for ns in &self.namespaces {
+			let nsid = ns.nsid;
+			opts.append(&mut qemu_arg("-drive", format!("if=none,file={},format={},id=vm.{id}.ns{nsid}.drive", qemu_escape(&ns.image_path), ns.format)));
+			opts.append(&mut qemu_arg("-device", format!("nvme-ns,drive=vm.{id}.ns{nsid}.drive,nsid={nsid},bus=vm.{id}.ctrl")));
+		}
+
+		opts
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.nqn.is_empty() {
+			return Err(ValidationError::EmptyField { field: "nqn" });
+		}
+
+		if self.namespaces.is_empty() {
+			return Err(ValidationError::EmptyField { field: "namespaces" });
+		}
+
+		check_nvme_nsids(self.namespaces.iter().map(|ns| ns.nsid))
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("nvme")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "nvme", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::NvmeSubsystem(self.clone()))
+	}
+}
+
+impl QemuOption for ThrottleGroup {
+	fn as_options(&self) -> Vec<String> {
+		qemu_arg("-object", format!("throttle-group,id=vm.{}.tg{}", self.id, self.limits.as_options("x-")))
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.id.is_empty() {
+			return Err(ValidationError::EmptyField { field: "id" });
+		}
+
+		self.limits.check()
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+impl QemuOption for Secret {
+	fn as_options(&self) -> Vec<String> {
+		let id = &self.id;
+		let object = match &self.source {
+			SecretSource::File { path } => format!("secret,id=vm.{id}.secret,file={}", qemu_escape(path)),
+			SecretSource::Inline { data } => format!("secret,id=vm.{id}.secret,data={}", qemu_escape(data)),
+			SecretSource::Keyring { serial } => format!("secret_keyring,id=vm.{id}.secret,serial={serial}")
+		};
+
+		qemu_arg("-object", object)
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.id.is_empty() {
+			return Err(ValidationError::EmptyField { field: "id" });
+		}
+
+		match &self.source {
+			SecretSource::File { path } if !std::path::Path::new(path).is_file() => Err(ValidationError::PathNotFound { path: path.clone() }),
+			SecretSource::Inline { data } if data.is_empty() => Err(ValidationError::EmptyField { field: "data" }),
+			_ => Ok(())
+		}
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+}
+
+impl QemuOption for Tpm {
+	fn as_options(&self) -> Vec<String> {
+		let device = match self.interface {
+			TpmInterface::Tis => "tpm-tis",
+			TpmInterface::Crb => "tpm-crb"
+		};
+
+		[
+			qemu_arg("-chardev", format!("socket,id=vm.tpm.chardev,path={}", qemu_escape(&self.socket_path))),
+			qemu_arg("-tpmdev", "emulator,id=vm.tpm,chardev=vm.tpm.chardev"),
+			qemu_arg("-device", format!("{device},tpmdev=vm.tpm"))
+		].concat()
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.socket_path.is_empty() {
+			return Err(ValidationError::EmptyField { field: "socket_path" });
+		}
+
+		match self.interface {
+			TpmInterface::Tis => Ok(()),
+
+			// The CRB interface is only discoverable through ACPI
+			TpmInterface::Crb => match machine.machine {
+				Some(MachineType::Pc { acpi: true, .. }) => Ok(()),
+				Some(MachineType::Q35 { acpi: true, .. }) => Ok(()),
+				Some(..) => Err(ValidationError::UnsupportedMachineType { requires: "a machine type with ACPI enabled" }),
+				None => Err(ValidationError::MissingMachineType)
+			}
+		}
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Tpm(self.clone()))
+	}
+}
+
+/// Checks an evdev node is really under `input_dir` once symlinks and `..` are resolved, so
+/// `/dev/input/by-id/...` links are fine, but `/dev/input/../sda` isn't an input device.
+fn check_evdev_path(evdev_path: &str, input_dir: &std::path::Path) -> Result<(), ValidationError> {
+	let Ok(path) = std::fs::canonicalize(evdev_path) else {
+		return Err(ValidationError::PathNotFound { path: String::from(evdev_path) });
+	};
+
+	if !path.starts_with(input_dir) {
+		return Err(ValidationError::InvalidField { field: "evdev_path", reason: "must be under /dev/input" });
+	}
+
+	Ok(())
+}
+
+impl QemuOption for VirtioInputHost {
+	fn as_options(&self) -> Vec<String> {
+		qemu_arg("-device", format!("virtio-input-host-pci,id=vm.{},evdev={}", self.id, qemu_escape(&self.evdev_path)))
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		check_evdev_path(&self.evdev_path, std::path::Path::new("/dev/input"))
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("input")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "input", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::VirtioInputHost(self.clone()))
+	}
+}
+
+#[cfg(feature = "testing")]
+impl QemuOption for EduDevice {
+	fn as_options(&self) -> Vec<String> {
+		qemu_arg("-device", "edu")
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::EduDevice(self.clone()))
+	}
+}
+
+impl QemuOption for VirtioCrypto {
+	fn as_options(&self) -> Vec<String> {
+		let id = &self.id;
+		[
+			qemu_arg("-object", format!("cryptodev-backend-builtin,id=vm.{id}.cryptodev")),
+			qemu_arg("-device", format!("virtio-crypto-pci,cryptodev=vm.{id}.cryptodev,id=vm.{id}"))
+		].concat()
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("crypto")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "crypto", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::VirtioCrypto(self.clone()))
+	}
+}
+
+impl QemuOption for Vsock {
+	fn as_options(&self) -> Vec<String> {
+		qemu_arg("-device", format!("vhost-vsock-pci,guest-cid={},id=vm.{}", self.guest_cid, self.id))
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("vsock")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "vsock", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// 0 and 1 are the hypervisor's, 2 is the host, and -1 means any
+		if self.guest_cid < 3 || self.guest_cid == u32::MAX {
+			return Err(ValidationError::InvalidField { field: "guest_cid", reason: "0 to 2 and 4294967295 are reserved" });
+		}
+
+		Ok(())
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Vsock(self.clone()))
+	}
+}
+
+impl QemuOption for Rng {
+	fn as_options(&self) -> Vec<String> {
+		match self {
+			Self::Virtio { id, source, max_bytes, period_ms } => {
+				let backend = match source {
+					Some(path) => format!("rng-random,id=vm.{id}.rng,filename={}", qemu_escape(path)),
+					None => format!("rng-builtin,id=vm.{id}.rng")
+				};
+
+				let mut device = format!("virtio-rng-pci,rng=vm.{id}.rng,id=vm.{id}");
+				if let Some(max_bytes) = max_bytes {
+					device.push_str(format!(",max-bytes={max_bytes}").as_str());
+				}
+				if let Some(period) = period_ms {
+					device.push_str(format!(",period={period}").as_str());
+				}
+
+				[
+					qemu_arg("-object", backend),
+					qemu_arg("-device", device)
+				].concat()
+			}
+		}
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::Virtio { source, max_bytes, period_ms, .. } => {
+				if let Some(path) = source {
+					if !std::path::Path::new(path).exists() {
+						return Err(ValidationError::PathNotFound { path: path.clone() });
+					}
+				}
+
+				if *max_bytes == Some(0) {
+					return Err(ValidationError::InvalidField { field: "max_bytes", reason: "must be greater than 0" });
+				}
+
+				if let Some(period) = period_ms {
+					if *period == 0 {
+						return Err(ValidationError::InvalidField { field: "period_ms", reason: "must be greater than 0" });
+					}
+
+					// The period does nothing on its own
+					if max_bytes.is_none() {
+						return Err(ValidationError::InvalidField { field: "period_ms", reason: "only used with max_bytes" });
+					}
+				}
+
+				Ok(())
+			}
+		}
+	}
+
+	fn label(&self) -> String {
+		let variant = match self {
+			Self::Virtio { .. } => "Virtio"
+		};
+
+		format!("Rng::{variant}")
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("rng")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		match self {
+			Self::Virtio { id, .. } => assign_if_empty(id, "rng", index)
+		}
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		match self {
+			Self::Virtio { id, .. } => Some(id)
+		}
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Rng(self.clone()))
+	}
+}
+
+impl QemuOption for Balloon {
+	fn as_options(&self) -> Vec<String> {
+		let mut device = format!("virtio-balloon-pci,id=vm.{}", self.id);
+		if self.free_page_reporting {
+			device.push_str(",free-page-reporting=on");
+		}
+		if self.deflate_on_oom {
+			device.push_str(",deflate-on-oom=on");
+		}
+
+		qemu_arg("-device", device)
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if machine.devices_of::<Balloon>().count() > 1 {
+			return Err(ValidationError::InvalidField { field: "id", reason: "a VM can only have one balloon" });
+		}
+
+		if self.stats_interval_secs == Some(0) {
+			return Err(ValidationError::InvalidField { field: "stats_interval_secs", reason: "must be greater than 0" });
+		}
+
+		Ok(())
+	}
+
+	fn label(&self) -> String {
+		String::from("Balloon")
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("balloon")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "balloon", index)
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Balloon(self.clone()))
+	}
+}
+
+impl QemuOption for NinePShare {
+	fn as_options(&self) -> Vec<String> {
+		let id = &self.id;
+		let security_model = match self.security_model {
+			NinePSecurityModel::Passthrough => "passthrough",
+			NinePSecurityModel::Mapped => "mapped-xattr",
+			NinePSecurityModel::MappedFile => "mapped-file",
+			NinePSecurityModel::None => "none"
+		};
+
+		let path = qemu_escape(&self.path);
+
+		if self.legacy_virtfs {
+			return qemu_arg("-virtfs", format!("local,id=vm.{id},path={path},mount_tag={},security_model={security_model}", self.mount_tag));
+		}
+
+		[
+			qemu_arg("-fsdev", format!("local,id=vm.{id}.fsdev,path={path},security_model={security_model}")),
+			qemu_arg("-device", format!("virtio-9p-pci,id=vm.{id},fsdev=vm.{id}.fsdev,mount_tag={}", self.mount_tag))
+		].concat()
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.mount_tag.is_empty() {
+			return Err(ValidationError::EmptyField { field: "mount_tag" });
+		}
+
+		if !std::path::Path::new(&self.path).is_dir() {
+			return Err(ValidationError::PathNotFound { path: self.path.clone() });
+		}
+
+		Ok(())
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("fs")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "fs", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::NinePShare(self.clone()))
+	}
+}
+
+impl QemuOption for SmartCard {
+	fn as_options(&self) -> Vec<String> {
+		// Cards go on the reader's own CCID bus, so they end up in the right reader when there's more than one
+		let id = &self.id;
+		match self.backend {
+			SmartCardBackend::Passthrough => [
+				qemu_arg("-device", format!("usb-ccid,id=vm.{id}")),
+				qemu_arg("-chardev", format!("spicevmc,id=vm.{id}.chardev,name=smartcard")),
+				qemu_arg("-device", format!("ccid-card-passthru,chardev=vm.{id}.chardev,bus=vm.{id}.0"))
+			].concat(),
+			SmartCardBackend::Emulated => [
+				qemu_arg("-device", format!("usb-ccid,id=vm.{id}")),
+				qemu_arg("-device", format!("ccid-card-emulated,backend=nss-emulated,bus=vm.{id}.0"))
+			].concat()
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// The card comes from the SPICE client
+		if matches!(self.backend, SmartCardBackend::Passthrough) && !matches!(machine.display, Some(DisplayBackend::Spice { .. })) {
+			return Err(ValidationError::NoSpice);
+		}
+
+		check_usb_bus(machine)
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("ccid")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "ccid", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::SmartCard(self.clone()))
+	}
+}
+
+impl QemuOption for IsaDebugCon {
+	fn as_options(&self) -> Vec<String> {
+		[
+			qemu_arg("-chardev", format!("file,id=vm.debugcon,path={}", qemu_escape(&self.file_path))),
+			qemu_arg("-device", format!("isa-debugcon,iobase={:#x},chardev=vm.debugcon", self.iobase))
+		].concat()
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// Port 0 is never going to be what anyone wants
+		if self.iobase == 0 {
+			return Err(ValidationError::InvalidField { field: "iobase", reason: "can't be 0" });
+		}
+
+		if self.file_path.is_empty() {
+			return Err(ValidationError::EmptyField { field: "file_path" });
+		}
+
+		Ok(())
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::IsaDebugCon(self.clone()))
+	}
+}
+
+impl QemuOption for Watchdog {
+	fn as_options(&self) -> Vec<String> {
+		let device = match self.model {
+			WatchdogModel::I6300esb => qemu_arg("-device", String::from("i6300esb,id=vm.watchdog")),
+			// The TCO watchdog is always there, but can't reboot the VM unless told it may
+			WatchdogModel::Itco => qemu_arg("-global", String::from("ICH9-LPC.noreboot=off"))
+		};
+
+		let action = match self.action {
+			WatchdogAction::Reset => "reset",
+			WatchdogAction::Poweroff => "poweroff",
+			WatchdogAction::Pause => "pause",
+			WatchdogAction::Debug => "debug"
+		};
+
+		[
+			device,
+			qemu_arg("-action", format!("watchdog={action}"))
+		].concat()
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// The action applies to the whole VM
+		if machine.devices_of::<Watchdog>().count() > 1 {
+			return Err(ValidationError::InvalidField { field: "model", reason: "a VM can only have one watchdog" });
+		}
+
+		if let WatchdogModel::Itco = self.model {
+			if !matches!(machine.machine, Some(MachineType::Q35 { .. })) {
+				return Err(ValidationError::UnsupportedMachineType { requires: "the Q35 machine type" });
+			}
+		}
+
+		Ok(())
+	}
+
+	fn label(&self) -> String {
+		let model = match self.model {
+			WatchdogModel::I6300esb => "I6300esb",
+			WatchdogModel::Itco => "Itco"
+		};
+
+		format!("Watchdog::{model}")
+	}
+
+	fn requires_pci(&self) -> bool {
+		matches!(self.model, WatchdogModel::I6300esb)
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Watchdog(self.clone()))
+	}
+}
+
+impl QemuOption for Pvpanic {
+	fn as_options(&self) -> Vec<String> {
+		[
+			qemu_arg("-device", String::from("pvpanic,id=vm.pvpanic")),
+			// Keep the crashed guest around (instead of shutting down), so it can be dumped
+			qemu_arg("-action", String::from("panic=pause"))
+		].concat()
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if machine.devices_of::<Pvpanic>().count() > 1 {
+			return Err(ValidationError::InvalidField { field: "crash_dump", reason: "a VM can only have one pvpanic device" });
+		}
+
+		// pvpanic is an ISA device
+		if !matches!(machine.machine, Some(MachineType::Pc { .. } | MachineType::Q35 { .. })) {
+			return Err(ValidationError::UnsupportedMachineType { requires: "the Pc or Q35 machine type" });
+		}
+
+		if let Some(path) = &self.crash_dump {
+			if path.is_empty() {
+				return Err(ValidationError::EmptyField { field: "crash_dump" });
+			}
+
+			let dir = std::path::Path::new(path).parent()
+				.filter(|dir| !dir.as_os_str().is_empty())
+				.unwrap_or(std::path::Path::new("."));
+			if !dir.is_dir() {
+				return Err(ValidationError::PathNotFound { path: dir.to_string_lossy().into_owned() });
+			}
+		}
+
+		Ok(())
+	}
+
+	fn label(&self) -> String {
+		String::from("Pvpanic")
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::Pvpanic(self.clone()))
+	}
+}
+
+impl QemuOption for SerialConsole {
+	fn as_options(&self) -> Vec<String> {
+		[
+			qemu_arg("-chardev", self.backend.chardev(&self.id)),
+			qemu_arg("-serial", format!("chardev:vm.{}.chardev", self.id))
+		].concat()
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		self.backend.validate(machine)
+	}
+
+	fn prepare_host(&self) -> Result<(), std::io::Error> {
+		self.backend.prepare_host()
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("serial")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "serial", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::SerialConsole(self.clone()))
+	}
+}
+
+impl SerialBackend {
+	/// The `-chardev` for this backend, with the id `vm.{id}.chardev`.
+	fn chardev(&self, id: &str) -> String {
+		match self {
+			// Otherwise Ctrl+C in the terminal kills QEMU, instead of going to the guest
+			Self::Stdio => format!("stdio,id=vm.{id}.chardev,signal=off"),
+			Self::Pty => format!("pty,id=vm.{id}.chardev"),
+			Self::UnixSocket { path } => format!("socket,id=vm.{id}.chardev,path={},server=on,wait=off", qemu_escape(path)),
+			Self::LogFile { path } => format!("file,id=vm.{id}.chardev,path={},append=on", qemu_escape(path))
+		}
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		match self {
+			Self::Stdio => {
+				// QEMU only has the one stdin to hand out
+				if machine.stdio_chardevs() > 1 {
+					return Err(ValidationError::InvalidField { field: "backend", reason: "only one serial console or channel can use stdio" });
+				}
+
+				Ok(())
+			},
+			Self::Pty => Ok(()),
+			Self::UnixSocket { path } | Self::LogFile { path } => {
+				if path.is_empty() {
+					return Err(ValidationError::EmptyField { field: "path" });
+				}
+
+				Ok(())
+			}
+		}
+	}
+
+	fn prepare_host(&self) -> Result<(), std::io::Error> {
+		// Like the guest agent's, a socket left behind by a previous run would stop QEMU from creating its own
+		match self {
+			Self::UnixSocket { path } => match std::fs::remove_file(path) {
+				Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+				_ => Ok(())
+			},
+			_ => Ok(())
+		}
+	}
+
+	/// Connect to the host side of the chardev `vm.{id}.chardev`. A pty is opened by the path
+	/// QEMU gives it, and a log file is read from the start (and can't be written to).
+	async fn connect(&self, id: &str, qmp: &QmpClient) -> Result<SerialConsoleIo, VMControlError> {
+		match self {
+			Self::Stdio => Err(VMControlError::ConsoleOnStdio),
+			Self::Pty => {
+				let chardev = format!("vm.{id}.chardev");
+				let path = qmp.execute(qmp::qmp::query_chardev {})
+					.await?
+					.into_iter()
+					.find(|info| info.label == chardev)
+					.and_then(|info| info.filename.strip_prefix("pty:").map(String::from))
+					.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("QEMU has no pty for {chardev}")))?;
+
+				// Opened twice, since a file only does one operation at a time,
+				// and a read waiting on the guest would hold up writes
+				let reader = tokio::fs::File::open(&path).await?;
+				let writer = tokio::fs::OpenOptions::new().write(true).open(&path).await?;
+
+				Ok(SerialConsoleIo {
+					reader: Box::new(reader),
+					writer: Some(Box::new(writer))
+				})
+			},
+			Self::UnixSocket { path } => {
+				let (reader, writer) = tokio::net::UnixStream::connect(path).await?.into_split();
+
+				Ok(SerialConsoleIo {
+					reader: Box::new(reader),
+					writer: Some(Box::new(writer))
+				})
+			},
+			Self::LogFile { path } => Ok(SerialConsoleIo {
+				reader: Box::new(tokio::fs::File::open(path).await?),
+				writer: None
+			})
+		}
+	}
+}
+
+impl QemuOption for VirtioSerialChannel {
+	fn as_options(&self) -> Vec<String> {
+		[
+			qemu_arg("-chardev", self.backend.chardev(&self.id)),
+			qemu_arg("-device", format!("virtserialport,bus={VIRTIO_SERIAL_ID}.0,chardev=vm.{0}.chardev,name={1},id=vm.{0}", self.id, qemu_escape(&self.name)))
+		].concat()
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.name.is_empty() {
+			return Err(ValidationError::EmptyField { field: "name" });
+		}
+
+		// The guest tells ports apart by name
+		let same_name = machine.devices_of::<VirtioSerialChannel>()
+			.filter(|channel| channel.name == self.name)
+			.count();
+
+		if same_name > 1 {
+			return Err(ValidationError::InvalidField { field: "name", reason: "another channel has the same name" });
+		}
+
+		self.backend.validate(machine)
+	}
+
+	fn prepare_host(&self) -> Result<(), std::io::Error> {
+		self.backend.prepare_host()
+	}
+
+	fn virtio_serial_ports(&self) -> u32 {
+		1
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("channel")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "channel", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::VirtioSerialChannel(self.clone()))
+	}
+}
+
+impl QemuOption for GuestAgent {
+	fn as_options(&self) -> Vec<String> {
+		[
+			qemu_arg("-chardev", format!("socket,id=vm.{}.chardev,path={},server=on,wait=off", self.id, qemu_escape(&self.socket_path))),
+			qemu_arg("-device", format!("virtserialport,bus={VIRTIO_SERIAL_ID}.0,chardev=vm.{0}.chardev,name=org.qemu.guest_agent.0", self.id))
+		].concat()
+	}
+
+	fn validate(&self, _machine: &VirtualMachine) -> Result<(), ValidationError> {
+		if self.socket_path.is_empty() {
+			return Err(ValidationError::EmptyField { field: "socket_path" });
+		}
+
+		Ok(())
+	}
+
+	fn prepare_host(&self) -> Result<(), std::io::Error> {
+		// Like the QMP socket, a socket left behind by a previous run would stop QEMU from creating its own
+		match std::fs::remove_file(&self.socket_path) {
+			Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+			_ => Ok(())
+		}
+	}
+
+	fn virtio_serial_ports(&self) -> u32 {
+		1
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("qga")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "qga", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::GuestAgent(self.clone()))
+	}
+}
+
+impl QemuOption for PciPassthrough {
+	fn as_options(&self) -> Vec<String> {
+		let mut base = format!("vfio-pci,host={},id=vm.{}", self.host_address, self.id);
+
+		if self.multifunction {
+			base.push_str(",multifunction=on");
+		}
+
+		if let Some(pair) = &self.failover_pair_id {
+			base.push_str(format!(",failover_pair_id=vm.{pair}").as_str());
+		}
+
+		base.push_str(rom_options(&self.rombar, &self.romfile).as_str());
+		qemu_arg("-device", base)
+	}
+
+	fn validate(&self, machine: &VirtualMachine) -> Result<(), ValidationError> {
+		// The standby has to be a virtio-net adapter which knows it's part of a failover pair
+		if let Some(pair) = &self.failover_pair_id {
+			let standby = machine.devices_of::<NetworkAdapter>().find_map(|adapter| match adapter {
+				NetworkAdapter::Virtio { id, failover, .. } if id == pair => Some(*failover),
+				_ => None
+			});
+
+			match standby {
+				None => return Err(ValidationError::InvalidField { field: "failover_pair_id", reason: "no virtio network adapter has this id" }),
+				Some(false) => return Err(ValidationError::InvalidField { field: "failover_pair_id", reason: "the network adapter doesn't have failover on" }),
+				Some(true) => {}
+			}
+		}
+
+		let sysfs = pci_sysfs_path(&self.host_address);
+		if !sysfs.exists() {
+			return Err(ValidationError::PathNotFound { path: sysfs.to_string_lossy().into_owned() });
+		}
+
+		check_romfile(&self.romfile)?;
+
+		if !self.rebind_driver && pci_driver(&self.host_address).as_deref() != Some("vfio-pci") {
+			return Err(ValidationError::NotBoundToVfio { address: self.host_address.clone() });
+		}
+
+		// Every device in the IOMMU group goes to the guest together, so the group is only usable if
+		// nothing else in it is in use by the host. Anything being passed through to this VM is fine.
+		let group = match std::fs::read_dir(sysfs.join("iommu_group/devices")) {
+			Ok(group) => group,
+			Err(..) => return Err(ValidationError::NoIommuGroup { address: self.host_address.clone() })
+		};
+
+		let passed_through = machine.devices_of::<PciPassthrough>()
+			.map(|dev| dev.host_address.as_str())
+			.collect::<Vec<&str>>();
+
+		for dev in group.flatten() {
+			let address = dev.file_name().to_string_lossy().into_owned();
+			if passed_through.contains(&address.as_str()) {
+				continue;
+			}
+
+			// Bridges stay with the host, which is fine
+			match pci_driver(&address).as_deref() {
+				None | Some("vfio-pci") | Some("pcieport") => {},
+				Some(..) => return Err(ValidationError::IommuGroupNotViable { address })
+			}
+		}
+
+		Ok(())
+	}
+
+	fn wants_root_port(&self) -> bool {
+		true
+	}
+
+	fn requested_root_port(&self) -> Option<&str> {
+		self.root_port.as_deref()
+	}
+
+	fn as_options_on_port(&self, port: &str) -> Vec<String> {
+		place_on_port(self.as_options(), port)
+	}
+
+	fn prepare_host(&self) -> Result<(), std::io::Error> {
+		if !self.rebind_driver || pci_driver(&self.host_address).as_deref() == Some("vfio-pci") {
+			return Ok(());
+		}
+
+		let sysfs = pci_sysfs_path(&self.host_address);
+		if pci_driver(&self.host_address).is_some() {
+			std::fs::write(sysfs.join("driver/unbind"), &self.host_address)?;
+		}
+
+		// driver_override makes sure only vfio-pci will take the device when it's probed
+		std::fs::write(sysfs.join("driver_override"), "vfio-pci")?;
+		std::fs::write("/sys/bus/pci/drivers_probe", &self.host_address)
+	}
+
+	fn release_host(&self) -> Result<(), std::io::Error> {
+		if !self.rebind_driver {
+			return Ok(());
+		}
+
+		let sysfs = pci_sysfs_path(&self.host_address);
+		if pci_driver(&self.host_address).is_some() {
+			std::fs::write(sysfs.join("driver/unbind"), &self.host_address)?;
+		}
+
+		// Clearing the override lets the host driver claim the device again
+		std::fs::write(sysfs.join("driver_override"), "\n")?;
+		std::fs::write("/sys/bus/pci/drivers_probe", &self.host_address)
+	}
+
+	fn id_prefix(&self) -> Option<&'static str> {
+		Some("hostdev")
+	}
+
+	fn assign_id(&mut self, index: usize) {
+		assign_if_empty(&mut self.id, "hostdev", index);
+	}
+
+	fn device_id(&self) -> Option<&str> {
+		Some(&self.id)
+	}
+
+	fn requires_pci(&self) -> bool {
+		true
+	}
+
+	fn clone_boxed(&self) -> Option<Box<dyn QemuOption>> {
+		Some(Box::new(self.clone()))
+	}
+
+	fn to_config(&self) -> Option<DeviceConfig> {
+		Some(DeviceConfig::PciPassthrough(self.clone()))
+	}
+}
+
+/// Fills in an empty id field with `{prefix}{index}`.
+pub(crate) fn assign_if_empty(id: &mut String, prefix: &str, index: usize) {
+	if id.is_empty() {
+		*id = format!("{prefix}{index}");
+	}
+}
+
+/// Quotes an argument for a POSIX shell, if it needs it.
+fn shell_quote(arg: &str) -> String {
+	let safe = !arg.is_empty() && arg.chars()
+		.all(|c| c.is_ascii_alphanumeric() || "-_=,./:@%+".contains(c));
+
+	if safe {
+		return String::from(arg);
+	}
+
+	format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// A device for [VirtualMachine::hotplug_into].
+pub enum HotplugDevice {
+	Drive(DiskDrive),
+
+	/// A network adapter, and the network it's connected to.
+	Nic(NetworkAdapter, Network)
+}
+
+/// What has to be removed from a running VM along with a device added by
+/// [VirtualMachine::hotplug_drive] or [VirtualMachine::hotplug_nic], once the guest lets go of it.
+struct HotpluggedDevice {
+	/// The hotplug port the device is on, if it needed one.
+	port: Option<String>,
+
+	backend: HotplugBackend,
+
+	/// Objects added for the device (e.g: its I/O thread).
+	objects: Vec<String>
+}
+
+enum HotplugBackend {
+	/// A block node, by node name.
+	BlockNode(String),
+
+	/// A netdev, by id.
+	Netdev(String)
+}
+
+impl HotpluggedDevice {
+	/// Add the objects and device from a device's command line options. The backend has to exist already.
+	async fn add(&mut self, qmp: &QmpClient, options: &[String], write_through: bool) -> Result<(), VMControlError> {
+		for object in option_values(options, "-object") {
+			let arguments = qmp::keyval_to_arguments("qom-type", object);
+			let id = arguments.get("id").and_then(|id| id.as_str()).map(String::from).unwrap_or_default();
+			let object = serde_json::from_value(serde_json::Value::Object(arguments)).map_err(std::io::Error::from)?;
+
+			qmp.execute(qmp::qmp::object_add(object)).await?;
+			self.objects.push(id);
+		}
+
+		if let Some(device) = option_values(options, "-device").next() {
+			let mut device = qmp::device_add_from_keyval(device);
+			if write_through {
+				device.arguments.insert(String::from("write-cache"), serde_json::Value::from(false));
+			}
+
+			qmp.execute(device).await?;
+		}
+
+		Ok(())
+	}
+
+	/// Remove the device's backend and objects, once the device itself is gone.
+	async fn remove_backend(&self, qmp: &QmpClient) -> Result<(), VMControlError> {
+		match &self.backend {
+			HotplugBackend::BlockNode(node) => qmp.execute(qmp::qmp::blockdev_del { node_name: node.clone() }).await?,
+			HotplugBackend::Netdev(id) => qmp.execute(qmp::qmp::netdev_del { id: id.clone() }).await?
+		};
+
+		for id in self.objects.iter().rev() {
+			qmp.execute(qmp::qmp::object_del { id: id.clone() }).await?;
+		}
+
+		Ok(())
+	}
+}
+
+/// The id of the virtio-serial controller shared by every virtio-serial port.
+const VIRTIO_SERIAL_ID: &str = "vm.virtio-serial";
+
+/// How many ports QEMU gives a virtio-serial controller by default.
+const VIRTIO_SERIAL_DEFAULT_PORTS: u32 = 31;
+
+/// The most ports a virtio-serial controller can have (it runs out of virtqueues past this).
+const VIRTIO_SERIAL_PORT_LIMIT: u32 = 511;
+
+/// The values given to `flag` in a list of command line options.
+fn option_values<'o>(options: &'o [String], flag: &'o str) -> impl Iterator<Item = &'o str> {
+	options.windows(2)
+		.filter(move |opt| opt[0] == flag)
+		.map(|opt| opt[1].as_str())
+}
+
+/// Update why QEMU is going to exit after `event`, if it says anything about that. A panic or watchdog
+/// sticks, since whatever stops the VM afterwards (e.g: the host cleaning up) is only a consequence.
+fn record_exit_cause(cause: &mut Option<ExitReason>, event: &QmpEvent) {
+	use qmp::qmp::{ShutdownCause, WatchdogAction};
+
+	if matches!(cause, Some(ExitReason::Panicked | ExitReason::Watchdog)) {
+		return;
+	}
+
+	let reason = match event {
+		QmpEvent::GuestPanicked { .. } => ExitReason::Panicked,
+		QmpEvent::Watchdog { action: WatchdogAction::shutdown | WatchdogAction::poweroff } => ExitReason::Watchdog,
+		QmpEvent::Shutdown { reason, .. } => match reason {
+			ShutdownCause::guest_shutdown => ExitReason::GuestPoweroff,
+			ShutdownCause::guest_reset => ExitReason::GuestReset,
+			ShutdownCause::guest_panic => ExitReason::Panicked,
+			ShutdownCause::host_qmp_quit | ShutdownCause::host_qmp_system_reset | ShutdownCause::host_signal | ShutdownCause::host_ui => ExitReason::Host,
+			_ => return
+		},
+		_ => return
+	};
+
+	*cause = Some(reason);
+}
+
+/// Keeps a VM's state up to date when QEMU shuts down without being asked to through
+/// [VirtualMachine] (e.g: the guest powered off, or QEMU crashed).
+///
+/// The process is cleaned up on the next [VirtualMachine::start].
+async fn watch_events(qmp: Arc<QmpClient>, state: Arc<watch::Sender<VMState>>, usb_passthrough: Arc<Mutex<Vec<String>>>, exit_cause: Arc<Mutex<Option<ExitReason>>>, crash_dump: Option<String>) {
+	let mut events = qmp.events();
+
+	while let Some(event) = events.next().await {
+		record_exit_cause(&mut exit_cause.lock().unwrap(), &event);
+
+		match event {
+			QmpEvent::Shutdown { .. } => {
+				state.send_if_modified(|state| {
+					let started = *state == VMState::Started;
+					if started {
+						*state = VMState::Stopping;
+					}
+					started
+				});
+			},
+			QmpEvent::GuestPanicked { .. } => {
+				let crashed = state.send_if_modified(|state| {
+					let started = *state == VMState::Started;
+					if started {
+						*state = VMState::Crashed;
+					}
+					started
+				});
+
+				// QEMU writes the dump in the background, so this doesn't hold up other events
+				if let (true, Some(path)) = (crashed, &crash_dump) {
+					let dump = qmp.execute(qmp::qmp::dump_guest_memory {
+						protocol: format!("file:{path}"),
+						paging: false,
+						detach: Some(true),
+						format: None,
+						begin: None,
+						length: None
+					}).await;
+
+					if let Err(err) = dump {
+						log::warn!("couldn't dump the crashed guest's memory to {path}: {err}");
+					}
+				}
+			},
+			QmpEvent::DeviceDeleted { device: Some(device), .. } => {
+				if let Some(id) = device.strip_prefix("vm.") {
+					usb_passthrough.lock().unwrap().retain(|passthrough| passthrough != id);
+				}
+			},
+			_ => {}
+		}
+	}
+
+	// QEMU closed the connection, so it's gone
+	state.send_if_modified(|state| {
+		let running = matches!(state, VMState::Started | VMState::Stopping | VMState::Crashed);
+		if running {
+			*state = VMState::Stopped;
+		}
+		running
+	});
+}
+
+/// A QEMU virtual machine.
+pub struct VirtualMachine {
+	/// The QEMU process, once started.
+	process: Option<Child>,
+
+	/// The swtpm process backing a managed [Tpm]. It's started before QEMU, and exits along with it.
+	swtpm: Option<Child>,
+
+	/// The QMP connection to the running QEMU process. Shared with the event watcher.
+	qmp: Option<Arc<QmpClient>>,
+
+	/// The D-Bus connection to QEMU's display, with [DisplayBackend::DbusP2p].
+	dbus: Option<DbusDisplay>,
+
+	/// The current state. Anyone interested in changes holds a receiver from [VirtualMachine::subscribe_state].
+	state: Arc<watch::Sender<VMState>>,
+
+	/// Follows QMP events, to notice the VM stopping on its own (e.g: the guest powering off).
+	event_watcher: Option<JoinHandle<()>>,
+
+	/// Devices hotplugged into the running VM, by id. Forgotten once it stops.
+	hotplugged: HashMap<String, HotpluggedDevice>,
+
+	/// Ids of the USB passthrough devices QEMU has. The event watcher drops any which get deleted.
+	usb_passthrough: Arc<Mutex<Vec<String>>>,
+
+	/// Why QEMU is going to exit, going by its events so far. Kept up to date by the event watcher.
+	exit_cause: Arc<Mutex<Option<ExitReason>>>,
+
+	/// Why QEMU exited last time, once it has.
+	exit_reason: Option<ExitReason>,
+
+	name: String,
+	uuid: Option<String>,
+	arch: Architecture,
+	accel: Accelerator,
+	machine: Option<MachineType>,
+	display: Option<DisplayBackend>,
+	sandbox: Option<Sandbox>,
+	log: Option<QemuLog>,
+	audio: Option<Audio>,
+
+	/// Where audio goes. Defaults to [AudioBackend::None] if there's a sound card.
+	audio_backend: Option<AudioBackend>,
+
+	numa: Option<NumaTopology>,
+	throttle_groups: Vec<ThrottleGroup>,
+	secrets: Vec<Secret>,
+
+	/// Ids of PCIe root ports left empty at boot, for devices to be hotplugged into.
+	hotplug_ports: Vec<String>,
+
+	/// Ids of named PCIe root ports, which devices can ask to be placed on.
+	root_ports: Vec<String>,
+
+	devices: Vec<Box<dyn QemuOption>>,
+	drives: Vec<Box<dyn QemuOption>>,
+
+	/// How many devices of each id prefix have been added, for assigning ids.
+	id_counters: HashMap<&'static str, usize>,
+
+	/// Redirect BIOS output to the serial console with the sga device.
+	serial_bios: bool,
+
+	/// Overrides the size of the shared virtio-serial controller (see [QemuOption::virtio_serial_ports]).
+	virtio_serial_max_ports: Option<u32>,
+
+	firmware: Option<Firmware>,
+	boot: Option<BootConfig>,
+
+	/// Overrides the architecture's QEMU binary (see [Architecture::qemu_binary]).
+	qemu_binary: Option<String>,
+
+	/// Overrides [default_state_dir].
+	state_dir: Option<String>,
+
+	/// How long the guest gets to shut down before QEMU is killed.
+	shutdown_timeout: Duration
+
+}
+
+
+impl VirtualMachine {
+	/// Create a VM named `name`. The name ends up in socket and state directory paths, so it can only
+	/// have ASCII letters, digits, `.`, `_` and `-` in it, and can't start with a `.`.
+	pub fn new(name: &str) -> Result<VirtualMachine, VMCreateError> {
+		let name_str = String::from(name);
+
+		let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+		if name_str.is_empty() || name_str.starts_with('.') || !name_str.chars().all(allowed) {
+			// stop that!
+			Err(VMCreateError::InvalidName)
+		} else {
+			Ok(VirtualMachine {
+				process: None,
+				swtpm: None,
+				qmp: None,
+				dbus: None,
+				state: Arc::new(watch::channel(VMState::Stopped).0),
+				event_watcher: None,
+				hotplugged: HashMap::new(),
+				usb_passthrough: Arc::new(Mutex::new(Vec::new())),
+				exit_cause: Arc::new(Mutex::new(None)),
+				exit_reason: None,
+				name: name_str,
+				uuid: None,
+				arch: Architecture::X86_64,
+				accel: Accelerator::Kvm,
+				machine: None,
+				display: None,
+				sandbox: None,
+				log: None,
+				audio: None,
+				audio_backend: None,
+				numa: None,
+				throttle_groups: Vec::new(),
+				secrets: Vec::new(),
+				hotplug_ports: Vec::new(),
+				root_ports: Vec::new(),
+				devices: Vec::new(),
+				drives: Vec::new(),
+				id_counters: HashMap::new(),
+				serial_bios: false,
+				virtio_serial_max_ports: None,
+				firmware: None,
+				boot: None,
+				qemu_binary: None,
+				state_dir: None,
+				shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT
+			})
+		}
+	}
+
+	/// Create a VM with a sane starting configuration for a modern Linux guest: Q35 with KVM,
+	/// the host CPU (4 cores), 4G of memory, virtio-scsi, virtio-net on user networking, virtio-gpu and virtio-rng.
+	/// Drives still need to be added, and anything else can be set afterwards as usual.
+	pub fn modern_linux_defaults(name: &str) -> Result<VirtualMachine, VMCreateError> {
+		let mut vm = VirtualMachine::new(name)?;
+
+		// Ids are left empty, so they get assigned automatically
+		vm.set_accelerator(Accelerator::Kvm)
+			.set_machine_type(MachineType::Q35 { acpi: true, usb: true, hmat: false, legacy_root_port: false })
+			.add_device(Cpu {
+				model: String::from("host"),
+				features: vec![],
+				core_count: 4,
+				dies: None,
+				sockets: None,
+				threads: None,
+				vcpus: None,
+				max_vcpus: None,
+				host_cache_info: false
+			})
+			.add_device(Memory { size: ByteSize::gib(4), prealloc: false, backend: None, max_size: None, slots: None })
+			.add_device(GraphicsAdapter::VirtioGpu { blob: false, hostmem: None, vga: false, virgl: false, venus: false })
+			.add_device(Rng::Virtio { id: String::new(), source: None, max_bytes: None, period_ms: None })
+			.add_device(DiskController::VirtioScsi { id: String::new() })
+			.add_device(Network::User { id: String::new(), host_forwards: Vec::new() })
+			.add_device(NetworkAdapter::Virtio {
+				id: String::new(),
+				netdev: String::new(),
+				mac: None,
+				rombar: None,
+				romfile: None,
+				failover: false,
+				host_mtu: None,
+				offloads: VirtioNetOffloads::default(),
+				queues: None
+			});
+
+		Ok(vm)
+	}
+
+	/// Make a copy of this VM's configuration under a new name and UUID, e.g: for stamping out
+	/// many VMs from one template. Every device and drive is copied; note that per-VM paths
+	/// (like disk images) are copied as-is, so they'll likely need to be changed afterwards.
+	/// The guest agent's socket and a managed TPM's are moved to the clone's own, and a [Vsock]
+	/// can't be copied at all, since its context id has to be unique on the host.
+	pub fn clone_with(&self, name: &str, uuid: &str) -> Result<VirtualMachine, VMCreateError> {
+		let mut vm = VirtualMachine::new(name)?;
+
+		let clone_all = |options: &Vec<Box<dyn QemuOption>>| {
+			options.iter()
+				.map(|opt| opt.clone_boxed())
+				.collect::<Option<Vec<_>>>()
+				.ok_or(VMCreateError::UncloneableDevice)
+		};
+
+		vm.uuid = Some(String::from(uuid));
+		vm.arch = self.arch.clone();
+		vm.accel = self.accel.clone();
+		vm.machine = self.machine.clone();
+		vm.display = self.display.clone();
+		vm.sandbox = self.sandbox.clone();
+		vm.log = self.log.clone();
+		vm.audio = self.audio.clone();
+		vm.audio_backend = self.audio_backend.clone();
+		vm.numa = self.numa.clone();
+		vm.throttle_groups = self.throttle_groups.clone();
+		vm.secrets = self.secrets.clone();
+		vm.hotplug_ports = self.hotplug_ports.clone();
+		vm.root_ports = self.root_ports.clone();
+		vm.devices = clone_all(&self.devices)?;
+		vm.drives = clone_all(&self.drives)?;
+
+		// Sockets named after the source VM would clash with its own
+		for device in vm.devices.iter_mut() {
+			let device = (**device).as_any_mut();
+			if let Some(agent) = device.downcast_mut::<GuestAgent>() {
+				agent.socket_path = ga::socket_path(name);
+			} else if let Some(tpm) = device.downcast_mut::<Tpm>().filter(|tpm| tpm.managed) {
+				*tpm = Tpm::managed(tpm.interface.clone(), name);
+			} else if let Some(vsock) = device.downcast_ref::<Vsock>() {
+				return Err(VMCreateError::VsockCid(vsock.id.clone()));
+			}
+		}
+
+		vm.id_counters = self.id_counters.clone();
+		vm.serial_bios = self.serial_bios;
+		vm.virtio_serial_max_ports = self.virtio_serial_max_ports;
+		vm.firmware = self.firmware.clone();
+		vm.boot = self.boot.clone();
+		vm.qemu_binary = self.qemu_binary.clone();
+		// The state directory isn't copied, since the clone needs its own (e.g: for UEFI variables)
+		vm.shutdown_timeout = self.shutdown_timeout;
+
+		Ok(vm)
+	}
+
+	/// Make a linked clone of `template` (see [VirtualMachine::clone_with]): each of its writable hard drives gets a
+	/// qcow2 overlay in the clone's state directory, so the clone starts out with the template's disks without copying them.
+	/// The template's images are shared by all of its clones, so the template mustn't be started again while they're around.
+	pub async fn clone_from(template: &VirtualMachine, name: &str, uuid: &str) -> Result<VirtualMachine, VMCreateError> {
+		let mut vm = template.clone_with(name, uuid)?;
+		let state_dir = vm.state_dir();
+
+		// Every drive is checked before any overlays are created, so a drive which
+		// can't be linked doesn't leave the others' overlays lying around
+		let overlays = vm.drives.iter_mut()
+			.filter_map(|drive| (**drive).as_any_mut().downcast_mut::<DiskDrive>())
+			.filter_map(|drive| drive.link_overlay(&state_dir).transpose())
+			.collect::<Result<Vec<(String, String)>, VMCreateError>>()?;
+
+		if !overlays.is_empty() {
+			std::fs::create_dir_all(&state_dir)?;
+		}
+
+		for (overlay, backing) in overlays {
+			Image::open(&backing).create_overlay(&overlay).await?;
+		}
+
+		Ok(vm)
+	}
+
+	/// Set the name of this VM.
+	pub fn set_name(&mut self, name: &str) -> &mut VirtualMachine {
+		self.name = String::from(name);
+		self
+	}
+
+	/// Set the UUID of this VM.
+	pub fn set_uuid(&mut self, uuid: &str) -> &mut VirtualMachine {
+		self.uuid = Some(String::from(uuid));
+		self
+	}
+
+	/// Set the guest architecture of this VM. Defaults to x86_64.
+	pub fn set_architecture(&mut self, arch: Architecture) -> &mut VirtualMachine {
+		self.arch = arch;
+		self
+	}
+
+	/// Set the accelerator this VM will use. Defaults to KVM.
+	pub fn set_accelerator(&mut self, accel: Accelerator) -> &mut VirtualMachine {
+		self.accel = accel;
+		self
+	}
+
+	pub fn set_machine_type(&mut self, machine: MachineType) -> &mut VirtualMachine {
+		self.machine = Some(machine);
+		self
+	}
+
+	/// Set the display backend of this VM. If never set, QEMU's default is used.
+	pub fn set_display_backend(&mut self, display: DisplayBackend) -> &mut VirtualMachine {
+		self.display = Some(display);
+		self
+	}
+
+	/// Enable QEMU's seccomp sandbox for this VM.
+	pub fn set_sandbox(&mut self, sandbox: Sandbox) -> &mut VirtualMachine {
+		self.sandbox = Some(sandbox);
+		self
+	}
+
+	/// Split the VM's memory and vCPUs into NUMA nodes.
+	pub fn set_numa_topology(&mut self, numa: NumaTopology) -> &mut VirtualMachine {
+		self.numa = Some(numa);
+		self
+	}
+
+	/// Set the sound device of this VM.
+	pub fn set_audio(&mut self, audio: Audio) -> &mut VirtualMachine {
+		self.audio = Some(audio);
+		self
+	}
+
+	/// Set where the VM's audio goes on the host. Without this, the sound card's output is discarded.
+	pub fn set_audio_backend(&mut self, backend: AudioBackend) -> &mut VirtualMachine {
+		self.audio_backend = Some(backend);
+		self
+	}
+
+	/// Log the given categories of QEMU debug output (`-d`) to a file (`-D`).
+	pub fn set_qemu_log(&mut self, categories: Vec<LogCategory>, file: &str) -> &mut VirtualMachine {
+		self.log = Some(QemuLog {
+			categories,
+			file: String::from(file)
+		});
+		self
+	}
+
+	/// Reserve an empty PCIe root port which devices can later be hotplugged into.
+	/// Only supported on Q35.
+	pub fn reserve_hotplug_port(&mut self, id: &str) -> &mut VirtualMachine {
+		self.hotplug_ports.push(String::from(id));
+		self
+	}
+
+	/// Add a named PCIe root port, which devices can ask to be placed on
+	/// (e.g: [PciPassthrough]'s `root_port`). Only supported on Q35.
+	pub fn add_root_port(&mut self, id: &str) -> &mut VirtualMachine {
+		self.root_ports.push(String::from(id));
+		self
+	}
+
+	/// Add a throttle group, which drives can then share I/O limits through.
+	pub fn add_throttle_group(&mut self, group: ThrottleGroup) -> &mut VirtualMachine {
+		self.throttle_groups.push(group);
+		self
+	}
+
+	/// Add a secret, which drives (and anything else taking one) can then refer to by id.
+	pub fn add_secret(&mut self, secret: Secret) -> &mut VirtualMachine {
+		self.secrets.push(secret);
+		self
+	}
+
+	/// Gives a device an id (if it was left empty) based on its type and how many
+	/// of that type were added before it, so the same configuration always gets the same ids.
+	/// Ids another device was explicitly given are skipped.
+	fn assign_device_id<T: QemuOption + ?Sized>(&mut self, dev: &mut T) {
+		if let Some(prefix) = dev.id_prefix() {
+			let mut index = self.id_counters.get(prefix).copied().unwrap_or(0);
+			while self.id_taken(&format!("{prefix}{index}")) {
+				index += 1;
+			}
+
+			dev.assign_id(index);
+			self.id_counters.insert(prefix, index + 1);
+		}
+	}
+
+	/// Whether a device or drive (including hotplugged ones) already has this id.
+	fn id_taken(&self, id: &str) -> bool {
+		self.hotplugged.contains_key(id) || self.devices.iter()
+			.chain(self.drives.iter())
+			.any(|dev| dev.device_id() == Some(id))
+	}
+
+	/// Add something which implements the Options trait to this VM.
+	/// Devices with an empty id are given one automatically (`net0`, `scsi0`, ...)
+	pub fn add_device<T: QemuOption>(&mut self, mut dev: T) -> &mut VirtualMachine {
+		self.assign_device_id(&mut dev);
+		self.devices.push(Box::new(dev));
+		self
+	}
+
+	pub fn add_drive<T: QemuOption>(&mut self, mut dev: T) -> &mut VirtualMachine {
+		self.assign_device_id(&mut dev);
+		self.drives.push(Box::new(dev));
+		self
+	}
+
+	/// Every device and drive of the given type (e.g: `vm.devices_of::<Balloon>()`), in the order they were added.
+	pub fn devices_of<T: QemuOption>(&self) -> impl Iterator<Item = &T> {
+		self.devices.iter()
+			.chain(self.drives.iter())
+			.filter_map(|dev| (**dev).as_any().downcast_ref::<T>())
+	}
+
+	/// Load a VM definition from a TOML file (see [VmConfig]).
+	pub fn from_config(path: &str) -> Result<VirtualMachine, VMConfigError> {
+		let config: VmConfig = toml::from_str(&std::fs::read_to_string(path)?)?;
+		VirtualMachine::from_vm_config(config)
+	}
+
+	/// Create a VM from an already loaded definition.
+	pub fn from_vm_config(config: VmConfig) -> Result<VirtualMachine, VMConfigError> {
+		let mut vm = VirtualMachine::new(&config.name)?;
+
+		if let Some(uuid) = &config.uuid {
+			vm.set_uuid(uuid);
+		}
+
+		if let Some(arch) = config.arch {
+			vm.set_architecture(arch);
+		}
+
+		if let Some(accel) = config.accel {
+			vm.set_accelerator(accel);
+		}
+
+		vm.set_machine_type(config.machine);
+		vm.display = config.display;
+		vm.sandbox = config.sandbox;
+		vm.log = config.log;
+		vm.audio = config.audio;
+		vm.audio_backend = config.audio_backend;
+		vm.numa = config.numa;
+		vm.serial_bios = config.serial_bios;
+		vm.virtio_serial_max_ports = config.virtio_serial_max_ports;
+		vm.firmware = config.firmware;
+		vm.boot = config.boot;
+		vm.qemu_binary = config.qemu_binary;
+		vm.state_dir = config.state_dir;
+		vm.hotplug_ports = config.hotplug_ports;
+		vm.root_ports = config.root_ports;
+		vm.throttle_groups = config.throttle_groups;
+		vm.secrets = config.secrets;
+
+		for dev in config.devices {
+			let mut dev = dev.into_option();
+			vm.assign_device_id(dev.as_mut());
+			vm.devices.push(dev);
+		}
+
+		for drive in config.drives {
+			let mut drive = drive.into_option();
+			vm.assign_device_id(drive.as_mut());
+			vm.drives.push(drive);
+		}
+
+		Ok(vm)
+	}
+
+	/// Describe this VM as a [VmConfig]. Fails if it has a device which can't be described.
+	pub fn to_vm_config(&self) -> Result<VmConfig, VMConfigError> {
+		let describe_all = |options: &Vec<Box<dyn QemuOption>>| {
+			options.iter()
+				.map(|opt| opt.to_config().ok_or_else(|| VMConfigError::UndescribableDevice(opt.label())))
+				.collect::<Result<Vec<DeviceConfig>, VMConfigError>>()
+		};
+
+		Ok(VmConfig {
+			name: self.name.clone(),
+			uuid: self.uuid.clone(),
+			arch: Some(self.arch.clone()),
+			accel: Some(self.accel.clone()),
+			machine: self.machine.clone().ok_or(VMConfigError::NoMachineType)?,
+			display: self.display.clone(),
+			sandbox: self.sandbox.clone(),
+			log: self.log.clone(),
+			audio: self.audio.clone(),
+			audio_backend: self.audio_backend.clone(),
+			numa: self.numa.clone(),
+			serial_bios: self.serial_bios,
+			virtio_serial_max_ports: self.virtio_serial_max_ports,
+			firmware: self.firmware.clone(),
+			boot: self.boot.clone(),
+			qemu_binary: self.qemu_binary.clone(),
+			state_dir: self.state_dir.clone(),
+			hotplug_ports: self.hotplug_ports.clone(),
+			root_ports: self.root_ports.clone(),
+			throttle_groups: self.throttle_groups.clone(),
+			secrets: self.secrets.clone(),
+			devices: describe_all(&self.devices)?,
+			drives: describe_all(&self.drives)?
+		})
+	}
+
+	/// Save this VM's definition as TOML, which [VirtualMachine::from_config] can load again.
+	pub fn to_config(&self) -> Result<String, VMConfigError> {
+		Ok(toml::to_string_pretty(&self.to_vm_config()?)?)
+	}
+
+	/// Set the firmware this VM boots with. QEMU uses SeaBIOS if this isn't set.
+	pub fn set_firmware(&mut self, firmware: Firmware) -> &mut VirtualMachine {
+		self.firmware = Some(firmware);
+		self
+	}
+
+	/// Set what this VM boots from, and whether the firmware shows a boot menu.
+	pub fn set_boot(&mut self, boot: BootConfig) -> &mut VirtualMachine {
+		self.boot = Some(boot);
+		self
+	}
+
+	/// Run the QEMU binary at `path`, instead of the architecture's emulator on `PATH`.
+	pub fn set_qemu_binary(&mut self, path: &str) -> &mut VirtualMachine {
+		self.qemu_binary = Some(String::from(path));
+		self
+	}
+
+	/// The path (or name, to be found on `PATH`) of the QEMU binary this VM runs.
+	fn qemu_binary_path(&self) -> &str {
+		self.qemu_binary.as_deref().unwrap_or(self.arch.qemu_binary())
+	}
+
+	/// The QEMU binary this VM runs, and what it supports. It's only probed the first time it's asked about.
+	pub fn qemu_binary(&self) -> Result<Arc<QemuBinary>, VMQemuBinaryError> {
+		match &self.qemu_binary {
+			Some(path) => QemuBinary::at(path),
+			None => QemuBinary::find(&self.arch)
+		}
+	}
+
+	/// Set the directory this VM keeps its persistent state (e.g: UEFI variables) in.
+	/// Defaults to [default_state_dir].
+	pub fn set_state_dir(&mut self, path: &str) -> &mut VirtualMachine {
+		self.state_dir = Some(String::from(path));
+		self
+	}
+
+	/// How many serial consoles and channels are on QEMU's standard input and output.
+	fn stdio_chardevs(&self) -> usize {
+		let consoles = self.devices_of::<SerialConsole>()
+			.map(|console| &console.backend);
+
+		let channels = self.devices_of::<VirtioSerialChannel>()
+			.map(|channel| &channel.backend);
+
+		consoles.chain(channels)
+			.filter(|backend| matches!(backend, SerialBackend::Stdio))
+			.count()
+	}
+
+	/// Whether all of guest memory is on backends other processes can map (e.g: for vhost-user).
+	pub(crate) fn guest_memory_shared(&self) -> bool {
+		let is_shared = |backend: &Option<MemoryBackend>| backend.as_ref().is_some_and(MemoryBackend::is_shared);
+
+		// NUMA nodes replace the main memory backend
+		match &self.numa {
+			Some(numa) if !numa.nodes.is_empty() => numa.nodes.iter().all(|node| is_shared(&node.backend)),
+			_ => self.devices_of::<Memory>().any(|memory| is_shared(&memory.backend))
+		}
+	}
+
+	/// The directory this VM keeps its persistent state in.
+	pub fn state_dir(&self) -> String {
+		match &self.state_dir {
+			Some(dir) => dir.clone(),
+			None => default_state_dir(&self.name)
+		}
+	}
+
+	/// Add the SeaBIOS serial graphics adapter (sga), which mirrors the BIOS
+	/// text console (and its menus) onto the first serial port. Needs a [SerialConsole].
+	pub fn enable_serial_bios(&mut self) -> &mut VirtualMachine {
+		self.serial_bios = true;
+		self
+	}
+
+	/// Set how many ports the shared virtio-serial controller has room for (QEMU's default is 31).
+	/// Port 0 is reserved, so this has to be more than the number of channels (including the guest agent).
+	/// If this isn't set, the controller is made big enough for them.
+	pub fn set_virtio_serial_max_ports(&mut self, max_ports: u32) -> &mut VirtualMachine {
+		self.virtio_serial_max_ports = Some(max_ports);
+		self
+	}
+
+	/// Set how long [VirtualMachine::shutdown] gives the guest to power off (and then QEMU to exit
+	/// after SIGTERM) before escalating. Defaults to [DEFAULT_SHUTDOWN_TIMEOUT].
+	pub fn set_shutdown_timeout(&mut self, timeout: Duration) -> &mut VirtualMachine {
+		self.shutdown_timeout = timeout;
+		self
+	}
+
+	/// Validates every option on this VM, returning all of the failures (instead of just the first).
+	fn validation_errors(&self) -> Vec<OptionValidationError> {
+		let mut errors = self.validated_options()
+			.into_iter()
+			.filter_map(|opt| opt.validate(self).err().map(|error| OptionValidationError {
+				option: opt.label(),
+				error
+			}))
+			.collect::<Vec<OptionValidationError>>();
+
+		if let Some(firmware) = &self.firmware {
+			if let Err(error) = firmware.validate() {
+				errors.push(OptionValidationError { option: firmware.label(), error });
+			}
+		}
+
+		// QEMU refuses to start with two devices of the same id. Netdevs have ids of their own,
+		// so a network and its adapter can share one
+		let mut seen = Vec::new();
+		for dev in self.devices.iter().chain(self.drives.iter()) {
+			let Some(id) = dev.device_id() else {
+				continue;
+			};
+
+			let key = ((**dev).as_any().is::<Network>(), id);
+			if seen.contains(&key) {
+				errors.push(OptionValidationError { option: dev.label(), error: ValidationError::DuplicateId { id: String::from(id) } });
+			} else {
+				seen.push(key);
+			}
+		}
+
+		// Two forwards listening on the same host port would only fail once QEMU is starting.
+		// A clash is put down to the network with the later of the forwards
+		let mut forwards: Vec<&PortForward> = Vec::new();
+		for network in self.devices_of::<Network>() {
+			for forward in network.host_forwards() {
+				if forwards.iter().any(|other| forward.clashes_with(other)) {
+					errors.push(OptionValidationError {
+						option: network.label(),
+						error: ValidationError::HostPortClash { proto: forward.proto.name(), port: forward.host_port }
+					});
+				}
+
+				forwards.push(forward);
+			}
+		}
+
+		if let Some((ports, max_ports)) = self.virtio_serial_layout() {
+			if !(2..=VIRTIO_SERIAL_PORT_LIMIT).contains(&max_ports) {
+				errors.push(OptionValidationError {
+					option: String::from("VirtioSerial"),
+					error: ValidationError::InvalidVirtioSerialMaxPorts(VIRTIO_SERIAL_PORT_LIMIT)
+				});
+			} else if ports >= max_ports {
+				// Port 0 is reserved for virtconsole
+				errors.push(OptionValidationError {
+					option: String::from("VirtioSerial"),
+					error: ValidationError::TooManyVirtioSerialPorts { ports, max_ports }
+				});
+			}
+		}
+
+		errors
+	}
+
+	/// How many virtio-serial ports the VM's devices (and display) use, and the controller's max_ports
+	/// (sized to fit if it wasn't set). [None] if there's no need for a controller at all.
+	fn virtio_serial_layout(&self) -> Option<(u32, u32)> {
+		let ports = self.validated_options().iter().map(|opt| opt.virtio_serial_ports()).sum::<u32>();
+		if ports == 0 && self.virtio_serial_max_ports.is_none() {
+			return None;
+		}
+
+		Some((ports, self.virtio_serial_max_ports.unwrap_or(VIRTIO_SERIAL_DEFAULT_PORTS.max(ports + 1))))
+	}
+
+	/// Anything in this VM's configuration which will work, but probably isn't what was wanted (e.g: a drive
+	/// with share-rw on). Unlike validation errors, these don't stop the VM from starting, so check them first.
+	pub fn validation_warnings(&self) -> Vec<OptionValidationWarning> {
+		self.validated_options()
+			.into_iter()
+			.flat_map(|opt| opt.warnings(self).into_iter().map(|warning| OptionValidationWarning {
+				option: opt.label(),
+				warning
+			}))
+			.collect()
+	}
+
+	/// Every option on this VM which gets validated.
+	fn validated_options(&self) -> Vec<&dyn QemuOption> {
+		let mut options: Vec<&dyn QemuOption> = vec![&self.accel];
+
+		if let Some(machine) = &self.machine {
+			options.push(machine);
+		}
+
+		options.extend(self.throttle_groups.iter().map(|group| group as &dyn QemuOption));
+		options.extend(self.secrets.iter().map(|secret| secret as &dyn QemuOption));
+		options.extend(self.devices.iter().chain(self.drives.iter()).map(|dev| dev.as_ref() as &dyn QemuOption));
+
+		if let Some(numa) = &self.numa {
+			options.push(numa);
+		}
+
+		if let Some(audio) = &self.audio {
+			options.push(audio);
+		}
+
+		if let Some(backend) = &self.audio_backend {
+			options.push(backend);
+		}
+
+		if let Some(display) = &self.display {
+			options.push(display);
+		}
+
+		if let Some(boot) = &self.boot {
+			options.push(boot);
+		}
+
+		if let Some(sandbox) = &self.sandbox {
+			options.push(sandbox);
+		}
+
+		if let Some(log) = &self.log {
+			options.push(log);
+		}
+
+		options
+	}
+
+	/// Builds every option for this VM, each paired with a label describing where it came from.
+	fn labeled_options(&self) -> Result<Vec<(String, Vec<String>)>, VMQemuProcessStartError> {
+		self.labeled_options_with(&self.accel)
+	}
+
+	/// Like [VirtualMachine::labeled_options], but running under `accel` (e.g: [Accelerator::Auto] once it's been resolved).
+	fn labeled_options_with(&self, accel: &Accelerator) -> Result<Vec<(String, Vec<String>)>, VMQemuProcessStartError> {
+
+		if self.machine.is_none() {
+			return Err(VMQemuProcessStartError::NoMachineType);
+		}
+
+		let machine = self.machine.as_ref().unwrap();
+
+		if !self.arch.has_pci() && self.devices.iter().chain(self.drives.iter()).any(|dev| dev.requires_pci()) {
+			return Err(VMQemuProcessStartError::PciDeviceWithoutPci);
+		}
+
+		let mut vec = vec![
+			(String::from("Defaults"), vec![String::from("-nodefaults")]),
+			(accel.label(), accel.as_options()),
+			(String::from("Name"), qemu_arg("-name", format!("{},process=sunlight_{}", self.name, self.name))),
+			(String::from("Qmp"), qemu_arg("-qmp", format!("unix:{},server=on,wait=off", qemu_escape(&qmp::socket_path(&self.name))))),
+			(machine.label(), machine.as_options())
+		];
+
+		if let Some(uuid) = &self.uuid {
+			vec.push((String::from("Uuid"), qemu_arg("-uuid", uuid)));
+		}
+
+		if let Some(firmware) = &self.firmware {
+			let drives = firmware.drives(&self.state_dir())
+				.iter()
+				.flat_map(|drive| drive.as_options())
+				.collect();
+			vec.push((firmware.label(), drives));
+		}
+
+		if let Some(boot) = &self.boot {
+			vec.push((boot.label(), boot.as_options()));
+		}
+
+		// Only Q35 has a PCIe root complex to put root ports on
+		let mut root_ports = match machine {
+			MachineType::Q35 { legacy_root_port, .. } => Some(RootPortAllocator::new(*legacy_root_port)),
+			_ => None
+		};
+
+		let requests_port = self.devices.iter().chain(self.drives.iter()).any(|dev| dev.requested_root_port().is_some());
+		if root_ports.is_none() && (!self.root_ports.is_empty() || !self.hotplug_ports.is_empty() || requests_port) {
+			return Err(VMQemuProcessStartError::RootPortsRequireQ35);
+		}
+
+		if let Some(allocator) = &mut root_ports {
+			for id in &self.root_ports {
+				vec.push((String::from("RootPort"), qemu_arg("-device", allocator.port(format!("vm.{id}").as_str(), false)?)));
+			}
+
+			for id in &self.hotplug_ports {
+				vec.push((String::from("HotplugPort"), qemu_arg("-device", allocator.port(format!("vm.{id}").as_str(), true)?)));
+			}
+		}
+
+		let errors = self.validation_errors();
+		if !errors.is_empty() {
+			return Err(VMQemuProcessStartError::ValidationFailed(errors));
+		}
+
+		// Throttle groups and secrets need to exist before any drive refers to one
+		for group in &self.throttle_groups {
+			vec.push((group.label(), group.as_options()));
+		}
+
+		for secret in &self.secrets {
+			vec.push((secret.label(), secret.as_options()));
+		}
+
+		// Likewise, the virtio-serial controller has to exist before its ports
+		if let Some((_, max_ports)) = self.virtio_serial_layout() {
+			vec.push((String::from("VirtioSerial"), qemu_arg("-device", format!("virtio-serial-pci,id={VIRTIO_SERIAL_ID},max_ports={max_ports}"))));
+		}
+
+		// Append devices and drives from the configuration, with anything
+		// that's referenced by other devices (controllers, netdevs) first.
+		// The sort is stable, so otherwise the order things were added in is kept
+		let mut devices = self.devices.iter()
+			.chain(self.drives.iter())
+			.collect::<Vec<&Box<dyn QemuOption>>>();
+		devices.sort_by_key(|dev| dev.order_key());
+
+		// Named root ports only have room for one device each
+		let mut used_ports = Vec::new();
+
+		for dev in devices {
+			// NICs without a MAC get a stable one when the VM has a UUID to derive it from
+			let derived = self.uuid.as_deref().and_then(|uuid| {
+				let mut adapter = (**dev).as_any().downcast_ref::<NetworkAdapter>()?.clone();
+				adapter.derive_mac(uuid).then(|| Box::new(adapter) as Box<dyn QemuOption>)
+			});
+			let options_dev = derived.as_deref().unwrap_or(dev.as_ref());
+
+			let boot_index = dev.boot_device().and_then(|boot_dev| {
+				self.boot.as_ref()?.order.iter().position(|listed| *listed == boot_dev)
+			});
+			let with_boot_index = |options| match boot_index {
+				Some(index) => set_boot_index(options, index),
+				None => options
+			};
+
+			let allocator = match &mut root_ports {
+				Some(allocator) if dev.wants_root_port() => allocator,
+				_ => {
+					vec.push((dev.label(), with_boot_index(options_dev.as_options())));
+					continue;
+				}
+			};
+
+			let port = match dev.requested_root_port() {
+				Some(port) => {
+					if !self.root_ports.iter().chain(self.hotplug_ports.iter()).any(|id| id == port) {
+						return Err(VMQemuProcessStartError::UnknownRootPort(String::from(port)));
+					}
+
+					if used_ports.contains(&port) {
+						return Err(VMQemuProcessStartError::RootPortInUse(String::from(port)));
+					}
+
+					used_ports.push(port);
+					format!("vm.{port}")
+				},
+				None => {
+					let (id, port) = allocator.allocate()?;
+					vec.push((String::from("RootPort"), qemu_arg("-device", port)));
+					id
+				}
+			};
+
+			vec.push((dev.label(), with_boot_index(options_dev.as_options_on_port(&port))));
+		}
+
+		if let Some(numa) = &self.numa {
+			vec.push((numa.label(), numa.as_options()));
+		}
+
+		if let Some(audio) = &self.audio {
+			// The sound card's audiodev has to exist first
+			let backend = self.audio_backend.as_ref().unwrap_or(&AudioBackend::None);
+			vec.push((backend.label(), backend.as_options()));
+
+			let audio = audio.for_machine(machine);
+			vec.push((audio.label(), audio.as_options()));
+		}
+
+		if let Some(display) = &self.display {
+			vec.push((display.label(), display.as_options()));
+		}
+
+		if let Some(sandbox) = &self.sandbox {
+			vec.push((sandbox.label(), sandbox.as_options()));
+		}
+
+		if let Some(log) = &self.log {
+			vec.push((log.label(), log.as_options()));
+		}
+
+		if self.serial_bios {
+			if self.devices_of::<SerialConsole>().next().is_none() {
+				return Err(VMQemuProcessStartError::SerialBiosWithoutConsole);
+			}
+
+			vec.push((String::from("SerialBios"), qemu_arg("-device", "sga")));
+		}
+
+		// Auto might end up under TCG (or already has)
+		if matches!(accel, Accelerator::Auto | Accelerator::Tcg { .. }) && matches!(self.accel, Accelerator::Auto) {
+			vec = vec.into_iter()
+				.map(|(label, options)| (label, emulate_host_cpu(options)))
+				.collect();
+		}
+
+		Ok(vec)
+	}
+
+	/// Generate the QEMU command arguments that will be used to run this VM. This includes some options
+	/// which are always generated, to aid Sunlight's out-of-band management of the VM.
+	pub fn to_arguments(&self) -> Result<Vec<String>, VMQemuProcessStartError> {
+		Ok(self.labeled_options()?
+			.into_iter()
+			.flat_map(|(_, opt)| opt)
+			.collect())
+	}
+
+	/// Like [VirtualMachine::to_arguments], but with the arguments grouped by the option
+	/// (or device) which generated them, and labeled (e.g: `GraphicsAdapter::StdVga`).
+	/// This is only meant for logging/debugging; use [VirtualMachine::to_arguments] to actually run QEMU.
+	pub fn to_arguments_annotated(&self) -> Result<Vec<(String, Vec<String>)>, VMQemuProcessStartError> {
+		self.labeled_options()
+	}
+
+	/// Generate the full QEMU command line for this VM as a single shell-quoted string,
+	/// suitable for pasting into a shell.
+	pub fn to_command_string(&self) -> Result<String, VMQemuProcessStartError> {
+		let mut command = vec![String::from(self.qemu_binary_path())];
+		command.append(&mut self.to_arguments()?);
+
+		Ok(command.iter()
+			.map(|arg| shell_quote(arg))
+			.collect::<Vec<String>>()
+			.join(" "))
+	}
+
+	/// Write a shell script which launches this VM (with one argument per line), and mark it executable.
+	/// Handy for reproducing or debugging a VM outside of Sunlight.
+	pub fn write_launch_script(&self, path: &str) -> Result<(), VMQemuProcessStartError> {
+		use std::os::unix::fs::PermissionsExt;
+
+		// Otherwise this only fails after all the work of building the command line
+		let error = match containing_dir(path) {
+			None => Some(ValidationError::EmptyField { field: "path" }),
+			Some(dir) if !dir_writable(dir) => Some(ValidationError::DirectoryNotWritable { path: dir.to_string_lossy().into_owned() }),
+			Some(..) => None
+		};
+
+		if let Some(error) = error {
+			return Err(VMQemuProcessStartError::ValidationFailed(vec![OptionValidationError { option: String::from("launch script"), error }]));
+		}
+
+		let mut script = format!("#!/bin/sh\nexec {}", shell_quote(self.qemu_binary_path()));
+		for arg in self.to_arguments()? {
+			script.push_str(format!(" \\\n\t{}", shell_quote(&arg)).as_str());
+		}
+		script.push('\n');
+
+		std::fs::write(path, script)?;
+		std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+		Ok(())
+	}
+
+	/// Start the VM, by spawning QEMU and connecting to its QMP socket.
+	pub async fn start(&mut self) -> Result<(), VMStartError> {
+		// QEMU exited on its own last time, so clean up after it first
+		if self.process.is_some() && self.state() == VMState::Stopped {
+			self.stopped();
+		}
+
+		self.transition(&[VMState::Stopped], VMState::Starting)
+			.map_err(VMStartError::InvalidState)?;
+
+		match self.spawn().await {
+			Ok(()) => {
+				self.state.send_replace(VMState::Started);
+				Ok(())
+			},
+			Err(err) => {
+				self.kill();
+				self.swtpm = None;
+				self.release_host();
+				self.state.send_replace(VMState::Stopped);
+				Err(err)
+			}
+		}
+	}
+
+	async fn spawn(&mut self) -> Result<(), VMStartError> {
+		let accel = self.accel.resolve();
+		if let (Accelerator::Auto, Accelerator::Tcg { .. }) = (&self.accel, &accel) {
+			log::warn!("no hardware acceleration is available, so {} will run (slowly) under TCG", self.name);
+		}
+
+		// Catch the accelerator being unusable here, instead of letting QEMU fail cryptically
+		if !accel_available(&accel) {
+			return Err(match accel {
+				Accelerator::Kvm => VMQemuProcessStartError::KvmUnavailable,
+				accel => VMQemuProcessStartError::AcceleratorUnavailable(accel.label())
+			}.into());
+		}
+
+		// Catch anything this QEMU doesn't have now, rather than from its exit status
+		let binary = self.qemu_binary()
+			.map_err(VMQemuProcessStartError::from)?;
+		let options = self.labeled_options_with(&accel)?;
+		binary.check_arguments(&options)?;
+
+		let args = options.into_iter()
+			.flat_map(|(_, opt)| opt)
+			.collect::<Vec<String>>();
+
+		if let Some(firmware) = &self.firmware {
+			firmware.prepare(&self.state_dir())
+				.map_err(VMQemuProcessStartError::IoError)?;
+		}
+
+		self.record_host_interfaces()
+			.map_err(VMQemuProcessStartError::IoError)?;
+
+		// Overlays on a backing image are created the first time they're needed
+		for (overlay, backing) in self.devices_of::<DiskDrive>().filter_map(DiskDrive::overlay) {
+			if !std::path::Path::new(overlay).exists() {
+				Image::open(backing).create_overlay(overlay).await
+					.map_err(VMQemuProcessStartError::from)?;
+			}
+		}
+
+		for dev in self.devices.iter().chain(self.drives.iter()) {
+			dev.prepare_host()
+				.map_err(VMQemuProcessStartError::IoError)?;
+		}
+
+		self.spawn_swtpm().await?;
+
+		// A socket left behind by a previous run would stop QEMU from creating its own
+		let qmp_path = qmp::socket_path(&self.name);
+		if let Err(err) = std::fs::remove_file(&qmp_path) {
+			if err.kind() != std::io::ErrorKind::NotFound {
+				return Err(VMQemuProcessStartError::IoError(err).into());
+			}
+		}
+
+		// A console on stdio needs the terminal's input
+		let child = Command::new(binary.path())
+			.args(args)
+			.stdin(if self.stdio_chardevs() > 0 { std::process::Stdio::inherit() } else { std::process::Stdio::null() })
+			.spawn()
+			.map_err(VMQemuProcessStartError::IoError)?;
+
+		// Held on to straight away, so QEMU is killed if anything from here on fails (or the VM is dropped)
+		let child = self.process.insert(child);
+		let qmp = QmpClient::connect_spawned(&qmp_path, child).await?;
+
+		// Guest stats polling is a QOM property, which can't be set on the command line
+		if let Some(balloon) = self.devices_of::<Balloon>().next() {
+			if let Some(interval) = balloon.stats_interval_secs {
+				qmp.execute(qmp::qmp::qom_set {
+					path: balloon.qom_path(),
+					property: String::from("guest-stats-polling-interval"),
+					value: serde_json::Value::from(interval)
+				}).await?;
+			}
+		}
+
+		// The display's D-Bus connection can only be handed over through QMP
+		if let Some(DisplayBackend::DbusP2p { .. }) = self.display {
+			self.dbus = Some(DbusDisplay::connect(&qmp).await?);
+		}
+
+		self.attach_qmp(qmp);
+		Ok(())
+	}
+
+	/// Hold on to the QMP connection to a freshly started QEMU, and start following its events.
+	fn attach_qmp(&mut self, qmp: QmpClient) {
+		let qmp = Arc::new(qmp);
+		let crash_dump = self.devices_of::<Pvpanic>()
+			.next()
+			.and_then(|pvpanic| pvpanic.crash_dump.clone());
+
+		*self.usb_passthrough.lock().unwrap() = self.devices_of::<UsbDevice>()
+			.filter_map(UsbDevice::passthrough_id)
+			.map(String::from)
+			.collect();
+
+		*self.exit_cause.lock().unwrap() = None;
+		self.exit_reason = None;
+
+		self.event_watcher = Some(tokio::spawn(watch_events(qmp.clone(), self.state.clone(), self.usb_passthrough.clone(), self.exit_cause.clone(), crash_dump)));
+		self.qmp = Some(qmp);
+	}
+
+	/// Why QEMU exited the last time the VM stopped, going by the last thing it said about it before
+	/// exiting (and its exit status, if it said nothing). None if the VM is running, or never ran.
+	pub fn exit_reason(&self) -> Option<ExitReason> {
+		self.exit_reason.clone()
+	}
+
+	/// The current state of this VM.
+	pub fn state(&self) -> VMState {
+		self.state.borrow().clone()
+	}
+
+	/// Get a receiver which is notified whenever this VM changes state, e.g:
+	/// for awaiting it to stop instead of polling [VirtualMachine::state].
+	pub fn subscribe_state(&self) -> watch::Receiver<VMState> {
+		self.state.subscribe()
+	}
+
+	/// Moves to the state `to`, if the VM is currently in one of the states in `from`.
+	/// Otherwise the state is left alone, and the current state is returned as the error.
+	fn transition(&self, from: &[VMState], to: VMState) -> Result<(), VMState> {
+		let current = self.state();
+
+		if !from.contains(&current) {
+			return Err(current);
+		}
+
+		self.state.send_replace(to);
+		Ok(())
+	}
+
+	/// The QMP connection to this VM, if it's running.
+	pub fn qmp(&self) -> Option<&QmpClient> {
+		self.qmp.as_deref()
+	}
+
+	/// Subscribe to this VM's QMP events (see [QmpClient::events]). The stream ends when the VM stops.
+	pub fn events(&self) -> Result<BoxStream<'static, QmpEvent>, VMControlError> {
+		Ok(self.running_qmp()?.events())
+	}
+
+	/// The D-Bus connection to this VM's display, if it's running with [DisplayBackend::DbusP2p].
+	pub fn dbus_display(&self) -> Option<&DbusDisplay> {
+		self.dbus.as_ref()
+	}
+
+	fn running_qmp(&self) -> Result<&QmpClient, VMControlError> {
+		if self.state() != VMState::Started {
+			return Err(VMControlError::InvalidState(self.state()));
+		}
+
+		self.qmp.as_deref().ok_or(VMControlError::NotRunning)
+	}
+
+	/// Connect to the guest agent of a running VM. The VM needs a [GuestAgent] device,
+	/// and the guest needs to be running qemu-ga for commands to complete.
+	pub async fn guest_agent(&self) -> Result<GuestAgentClient, VMControlError> {
+		self.running_qmp()?;
+
+		let socket = self.devices_of::<GuestAgent>()
+			.next()
+			.map(|agent| agent.socket_path.as_str())
+			.ok_or(VMControlError::NoGuestAgent)?;
+
+		Ok(GuestAgentClient::connect(socket).await?)
+	}
+
+	/// Connect to the (first) serial console of a running VM. A pty is opened by the path
+	/// QEMU gives it, and a log file is read from the start (and can't be written to).
+	pub async fn console(&self) -> Result<SerialConsoleIo, VMControlError> {
+		let qmp = self.running_qmp()?;
+
+		let console = self.devices_of::<SerialConsole>()
+			.next()
+			.ok_or(VMControlError::NoSerialConsole)?;
+
+		console.backend.connect(&console.id, qmp).await
+	}
+
+	/// Connect to the host side of the [VirtioSerialChannel] named `name` in a running VM.
+	pub async fn channel(&self, name: &str) -> Result<SerialConsoleIo, VMControlError> {
+		let qmp = self.running_qmp()?;
+
+		let channel = self.devices_of::<VirtioSerialChannel>()
+			.find(|channel| channel.name == name)
+			.ok_or_else(|| VMControlError::NoSuchChannel(String::from(name)))?;
+
+		channel.backend.connect(&channel.id, qmp).await
+	}
+
+	/// Waits up to `timeout` for QEMU to exit. Returns whether it did.
+	async fn wait_exit(&mut self, timeout: Duration) -> Result<bool, VMControlError> {
+		let child = self.process.as_mut().ok_or(VMControlError::NotRunning)?;
+
+		match tokio::time::timeout(timeout, child.wait()).await {
+			Ok(status) => {
+				status?;
+				Ok(true)
+			},
+			Err(..) => Ok(false)
+		}
+	}
+
+	/// Kill QEMU outright, without waiting for it to exit, and forget about it. The guest gets no warning.
+	fn kill(&mut self) {
+		if let Some(mut child) = self.process.take() {
+			// This fails if QEMU has already exited and been waited on, which is fine
+			let _ = child.start_kill();
+		}
+	}
+
+	/// Forget about the (now exited) QEMU process.
+	fn stopped(&mut self) {
+		if let Some(watcher) = self.event_watcher.take() {
+			watcher.abort();
+		}
+
+		// Only a process which has been waited on has a status
+		if let Some(Ok(Some(status))) = self.process.as_mut().map(Child::try_wait) {
+			let cause = self.exit_cause.lock().unwrap().take();
+			self.exit_reason = Some(cause.unwrap_or(ExitReason::Crashed(status)));
+		}
+
+		self.dbus = None;
+		self.qmp = None;
+		self.process = None;
+		self.swtpm = None;
+		self.hotplugged.clear();
+		self.release_host();
+		self.state.send_replace(VMState::Stopped);
+	}
+
+	/// Give any host resources devices took in [QemuOption::prepare_host] back. This is best
+	/// effort, since the VM is stopped either way, and one device failing shouldn't stop the rest.
+	fn release_host(&self) {
+		let mut released = true;
+		for dev in self.devices.iter().chain(self.drives.iter()) {
+			if let Err(err) = dev.release_host() {
+				log::warn!("couldn't release host resources for {}: {err}", dev.label());
+				released = false;
+			}
+		}
+
+		// Otherwise the next start tries again
+		if released {
+			let _ = std::fs::remove_file(std::path::Path::new(&self.state_dir()).join(HOST_INTERFACES_FILE));
+		}
+	}
+
+	/// Delete host interfaces left behind by a run which didn't get to clean up after itself (e.g: because
+	/// Sunlight crashed), then record the ones this run is about to create, in case that happens again.
+	fn record_host_interfaces(&self) -> Result<(), std::io::Error> {
+		let state_dir = self.state_dir();
+		let path = std::path::Path::new(&state_dir).join(HOST_INTERFACES_FILE);
+
+		if let Ok(stale) = std::fs::read_to_string(&path) {
+			for name in stale.lines().filter(|name| !name.is_empty()) {
+				delete_interface(name)?;
+			}
+		}
+
+		let interfaces = self.devices_of::<Network>()
+			.filter_map(Network::host_interface)
+			.map(|name| format!("{name}\n"))
+			.collect::<String>();
+
+		if interfaces.is_empty() {
+			return match std::fs::remove_file(&path) {
+				Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+				_ => Ok(())
+			};
+		}
+
+		std::fs::create_dir_all(&state_dir)?;
+		std::fs::write(&path, interfaces)
+	}
+
+	/// Start swtpm for a managed [Tpm], and wait for its control socket to show up. swtpm is
+	/// told to exit once QEMU disconnects, and is killed when dropped in case it doesn't.
+	async fn spawn_swtpm(&mut self) -> Result<(), VMQemuProcessStartError> {
+		let Some(socket_path) = self.devices_of::<Tpm>().find_map(Tpm::swtpm_socket).map(String::from) else {
+			return Ok(());
+		};
+
+		let tpm_state = std::path::Path::new(&self.state_dir()).join("tpm");
+		std::fs::create_dir_all(&tpm_state)?;
+
+		// swtpm won't start if a previous run's socket is still there
+		if let Err(err) = std::fs::remove_file(&socket_path) {
+			if err.kind() != std::io::ErrorKind::NotFound {
+				return Err(err.into());
+			}
+		}
+
+		let mut child = swtpm_command(&tpm_state, &socket_path).spawn()?;
+		wait_for_socket(&mut child, &socket_path, SWTPM_TIMEOUT).await?;
+
+		self.swtpm = Some(child);
+		Ok(())
+	}
+
+	/// If stopping the VM failed and QEMU is somehow still around, the VM goes back to the state it was in.
+	fn stop_failed(&mut self, previous: VMState) {
+		if self.process.is_some() {
+			self.state.send_replace(previous);
+		}
+	}
+
+	/// Ask the guest to shut down (like pressing the power button), and wait for it to.
+	/// If the guest ignores the request, QEMU is sent SIGTERM, and then SIGKILL if that
+	/// doesn't work either, waiting the shutdown timeout between each step.
+	pub async fn shutdown(&mut self) -> Result<(), VMControlError> {
+		self.transition(&[VMState::Started], VMState::Stopping)
+			.map_err(VMControlError::InvalidState)?;
+
+		let result = self.powerdown().await;
+		if result.is_err() {
+			self.stop_failed(VMState::Started);
+		}
+
+		result
+	}
+
+	/// Shut the VM down (the same way as [VirtualMachine::shutdown]) and start it again, e.g: to apply changes
+	/// which can't be made while it's running. Devices which were hotplugged don't come back.
+	pub async fn restart(&mut self) -> Result<(), VMControlError> {
+		self.shutdown().await?;
+		self.start().await.map_err(VMControlError::RestartFailed)
+	}
+
+	async fn powerdown(&mut self) -> Result<(), VMControlError> {
+		let timeout = self.shutdown_timeout;
+
+		self.qmp.as_ref()
+			.ok_or(VMControlError::NotRunning)?
+			.execute(qmp::qmp::system_powerdown {})
+			.await?;
+
+		if self.wait_exit(timeout).await? {
+			self.stopped();
+			return Ok(());
+		}
+
+		// The guest didn't listen (or has no ACPI), so ask QEMU itself to go away
+		if let Some(pid) = self.process.as_ref().and_then(|child| child.id()) {
+			// It's fine if this fails; the process may have just exited on its own
+			let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), nix::sys::signal::Signal::SIGTERM);
+		}
+
+		if !self.wait_exit(timeout).await? {
+			self.process.as_mut().unwrap().kill().await?;
+		}
+
+		self.stopped();
+		Ok(())
+	}
+
+	/// Immediately stop the VM, without giving the guest a chance to shut down (`quit`).
+	/// This is also how a [VMState::Crashed] VM is gotten rid of.
+	pub async fn force_stop(&mut self) -> Result<(), VMControlError> {
+		let previous = self.state();
+		self.transition(&[VMState::Started, VMState::Crashed], VMState::Stopping)
+			.map_err(VMControlError::InvalidState)?;
+
+		let result = self.quit().await;
+		if result.is_err() {
+			self.stop_failed(previous);
+		}
+
+		result
+	}
+
+	async fn quit(&mut self) -> Result<(), VMControlError> {
+		// QEMU may exit before it gets around to replying, so the result doesn't say much
+		let _ = self.qmp.as_ref()
+			.ok_or(VMControlError::NotRunning)?
+			.execute(qmp::qmp::quit {})
+			.await;
+
+		if !self.wait_exit(self.shutdown_timeout).await? {
+			self.process.as_mut().unwrap().kill().await?;
+		}
+
+		self.stopped();
+		Ok(())
+	}
+
+	/// Reset the VM, like pressing the reset button.
+	pub async fn reset(&self) -> Result<(), VMControlError> {
+		self.running_qmp()?
+			.execute(qmp::qmp::system_reset {})
+			.await?;
+		Ok(())
+	}
+
+	/// Pause the VM's vCPUs.
+	pub async fn pause(&self) -> Result<(), VMControlError> {
+		self.running_qmp()?
+			.execute(qmp::qmp::stop {})
+			.await?;
+		Ok(())
+	}
+
+	/// Resume the VM's vCPUs after [VirtualMachine::pause].
+	pub async fn resume(&self) -> Result<(), VMControlError> {
+		self.running_qmp()?
+			.execute(qmp::qmp::cont {})
+			.await?;
+		Ok(())
+	}
+
+	/// Change the password VNC clients have to give. Clients which are already connected stay connected.
+	/// This only works if the VNC server was started with a password ([DisplayBackend::Vnc]'s `password_secret`).
+	pub async fn set_vnc_password(&self, password: &str) -> Result<(), VMControlError> {
+		let qmp = self.running_qmp()?;
+
+		if !matches!(self.display, Some(DisplayBackend::Vnc { password_secret: Some(..), .. })) {
+			return Err(VMControlError::NoVncPassword);
+		}
+
+		qmp.execute(qmp::qmp::set_password(qmp::qmp::SetPasswordOptions::vnc {
+			base: qmp::qmp::SetPasswordOptionsBase {
+				password: String::from(password),
+				connected: None
+			},
+			vnc: qmp::qmp::SetPasswordOptionsVnc {
+				display: None
+			}
+		})).await?;
+		Ok(())
+	}
+
+	/// Inflate or deflate the VM's [Balloon] so the guest is left with `target` of memory.
+	/// The guest gives memory up (or takes it back) in its own time, so this returns straight away.
+	pub async fn set_balloon(&self, target: ByteSize) -> Result<(), VMControlError> {
+		let qmp = self.running_qmp()?;
+
+		if self.devices_of::<Balloon>().next().is_none() {
+			return Err(VMControlError::NoBalloon);
+		}
+
+		qmp.execute(qmp::qmp::balloon { value: target.as_bytes() as i64 }).await?;
+		Ok(())
+	}
+
+	/// How much memory the VM's [Balloon] has left the guest with, and (if the balloon has a
+	/// `stats_interval_secs`) how the guest says it's using it.
+	pub async fn balloon_stats(&self) -> Result<BalloonStats, VMControlError> {
+		let qmp = self.running_qmp()?;
+
+		let balloon = self.devices_of::<Balloon>()
+			.next()
+			.ok_or(VMControlError::NoBalloon)?;
+
+		let info = qmp.execute(qmp::qmp::query_balloon {}).await?;
+		let guest = match balloon.stats_interval_secs {
+			Some(..) => qmp.execute(qmp::qmp::qom_get {
+				path: balloon.qom_path(),
+				property: String::from("guest-stats")
+			}).await?,
+			None => serde_json::Value::Null
+		};
+
+		Ok(BalloonStats::new(info.actual, &guest))
+	}
+
+	/// The USB devices passed through from the host (see [UsbDevice::HostById] and [UsbDevice::HostByPort]) which
+	/// the VM still has, and whether each one currently has a host device behind it.
+	pub async fn usb_devices(&self) -> Result<Vec<UsbPassthrough>, VMControlError> {
+		let qmp = self.running_qmp()?;
+		let ids = self.usb_passthrough.lock().unwrap().clone();
+
+		let mut devices = Vec::new();
+		for id in ids {
+			let attached = qmp.execute(qmp::qmp::qom_get {
+				path: format!("/machine/peripheral/vm.{id}"),
+				property: String::from("attached")
+			}).await?;
+
+			devices.push(UsbPassthrough { id, attached: attached.as_bool().unwrap_or(false) });
+		}
+
+		Ok(devices)
+	}
+
+	/// Listen on the unix socket `host_unix_path`, and relay each connection to `guest_port` on the VM's
+	/// [Vsock] (like `socat UNIX-LISTEN:... VSOCK-CONNECT:...`). The guest has to be listening on the port.
+	pub async fn forward_vsock(&self, host_unix_path: &str, guest_port: u32) -> Result<VsockForward, VMControlError> {
+		self.running_qmp()?;
+
+		let cid = self.devices_of::<Vsock>()
+			.next()
+			.ok_or(VMControlError::NoVsock)?
+			.guest_cid;
+
+		let listener = tokio::net::UnixListener::bind(host_unix_path)?;
+		let relay = tokio::spawn(relay_connections(listener, move || VsockStream::connect(cid, guest_port)));
+
+		Ok(VsockForward { path: String::from(host_unix_path), relay })
+	}
+
+	/// Every device on the guest's PCI buses, e.g: to check devices ended up where they were meant to.
+	pub async fn pci_devices(&self) -> Result<Vec<PciDevice>, VMControlError> {
+		let buses = self.running_qmp()?
+			.execute(qmp::qmp::query_pci {})
+			.await?;
+
+		Ok(PciDevice::from_qmp(buses))
+	}
+
+	/// Start dumping the guest's memory to `path`, e.g: to get a core out of a wedged (or [VMState::Crashed]) guest.
+	/// QEMU writes the dump in the background, with the vCPUs paused until it's done.
+	///
+	/// With `paging`, guest virtual addresses are translated, so the core can be read without knowing
+	/// the guest kernel's page tables. This only works with [DumpFormat::Elf], and is a lot slower.
+	pub async fn dump_memory(&self, path: &str, format: DumpFormat, paging: bool) -> Result<MemoryDump<'_>, VMControlError> {
+		// Dumping a crashed guest is the main reason to do this
+		let state = self.state();
+		if !matches!(state, VMState::Started | VMState::Crashed) {
+			return Err(VMControlError::InvalidState(state));
+		}
+
+		let qmp = self.qmp.as_deref().ok_or(VMControlError::NotRunning)?;
+		let events = qmp.events();
+
+		qmp.execute(qmp::qmp::dump_guest_memory {
+			protocol: format!("file:{path}"),
+			paging,
+			detach: Some(true),
+			format: Some(format.to_qmp()),
+			begin: None,
+			length: None
+		}).await?;
+
+		Ok(MemoryDump::new(qmp, events))
+	}
+
+	/// Take an external snapshot of a running disk drive. A new qcow2 overlay is created at
+	/// `overlay_path`, with the current image as its backing file, and the guest carries on
+	/// writing to the overlay. The old image is left as it was at the time of the snapshot.
+	pub async fn snapshot_disk(&self, drive_id: &str, overlay_path: &str) -> Result<(), VMControlError> {
+		self.running_qmp()?
+			.execute(qmp::qmp::blockdev_snapshot_sync(qmp::qmp::BlockdevSnapshotSync {
+				device: Some(format!("vm.{drive_id}.drive")),
+				node_name: None,
+				snapshot_file: String::from(overlay_path),
+				snapshot_node_name: None,
+				format: Some(String::from("qcow2")),
+				mode: Some(qmp::qmp::NewImageMode::absolute_paths)
+			}))
+			.await?;
+		Ok(())
+	}
+
+	/// Start merging a drive's active overlay back down into its backing file (e.g: after
+	/// [VirtualMachine::snapshot_disk]). Once the job finishes, the guest writes to the backing file again.
+	pub async fn block_commit(&self, drive_id: &str) -> Result<BlockJob<'_>, VMControlError> {
+		let qmp = self.running_qmp()?;
+		let job_id = format!("vm.{drive_id}.commit");
+
+		#[allow(deprecated)]
+		qmp.execute(qmp::qmp::block_commit {
+			device: format!("vm.{drive_id}.drive"),
+			job_id: Some(job_id.clone()),
+			auto_dismiss: Some(false),
+			auto_finalize: None,
+			backing_file: None,
+			base: None,
+			base_node: None,
+			filter_node_name: None,
+			on_error: None,
+			speed: None,
+			top: None,
+			top_node: None
+		}).await?;
+
+		Ok(BlockJob::new(qmp, job_id, true))
+	}
+
+	/// Start copying the data a drive's backing chain holds into its active image, so the
+	/// image no longer depends on its backing files once the job finishes.
+	pub async fn block_stream(&self, drive_id: &str) -> Result<BlockJob<'_>, VMControlError> {
+		let qmp = self.running_qmp()?;
+		let job_id = format!("vm.{drive_id}.stream");
+
+		qmp.execute(qmp::qmp::block_stream {
+			device: format!("vm.{drive_id}.drive"),
+			job_id: Some(job_id.clone()),
+			auto_dismiss: Some(false),
+			auto_finalize: None,
+			backing_file: None,
+			base: None,
+			base_node: None,
+			bottom: None,
+			filter_node_name: None,
+			on_error: None,
+			speed: None
+		}).await?;
+
+		Ok(BlockJob::new(qmp, job_id, false))
+	}
+
+	/// The port to hotplug a PCI device into: `requested`, if it's a free reserved port, or else any free one.
+	/// Q35 can't hotplug devices into the root complex, so they go on a port reserved with
+	/// [VirtualMachine::reserve_hotplug_port]. Other machine types hotplug into the PCI bus itself.
+	fn hotplug_port(&self, requested: Option<&str>) -> Result<Option<String>, VMControlError> {
+		let in_use = |port: &str| {
+			self.hotplugged.values().any(|dev| dev.port.as_deref() == Some(port))
+				|| self.devices.iter().chain(self.drives.iter()).any(|dev| dev.requested_root_port() == Some(port))
+		};
+
+		if let Some(port) = requested {
+			if !matches!(self.machine, Some(MachineType::Q35 { .. })) || !self.hotplug_ports.iter().any(|id| id == port) {
+				return Err(VMControlError::NotAHotplugPort(String::from(port)));
+			}
+
+			if in_use(port) {
+				return Err(VMControlError::HotplugPortInUse(String::from(port)));
+			}
+
+			return Ok(Some(String::from(port)));
+		}
+
+		if !matches!(self.machine, Some(MachineType::Q35 { .. })) {
+			return Ok(None);
+		}
+
+		match self.hotplug_ports.iter().find(|port| !in_use(port)) {
+			Some(port) => Ok(Some(port.clone())),
+			None => Err(VMControlError::NoFreeHotplugPort)
+		}
+	}
+
+	/// Add a hotplugged device's objects and the device itself, once its backend exists.
+	/// If that fails, the backend is removed again.
+	async fn finish_hotplug(&mut self, id: String, options: &[String], write_through: bool, mut dev: HotpluggedDevice) -> Result<String, VMControlError> {
+		let qmp = self.running_qmp()?;
+
+		if let Err(err) = dev.add(qmp, options, write_through).await {
+			// Best effort; the original error is the interesting one
+			let _ = dev.remove_backend(qmp).await;
+			return Err(err);
+		}
+
+		self.hotplugged.insert(id.clone(), dev);
+		Ok(id)
+	}
+
+	/// Hotplug a hard drive into the running VM. Virtio drives need a free hotplug port on Q35, and SCSI drives
+	/// need a SCSI controller. IDE drives can't be hotplugged. Returns the drive's id (assigned if left empty).
+	pub async fn hotplug_drive(&mut self, drive: DiskDrive) -> Result<String, VMControlError> {
+		self.plug_drive(drive, None).await
+	}
+
+	/// Hotplug a device onto a specific port reserved with [VirtualMachine::reserve_hotplug_port], which has to
+	/// be empty. Otherwise this is the same as [VirtualMachine::hotplug_drive] or [VirtualMachine::hotplug_nic];
+	/// SCSI drives can't be put on a port, since they go on their controller. Returns the device's id.
+	pub async fn hotplug_into(&mut self, port_id: &str, device: HotplugDevice) -> Result<String, VMControlError> {
+		match device {
+			HotplugDevice::Drive(drive) => self.plug_drive(drive, Some(port_id)).await,
+			HotplugDevice::Nic(adapter, network) => self.plug_nic(adapter, network, Some(port_id)).await
+		}
+	}
+
+	async fn plug_drive(&mut self, mut drive: DiskDrive, port: Option<&str>) -> Result<String, VMControlError> {
+		self.running_qmp()?;
+		self.assign_device_id(&mut drive);
+		drive.validate(self).map_err(|error| OptionValidationError { option: drive.label(), error })?;
+
+		let (id, port) = match &drive {
+			DiskDrive::HdDrive { id, interface: DiskInterface::Virtio, .. } => (id.clone(), self.hotplug_port(port)?),
+			DiskDrive::HdDrive { id, interface: DiskInterface::Scsi, .. } if port.is_none() => (id.clone(), None),
+			_ => return Err(VMControlError::NotHotpluggable(drive.label()))
+		};
+
+		let options = match &port {
+			Some(port) => place_on_port(drive.as_options(), format!("vm.{port}").as_str()),
+			None => drive.as_options()
+		};
+
+		if let Some((overlay, backing)) = drive.overlay() {
+			if !std::path::Path::new(overlay).exists() {
+				Image::open(backing).create_overlay(overlay).await?;
+			}
+		}
+
+		let blockdev = drive.blockdev_options()
+			.ok_or_else(|| VMControlError::NotHotpluggable(drive.label()))?
+			.map_err(std::io::Error::from)?;
+
+		self.running_qmp()?
+			.execute(qmp::qmp::blockdev_add(blockdev))
+			.await?;
+
+		let dev = HotpluggedDevice {
+			port,
+			backend: HotplugBackend::BlockNode(format!("vm.{id}.drive")),
+			objects: Vec::new()
+		};
+
+		let id = self.finish_hotplug(id, &options, drive.write_through(), dev).await?;
+
+		// blockdev-add has no equivalent of -drive's throttling options
+		if let DiskDrive::HdDrive { throttle: Some(limits), .. } = &drive {
+			if let Err(err) = self.set_io_limits(&id, limits).await {
+				// The drive is already in the guest, so it has to be unplugged again
+				let _ = self.hot_unplug(&id).await;
+				return Err(err);
+			}
+		}
+
+		Ok(id)
+	}
+
+	/// Replace a hard drive's own I/O limits (see `throttle` on [DiskDrive::HdDrive]) while the VM is running,
+	/// e.g: to rein in a drive which is starving others. Limits left as None are removed. Throttle groups
+	/// aren't affected, and the change is forgotten when the VM stops.
+	pub async fn set_io_limits(&self, drive_id: &str, limits: &IoLimits) -> Result<(), VMControlError> {
+		limits.check().map_err(|error| OptionValidationError { option: String::from("IoLimits"), error })?;
+
+		self.running_qmp()?
+			.execute(limits.block_set_io_throttle(format!("vm.{drive_id}")))
+			.await?;
+		Ok(())
+	}
+
+	/// Hotplug a network adapter into the running VM, along with the network it's connected to (whatever
+	/// the adapter's `netdev` says). On Q35 this needs a free hotplug port. Returns the adapter's id
+	/// (assigned if left empty); unplugging the adapter removes the network too.
+	pub async fn hotplug_nic(&mut self, adapter: NetworkAdapter, network: Network) -> Result<String, VMControlError> {
+		self.plug_nic(adapter, network, None).await
+	}
+
+	async fn plug_nic(&mut self, mut adapter: NetworkAdapter, mut network: Network, port: Option<&str>) -> Result<String, VMControlError> {
+		self.running_qmp()?;
+
+		// Nothing would clean up a tap after the network is unplugged, and
+		// vhost-user needs a chardev, which this doesn't know how to add
+		if network.host_interface().is_some() || matches!(network, Network::VhostUser { .. }) {
+			return Err(VMControlError::NotHotpluggable(network.label()));
+		}
+
+		self.assign_device_id(&mut network);
+		self.assign_device_id(&mut adapter);
+
+		let network_id = String::from(network.id());
+
+		let id = match &mut adapter {
+			NetworkAdapter::Virtio { id, netdev, .. }
+			| NetworkAdapter::Rtl8139 { id, netdev, .. }
+			| NetworkAdapter::E1000 { id, netdev, .. }
+			| NetworkAdapter::E1000e { id, netdev, .. }
+			| NetworkAdapter::Vmxnet3 { id, netdev, .. } => {
+				netdev.clone_from(&network_id);
+				id.clone()
+			}
+		};
+
+		for dev in [&network as &dyn QemuOption, &adapter] {
+			dev.validate(self).map_err(|error| OptionValidationError { option: dev.label(), error })?;
+		}
+
+		if let Some(uuid) = &self.uuid {
+			adapter.derive_mac(uuid);
+		}
+
+		let port = self.hotplug_port(port)?;
+		let options = match &port {
+			Some(port) => place_on_port(adapter.as_options(), format!("vm.{port}").as_str()),
+			None => adapter.as_options()
+		};
+
+		let netdev = network.netdev().map_err(std::io::Error::from)?;
+
+		self.running_qmp()?
+			.execute(qmp::qmp::netdev_add(netdev))
+			.await?;
+
+		let dev = HotpluggedDevice {
+			port,
+			backend: HotplugBackend::Netdev(format!("vm.{network_id}")),
+			objects: Vec::new()
+		};
+
+		self.finish_hotplug(id, &options, false, dev).await
+	}
+
+	/// Unplug a device added with [VirtualMachine::hotplug_drive] or [VirtualMachine::hotplug_nic]. This waits for
+	/// the guest to let go of the device (which it has to cooperate with) before removing the device's backend.
+	pub async fn hot_unplug(&mut self, id: &str) -> Result<(), VMControlError> {
+		let qmp = self.running_qmp()?;
+		let dev = self.hotplugged.get(id).ok_or_else(|| VMControlError::NotHotplugged(String::from(id)))?;
+		let qemu_id = format!("vm.{id}");
+
+		// Subscribe first, so the event can't be missed
+		let mut events = qmp.events();
+		qmp.execute(qmp::qmp::device_del { id: qemu_id.clone() }).await?;
+
+		let deleted = async {
+			while let Some(event) = events.next().await {
+				if matches!(event, QmpEvent::DeviceDeleted { device: Some(device), .. } if device == qemu_id) {
+					return true;
+				}
+			}
+
+			false
+		};
+
+		match tokio::time::timeout(HOT_UNPLUG_TIMEOUT, deleted).await {
+			Ok(true) => {},
+			// QEMU went away
+			Ok(false) => return Err(VMControlError::NotRunning),
+			Err(..) => return Err(VMControlError::UnplugTimeout(String::from(id)))
+		}
+
+		dev.remove_backend(qmp).await?;
+		self.hotplugged.remove(id);
+		Ok(())
+	}
+
+	/// Forward a host port to the guest through a user network, while the VM is running.
+	/// Forwards added this way are forgotten when the VM stops.
+	pub async fn add_port_forward(&self, network_id: &str, forward: &PortForward) -> Result<(), VMControlError> {
+		self.monitor_command(format!("hostfwd_add vm.{network_id} {}", forward.rule())).await
+	}
+
+	/// Remove a port forward from a user network, by its host side (the guest side is ignored).
+	pub async fn remove_port_forward(&self, network_id: &str, forward: &PortForward) -> Result<(), VMControlError> {
+		self.monitor_command(format!("hostfwd_remove vm.{network_id} {}", forward.host_side())).await
+	}
+
+	/// Run a human monitor command, for the few things QMP has no command for. Those
+	/// report errors by printing them, so any output is treated as an error.
+	async fn monitor_command(&self, command_line: String) -> Result<(), VMControlError> {
+		let output = self.running_qmp()?
+			.execute(qmp::qmp::human_monitor_command { command_line, cpu_index: None })
+			.await?;
+
+		match output.trim() {
+			"" => Ok(()),
+			error => Err(VMControlError::MonitorCommandFailed(String::from(error)))
+		}
+	}
+
+	/// Insert an ISO into a CD drive, replacing whatever was in it. If the guest has locked the
+	/// drive's tray, it's asked to eject the current disc and this fails; try again once it has.
+	pub async fn change_media(&self, drive_id: &str, iso_path: &str) -> Result<(), VMControlError> {
+		#[allow(deprecated)]
+		self.running_qmp()?
+			.execute(qmp::qmp::blockdev_change_medium {
+				id: Some(format!("vm.{drive_id}")),
+				device: None,
+				filename: String::from(iso_path),
+				format: Some(String::from("raw")),
+				force: None,
+				read_only_mode: None
+			})
+			.await?;
+		Ok(())
+	}
+
+	/// Eject the disc from a CD drive. Like [VirtualMachine::change_media], this fails if the guest has locked the tray.
+	pub async fn eject(&self, drive_id: &str) -> Result<(), VMControlError> {
+		#[allow(deprecated)]
+		self.running_qmp()?
+			.execute(qmp::qmp::eject {
+				id: Some(format!("vm.{drive_id}")),
+				device: None,
+				force: None
+			})
+			.await?;
+		Ok(())
+	}
+
+	/// Wait for the QEMU process to exit. Returns immediately if it was never started.
+	pub async fn wait(&mut self) -> Result<Option<std::process::ExitStatus>, std::io::Error> {
+		let status = match &mut self.process {
+			Some(child) => child.wait().await?,
+			None => return Ok(None)
+		};
+
+		// QEMU went away by itself (e.g: the guest powered off)
+		self.stopped();
+		Ok(Some(status))
+	}
+}
+
+/// Dropping a VM which is still running kills QEMU, without waiting for it to exit. This is a hard kill,
+/// like pulling the plug on the guest; use [VirtualMachine::shutdown] first to stop it gracefully.
+impl Drop for VirtualMachine {
+	fn drop(&mut self) {
+		if self.process.is_none() {
+			return;
+		}
+
+		if let Some(watcher) = self.event_watcher.take() {
+			watcher.abort();
+		}
+
+		// Closes the QMP connection. QEMU doesn't get to remove its socket, so that goes too
+		self.qmp = None;
+		self.kill();
+		let _ = std::fs::remove_file(qmp::socket_path(&self.name));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::qemu::mock_qmp::MockQmp;
+	use serde_json::json;
+
+	/// A VM which needs nothing from the host: TCG, on a PC machine.
+	fn test_vm() -> VirtualMachine {
+		let mut vm = VirtualMachine::new("test").unwrap();
+		vm.set_accelerator(Accelerator::Tcg { multithreaded: None, tb_size_mb: None })
+			.set_machine_type(MachineType::Pc { acpi: true, usb: false });
+		vm
+	}
+
+	/// The values given to every `flag` on a full command line.
+	fn values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+		args.windows(2)
+			.filter(|pair| pair[0] == flag)
+			.map(|pair| pair[1].as_str())
+			.collect()
+	}
+
+	/// A path under the system temporary directory which is unique to this test run.
+	fn temp_path(name: &str) -> std::path::PathBuf {
+		std::env::temp_dir().join(format!("sunlight-test-{}-{name}", std::process::id()))
+	}
+
+	#[test]
+	fn kvm_needs_a_usable_device() {
+		let device = temp_path("kvm");
+		std::fs::write(&device, "").unwrap();
+		let usable = kvm_usable(device.to_str().unwrap());
+		std::fs::remove_file(&device).unwrap();
+
+		assert!(usable);
+		assert!(!kvm_usable(device.to_str().unwrap()));
+	}
+
+	#[test]
+	fn arguments_dont_depend_on_the_host_accelerator() {
+		let mut vm = test_vm();
+		vm.set_accelerator(Accelerator::Kvm);
+
+		// Whether or not this host has KVM
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-accel"), ["kvm"]);
+	}
+
+	#[test]
+	fn auto_lets_qemu_pick_the_accelerator() {
+		let mut vm = test_vm();
+		vm.set_accelerator(Accelerator::Auto);
+
+		let args = vm.to_arguments().unwrap();
+		let accels = values(&args, "-accel");
+		assert_eq!(accels.last(), Some(&"tcg"));
+
+		if cfg!(target_os = "linux") {
+			assert_eq!(accels, ["kvm", "tcg"]);
+		}
+	}
+
+	fn host_cpu() -> Cpu {
+		Cpu {
+			model: String::from("host"),
+			features: vec![String::from("-svm")],
+			core_count: 2,
+			dies: None,
+			sockets: None,
+			threads: None,
+			vcpus: None,
+			max_vcpus: None,
+			host_cache_info: false
+		}
+	}
+
+	#[test]
+	fn host_cpu_needs_hardware_acceleration() {
+		let mut vm = test_vm();
+		vm.add_device(host_cpu());
+
+		assert!(matches!(
+			vm.to_arguments(),
+			Err(VMQemuProcessStartError::ValidationFailed(errors)) if matches!(errors[0].error, ValidationError::InvalidField { field: "model", .. })
+		));
+	}
+
+	#[test]
+	fn auto_emulates_the_host_cpu_under_tcg() {
+		let mut vm = test_vm();
+		vm.set_accelerator(Accelerator::Auto)
+			.add_device(host_cpu());
+
+		let tcg = vm.labeled_options_with(&Accelerator::Tcg { multithreaded: None, tb_size_mb: None }).unwrap();
+		let args = tcg.into_iter().flat_map(|(_, options)| options).collect::<Vec<String>>();
+		assert_eq!(values(&args, "-cpu"), ["max,-svm"]);
+
+		let kvm = vm.labeled_options_with(&Accelerator::Kvm).unwrap();
+		let args = kvm.into_iter().flat_map(|(_, options)| options).collect::<Vec<String>>();
+		assert_eq!(values(&args, "-cpu"), ["host,-svm"]);
+
+		// Without knowing which accelerator QEMU will pick
+		assert_eq!(values(&vm.to_arguments().unwrap(), "-cpu"), ["max,-svm"]);
+	}
+
+	#[test]
+	fn flags_without_values_dont_throw_off_option_lookups() {
+		let options = ["-nodefaults", "-cpu", "host,-svm", "-device", "virtio-rng-pci"].map(String::from).to_vec();
+
+		assert_eq!(option_values(&options, "-device").collect::<Vec<&str>>(), ["virtio-rng-pci"]);
+		assert_eq!(emulate_host_cpu(options), ["-nodefaults", "-cpu", "max,-svm", "-device", "virtio-rng-pci"]);
+	}
+
+	fn hd_drive(id: &str, image_path: &str) -> DiskDrive {
+		DiskDrive::HdDrive {
+			id: String::from(id),
+			interface: DiskInterface::Virtio,
+			image_path: String::from(image_path),
+			readonly: false,
+			format: DiskFormat::Qcow2,
+			ssd: false,
+			cache: None,
+			aio: None,
+			throttle_group: None,
+			throttle: None,
+			share_rw: false,
+			logical_block_size: None,
+			physical_block_size: None,
+			discard: false,
+			detect_zeroes: None,
+			iothread: false,
+			num_queues: None,
+			backing: None,
+			encryption: None
+		}
+	}
+
+	#[test]
+	fn shared_writable_drives_warn() {
+		let mut vm = test_vm();
+		vm.add_drive(hd_drive("plain", "/tmp/plain.qcow2"));
+
+		let mut shared = hd_drive("shared", "/tmp/shared.qcow2");
+		if let DiskDrive::HdDrive { share_rw, .. } = &mut shared {
+			*share_rw = true;
+		}
+		vm.add_drive(shared);
+
+		let warnings = vm.validation_warnings();
+		assert_eq!(warnings.len(), 1);
+		assert_eq!(warnings[0].option, "DiskDrive::HdDrive");
+		assert_eq!(warnings[0].warning, ValidationWarning::SharedWritableImage);
+
+		// Still only a warning
+		assert!(values(&vm.to_arguments().unwrap(), "-device").contains(&"virtio-blk-pci,id=vm.shared,drive=vm.shared.drive,share-rw=on"));
+	}
+
+	#[test]
+	fn virtio_drives_get_their_own_tuning() {
+		let drive = |interface, iothread, num_queues, discard, detect_zeroes| {
+			let mut drive = hd_drive("data", "/tmp/data.qcow2");
+			if let DiskDrive::HdDrive { interface: i, iothread: t, num_queues: q, discard: d, detect_zeroes: z, .. } = &mut drive {
+				(*i, *t, *q, *d, *z) = (interface, iothread, num_queues, discard, detect_zeroes);
+			}
+			drive
+		};
+		let vm = test_vm();
+		let invalid = |drive: DiskDrive| match drive.validate(&vm) {
+			Err(ValidationError::InvalidField { field, .. }) => field,
+			other => panic!("unexpected result: {:?}", other.err())
+		};
+
+		let tuned = drive(DiskInterface::Virtio, true, Some(4), true, Some(DetectZeroes::Unmap));
+		assert!(tuned.validate(&vm).is_ok());
+		assert!(tuned.requires_pci());
+		assert_eq!(tuned.as_options(), [
+			"-object", "iothread,id=vm.data.iothread",
+			"-drive", "if=none,file=/tmp/data.qcow2,format=qcow2,id=vm.data.drive,readonly=off,discard=unmap,detect-zeroes=unmap",
+			"-device", "virtio-blk-pci,id=vm.data,drive=vm.data.drive,iothread=vm.data.iothread,num-queues=4"
+		]);
+
+		assert_eq!(invalid(drive(DiskInterface::Ide, true, None, false, None)), "iothread");
+		assert_eq!(invalid(drive(DiskInterface::Scsi, false, Some(2), false, None)), "num_queues");
+		assert_eq!(invalid(drive(DiskInterface::Virtio, false, Some(0), false, None)), "num_queues");
+		assert_eq!(invalid(drive(DiskInterface::Virtio, false, None, false, Some(DetectZeroes::Unmap))), "detect_zeroes");
+		assert!(drive(DiskInterface::Virtio, false, None, false, Some(DetectZeroes::On)).validate(&vm).is_ok());
+	}
+
+	#[test]
+	fn devices_are_found_by_type() {
+		let mut vm = test_vm();
+		let balloon = || Balloon { id: String::new(), free_page_reporting: false, deflate_on_oom: false, stats_interval_secs: None };
+		vm.add_device(balloon())
+			.add_drive(hd_drive("root", "/tmp/root.qcow2"));
+
+		assert_eq!(vm.devices_of::<Balloon>().map(|balloon| balloon.id.as_str()).collect::<Vec<_>>(), ["balloon0"]);
+		assert_eq!(vm.devices_of::<DiskDrive>().count(), 1);
+		assert_eq!(vm.devices_of::<Watchdog>().count(), 0);
+		assert!(vm.validation_errors().is_empty());
+
+		vm.add_device(balloon());
+		let errors = vm.validation_errors();
+		assert_eq!(errors.len(), 2);
+		assert!(errors.iter().all(|error| error.option == "Balloon"));
+	}
+
+	#[test]
+	fn boot_order_becomes_bootindex() {
+		let cd = DiskDrive::CdDrive { id: String::from("cd"), interface: DiskInterface::Ide, iso_path: None };
+		let nic = NetworkAdapter::Rtl8139 { id: String::from("nic"), netdev: String::from("lan"), mac: None, rombar: None, romfile: None };
+		let mut vm = test_vm();
+		vm.add_device(Network::User { id: String::from("lan"), host_forwards: Vec::new() })
+			.add_device(nic)
+			.add_drive(hd_drive("root", "/tmp/root.qcow2"))
+			.add_drive(cd)
+			.set_boot(BootConfig {
+				order: vec![BootDevice::Network(String::from("nic")), BootDevice::Drive(String::from("root"))],
+				menu: true,
+				menu_timeout_ms: Some(3000),
+				splash: None,
+				strict: true
+			});
+
+		// Listed devices get their place in the order, and the rest are left to the firmware
+		let args = vm.to_arguments().unwrap();
+		let devices = values(&args, "-device");
+		assert_eq!(values(&args, "-boot"), ["menu=on,splash-time=3000,strict=on"]);
+		assert!(devices.contains(&"rtl8139,id=vm.nic,netdev=vm.lan,bootindex=0"));
+		assert!(devices.iter().any(|device| device.contains("id=vm.root") && device.ends_with(",bootindex=1")));
+		assert!(devices.contains(&"ide-cd,drive=vm.cd.drive,id=vm.cd"));
+		assert_eq!(set_boot_index(qemu_arg("-object", "rng-builtin,id=vm.rng"), 0), ["-object", "rng-builtin,id=vm.rng"]);
+
+		assert!(BootConfig::default().as_options().is_empty());
+
+		let invalid = |boot: BootConfig| match boot.validate(&vm) {
+			Err(ValidationError::InvalidField { field, reason }) => (field, reason),
+			_ => panic!("the boot config should have failed validation")
+		};
+		let drive = |id: &str| BootDevice::Drive(String::from(id));
+		assert_eq!(invalid(BootConfig { order: vec![drive("nic")], ..Default::default() }).0, "order");
+		assert_eq!(invalid(BootConfig { order: vec![drive("cd"), drive("cd")], ..Default::default() }), ("order", "lists a device more than once"));
+		assert_eq!(invalid(BootConfig { strict: true, ..Default::default() }).0, "strict");
+		assert_eq!(invalid(BootConfig { menu_timeout_ms: Some(1000), ..Default::default() }).0, "menu_timeout_ms");
+		assert_eq!(invalid(BootConfig { splash: Some(String::from("/boot/splash.bmp")), ..Default::default() }).0, "splash");
+		assert!(matches!(
+			BootConfig { menu: true, splash: Some(temp_path("splash.bmp").to_string_lossy().into_owned()), ..Default::default() }.validate(&vm),
+			Err(ValidationError::PathNotFound { .. })
+		));
+	}
+
+	fn multiqueue_vm(network: Network, queues: u8) -> VirtualMachine {
+		let mut vm = test_vm();
+		vm.add_device(Cpu { model: String::from("qemu64"), ..host_cpu() })
+			.add_device(network)
+			.add_device(NetworkAdapter::Virtio {
+				id: String::from("nic"),
+				netdev: String::from("lan"),
+				mac: None,
+				rombar: None,
+				romfile: None,
+				failover: false,
+				host_mtu: None,
+				offloads: VirtioNetOffloads::default(),
+				queues: Some(queues)
+			});
+		vm
+	}
+
+	#[test]
+	fn multiqueue_needs_a_multiqueue_netdev() {
+		let user = Network::User { id: String::from("lan"), host_forwards: Vec::new() };
+		let errors = multiqueue_vm(user, 2).validation_errors();
+		assert_eq!(errors.len(), 1);
+		assert!(matches!(errors[0].error, ValidationError::InvalidField { field: "queues", reason: "the netdev doesn't support multiqueue" }));
+
+		let tap = |queues| Network::Tap { id: String::from("lan"), dev: String::from("tap0"), managed: None, queues };
+		let errors = multiqueue_vm(tap(None), 2).validation_errors();
+		assert!(matches!(errors[0].error, ValidationError::InvalidField { field: "queues", reason: "the netdev has fewer queues than the adapter" }));
+
+		let args = multiqueue_vm(tap(Some(2)), 2).to_arguments().unwrap();
+		assert_eq!(values(&args, "-netdev"), ["tap,vhost=on,script=no,downscript=no,ifname=tap0,id=vm.lan,queues=2"]);
+		assert_eq!(values(&args, "-device").last(), Some(&"virtio-net-pci,id=vm.nic,netdev=vm.lan,mq=on,vectors=6"));
+	}
+
+	#[test]
+	fn emulated_nics_only_differ_by_model() {
+		let mac = Some("52:54:00:12:34:56".parse::<MacAddr>().unwrap());
+		let e1000 = NetworkAdapter::E1000 { id: String::from("nic"), netdev: String::from("lan"), mac, rombar: Some(false), romfile: None };
+		let e1000e = NetworkAdapter::E1000e { id: String::from("nic"), netdev: String::from("lan"), mac: None, rombar: None, romfile: None };
+		let vmxnet3 = NetworkAdapter::Vmxnet3 { id: String::new(), netdev: String::new(), mac, rombar: None, romfile: None };
+
+		assert_eq!(e1000.as_options(), ["-device", "e1000,id=vm.nic,netdev=vm.lan,mac=52:54:00:12:34:56,rombar=0"]);
+		assert_eq!(e1000e.as_options(), ["-device", "e1000e,id=vm.nic,netdev=vm.lan"]);
+
+		// The 82574L is PCIe only
+		let mut vm = test_vm();
+		assert!(e1000.validate(&vm).is_ok());
+		assert!(matches!(e1000e.validate(&vm), Err(ValidationError::UnsupportedMachineType { requires: "the Q35 machine type" })));
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false });
+		assert!(e1000e.validate(&vm).is_ok());
+
+		vm.add_device(Network::User { id: String::new(), host_forwards: Vec::new() })
+			.add_device(vmxnet3);
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-netdev"), ["user,id=vm.netdev0"]);
+		assert!(values(&args, "-device").contains(&"vmxnet3,id=vm.net0,netdev=vm.netdev0,mac=52:54:00:12:34:56"));
+	}
+
+	#[tokio::test]
+	async fn vhost_user_networks_need_shared_memory() {
+		let socket = temp_path("vhost-user.sock");
+		let vhost_user = |socket: &std::path::Path, queues| Network::VhostUser { id: String::from("lan"), socket_path: socket.to_str().unwrap().to_owned(), queues };
+		let memory = |share| Memory { size: ByteSize::gib(1), prealloc: false, backend: Some(MemoryBackend::Memfd { share, hugepage_size: None }), max_size: None, slots: None };
+
+		assert_eq!(vhost_user(std::path::Path::new("/run/a,b.sock"), Some(2)).as_options(), [
+			"-chardev", "socket,id=vm.lan.chardev,path=/run/a,,b.sock",
+			"-netdev", "vhost-user,id=vm.lan,chardev=vm.lan.chardev,queues=2"
+		]);
+
+		let mut vm = test_vm();
+		vm.add_device(memory(false));
+		assert!(matches!(vhost_user(&socket, None).validate(&vm), Err(ValidationError::PathNotFound { .. })));
+
+		std::fs::write(&socket, "").unwrap();
+		assert!(matches!(vhost_user(&socket, None).validate(&vm), Err(ValidationError::MemoryNotShared)));
+		assert!(matches!(vhost_user(&socket, Some(0)).validate(&vm), Err(ValidationError::InvalidField { field: "queues", .. })));
+
+		let mut vm = test_vm();
+		vm.add_device(memory(true));
+		let valid = vhost_user(&socket, Some(2)).validate(&vm);
+		std::fs::remove_file(&socket).unwrap();
+		assert!(valid.is_ok());
+
+		// There's no chardev for it to connect through
+		let server = MockQmp::start("vhost-user-hotplug", |_, _| Ok(json!({})));
+		let mut vm = running_test_vm(&server).await;
+		let adapter = NetworkAdapter::Virtio {
+			id: String::new(),
+			netdev: String::new(),
+			mac: None,
+			rombar: None,
+			romfile: None,
+			failover: false,
+			host_mtu: None,
+			offloads: VirtioNetOffloads::default(),
+			queues: None
+		};
+		assert!(matches!(vm.hotplug_nic(adapter, vhost_user(&socket, None)).await, Err(VMControlError::NotHotpluggable(label)) if label == "Network::VhostUser"));
+		assert!(server.commands().is_empty());
+	}
+
+	#[test]
+	fn managed_taps_are_recorded_for_cleanup() {
+		let tap = |dev: &str, managed| Network::Tap { id: String::from("lan"), dev: String::from(dev), managed, queues: None };
+		let vm = test_vm();
+
+		assert_eq!(tap("tap,0", None).as_options(), ["-netdev", "tap,vhost=on,script=no,downscript=no,ifname=tap,,0,id=vm.lan"]);
+		assert_eq!(tap("tap0", None).host_interface(), None);
+		assert_eq!(tap("tap0", Some(ManagedTap::default())).host_interface(), Some("tap0"));
+
+		assert!(tap("tap0", Some(ManagedTap::default())).validate(&vm).is_ok());
+		assert!(matches!(tap("", Some(ManagedTap::default())).validate(&vm), Err(ValidationError::InvalidField { field: "dev", .. })));
+		assert!(matches!(tap("sunlight-tap-0000", Some(ManagedTap::default())).validate(&vm), Err(ValidationError::InvalidField { field: "dev", .. })));
+		let bridged = ManagedTap { bridge: Some(String::from("sunlight-nobr0")) };
+		assert!(matches!(tap("tap0", Some(bridged)).validate(&vm), Err(ValidationError::NoSuchBridge { .. })));
+
+		// Stale entries are cleaned up (there's no such interface here), then replaced by this run's
+		let state_dir = temp_path("host-interfaces");
+		let record = state_dir.join(HOST_INTERFACES_FILE);
+		std::fs::create_dir_all(&state_dir).unwrap();
+		std::fs::write(&record, "sunlight-gone0\n").unwrap();
+
+		let mut vm = test_vm();
+		vm.set_state_dir(state_dir.to_str().unwrap())
+			.add_device(tap("sltap0", Some(ManagedTap::default())))
+			.add_device(Network::User { id: String::from("user"), host_forwards: Vec::new() });
+		vm.record_host_interfaces().unwrap();
+		assert_eq!(std::fs::read_to_string(&record).unwrap(), "sltap0\n");
+
+		let mut vm = test_vm();
+		vm.set_state_dir(state_dir.to_str().unwrap())
+			.add_device(tap("tap0", None));
+		vm.record_host_interfaces().unwrap();
+		assert!(!record.exists());
+		std::fs::remove_dir(&state_dir).unwrap();
+	}
+
+	#[test]
+	fn more_queues_than_vcpus_warns() {
+		let tap = Network::Tap { id: String::from("lan"), dev: String::from("tap0"), managed: None, queues: Some(4) };
+		let vm = multiqueue_vm(tap, 4);
+
+		let warnings = vm.validation_warnings();
+		assert_eq!(warnings.len(), 1);
+		assert_eq!(warnings[0].option, "NetworkAdapter::Virtio");
+		assert_eq!(warnings[0].warning, ValidationWarning::MoreQueuesThanVcpus { queues: 4, vcpus: 2 });
+		assert!(vm.validation_errors().is_empty());
+	}
+
+	#[test]
+	fn log_directory_must_be_writable() {
+		let log = |file: &std::path::Path| QemuLog { categories: vec![LogCategory::GuestErrors], file: file.to_string_lossy().into_owned() };
+		let vm = test_vm();
+
+		assert!(log(&std::env::temp_dir().join("qemu.log")).validate(&vm).is_ok());
+		assert!(matches!(
+			log(&temp_path("missing").join("qemu.log")).validate(&vm),
+			Err(ValidationError::DirectoryNotWritable { .. })
+		));
+
+		// Not a directory at all
+		let file = temp_path("not-a-dir");
+		std::fs::write(&file, "").unwrap();
+		let result = log(&file.join("qemu.log")).validate(&vm);
+		std::fs::remove_file(&file).unwrap();
+		assert!(matches!(result, Err(ValidationError::DirectoryNotWritable { .. })));
+	}
+
+	#[test]
+	fn launch_script_directory_must_be_writable() {
+		let path = temp_path("missing").join("launch.sh");
+
+		assert!(matches!(
+			test_vm().write_launch_script(path.to_str().unwrap()),
+			Err(VMQemuProcessStartError::ValidationFailed(errors)) if matches!(errors[0].error, ValidationError::DirectoryNotWritable { .. })
+		));
+		assert!(!path.exists());
+	}
+
+	#[tokio::test]
+	async fn encrypted_drives_unlock_with_a_secret() {
+		let secret = |source| Secret { id: String::from("key"), source };
+		assert_eq!(secret(SecretSource::File { path: String::from("/run/keys/a,b") }).as_options(), ["-object", "secret,id=vm.key.secret,file=/run/keys/a,,b"]);
+		assert_eq!(secret(SecretSource::Inline { data: String::from("pass,word") }).as_options(), ["-object", "secret,id=vm.key.secret,data=pass,,word"]);
+		assert_eq!(secret(SecretSource::Keyring { serial: 123456 }).as_options(), ["-object", "secret_keyring,id=vm.key.secret,serial=123456"]);
+
+		let vm = test_vm();
+		assert!(secret(SecretSource::Keyring { serial: 123456 }).validate(&vm).is_ok());
+		assert!(matches!(Secret { id: String::new(), ..secret(SecretSource::Keyring { serial: 1 }) }.validate(&vm), Err(ValidationError::EmptyField { field: "id" })));
+		assert!(matches!(secret(SecretSource::Inline { data: String::new() }).validate(&vm), Err(ValidationError::EmptyField { field: "data" })));
+		let missing = temp_path("luks.key").to_string_lossy().into_owned();
+		assert!(matches!(secret(SecretSource::File { path: missing }).validate(&vm), Err(ValidationError::PathNotFound { .. })));
+
+		let encrypted = |format| {
+			let mut drive = hd_drive("secret", "/tmp/secret.qcow2");
+			if let DiskDrive::HdDrive { encryption, format: drive_format, .. } = &mut drive {
+				*encryption = Some(LuksConfig { secret: String::from("key") });
+				*drive_format = format;
+			}
+			drive
+		};
+
+		let mut vm = test_vm();
+		vm.add_drive(encrypted(DiskFormat::Qcow2));
+		assert!(matches!(&vm.validation_errors()[0].error, ValidationError::UnknownSecret { secret } if secret == "key"));
+		assert!(matches!(encrypted(DiskFormat::Raw).validate(&vm), Err(ValidationError::InvalidField { field: "format", .. })));
+
+		// The secret has to be defined before the drive refers to it
+		vm.add_secret(secret(SecretSource::Keyring { serial: 123456 }));
+		let args = vm.to_arguments().unwrap();
+		let secret_at = args.iter().position(|arg| arg == "secret_keyring,id=vm.key.secret,serial=123456").unwrap();
+		let drive_at = args.iter().position(|arg| arg.contains("encrypt.format=luks,encrypt.key-secret=vm.key.secret")).unwrap();
+		assert!(secret_at < drive_at);
+
+		let server = MockQmp::start("luks-hotplug", |_, _| Ok(json!({})));
+		let mut vm = running_test_vm(&server).await;
+		vm.add_secret(secret(SecretSource::Keyring { serial: 123456 }));
+		vm.hotplug_drive(encrypted(DiskFormat::Qcow2)).await.unwrap();
+		let blockdev = &server.received()[0];
+		assert_eq!(blockdev.execute, "blockdev-add");
+		assert_eq!(blockdev.arguments["encrypt"], json!({ "format": "luks", "key-secret": "vm.key.secret" }));
+	}
+
+	#[tokio::test]
+	async fn encrypted_drives_cant_be_linked() {
+		let mut template = test_vm();
+		let mut encrypted = hd_drive("secret", "/tmp/secret.qcow2");
+		if let DiskDrive::HdDrive { encryption, .. } = &mut encrypted {
+			*encryption = Some(LuksConfig { secret: String::from("key") });
+		}
+		template.add_drive(hd_drive("plain", "/tmp/plain.qcow2"))
+			.add_drive(encrypted);
+
+		let name = format!("sunlight-test-{}-clone", std::process::id());
+		let result = VirtualMachine::clone_from(&template, &name, "8c4f2b8e-6d0a-4a8e-9b7e-0f6f3c1d2e4a").await;
+		assert!(matches!(result, Err(VMCreateError::EncryptedDrive(id)) if id == "secret"));
+
+		// Nothing was created for the plain drive either
+		assert!(!std::path::Path::new(&default_state_dir(&name)).exists());
+	}
+
+	#[tokio::test]
+	async fn devices_are_hotplugged_into_reserved_ports() {
+		let server = MockQmp::start("hotplug-into", |_, _| Ok(json!({})));
+		let image = temp_path("hotplug.qcow2");
+		std::fs::write(&image, "").unwrap();
+		let image = image.to_str().unwrap();
+
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.reserve_hotplug_port("hp0")
+			.reserve_hotplug_port("hp1");
+		vm.qmp = Some(Arc::new(QmpClient::connect(server.path()).await.unwrap()));
+		vm.state.send_replace(VMState::Started);
+
+		let plugged = vm.hotplug_into("hp1", HotplugDevice::Drive(hd_drive("data", image))).await;
+		let taken = vm.hotplug_into("hp1", HotplugDevice::Drive(hd_drive("more", image))).await;
+		let unknown = vm.hotplug_into("hp2", HotplugDevice::Drive(hd_drive("more", image))).await;
+		std::fs::remove_file(image).unwrap();
+
+		assert_eq!(plugged.unwrap(), "data");
+		let added = server.received().into_iter().find(|command| command.execute == "device_add").unwrap();
+		assert_eq!(added.arguments["id"], "vm.data");
+		assert_eq!(added.arguments["bus"], "vm.hp1");
+
+		assert!(matches!(taken, Err(VMControlError::HotplugPortInUse(port)) if port == "hp1"));
+		assert!(matches!(unknown, Err(VMControlError::NotAHotplugPort(port)) if port == "hp2"));
+		assert_eq!(server.commands().iter().filter(|command| *command == "device_add").count(), 1);
+	}
+
+	/// A [test_vm] which is running, as far as it knows, and talking to `server`.
+	async fn running_test_vm(server: &MockQmp) -> VirtualMachine {
+		let mut vm = test_vm();
+		vm.qmp = Some(Arc::new(QmpClient::connect(server.path()).await.unwrap()));
+		vm.state.send_replace(VMState::Started);
+		vm
+	}
+
+	#[tokio::test]
+	async fn cd_media_is_changed_through_qmp() {
+		let cd = |iso_path: Option<&str>| DiskDrive::CdDrive { id: String::from("cd"), interface: DiskInterface::Ide, iso_path: iso_path.map(String::from) };
+		let vm = test_vm();
+
+		assert_eq!(cd(None).as_options(), [
+			"-drive", "if=none,media=cdrom,aio=io_uring,id=vm.cd.drive",
+			"-device", "ide-cd,drive=vm.cd.drive,id=vm.cd"
+		]);
+		assert_eq!(values(&cd(Some("/isos/a,b.iso")).as_options(), "-drive"), ["if=none,media=cdrom,aio=io_uring,id=vm.cd.drive,file=/isos/a,,b.iso,format=raw"]);
+		assert!(matches!(cd(Some("/nonexistent/sunlight.iso")).validate(&vm), Err(ValidationError::PathNotFound { .. })));
+		let virtio = DiskDrive::CdDrive { id: String::from("cd"), interface: DiskInterface::Virtio, iso_path: None };
+		assert!(matches!(virtio.validate(&vm), Err(ValidationError::InvalidField { field: "interface", .. })));
+
+		assert!(matches!(vm.eject("cd").await, Err(VMControlError::InvalidState(VMState::Stopped))));
+
+		let server = MockQmp::start("change-media", |_, _| Ok(json!({})));
+		let vm = running_test_vm(&server).await;
+		vm.change_media("cd", "/isos/installer.iso").await.unwrap();
+		vm.eject("cd").await.unwrap();
+
+		let received = server.received();
+		assert_eq!(server.commands(), ["blockdev-change-medium", "eject"]);
+		assert_eq!(received[0].arguments["id"], "vm.cd");
+		assert_eq!(received[0].arguments["filename"], "/isos/installer.iso");
+		assert_eq!(received[0].arguments["format"], "raw");
+		assert_eq!(received[1].arguments["id"], "vm.cd");
+	}
+
+	#[tokio::test]
+	async fn ports_are_forwarded_into_user_networks() {
+		let ssh = PortForward { proto: ForwardProtocol::Tcp, host_addr: None, host_port: 2222, guest_addr: None, guest_port: 22 };
+		let dns = PortForward { proto: ForwardProtocol::Udp, host_addr: Some(String::from("127.0.0.1")), host_port: 5353, guest_addr: Some(String::from("10.0.2.15")), guest_port: 53 };
+
+		assert_eq!(ssh.rule(), "tcp::2222-:22");
+		assert_eq!(dns.rule(), "udp:127.0.0.1:5353-10.0.2.15:53");
+		assert_eq!(
+			Network::User { id: String::from("lan"), host_forwards: vec![ssh.clone(), dns.clone()] }.as_options(),
+			["-netdev", "user,id=vm.lan,hostfwd=tcp::2222-:22,hostfwd=udp:127.0.0.1:5353-10.0.2.15:53"]
+		);
+
+		// The monitor prints errors rather than failing the command
+		let server = MockQmp::start("port-forwards", |_, arguments| match arguments["command-line"].as_str() {
+			Some(line) if line.contains(":8080") => Ok(json!("Could not set up host forwarding rule 'tcp::8080-:80'\r\n")),
+			_ => Ok(json!(""))
+		});
+		let vm = running_test_vm(&server).await;
+
+		vm.add_port_forward("lan", &ssh).await.unwrap();
+		vm.remove_port_forward("lan", &dns).await.unwrap();
+		let web = PortForward { host_port: 8080, guest_port: 80, ..ssh };
+		assert!(matches!(vm.add_port_forward("lan", &web).await, Err(VMControlError::MonitorCommandFailed(error)) if error.starts_with("Could not set up")));
+
+		let lines = server.received().into_iter().map(|command| command.arguments["command-line"].as_str().unwrap().to_owned()).collect::<Vec<String>>();
+		assert_eq!(lines, [
+			"hostfwd_add vm.lan tcp::2222-:22",
+			"hostfwd_remove vm.lan udp:127.0.0.1:5353",
+			"hostfwd_add vm.lan tcp::8080-:80"
+		]);
+	}
+
+	#[tokio::test]
+	async fn hotplugged_drives_get_their_block_node_first() {
+		let server = MockQmp::start("hotplug-order", |_, _| Ok(json!({})));
+		let image = temp_path("hotplug-order.qcow2");
+		std::fs::write(&image, "").unwrap();
+
+		let mut vm = running_test_vm(&server).await;
+		let plugged = vm.hotplug_drive(hd_drive("data", image.to_str().unwrap())).await;
+		std::fs::remove_file(&image).unwrap();
+
+		assert_eq!(plugged.unwrap(), "data");
+		assert_eq!(server.commands(), ["blockdev-add", "device_add"]);
+
+		let received = server.received();
+		assert_eq!(received[0].arguments["node-name"], "vm.data.drive");
+		assert_eq!(received[1].arguments["drive"], "vm.data.drive");
+		assert!(vm.hotplugged.contains_key("data"));
+	}
+
+	#[tokio::test]
+	async fn failed_hotplugs_remove_the_block_node_again() {
+		let server = MockQmp::start("hotplug-rollback", |command, _| match command {
+			"device_add" => Err(String::from("Bus 'pci.0' does not support hotplugging")),
+			_ => Ok(json!({}))
+		});
+		let image = temp_path("hotplug-rollback.qcow2");
+		std::fs::write(&image, "").unwrap();
+
+		let mut vm = running_test_vm(&server).await;
+		let plugged = vm.hotplug_drive(hd_drive("data", image.to_str().unwrap())).await;
+		std::fs::remove_file(&image).unwrap();
+
+		// QEMU's error is the one reported, not anything from cleaning up
+		assert!(matches!(plugged, Err(VMControlError::QmpCommandFailure(VMQmpCommandError::QemuError(desc))) if desc.contains("hotplugging")));
+		assert_eq!(server.commands(), ["blockdev-add", "device_add", "blockdev-del"]);
+		assert_eq!(server.received()[2].arguments["node-name"], "vm.data.drive");
+		assert!(vm.hotplugged.is_empty());
+	}
+
+	#[tokio::test]
+	async fn unplugged_devices_are_forgotten_once_qemu_lets_go() {
+		let server = MockQmp::start("hot-unplug", |_, _| Ok(json!({})));
+		let image = temp_path("hot-unplug.qcow2");
+		std::fs::write(&image, "").unwrap();
+
+		let mut vm = running_test_vm(&server).await;
+		let plugged = vm.hotplug_drive(hd_drive("data", image.to_str().unwrap())).await;
+		std::fs::remove_file(&image).unwrap();
+		plugged.unwrap();
+
+		// The guest lets go of the device some time after device_del
+		let guest = async {
+			while !server.commands().iter().any(|command| command == "device_del") {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+			server.event("DEVICE_DELETED", json!({ "device": "vm.other", "path": "/machine/peripheral/vm.other" }));
+			server.event("DEVICE_DELETED", json!({ "device": "vm.data", "path": "/machine/peripheral/vm.data" }));
+		};
+		let (unplugged, _) = tokio::join!(vm.hot_unplug("data"), guest);
+
+		unplugged.unwrap();
+		assert_eq!(server.commands(), ["blockdev-add", "device_add", "device_del", "blockdev-del"]);
+		assert!(!vm.hotplugged.contains_key("data"));
+		assert!(matches!(vm.hot_unplug("data").await, Err(VMControlError::NotHotplugged(id)) if id == "data"));
+	}
+
+	#[tokio::test]
+	async fn usb_passthrough_follows_deletions() {
+		let server = MockQmp::start("usb-devices", |_, arguments| Ok(json!(arguments["path"] == "/machine/peripheral/vm.key")));
+
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Pc { acpi: true, usb: true })
+			.add_device(UsbDevice::HostById { id: String::from("key"), vendor_id: 0x1050, product_id: 0x0407 })
+			.add_device(UsbDevice::HostByPort { id: String::from("reader"), bus: 1, port: String::from("2") })
+			.add_device(UsbDevice::Storage { id: String::from("stick"), image_path: String::from("/tmp/stick.img"), format: DiskFormat::Raw, readonly: true });
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+
+		let passthrough = |id: &str, attached| UsbPassthrough { id: String::from(id), attached };
+		assert_eq!(vm.usb_devices().await.unwrap(), [passthrough("key", true), passthrough("reader", false)]);
+
+		server.event("DEVICE_DELETED", json!({ "device": "vm.reader", "path": "/machine/peripheral/vm.reader" }));
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while vm.usb_passthrough.lock().unwrap().len() != 1 {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		}).await.unwrap();
+
+		assert_eq!(vm.usb_devices().await.unwrap(), [passthrough("key", true)]);
+	}
+
+	#[test]
+	fn pci_devices_include_bridged_ones() {
+		let range = json!({ "base": 0, "limit": 0 });
+		let device = |slot: u8, class: u16, vendor: u16, device: u16, qdev_id: &str| json!({
+			"bus": 0, "slot": slot, "function": 0, "irq_pin": 0, "qdev_id": qdev_id, "regions": [],
+			"class_info": { "class": class }, "id": { "vendor": vendor, "device": device }
+		});
+
+		let mut nic = device(0, 0x0200, 0x1af4, 0x1041, "vm.net0");
+		nic["bus"] = json!(1);
+		let mut port = device(2, 0x0604, 0x1b36, 0x000c, "vm.port0");
+		port["pci_bridge"] = json!({
+			"bus": { "number": 1, "secondary": 1, "subordinate": 1, "io_range": range, "memory_range": range, "prefetchable_range": range },
+			"devices": [nic]
+		});
+		let mut host_bridge = device(0, 0x0600, 0x8086, 0x29c0, "");
+		host_bridge["class_info"]["desc"] = json!("Host bridge");
+
+		let response = json!([{ "bus": 0, "devices": [host_bridge, port] }]);
+		let devices = PciDevice::from_qmp(serde_json::from_value(response).unwrap());
+
+		assert_eq!(devices.len(), 3);
+		assert_eq!(devices[0].id, None);
+		assert_eq!(devices[0].class_description.as_deref(), Some("Host bridge"));
+		assert_eq!(devices[1].id.as_deref(), Some("port0"));
+		assert_eq!(devices[2], PciDevice {
+			bus: 1,
+			slot: 0,
+			function: 0,
+			class: 0x0200,
+			class_description: None,
+			vendor_id: 0x1af4,
+			device_id: 0x1041,
+			id: Some(String::from("net0"))
+		});
+	}
+
+	#[tokio::test]
+	async fn vsock_connections_are_relayed() {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		// Stands in for the guest's end, echoing back whatever it's sent
+		let guest_path = temp_path("vsock-guest.sock");
+		let guest = tokio::net::UnixListener::bind(&guest_path).unwrap();
+		tokio::spawn(async move {
+			let (mut stream, _) = guest.accept().await.unwrap();
+			let (mut read, mut write) = stream.split();
+			let _ = tokio::io::copy(&mut read, &mut write).await;
+		});
+
+		let host_path = temp_path("vsock-host.sock");
+		let listener = tokio::net::UnixListener::bind(&host_path).unwrap();
+		let target = guest_path.clone();
+		let relay = tokio::spawn(relay_connections(listener, move || tokio::net::UnixStream::connect(target.clone())));
+
+		let mut client = tokio::net::UnixStream::connect(&host_path).await.unwrap();
+		client.write_all(b"hello guest").await.unwrap();
+		let mut echoed = [0u8; 11];
+		client.read_exact(&mut echoed).await.unwrap();
+
+		relay.abort();
+		std::fs::remove_file(&guest_path).unwrap();
+		std::fs::remove_file(&host_path).unwrap();
+		assert_eq!(&echoed, b"hello guest");
+	}
+
+	#[tokio::test]
+	async fn vsock_streams_read_and_write() {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		// Any stream socket will do to stand in for a vsock one
+		let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+		ours.set_nonblocking(true).unwrap();
+		theirs.set_nonblocking(true).unwrap();
+		let mut stream = VsockStream { fd: AsyncFd::new(OwnedFd::from(ours)).unwrap() };
+		let mut theirs = tokio::net::UnixStream::from_std(theirs).unwrap();
+
+		stream.write_all(b"ping").await.unwrap();
+		let mut received = [0u8; 4];
+		theirs.read_exact(&mut received).await.unwrap();
+		assert_eq!(&received, b"ping");
+
+		theirs.write_all(b"pong").await.unwrap();
+		stream.read_exact(&mut received).await.unwrap();
+		assert_eq!(&received, b"pong");
+
+		// Shutting down our end is seen as the end of the stream
+		stream.shutdown().await.unwrap();
+		let mut rest = Vec::new();
+		theirs.read_to_end(&mut rest).await.unwrap();
+		assert!(rest.is_empty());
+	}
+
+	#[tokio::test]
+	async fn forwarding_vsock_needs_a_vsock_device() {
+		let server = MockQmp::start("vsock", |_, _| Ok(json!({})));
+		let mut vm = test_vm();
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+
+		let path = temp_path("vsock-missing.sock");
+		assert!(matches!(vm.forward_vsock(path.to_str().unwrap(), 22).await, Err(VMControlError::NoVsock)));
+		assert!(!path.exists());
+
+		let vsock = Vsock { id: String::from("vsock0"), guest_cid: 2 };
+		assert!(matches!(vsock.validate(&vm), Err(ValidationError::InvalidField { field: "guest_cid", .. })));
+		assert_eq!(values(&Vsock { guest_cid: 3, ..vsock }.as_options(), "-device"), ["vhost-vsock-pci,guest-cid=3,id=vm.vsock0"]);
+	}
+
+	/// A stand-in QEMU binary named `name`. It answers the probes for what it supports, and otherwise
+	/// writes its arguments (one per line) to the second path returned, to say it's started, and idles like a VM.
+	fn fake_qemu(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+		use std::os::unix::fs::PermissionsExt;
+
+		let binary = temp_path(name);
+		let started = temp_path(&format!("{name}-started"));
+		let script = format!("#!/bin/sh\ncase \"$*\" in\n\
+			--version) echo 'QEMU emulator version 8.2.0' ;;\n\
+			'-device help') ;;\n\
+			'-machine help') printf 'Supported machines are:\\npc Standard PC\\n' ;;\n\
+			*) printf '%s\\n' \"$@\" > '{started}.tmp'; mv '{started}.tmp' '{started}'; exec sleep 30 ;;\n\
+			esac\n", started = started.display());
+		std::fs::write(&binary, script).unwrap();
+		std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+		(binary, started)
+	}
+
+	/// A VM named `name` which runs `binary` as QEMU.
+	fn fake_qemu_vm(name: &str, binary: &std::path::Path) -> VirtualMachine {
+		let mut vm = VirtualMachine::new(name).unwrap();
+		vm.set_accelerator(Accelerator::Tcg { multithreaded: None, tb_size_mb: None })
+			.set_machine_type(MachineType::Pc { acpi: true, usb: false })
+			.set_qemu_binary(binary.to_str().unwrap())
+			.set_shutdown_timeout(Duration::from_millis(200));
+		vm
+	}
+
+	#[tokio::test]
+	async fn restart_starts_a_fresh_qemu() {
+		let (binary, started) = fake_qemu("fake-qemu");
+		let name = format!("restart-{}", std::process::id());
+		let mut vm = fake_qemu_vm(&name, &binary);
+
+		// Stands in for each QEMU's QMP socket, once it's started
+		let qmp_path = qmp::socket_path(&name);
+		let servers = tokio::spawn(async move {
+			let mut servers = Vec::new();
+			while servers.len() < 2 {
+				if std::fs::remove_file(&started).is_ok() {
+					servers.push(MockQmp::start_at(&qmp_path, |_, _| Ok(json!({}))));
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+			servers
+		});
+
+		vm.start().await.unwrap();
+		let first = vm.process.as_ref().and_then(Child::id);
+		vm.restart().await.unwrap();
+
+		let servers = servers.await.unwrap();
+		assert_eq!(vm.state(), VMState::Started);
+		assert_ne!(vm.process.as_ref().and_then(Child::id), first);
+		assert_eq!(servers[0].commands(), ["system_powerdown"]);
+		assert!(servers[1].commands().is_empty());
+
+		vm.force_stop().await.unwrap();
+		std::fs::remove_file(&binary).unwrap();
+	}
+
+	#[test]
+	fn watchdogs_set_the_watchdog_action() {
+		let watchdog = |model, action| Watchdog { model, action };
+
+		assert_eq!(watchdog(WatchdogModel::I6300esb, WatchdogAction::Reset).as_options(), [
+			"-device", "i6300esb,id=vm.watchdog",
+			"-action", "watchdog=reset"
+		]);
+		assert_eq!(watchdog(WatchdogModel::Itco, WatchdogAction::Pause).as_options(), [
+			"-global", "ICH9-LPC.noreboot=off",
+			"-action", "watchdog=pause"
+		]);
+
+		// The TCO watchdog is part of Q35's ICH9
+		let mut vm = test_vm();
+		assert!(matches!(watchdog(WatchdogModel::Itco, WatchdogAction::Reset).validate(&vm), Err(ValidationError::UnsupportedMachineType { .. })));
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false });
+		assert!(watchdog(WatchdogModel::Itco, WatchdogAction::Reset).validate(&vm).is_ok());
+
+		vm.add_device(watchdog(WatchdogModel::I6300esb, WatchdogAction::Poweroff))
+			.add_device(watchdog(WatchdogModel::Itco, WatchdogAction::Debug));
+		assert!(vm.validation_errors().iter().any(|error| matches!(error.error, ValidationError::InvalidField { field: "model", .. })));
+	}
+
+	#[test]
+	fn panics_outlast_the_shutdown_after_them() {
+		use qmp::qmp::{GuestPanicAction, ShutdownCause, WatchdogAction};
+
+		let shutdown = |reason| QmpEvent::Shutdown { guest: false, reason };
+		let cause = |events: &[QmpEvent]| {
+			let mut cause = None;
+			for event in events {
+				record_exit_cause(&mut cause, event);
+			}
+			cause
+		};
+
+		assert_eq!(cause(&[shutdown(ShutdownCause::guest_shutdown)]), Some(ExitReason::GuestPoweroff));
+		assert_eq!(cause(&[QmpEvent::Watchdog { action: WatchdogAction::reset }, shutdown(ShutdownCause::host_signal)]), Some(ExitReason::Host));
+		assert_eq!(cause(&[QmpEvent::Watchdog { action: WatchdogAction::poweroff }, shutdown(ShutdownCause::host_signal)]), Some(ExitReason::Watchdog));
+		assert_eq!(cause(&[QmpEvent::GuestPanicked { action: GuestPanicAction::pause }, shutdown(ShutdownCause::host_qmp_quit)]), Some(ExitReason::Panicked));
+		assert_eq!(cause(&[QmpEvent::Stop]), None);
+	}
+
+	#[tokio::test]
+	async fn exit_reason_comes_from_the_last_event() {
+		let server = MockQmp::start("exit-reason", |_, _| Ok(json!({})));
+		let mut vm = test_vm();
+		vm.process = Some(Command::new("sleep").arg("30").kill_on_drop(true).spawn().unwrap());
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+
+		let mut state = vm.subscribe_state();
+		server.event("GUEST_PANICKED", json!({ "action": "pause" }));
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while *state.borrow_and_update() != VMState::Crashed {
+				state.changed().await.unwrap();
+			}
+		}).await.unwrap();
+		assert_eq!(vm.exit_reason(), None);
+
+		vm.process.as_mut().unwrap().start_kill().unwrap();
+		vm.wait().await.unwrap();
+		assert_eq!(vm.exit_reason(), Some(ExitReason::Panicked));
+	}
+
+	#[tokio::test]
+	async fn panicked_guests_are_dumped() {
+		let pvpanic = |crash_dump: Option<&str>| Pvpanic { crash_dump: crash_dump.map(String::from) };
+		assert_eq!(pvpanic(None).as_options(), ["-device", "pvpanic,id=vm.pvpanic", "-action", "panic=pause"]);
+
+		let mut vm = test_vm();
+		assert!(pvpanic(Some("crash.elf")).validate(&vm).is_ok());
+		assert!(matches!(pvpanic(Some("")).validate(&vm), Err(ValidationError::EmptyField { field: "crash_dump" })));
+		let missing = temp_path("crashes").join("crash.elf").to_string_lossy().into_owned();
+		assert!(matches!(pvpanic(Some(&missing)).validate(&vm), Err(ValidationError::PathNotFound { .. })));
+
+		vm.add_device(pvpanic(None)).add_device(pvpanic(None));
+		assert!(matches!(vm.validation_errors()[0].error, ValidationError::InvalidField { reason: "a VM can only have one pvpanic device", .. }));
+
+		let server = MockQmp::start("pvpanic-dump", |_, _| Ok(json!({})));
+		let mut vm = test_vm();
+		vm.add_device(pvpanic(Some("/var/crash/test.elf")));
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+
+		let mut state = vm.subscribe_state();
+		server.event("GUEST_PANICKED", json!({ "action": "pause" }));
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while *state.borrow_and_update() != VMState::Crashed {
+				state.changed().await.unwrap();
+			}
+
+			while server.commands().is_empty() {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		}).await.unwrap();
+
+		let received = server.received();
+		assert_eq!(received[0].execute, "dump-guest-memory");
+		assert_eq!(received[0].arguments["protocol"], "file:/var/crash/test.elf");
+		assert_eq!(received[0].arguments["paging"], false);
+		assert_eq!(received[0].arguments["detach"], true);
+	}
+
+	#[tokio::test]
+	async fn memory_dumps_report_progress_and_completion() {
+		let server = MockQmp::start("memory-dump", |command, _| match command {
+			"query-dump" => Ok(json!({ "status": "active", "completed": 512, "total": 1024 })),
+			_ => Ok(json!({}))
+		});
+		let vm = running_test_vm(&server).await;
+
+		let dump = vm.dump_memory("/var/crash/test.dmp", DumpFormat::KdumpZlib, false).await.unwrap();
+		let progress = dump.progress().await.unwrap();
+		assert_eq!((progress.completed, progress.total), (512, 1024));
+
+		server.event("DUMP_COMPLETED", json!({ "result": { "status": "failed", "completed": 512, "total": 1024 }, "error": "No space left on device" }));
+		assert!(matches!(dump.wait().await, Err(VMControlError::DumpFailed(reason)) if reason == "No space left on device"));
+
+		let received = server.received();
+		assert_eq!(received[0].execute, "dump-guest-memory");
+		assert_eq!(received[0].arguments, json!({ "protocol": "file:/var/crash/test.dmp", "paging": false, "detach": true, "format": "kdump-zlib" }));
+		assert_eq!(received[1].execute, "query-dump");
+
+		let dump = vm.dump_memory("/var/crash/test.elf", DumpFormat::Elf, true).await.unwrap();
+		server.event("DUMP_COMPLETED", json!({ "result": { "status": "completed", "completed": 1024, "total": 1024 } }));
+		assert!(dump.wait().await.is_ok());
+
+		vm.state.send_replace(VMState::Stopping);
+		assert!(matches!(vm.dump_memory("/var/crash/test.elf", DumpFormat::Elf, false).await, Err(VMControlError::InvalidState(VMState::Stopping))));
+		assert_eq!(server.commands().len(), 3);
+	}
+
+	#[tokio::test]
+	async fn unexplained_exits_are_crashes() {
+		let server = MockQmp::start("exit-crash", |_, _| Ok(json!({})));
+		let mut vm = test_vm();
+		vm.process = Some(Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap());
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+
+		let status = vm.wait().await.unwrap().unwrap();
+		assert_eq!(status.code(), Some(3));
+		assert_eq!(vm.exit_reason(), Some(ExitReason::Crashed(status)));
+	}
+
+	#[test]
+	fn failover_pair_must_be_a_failover_adapter() {
+		let mut vm = test_vm();
+		let passthrough = PciPassthrough {
+			id: String::from("vf"),
+			host_address: String::from("0000:ff:1f.7"),
+			rombar: None,
+			romfile: None,
+			multifunction: false,
+			rebind_driver: false,
+			failover_pair_id: Some(String::from("nic")),
+			root_port: None
+		};
+		let adapter = |failover| NetworkAdapter::Virtio {
+			id: String::from("nic"),
+			netdev: String::from("lan"),
+			mac: Some("52:54:00:12:34:56".parse().unwrap()),
+			rombar: None,
+			romfile: None,
+			failover,
+			host_mtu: None,
+			offloads: VirtioNetOffloads::default(),
+			queues: None
+		};
+
+		assert!(matches!(
+			passthrough.validate(&vm),
+			Err(ValidationError::InvalidField { field: "failover_pair_id", reason: "no virtio network adapter has this id" })
+		));
+
+		vm.add_device(adapter(false));
+		assert!(matches!(
+			passthrough.validate(&vm),
+			Err(ValidationError::InvalidField { field: "failover_pair_id", reason: "the network adapter doesn't have failover on" })
+		));
+
+		// Past the pairing, onto the (missing) host device
+		let mut vm = test_vm();
+		vm.add_device(adapter(true));
+		assert!(matches!(passthrough.validate(&vm), Err(ValidationError::PathNotFound { .. })));
+	}
+
+	#[test]
+	fn assigned_ids_skip_taken_ones() {
+		let mut vm = test_vm();
+		vm.add_drive(hd_drive("disk1", "/tmp/explicit.qcow2"))
+			.add_drive(hd_drive("", "/tmp/first.qcow2"))
+			.add_drive(hd_drive("", "/tmp/second.qcow2"));
+
+		let ids = vm.devices_of::<DiskDrive>().filter_map(|drive| drive.device_id()).collect::<Vec<&str>>();
+		assert_eq!(ids, ["disk1", "disk2", "disk3"]);
+		assert!(vm.validation_errors().is_empty());
+	}
+
+	#[test]
+	fn duplicate_ids_are_rejected() {
+		let mut vm = test_vm();
+		vm.add_drive(hd_drive("", "/tmp/first.qcow2"))
+			.add_drive(hd_drive("disk0", "/tmp/second.qcow2"))
+			// Netdevs have ids of their own
+			.add_device(Network::User { id: String::from("disk0"), host_forwards: Vec::new() });
+
+		let errors = vm.validation_errors();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].error, ValidationError::DuplicateId { id: String::from("disk0") });
+	}
+
+	#[test]
+	fn smart_cards_get_their_own_ids() {
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Pc { acpi: true, usb: true })
+			.add_device(SmartCard { id: String::new(), backend: SmartCardBackend::Emulated })
+			.add_device(SmartCard { id: String::new(), backend: SmartCardBackend::Emulated });
+
+		let args = vm.to_arguments().unwrap();
+		let readers = values(&args, "-device").into_iter().filter(|dev| dev.starts_with("usb-ccid")).collect::<Vec<&str>>();
+		assert_eq!(readers, ["usb-ccid,id=vm.ccid0", "usb-ccid,id=vm.ccid1"]);
+		assert!(values(&args, "-device").contains(&"ccid-card-emulated,backend=nss-emulated,bus=vm.ccid1.0"));
+
+		// Definitions from before readers had ids still load
+		let card: SmartCard = toml::from_str("backend = \"Emulated\"").unwrap();
+		assert!(card.id.is_empty());
+	}
+
+	#[test]
+	fn evdev_paths_are_resolved_before_checking() {
+		let root = temp_path("evdev");
+		let input = root.join("input");
+		std::fs::create_dir_all(input.join("by-id")).unwrap();
+		std::fs::write(input.join("event0"), "").unwrap();
+		std::fs::write(root.join("sda"), "").unwrap();
+		std::os::unix::fs::symlink("../event0", input.join("by-id/keyboard")).unwrap();
+		std::os::unix::fs::symlink("../../sda", input.join("by-id/disk")).unwrap();
+
+		let input = std::fs::canonicalize(&input).unwrap();
+		let check = |path: &str| check_evdev_path(input.join(path).to_str().unwrap(), &input);
+		let results = [check("event0"), check("by-id/keyboard"), check("../sda"), check("by-id/disk"), check("event1")];
+		std::fs::remove_dir_all(&root).unwrap();
+
+		assert!(results[0].is_ok());
+		assert!(results[1].is_ok());
+		assert!(matches!(results[2], Err(ValidationError::InvalidField { field: "evdev_path", .. })));
+		assert!(matches!(results[3], Err(ValidationError::InvalidField { field: "evdev_path", .. })));
+		assert!(matches!(results[4], Err(ValidationError::PathNotFound { .. })));
+	}
+
+	#[test]
+	fn only_x86_has_dies() {
+		let cpu = Cpu { model: String::from("qemu64"), dies: Some(2), ..host_cpu() };
+		let mut vm = test_vm();
+		assert!(cpu.validate(&vm).is_ok());
+		assert!(values(&cpu.as_options(), "-smp")[0].contains(",dies=2,"));
+
+		vm.set_architecture(Architecture::S390x);
+		assert!(matches!(cpu.validate(&vm), Err(ValidationError::InvalidField { field: "dies", .. })));
+		assert!(Cpu { dies: None, ..cpu }.validate(&vm).is_ok());
+	}
+
+	#[test]
+	fn vgpu_pci_ids_go_together() {
+		let uuid = "a1b2c3d4-0000-0000-0000-000000000000";
+		let id = |value: &str| Some(String::from(value));
+		let vgpu = GraphicsAdapter::VgpuVga {
+			uuid: String::from(uuid),
+			use_ramfb: false,
+			pci_vendor_id: id("0x10de"),
+			pci_device_id: id("0x1e87"),
+			pci_sub_vendor_id: id("0x1043"),
+			pci_sub_device_id: id("0x866a"),
+			rombar: None,
+			romfile: None,
+			root_port: None
+		};
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.set_uuid(uuid);
+
+		assert!(vgpu.validate(&vm).is_ok());
+		let device = values(&vgpu.as_options(), "-device")[0].to_owned();
+		assert!(device.contains(",x-pci-vendor-id=0x10de,x-pci-device-id=0x1e87,x-pci-sub-vendor-id=0x1043,x-pci-sub-device-id=0x866a"));
+
+		let mut partial = vgpu.clone();
+		if let GraphicsAdapter::VgpuVga { pci_sub_device_id: missing, .. } = &mut partial {
+			*missing = None;
+		}
+		assert!(matches!(partial.validate(&vm), Err(ValidationError::InvalidField { field: "pci_vendor_id", .. })));
+		assert!(!values(&partial.as_options(), "-device")[0].contains("x-pci"));
+	}
+
+	#[test]
+	fn launch_script_uses_the_configured_binary() {
+		let path = temp_path("launch.sh");
+		let mut vm = test_vm();
+		vm.set_qemu_binary("/opt/qemu/bin/qemu-system-x86_64");
+
+		vm.write_launch_script(path.to_str().unwrap()).unwrap();
+		let script = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert!(script.starts_with("#!/bin/sh\nexec /opt/qemu/bin/qemu-system-x86_64 "), "{script}");
+		assert!(vm.to_command_string().unwrap().starts_with("/opt/qemu/bin/qemu-system-x86_64 "));
+	}
+
+	#[test]
+	fn debugcon_writes_to_its_file() {
+		let debugcon = IsaDebugCon::new("/tmp/ovmf debug.log");
+		let args = debugcon.as_options();
+		assert_eq!(values(&args, "-chardev"), ["file,id=vm.debugcon,path=/tmp/ovmf debug.log"]);
+		assert_eq!(values(&args, "-device"), ["isa-debugcon,iobase=0x402,chardev=vm.debugcon"]);
+
+		let vm = test_vm();
+		assert!(debugcon.validate(&vm).is_ok());
+		assert!(matches!(IsaDebugCon { iobase: 0, ..debugcon.clone() }.validate(&vm), Err(ValidationError::InvalidField { field: "iobase", .. })));
+		assert!(matches!(IsaDebugCon::new("").validate(&vm), Err(ValidationError::EmptyField { field: "file_path" })));
+	}
+
+	#[test]
+	fn serial_bios_needs_a_console() {
+		let mut vm = test_vm();
+		vm.enable_serial_bios();
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::SerialBiosWithoutConsole)));
+
+		vm.add_device(SerialConsole { id: String::from("serial0"), backend: SerialBackend::Pty });
+		assert!(values(&vm.to_arguments().unwrap(), "-device").contains(&"sga"));
+	}
+
+	#[tokio::test]
+	async fn serial_consoles_connect_to_their_backend() {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		let console = |backend| SerialConsole { id: String::from("serial0"), backend };
+		assert_eq!(console(SerialBackend::Stdio).as_options(), [
+			"-chardev", "stdio,id=vm.serial0.chardev,signal=off",
+			"-serial", "chardev:vm.serial0.chardev"
+		]);
+		assert_eq!(console(SerialBackend::UnixSocket { path: String::from("/run/a,b.sock") }).as_options()[1],
+			"socket,id=vm.serial0.chardev,path=/run/a,,b.sock,server=on,wait=off");
+		assert_eq!(console(SerialBackend::LogFile { path: String::from("/var/log/vm.log") }).as_options()[1],
+			"file,id=vm.serial0.chardev,path=/var/log/vm.log,append=on");
+
+		let mut vm = test_vm();
+		vm.add_device(console(SerialBackend::Stdio))
+			.add_device(SerialConsole { id: String::from("serial1"), backend: SerialBackend::Stdio });
+		assert!(matches!(vm.validation_errors()[0].error, ValidationError::InvalidField { field: "backend", reason: "only one serial console or channel can use stdio" }));
+
+		// QEMU says where it put the pty, which a plain file stands in for here
+		let pty = temp_path("console-pty");
+		std::fs::write(&pty, "login: ").unwrap();
+		let filename = format!("pty:{}", pty.display());
+		let server = MockQmp::start("console", move |_, _| Ok(json!([
+			{ "label": "vm.monitor", "filename": "unix:/tmp/monitor.sock", "frontend-open": true },
+			{ "label": "vm.serial0.chardev", "filename": filename, "frontend-open": true }
+		])));
+
+		let mut vm = running_test_vm(&server).await;
+		assert!(matches!(vm.console().await, Err(VMControlError::NoSerialConsole)));
+
+		vm.add_device(console(SerialBackend::Pty));
+		let mut io = vm.console().await.unwrap();
+		let mut prompt = String::new();
+		io.reader.read_to_string(&mut prompt).await.unwrap();
+		std::fs::remove_file(&pty).unwrap();
+		assert_eq!(prompt, "login: ");
+		assert!(io.writer.is_some());
+
+		// The other backends don't need to ask QEMU anything
+		let qmp = vm.qmp.as_deref().unwrap();
+		let log = temp_path("console.log");
+		std::fs::write(&log, "Booting...").unwrap();
+		let mut io = SerialBackend::LogFile { path: log.to_string_lossy().into_owned() }.connect("serial0", qmp).await.unwrap();
+		let mut output = String::new();
+		io.reader.read_to_string(&mut output).await.unwrap();
+		std::fs::remove_file(&log).unwrap();
+		assert_eq!(output, "Booting...");
+		assert!(io.writer.is_none());
+
+		let socket = temp_path("console.sock");
+		let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+		let io = SerialBackend::UnixSocket { path: socket.to_string_lossy().into_owned() }.connect("serial0", qmp).await.unwrap();
+		io.writer.unwrap().write_all(b"root\n").await.unwrap();
+		let (mut guest, _) = listener.accept().await.unwrap();
+		let mut line = [0u8; 5];
+		guest.read_exact(&mut line).await.unwrap();
+		std::fs::remove_file(&socket).unwrap();
+		assert_eq!(&line, b"root\n");
+
+		assert!(matches!(SerialBackend::Stdio.connect("serial0", qmp).await, Err(VMControlError::ConsoleOnStdio)));
+		assert_eq!(server.commands(), ["query-chardev"]);
+	}
+
+	#[test]
+	fn option_roms_can_be_replaced_or_hidden() {
+		assert_eq!(rom_options(&None, &None), "");
+		assert_eq!(rom_options(&Some(false), &None), ",rombar=0");
+		assert_eq!(rom_options(&Some(true), &Some(String::from("/roms/efi,net.rom"))), ",rombar=1,romfile=/roms/efi,,net.rom");
+
+		let romfile = temp_path("option.rom");
+		std::fs::write(&romfile, "").unwrap();
+		let found = check_romfile(&Some(String::from(romfile.to_str().unwrap())));
+		std::fs::remove_file(&romfile).unwrap();
+
+		assert!(found.is_ok());
+		assert!(check_romfile(&None).is_ok());
+		assert!(matches!(check_romfile(&Some(String::from(romfile.to_str().unwrap()))), Err(ValidationError::PathNotFound { .. })));
+	}
+
+	#[test]
+	fn failover_pairs_the_adapter_with_its_primary() {
+		let passthrough = PciPassthrough {
+			id: String::from("vf"),
+			host_address: String::from("0000:ff:1f.7"),
+			rombar: None,
+			romfile: None,
+			multifunction: false,
+			rebind_driver: false,
+			failover_pair_id: Some(String::from("nic")),
+			root_port: None
+		};
+		let adapter = |mac: Option<&str>| NetworkAdapter::Virtio {
+			id: String::from("nic"),
+			netdev: String::from("lan"),
+			mac: mac.map(|mac| mac.parse().unwrap()),
+			rombar: None,
+			romfile: None,
+			failover: true,
+			host_mtu: None,
+			offloads: VirtioNetOffloads::default(),
+			queues: None
+		};
+
+		assert_eq!(passthrough.as_options(), ["-device", "vfio-pci,host=0000:ff:1f.7,id=vm.vf,failover_pair_id=vm.nic"]);
+		assert_eq!(adapter(Some("52:54:00:12:34:56")).as_options(), ["-device", "virtio-net-pci,id=vm.nic,netdev=vm.lan,mac=52:54:00:12:34:56,failover=on"]);
+
+		// Neither a MAC nor a UUID to derive one from
+		let mut vm = test_vm();
+		vm.uuid = None;
+		assert!(matches!(adapter(None).validate(&vm), Err(ValidationError::EmptyField { field: "mac" })));
+
+		assert!(matches!(
+			adapter(Some("52:54:00:12:34:56")).validate(&vm),
+			Err(ValidationError::InvalidField { field: "failover", reason: "no passthrough device has this adapter as its failover_pair_id" })
+		));
+
+		vm.add_device(passthrough);
+		assert!(adapter(Some("52:54:00:12:34:56")).validate(&vm).is_ok());
+	}
+
+	#[test]
+	fn egl_headless_needs_a_gl_adapter() {
+		let virgl = GraphicsAdapter::VirtioGpu { blob: false, hostmem: None, vga: false, virgl: true, venus: false };
+
+		assert_eq!(DisplayBackend::EglHeadless { rendernode: None }.as_options(), ["-display", "egl-headless"]);
+		assert_eq!(
+			DisplayBackend::EglHeadless { rendernode: Some(String::from("/dev/dri/renderD128")) }.as_options(),
+			["-display", "egl-headless,rendernode=/dev/dri/renderD128"]
+		);
+
+		let mut vm = test_vm();
+		vm.set_display_backend(DisplayBackend::EglHeadless { rendernode: None })
+			.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(16) });
+		let errors = vm.validation_errors();
+		assert_eq!(errors.len(), 1);
+		assert!(matches!(errors[0].error, ValidationError::NoGlAdapter));
+
+		let mut vm = test_vm();
+		vm.set_display_backend(DisplayBackend::EglHeadless { rendernode: None })
+			.add_device(virgl.clone());
+		assert!(vm.validation_errors().is_empty());
+		assert!(values(&vm.to_arguments().unwrap(), "-device").contains(&"virtio-gpu-gl-pci,id=vm.vga"));
+
+		let missing = temp_path("renderD128").to_string_lossy().into_owned();
+		assert!(matches!(
+			DisplayBackend::EglHeadless { rendernode: Some(missing) }.validate(&vm),
+			Err(ValidationError::PathNotFound { .. })
+		));
+
+		// virgl on the other end of the pairing needs a GL display too
+		let mut vm = test_vm();
+		vm.set_display_backend(DisplayBackend::None)
+			.add_device(virgl);
+		assert!(matches!(vm.validation_errors()[0].error, ValidationError::NoGlDisplay));
+	}
+
+	#[test]
+	fn window_displays_need_a_host_display() {
+		assert_eq!(DisplayBackend::Gtk { gl: true }.as_options(), ["-display", "gtk,gl=on"]);
+		assert_eq!(DisplayBackend::Sdl { gl: false }.as_options(), ["-display", "sdl,gl=off"]);
+		assert_eq!(DisplayBackend::DbusP2p { gl: false }.as_options(), ["-display", "dbus,p2p=on,gl=off"]);
+		assert!(DisplayBackend::Sdl { gl: true }.renders_with_gl());
+		assert!(!DisplayBackend::DbusP2p { gl: false }.renders_with_gl());
+
+		let mut vm = test_vm();
+		vm.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(16) });
+		assert!(DisplayBackend::DbusP2p { gl: false }.validate(&vm).is_ok());
+		assert!(matches!(DisplayBackend::DbusP2p { gl: true }.validate(&vm), Err(ValidationError::NoGlAdapter)));
+
+		// Whether this machine has a display isn't up to the test, and changing the environment would race other tests
+		let has_display = std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some();
+		for window in [DisplayBackend::Gtk { gl: true }, DisplayBackend::Sdl { gl: true }] {
+			match window.validate(&vm) {
+				Err(ValidationError::NoGlAdapter) => assert!(has_display),
+				Err(ValidationError::NoHostDisplay) => assert!(!has_display),
+				_ => panic!("{} should have failed validation", window.label())
+			}
+		}
+	}
+
+	#[test]
+	fn crb_tpms_need_acpi() {
+		let tpm = |interface| Tpm { interface, socket_path: String::from("/run/swtpm.sock"), managed: false };
+
+		assert_eq!(tpm(TpmInterface::Crb).as_options(), [
+			"-chardev", "socket,id=vm.tpm.chardev,path=/run/swtpm.sock",
+			"-tpmdev", "emulator,id=vm.tpm,chardev=vm.tpm.chardev",
+			"-device", "tpm-crb,tpmdev=vm.tpm"
+		]);
+		assert!(tpm(TpmInterface::Crb).validate(&test_vm()).is_ok());
+
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Pc { acpi: false, usb: false });
+		assert!(tpm(TpmInterface::Tis).validate(&vm).is_ok());
+		assert!(matches!(
+			tpm(TpmInterface::Crb).validate(&vm),
+			Err(ValidationError::UnsupportedMachineType { requires: "a machine type with ACPI enabled" })
+		));
+
+		let unset = Tpm { socket_path: String::new(), ..tpm(TpmInterface::Tis) };
+		assert!(matches!(unset.validate(&vm), Err(ValidationError::EmptyField { field: "socket_path" })));
+	}
+
+	#[tokio::test]
+	async fn managed_tpms_wait_for_swtpm() {
+		let tpm = Tpm::managed(TpmInterface::Tis, "test");
+		assert!(tpm.socket_path.ends_with("/sunlight_test.swtpm"));
+		assert_eq!(tpm.swtpm_socket(), Some(tpm.socket_path.as_str()));
+		assert_eq!(Tpm { managed: false, ..tpm.clone() }.swtpm_socket(), None);
+
+		let command = swtpm_command(std::path::Path::new("/var/lib/sunlight/test/tpm"), "/run/swtpm.sock");
+		assert_eq!(command.as_std().get_program(), "swtpm");
+		assert_eq!(command.as_std().get_args().collect::<Vec<_>>(), [
+			"socket", "--tpm2", "--terminate",
+			"--tpmstate", "dir=/var/lib/sunlight/test/tpm",
+			"--ctrl", "type=unixio,path=/run/swtpm.sock"
+		]);
+
+		// Shell commands stand in for swtpm
+		let socket = temp_path("swtpm.sock");
+		let socket_path = socket.to_str().unwrap();
+		let shell = |script: &str| Command::new("sh").args(["-c", script]).kill_on_drop(true).spawn().unwrap();
+
+		let mut exits = shell("exit 3");
+		assert!(matches!(wait_for_socket(&mut exits, socket_path, SWTPM_TIMEOUT).await, Err(VMQemuProcessStartError::SwtpmExited(status)) if status.code() == Some(3)));
+
+		let mut hangs = shell("sleep 5");
+		assert!(matches!(wait_for_socket(&mut hangs, socket_path, Duration::from_millis(100)).await, Err(VMQemuProcessStartError::SwtpmTimeout)));
+
+		let mut listens = shell(&format!("sleep 0.1; touch {socket_path}; sleep 5"));
+		let listened = wait_for_socket(&mut listens, socket_path, SWTPM_TIMEOUT).await;
+		std::fs::remove_file(&socket).unwrap();
+		assert!(listened.is_ok());
+	}
+
+	#[test]
+	fn spawn_deny_forbids_bridges() {
+		let sandbox = |deny_spawn| Sandbox { deny_obsolete: true, deny_elevate_privileges: false, deny_spawn, deny_resource_control: false };
+		let mut vm = test_vm();
+		vm.add_device(Network::User { id: String::from("lan"), host_forwards: Vec::new() });
+
+		assert_eq!(sandbox(true).as_options(), ["-sandbox", "on,obsolete=deny,spawn=deny"]);
+		assert!(sandbox(true).validate(&vm).is_ok());
+
+		vm.add_device(Network::Bridge { id: String::from("wan"), bridge: String::from("br0"), helper: None });
+		assert!(matches!(sandbox(true).validate(&vm), Err(ValidationError::SandboxForbidsHelper)));
+		assert!(sandbox(false).validate(&vm).is_ok());
+	}
+
+	#[test]
+	fn bridges_go_through_the_helper() {
+		let bridge = |bridge: &str, helper: Option<&str>| Network::Bridge { id: String::from("wan"), bridge: String::from(bridge), helper: helper.map(String::from) };
+
+		assert_eq!(bridge("br0", None).as_options(), ["-netdev", "bridge,br=br0,id=vm.wan"]);
+		assert_eq!(bridge("br,0", Some("/opt/qemu,8/qemu-bridge-helper")).as_options(), ["-netdev", "bridge,br=br,,0,id=vm.wan,helper=/opt/qemu,,8/qemu-bridge-helper"]);
+
+		let vm = test_vm();
+		assert!(matches!(bridge("sunlight-nobr0", None).validate(&vm), Err(ValidationError::NoSuchBridge { bridge }) if bridge == "sunlight-nobr0"));
+	}
+
+	#[test]
+	fn annotated_arguments_are_labeled_by_variant() {
+		let mut vm = test_vm();
+		vm.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(16) })
+			.add_device(Network::User { id: String::from("lan"), host_forwards: Vec::new() })
+			.add_drive(hd_drive("root", "/tmp/root.qcow2"));
+
+		let annotated = vm.to_arguments_annotated().unwrap();
+		let labeled = |label: &str| annotated.iter().find(|(name, _)| name == label).map(|(_, options)| options.clone());
+
+		assert_eq!(labeled("GraphicsAdapter::StdVga").unwrap(), ["-device", "VGA,vgamem_mb=16,id=vm.vga"]);
+		assert_eq!(values(&labeled("Network::User").unwrap(), "-netdev"), ["user,id=vm.lan"]);
+		assert!(labeled("DiskDrive::HdDrive").is_some());
+
+		// The same arguments, just grouped
+		let flattened = annotated.into_iter().flat_map(|(_, options)| options).collect::<Vec<String>>();
+		assert_eq!(flattened, vm.to_arguments().unwrap());
+	}
+
+	#[test]
+	fn host_mtu_has_a_floor() {
+		let adapter = |host_mtu| NetworkAdapter::Virtio {
+			id: String::from("nic"),
+			netdev: String::from("lan"),
+			mac: None,
+			rombar: None,
+			romfile: None,
+			failover: false,
+			host_mtu,
+			offloads: VirtioNetOffloads::default(),
+			queues: None
+		};
+		let vm = test_vm();
+
+		assert_eq!(adapter(Some(9000)).as_options(), ["-device", "virtio-net-pci,id=vm.nic,netdev=vm.lan,host_mtu=9000"]);
+		assert!(adapter(Some(9000)).validate(&vm).is_ok());
+		assert!(adapter(Some(576)).validate(&vm).is_ok());
+		assert!(adapter(Some(u16::MAX)).validate(&vm).is_ok());
+		assert!(matches!(
+			adapter(Some(575)).validate(&vm),
+			Err(ValidationError::InvalidField { field: "host_mtu", reason: "must be at least 576" })
+		));
+	}
+
+	#[test]
+	fn drives_share_a_throttle_group() {
+		let grouped = |id: &str, group: &str| {
+			let mut drive = hd_drive(id, &format!("/tmp/{id}.qcow2"));
+			if let DiskDrive::HdDrive { throttle_group, .. } = &mut drive {
+				*throttle_group = Some(String::from(group));
+			}
+			drive
+		};
+
+		let mut vm = test_vm();
+		vm.add_throttle_group(ThrottleGroup { id: String::from("shared"), limits: IoLimits { iops_total: Some(1000), ..IoLimits::default() } })
+			.add_drive(grouped("a", "shared"))
+			.add_drive(grouped("b", "shared"));
+		assert!(vm.validation_errors().is_empty());
+
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-object"), ["throttle-group,id=vm.shared.tg,x-iops-total=1000"]);
+		assert_eq!(values(&args, "-drive"), [
+			"if=none,driver=throttle,throttle-group=vm.shared.tg,file.driver=qcow2,file.file.filename=/tmp/a.qcow2,id=vm.a.drive,readonly=off",
+			"if=none,driver=throttle,throttle-group=vm.shared.tg,file.driver=qcow2,file.file.filename=/tmp/b.qcow2,id=vm.b.drive,readonly=off"
+		]);
+
+		vm.add_drive(grouped("c", "missing"));
+		let errors = vm.validation_errors();
+		assert_eq!(errors.len(), 1);
+		assert!(matches!(&errors[0].error, ValidationError::UnknownThrottleGroup { group } if group == "missing"));
+	}
+
+	#[tokio::test]
+	async fn io_limits_are_changed_at_runtime() {
+		let limits = IoLimits { iops_read: Some(500), iops_write: Some(200), bps_total: Some(IO_LIMIT_MAX), ..IoLimits::default() };
+		assert!(limits.check().is_ok());
+		assert_eq!(limits.as_options("throttling."), ",throttling.iops-read=500,throttling.iops-write=200,throttling.bps-total=1000000000000000");
+
+		let invalid = |limits: IoLimits| match limits.check() {
+			Err(ValidationError::InvalidField { field, .. }) => field,
+			_ => panic!("the limits should have failed validation")
+		};
+		assert_eq!(invalid(IoLimits { iops_total: Some(1000), iops_write: Some(100), ..IoLimits::default() }), "iops_total");
+		assert_eq!(invalid(IoLimits { bps_total: Some(1000), bps_read: Some(100), ..IoLimits::default() }), "bps_total");
+		assert_eq!(invalid(IoLimits { bps_write: Some(IO_LIMIT_MAX + 1), ..IoLimits::default() }), "limits");
+
+		let server = MockQmp::start("io-limits", |_, _| Ok(json!({})));
+		let vm = running_test_vm(&server).await;
+
+		// Anything left unset is removed, rather than left as it was
+		vm.set_io_limits("data", &limits).await.unwrap();
+		let received = server.received();
+		assert_eq!(received[0].execute, "block_set_io_throttle");
+		assert_eq!(received[0].arguments["id"], "vm.data");
+		for (limit, value) in [("iops", 0), ("iops_rd", 500), ("iops_wr", 200), ("bps", IO_LIMIT_MAX), ("bps_rd", 0), ("bps_wr", 0)] {
+			assert_eq!(received[0].arguments[limit], value, "{limit}");
+		}
+
+		let conflicting = IoLimits { iops_total: Some(1000), iops_read: Some(100), ..IoLimits::default() };
+		assert!(matches!(vm.set_io_limits("data", &conflicting).await, Err(VMControlError::InvalidDevice(OptionValidationError { error: ValidationError::InvalidField { field: "iops_total", .. }, .. }))));
+		assert_eq!(server.commands().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn drives_whose_io_limits_fail_are_unplugged_again() {
+		let server = MockQmp::start("io-limits-rollback", |command, _| match command {
+			"block_set_io_throttle" => Err(String::from("bps-total value too large")),
+			_ => Ok(json!({}))
+		});
+		let image = temp_path("io-limits-rollback.qcow2");
+		std::fs::write(&image, "").unwrap();
+
+		let mut drive = hd_drive("data", image.to_str().unwrap());
+		if let DiskDrive::HdDrive { throttle, .. } = &mut drive {
+			*throttle = Some(IoLimits { bps_total: Some(IO_LIMIT_MAX), ..IoLimits::default() });
+		}
+
+		let mut vm = running_test_vm(&server).await;
+		let guest = async {
+			while !server.commands().iter().any(|command| command == "device_del") {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+			server.event("DEVICE_DELETED", json!({ "device": "vm.data", "path": "/machine/peripheral/vm.data" }));
+		};
+		let (plugged, _) = tokio::join!(vm.hotplug_drive(drive), guest);
+		std::fs::remove_file(&image).unwrap();
+
+		assert!(matches!(plugged, Err(VMControlError::QmpCommandFailure(VMQmpCommandError::QemuError(desc))) if desc.contains("too large")));
+		assert_eq!(server.commands(), ["blockdev-add", "device_add", "block_set_io_throttle", "device_del", "blockdev-del"]);
+		assert!(vm.hotplugged.is_empty());
+	}
+
+	#[test]
+	fn root_ports_get_their_own_chassis() {
+		let root_ports = |legacy_root_port| {
+			let mut vm = test_vm();
+			vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port })
+				.add_root_port("a")
+				.add_root_port("b");
+			vm.to_arguments_annotated().unwrap()
+				.into_iter()
+				.filter(|(label, _)| label == "RootPort")
+				.flat_map(|(_, options)| options)
+				.collect::<Vec<String>>()
+		};
+
+		assert_eq!(root_ports(false), [
+			"-device", "pcie-root-port,id=vm.a,chassis=1,slot=0,bus=pcie.0",
+			"-device", "pcie-root-port,id=vm.b,chassis=2,slot=0,bus=pcie.0"
+		]);
+		assert_eq!(values(&root_ports(true), "-device"), [
+			"ioh3420,id=vm.a,chassis=1,slot=0,bus=pcie.0",
+			"ioh3420,id=vm.b,chassis=2,slot=0,bus=pcie.0"
+		]);
+
+		// Chassis numbers are a u8, and 0 isn't one
+		let mut allocator = RootPortAllocator::new(false);
+		for _ in 0..u8::MAX {
+			allocator.allocate().unwrap();
+		}
+		assert!(matches!(allocator.allocate(), Err(VMQemuProcessStartError::TooManyRootPorts)));
+
+		let mut vm = test_vm();
+		vm.add_root_port("a");
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::RootPortsRequireQ35)));
+	}
+
+	/// A device which wants a root port, optionally asking for a named one.
+	struct OnRootPort(Option<&'static str>);
+
+	impl QemuOption for OnRootPort {
+		fn as_options(&self) -> Vec<String> {
+			qemu_arg("-device", "virtio-rng-pci")
+		}
+
+		fn wants_root_port(&self) -> bool {
+			true
+		}
+
+		fn requested_root_port(&self) -> Option<&str> {
+			self.0
+		}
+
+		fn as_options_on_port(&self, port: &str) -> Vec<String> {
+			place_on_port(self.as_options(), port)
+		}
+	}
+
+	#[test]
+	fn devices_get_root_ports_allocated_in_order() {
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.add_root_port("gpu")
+			.add_device(OnRootPort(None))
+			.add_device(OnRootPort(Some("gpu")))
+			.add_device(OnRootPort(None));
+
+		let args = vm.to_arguments().unwrap();
+		let devices = values(&args, "-device")
+			.into_iter()
+			.filter(|device| device.starts_with("pcie-root-port") || device.starts_with("virtio-rng-pci"))
+			.collect::<Vec<&str>>();
+
+		// The device asking for a named port goes on it, and the others each get the next new one
+		assert_eq!(devices, [
+			"pcie-root-port,id=vm.gpu,chassis=1,slot=0,bus=pcie.0",
+			"pcie-root-port,id=vm.rp0,chassis=2,slot=0,bus=pcie.0",
+			"virtio-rng-pci,bus=vm.rp0,addr=0x0",
+			"virtio-rng-pci,bus=vm.gpu,addr=0x0",
+			"pcie-root-port,id=vm.rp1,chassis=3,slot=0,bus=pcie.0",
+			"virtio-rng-pci,bus=vm.rp1,addr=0x0"
+		]);
+
+		vm.add_device(OnRootPort(Some("gpu")));
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::RootPortInUse(port)) if port == "gpu"));
+
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.add_device(OnRootPort(Some("missing")));
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::UnknownRootPort(port)) if port == "missing"));
+	}
+
+	#[test]
+	fn allocated_root_ports_run_out() {
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.add_root_port("named");
+		for _ in 1..u8::MAX {
+			vm.add_device(OnRootPort(None));
+		}
+		assert_eq!(values(&vm.to_arguments().unwrap(), "-device").iter().filter(|device| device.starts_with("pcie-root-port")).count(), 255);
+
+		// Named ports use up chassis numbers too
+		vm.add_device(OnRootPort(None));
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::TooManyRootPorts)));
+	}
+
+	#[test]
+	fn clones_get_a_new_identity() {
+		let mut vm = test_vm();
+		vm.set_uuid("00000000-0000-0000-0000-000000000001")
+			.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(16) })
+			.add_device(Network::User { id: String::from("lan"), host_forwards: Vec::new() })
+			.add_drive(hd_drive("root", "/tmp/root.qcow2"));
+
+		let clone = vm.clone_with("clone", "00000000-0000-0000-0000-000000000002").unwrap();
+		assert_eq!(clone.name, "clone");
+		assert_eq!(clone.uuid.as_deref(), Some("00000000-0000-0000-0000-000000000002"));
+		assert_eq!(clone.devices.len(), vm.devices.len());
+		assert_eq!(clone.drives.len(), vm.drives.len());
+		assert_eq!(values(&clone.to_arguments().unwrap(), "-device"), values(&vm.to_arguments().unwrap(), "-device"));
+
+		vm.add_device(GuestAgent::new("test"))
+			.add_device(Tpm::managed(TpmInterface::Tis, "test"));
+		let clone = vm.clone_with("clone", "00000000-0000-0000-0000-000000000002").unwrap();
+		assert_eq!(clone.devices_of::<GuestAgent>().next().unwrap().socket_path, ga::socket_path("clone"));
+		assert_ne!(clone.devices_of::<GuestAgent>().next().unwrap().socket_path, vm.devices_of::<GuestAgent>().next().unwrap().socket_path);
+		assert_ne!(clone.devices_of::<Tpm>().next().unwrap().socket_path, vm.devices_of::<Tpm>().next().unwrap().socket_path);
+
+		let mut with_vsock = test_vm();
+		with_vsock.add_device(Vsock { id: String::from("vsock0"), guest_cid: 3 });
+		assert!(matches!(with_vsock.clone_with("clone", "00000000-0000-0000-0000-000000000002"), Err(VMCreateError::VsockCid(id)) if id == "vsock0"));
+
+		struct Opaque;
+		impl QemuOption for Opaque {
+			fn as_options(&self) -> Vec<String> {
+				Vec::new()
+			}
+		}
+
+		vm.add_device(Opaque);
+		assert!(matches!(vm.clone_with("clone", "00000000-0000-0000-0000-000000000002"), Err(VMCreateError::UncloneableDevice)));
+	}
+
+	#[test]
+	fn vm_names_are_safe_in_paths() {
+		for name in ["win11", "build-01", "web_server.prod", "a..b"] {
+			assert!(VirtualMachine::new(name).is_ok(), "{name}");
+		}
+
+		for name in ["", ".hidden", "..", "../escape", "a/b", "my vm", "vm,process=x", "vm\n"] {
+			assert!(matches!(VirtualMachine::new(name), Err(VMCreateError::InvalidName)), "{name}");
+		}
+	}
+
+	#[test]
+	fn blob_resources_need_shared_memory() {
+		let gpu = |venus| GraphicsAdapter::VirtioGpu { blob: true, hostmem: Some(ByteSize::mib(256)), vga: false, virgl: false, venus };
+		let memory = |share| Memory { size: ByteSize::gib(1), prealloc: false, backend: Some(MemoryBackend::Memfd { share, hugepage_size: None }), max_size: None, slots: None };
+
+		assert_eq!(gpu(false).as_options(), ["-device", "virtio-gpu-pci,id=vm.vga,blob=on,hostmem=256M"]);
+
+		let mut vm = test_vm();
+		assert!(matches!(gpu(false).validate(&vm), Err(ValidationError::UnsupportedMachineType { requires: "the Q35 machine type" })));
+
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.add_device(memory(false));
+		assert!(matches!(gpu(false).validate(&vm), Err(ValidationError::MemoryNotShared)));
+
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.add_device(memory(true));
+		assert!(gpu(false).validate(&vm).is_ok());
+		assert!(matches!(gpu(true).validate(&vm), Err(ValidationError::InvalidField { field: "venus", reason: "needs virgl" })));
+	}
+
+	#[test]
+	fn virgl_and_venus_pick_the_gl_device() {
+		let gpu = |vga, virgl, venus| GraphicsAdapter::VirtioGpu { blob: venus, hostmem: venus.then(|| ByteSize::mib(256)), vga, virgl, venus };
+		let device = |gpu: GraphicsAdapter| gpu.as_options()[1].clone();
+
+		assert_eq!(device(gpu(false, false, false)), "virtio-gpu-pci,id=vm.vga");
+		assert_eq!(device(gpu(true, false, false)), "virtio-vga,id=vm.vga");
+		assert_eq!(device(gpu(true, true, false)), "virtio-vga-gl,id=vm.vga");
+		assert_eq!(device(gpu(false, true, true)), "virtio-gpu-gl-pci,id=vm.vga,venus=on,blob=on,hostmem=256M");
+		assert!(gpu(false, true, false).provides_gl());
+		assert!(!gpu(true, false, false).provides_gl());
+
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.set_display_backend(DisplayBackend::Gtk { gl: true })
+			.add_device(Memory { size: ByteSize::gib(1), prealloc: false, backend: Some(MemoryBackend::Memfd { share: true, hugepage_size: None }), max_size: None, slots: None });
+		assert!(gpu(true, true, false).validate(&vm).is_ok());
+		assert!(gpu(false, true, true).validate(&vm).is_ok());
+
+		// Venus maps its resources through hostmem
+		let no_hostmem = GraphicsAdapter::VirtioGpu { blob: true, hostmem: None, vga: false, virgl: true, venus: true };
+		assert!(matches!(no_hostmem.validate(&vm), Err(ValidationError::InvalidField { field: "venus", reason: "needs blob and hostmem" })));
+
+		vm.set_display_backend(DisplayBackend::Gtk { gl: false });
+		assert!(matches!(gpu(false, true, false).validate(&vm), Err(ValidationError::NoGlDisplay)));
+		assert!(gpu(true, false, false).validate(&vm).is_ok());
+	}
+
+	#[test]
+	fn sound_cards_play_through_their_audiodev() {
+		let sound = |audio, backend: Option<AudioBackend>| {
+			let mut vm = test_vm();
+			vm.set_audio(audio);
+			if let Some(backend) = backend {
+				vm.set_audio_backend(backend);
+			}
+
+			let args = vm.to_arguments().unwrap();
+			let audiodev = args.iter().position(|arg| arg == "-audiodev").unwrap();
+			let card = args.iter().position(|arg| arg.contains("id=vm.sound")).unwrap();
+			// The card refers to the audiodev, so it has to come first
+			assert!(audiodev < card);
+			(args[audiodev + 1].clone(), args[card].clone())
+		};
+
+		assert_eq!(sound(Audio::Ac97, None), (String::from("none,id=vm.audio"), String::from("AC97,id=vm.sound,audiodev=vm.audio")));
+		assert_eq!(sound(Audio::VirtioSound, Some(AudioBackend::Pipewire)), (String::from("pipewire,id=vm.audio"), String::from("virtio-sound-pci,id=vm.sound,audiodev=vm.audio")));
+		assert_eq!(sound(Audio::Ac97, Some(AudioBackend::PulseAudio { server: Some(String::from("unix:/run/pulse,1")) })).0, "pa,id=vm.audio,server=unix:/run/pulse,,1");
+		assert_eq!(sound(Audio::Ac97, Some(AudioBackend::Alsa { device: Some(String::from("hw:0")) })).0, "alsa,id=vm.audio,in.dev=hw:0,out.dev=hw:0");
+
+		let mut vm = test_vm();
+		vm.set_audio_backend(AudioBackend::Pipewire);
+		assert!(matches!(AudioBackend::Pipewire.validate(&vm), Err(ValidationError::InvalidField { field: "audio_backend", .. })));
+		vm.set_audio(Audio::Ac97);
+		assert!(matches!(AudioBackend::Alsa { device: Some(String::new()) }.validate(&vm), Err(ValidationError::EmptyField { field: "device" })));
+	}
+
+	#[test]
+	fn hda_controller_follows_the_machine_type() {
+		let sound = |machine, controller| {
+			let mut vm = test_vm();
+			vm.set_machine_type(machine)
+				.set_audio(Audio::IntelHda { controller });
+			values(&vm.to_arguments().unwrap(), "-device")
+				.into_iter()
+				.filter(|device| device.contains("hda"))
+				.map(String::from)
+				.collect::<Vec<String>>()
+		};
+		let pc = || MachineType::Pc { acpi: true, usb: false };
+		let q35 = || MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false };
+
+		assert_eq!(sound(pc(), None), ["intel-hda,id=vm.sound", "hda-duplex,bus=vm.sound.0,audiodev=vm.audio"]);
+		assert_eq!(sound(q35(), None), ["ich9-intel-hda,id=vm.sound", "hda-duplex,bus=vm.sound.0,audiodev=vm.audio"]);
+		assert_eq!(sound(q35(), Some(HdaController::Ich6))[0], "intel-hda,id=vm.sound");
+		assert_eq!(sound(pc(), Some(HdaController::Ich9))[0], "ich9-intel-hda,id=vm.sound");
+
+		let vm = VirtualMachine::new("test").unwrap();
+		assert!(matches!(Audio::IntelHda { controller: None }.validate(&vm), Err(ValidationError::MissingMachineType)));
+	}
+
+	#[tokio::test]
+	async fn dropping_a_vm_kills_qemu() {
+		let (binary, started) = fake_qemu("fake-qemu-dropped");
+		let name = format!("dropped-{}", std::process::id());
+		let mut vm = fake_qemu_vm(&name, &binary);
+
+		let qmp_path = qmp::socket_path(&name);
+		let server = tokio::spawn(async move {
+			while std::fs::remove_file(&started).is_err() {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+			MockQmp::start_at(&qmp_path, |_, _| Ok(json!({})))
+		});
+
+		vm.start().await.unwrap();
+		let _server = server.await.unwrap();
+		let pid = vm.process.as_ref().and_then(Child::id).unwrap();
+		drop(vm);
+		assert!(!std::path::Path::new(&qmp::socket_path(&name)).exists());
+
+		// Gone, or at least dead and waiting to be reaped
+		let stat = format!("/proc/{pid}/stat");
+		let dead = || std::fs::read_to_string(&stat).map_or(true, |stat| stat.rsplit(')').next().is_some_and(|rest| rest.trim_start().starts_with('Z')));
+		for _ in 0..100 {
+			if dead() {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		assert!(dead());
+		std::fs::remove_file(&binary).unwrap();
+	}
+
+	#[test]
+	fn hotpluggable_memory_needs_slots_and_a_maximum() {
+		let memory = |max_size, slots| Memory { size: ByteSize::gib(2), prealloc: false, backend: None, max_size, slots };
+		let vm = test_vm();
+
+		assert_eq!(memory(None, None).as_options(), ["-m", "2G"]);
+		assert_eq!(memory(Some(ByteSize::gib(8)), Some(4)).as_options(), ["-m", "2G,slots=4,maxmem=8G"]);
+		assert!(memory(None, None).validate(&vm).is_ok());
+		assert!(memory(Some(ByteSize::gib(8)), Some(4)).validate(&vm).is_ok());
+
+		assert!(matches!(
+			memory(Some(ByteSize::gib(8)), Some(0)).validate(&vm),
+			Err(ValidationError::InvalidField { field: "slots", reason: "must be at least 1" })
+		));
+		assert!(matches!(
+			memory(Some(ByteSize::gib(1)), Some(4)).validate(&vm),
+			Err(ValidationError::InvalidField { field: "max_size", reason: "can't be smaller than size" })
+		));
+		assert!(matches!(
+			memory(None, Some(4)).validate(&vm),
+			Err(ValidationError::InvalidField { field: "max_size", reason: "max_size and slots have to be set together" })
+		));
+		assert!(matches!(memory(Some(ByteSize::gib(8)), None).validate(&vm), Err(ValidationError::InvalidField { field: "max_size", .. })));
+	}
 
-	name: String,
-	uuid: Option<String>,
-	machine: Option<MachineType>,
-	devices: Vec<Box<dyn QemuOption + 'a>>,
-	drives: Vec<Box<dyn QemuOption + 'a>>
+	#[cfg(feature = "testing")]
+	#[test]
+	fn edu_device_is_emitted() {
+		let mut vm = test_vm();
+		vm.add_device(EduDevice);
+		assert!(values(&vm.to_arguments().unwrap(), "-device").contains(&"edu"));
+	}
 
-}
+	#[test]
+	fn modern_linux_defaults_are_launchable() {
+		let mut vm = VirtualMachine::modern_linux_defaults("linux").unwrap();
+		// Whether /dev/kvm is usable here doesn't matter
+		vm.set_accelerator(Accelerator::Auto);
+		assert!(vm.validation_errors().is_empty());
+		assert!(vm.validation_warnings().is_empty());
 
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-machine"), ["q35,acpi=on,usb=on,hmat=off"]);
+		assert_eq!(values(&args, "-m"), ["4G"]);
+		assert_eq!(values(&args, "-netdev"), ["user,id=vm.netdev0"]);
+		assert_eq!(values(&args, "-device"), [
+			"virtio-scsi-pci,num_queues=6,iothread=vm.scsi0.block_thread,id=vm.scsi0",
+			"virtio-gpu-pci,id=vm.vga",
+			"virtio-rng-pci,rng=vm.rng0.rng,id=vm.rng0",
+			"virtio-net-pci,id=vm.net0,netdev=vm.netdev0"
+		]);
 
-impl<'a> VirtualMachine<'a> {
-	pub fn new(name: &str) -> Result<VirtualMachine<'a>, VMCreateError> {
-		let name_str = String::from(name);
+		// Still overridable
+		vm.set_machine_type(MachineType::Pc { acpi: true, usb: false });
+		assert_eq!(values(&vm.to_arguments().unwrap(), "-machine"), ["pc,acpi=on,usb=off"]);
+	}
 
-		if name_str.contains(' ') {
-			// stop that!
-			Err(VMCreateError::InvalidName)
-		} else {
-			Ok(VirtualMachine {
-				process: None,
-				name: name_str,
-				uuid: None,
-				machine: None,
-				devices: Vec::new(),
-				drives: Vec::new()
+	#[test]
+	fn ramfb_needs_a_display() {
+		let uuid = "a1b2c3d4-0000-0000-0000-000000000000";
+		let vgpu = GraphicsAdapter::VgpuVga {
+			uuid: String::from(uuid),
+			use_ramfb: true,
+			pci_vendor_id: None,
+			pci_device_id: None,
+			pci_sub_vendor_id: None,
+			pci_sub_device_id: None,
+			rombar: None,
+			romfile: None,
+			root_port: None
+		};
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.set_uuid(uuid)
+			.set_display_backend(DisplayBackend::None);
+
+		assert_eq!(vgpu.as_options(), ["-device", "vfio-pci-nohotplug,sysfsdev=/sys/bus/mdev/devices/a1b2c3d4-0000-0000-0000-000000000000,display=on,ramfb=on,id=vm.vgpu"]);
+		assert!(matches!(
+			vgpu.validate(&vm),
+			Err(ValidationError::InvalidField { field: "use_ramfb", reason: "there's no display to show it on" })
+		));
+
+		vm.set_display_backend(DisplayBackend::Gtk { gl: true });
+		assert!(vgpu.validate(&vm).is_ok());
+
+		// Region-based vGPU displays don't need GL
+		vm.set_display_backend(DisplayBackend::Gtk { gl: false });
+		assert!(vgpu.validate(&vm).is_ok());
+	}
+
+	#[test]
+	fn xhci_port_counts_are_limited() {
+		let xhci = |usb2_ports, usb3_ports| UsbController::Xhci { id: String::from("xhci"), usb2_ports, usb3_ports };
+		let vm = test_vm();
+
+		assert_eq!(xhci(None, None).as_options(), ["-device", "qemu-xhci,id=vm.xhci"]);
+		assert_eq!(xhci(Some(8), Some(15)).as_options(), ["-device", "qemu-xhci,id=vm.xhci,p2=8,p3=15"]);
+		assert_eq!(
+			UsbController::NecXhci { id: String::from("xhci"), usb2_ports: Some(2), usb3_ports: None }.as_options(),
+			["-device", "nec-usb-xhci,id=vm.xhci,p2=2"]
+		);
+
+		assert!(xhci(Some(8), Some(15)).validate(&vm).is_ok());
+		assert!(matches!(xhci(Some(0), None).validate(&vm), Err(ValidationError::InvalidField { field: "usb2_ports", .. })));
+		assert!(matches!(xhci(None, Some(16)).validate(&vm), Err(ValidationError::InvalidField { field: "usb3_ports", reason: "must be between 1 and 15" })));
+	}
+
+	#[test]
+	fn host_cache_info_needs_the_host_model() {
+		let mut vm = test_vm();
+		vm.set_accelerator(Accelerator::Kvm);
+
+		let cpu = Cpu { host_cache_info: true, dies: Some(2), sockets: Some(2), ..host_cpu() };
+		assert_eq!(cpu.as_options(), ["-cpu", "host,host-cache-info=on,-svm", "-smp", "8,sockets=2,dies=2,cores=2,threads=1,maxcpus=8"]);
+		assert!(cpu.validate(&vm).is_ok());
+
+		let qemu64 = Cpu { model: String::from("qemu64"), ..cpu.clone() };
+		assert!(matches!(
+			qemu64.validate(&vm),
+			Err(ValidationError::InvalidField { field: "host_cache_info", reason: "only works with the host CPU model" })
+		));
+
+		// The topology has to account for the dies too
+		let short = Cpu { max_vcpus: Some(4), ..cpu };
+		assert!(matches!(short.validate(&vm), Err(ValidationError::InvalidField { field: "max_vcpus", .. })));
+	}
+
+	#[test]
+	fn nvme_drives_are_a_controller_and_namespaces() {
+		let namespace = |nsid| NvmeNamespace { nsid, image_path: format!("/tmp/ns{nsid}.raw"), format: DiskFormat::Raw };
+		let nvme = |serial: Option<&str>, namespaces| DiskDrive::Nvme {
+			id: String::from("nvme"),
+			image_path: String::from("/tmp/nvme.qcow2"),
+			format: DiskFormat::Qcow2,
+			serial: serial.map(String::from),
+			namespaces
+		};
+		let vm = test_vm();
+
+		// The serial defaults to the id, and the image itself is namespace 1
+		assert_eq!(nvme(None, Vec::new()).as_options(), [
+			"-drive", "if=none,file=/tmp/nvme.qcow2,format=qcow2,id=vm.nvme.drive",
+			"-device", "nvme,id=vm.nvme,serial=nvme",
+			"-device", "nvme-ns,drive=vm.nvme.drive,nsid=1,bus=vm.nvme"
+		]);
+		assert_eq!(nvme(Some("SL0001"), vec![namespace(2)]).as_options()[2..], [
+			"-device", "nvme,id=vm.nvme,serial=SL0001",
+			"-device", "nvme-ns,drive=vm.nvme.drive,nsid=1,bus=vm.nvme",
+			"-drive", "if=none,file=/tmp/ns2.raw,format=raw,id=vm.nvme.ns2.drive",
+			"-device", "nvme-ns,drive=vm.nvme.ns2.drive,nsid=2,bus=vm.nvme"
+		][..]);
+
+		assert!(nvme(None, vec![namespace(2), namespace(3)]).validate(&vm).is_ok());
+		assert!(matches!(nvme(None, vec![namespace(1)]).validate(&vm), Err(ValidationError::InvalidField { field: "nsid", .. })));
+		assert!(matches!(nvme(Some(""), Vec::new()).validate(&vm), Err(ValidationError::EmptyField { field: "serial" })));
+	}
+
+	#[test]
+	fn nvme_subsystems_have_unique_namespaces() {
+		let namespace = |nsid| NvmeNamespace { nsid, image_path: format!("/tmp/ns{nsid}.qcow2"), format: DiskFormat::Qcow2 };
+		let subsystem = |namespaces| NvmeSubsystem { id: String::from("nvme0"), nqn: String::from("nqn.2019-08.org.qemu:sunlight"), namespaces };
+		let vm = test_vm();
+
+		let pair = subsystem(vec![namespace(1), namespace(2)]);
+		assert!(pair.validate(&vm).is_ok());
+		assert_eq!(pair.as_options(), [
+			"-device", "nvme-subsys,id=vm.nvme0,nqn=nqn.2019-08.org.qemu:sunlight",
+			"-device", "nvme,id=vm.nvme0.ctrl,serial=nvme0,subsys=vm.nvme0",
+			"-drive", "if=none,file=/tmp/ns1.qcow2,format=qcow2,id=vm.nvme0.ns1.drive",
+			"-device", "nvme-ns,drive=vm.nvme0.ns1.drive,nsid=1,bus=vm.nvme0.ctrl",
+			"-drive", "if=none,file=/tmp/ns2.qcow2,format=qcow2,id=vm.nvme0.ns2.drive",
+			"-device", "nvme-ns,drive=vm.nvme0.ns2.drive,nsid=2,bus=vm.nvme0.ctrl"
+		]);
+
+		let nsid_error = |subsystem: NvmeSubsystem| matches!(subsystem.validate(&vm), Err(ValidationError::InvalidField { field: "nsid", .. }));
+		assert!(nsid_error(subsystem(vec![namespace(1), namespace(1)])));
+		assert!(nsid_error(subsystem(vec![namespace(0)])));
+		assert!(matches!(subsystem(Vec::new()).validate(&vm), Err(ValidationError::EmptyField { field: "namespaces" })));
+	}
+
+	#[test]
+	fn usb_devices_go_on_a_usb_controller() {
+		let image = temp_path("usb-storage.img");
+		std::fs::write(&image, "").unwrap();
+		let storage = UsbDevice::Storage { id: String::from("stick"), image_path: String::from(image.to_str().unwrap()), format: DiskFormat::Raw, readonly: true };
+
+		let mut vm = test_vm();
+		vm.add_device(storage.clone());
+		assert!(matches!(storage.validate(&vm), Err(ValidationError::NoUsbBus)));
+
+		// The controller is added first, wherever it was listed
+		vm.add_device(UsbController::Uhci { id: String::from("uhci") });
+		let args = vm.to_arguments().unwrap();
+		std::fs::remove_file(&image).unwrap();
+		assert_eq!(values(&args, "-device"), ["piix3-usb-uhci,id=vm.uhci", "usb-storage,drive=vm.stick.drive,id=vm.stick"]);
+		assert_eq!(values(&args, "-drive").last().copied(), Some(format!("if=none,file={},format=raw,id=vm.stick.drive,readonly=on", image.display()).as_str()));
+
+		assert!(matches!(storage.validate(&vm), Err(ValidationError::PathNotFound { .. })));
+
+		let by_id = UsbDevice::HostById { id: String::from("key"), vendor_id: 0x1050, product_id: 0x0407 };
+		assert_eq!(by_id.as_options(), ["-device", "usb-host,vendorid=0x1050,productid=0x0407,id=vm.key"]);
+		let missing = UsbDevice::HostById { id: String::from("key"), vendor_id: 0xfffe, product_id: 0xfffe };
+		assert!(matches!(missing.validate(&vm), Err(ValidationError::UsbDeviceNotFound { device }) if device == "fffe:fffe"));
+
+		let by_port = |port: &str| UsbDevice::HostByPort { id: String::from("hub"), bus: 3, port: String::from(port) };
+		assert_eq!(by_port("1.4").as_options(), ["-device", "usb-host,hostbus=3,hostport=1.4,id=vm.hub"]);
+		assert!(matches!(by_port("").validate(&vm), Err(ValidationError::EmptyField { field: "port" })));
+	}
+
+	#[test]
+	fn usb_input_needs_a_usb_bus() {
+		assert_eq!(InputDevice::UsbMouse.as_options(), ["-device", "usb-mouse"]);
+		assert_eq!(InputDevice::UsbKeyboard.as_options(), ["-device", "usb-kbd"]);
+
+		// test_vm's machine has its USB bus off
+		let mut vm = test_vm();
+		assert!(matches!(InputDevice::UsbKeyboard.validate(&vm), Err(ValidationError::NoUsbBus)));
+
+		vm.add_device(UsbController::Xhci { id: String::from("xhci"), usb2_ports: None, usb3_ports: None });
+		assert!(InputDevice::UsbKeyboard.validate(&vm).is_ok());
+
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Pc { acpi: true, usb: true });
+		assert!(InputDevice::UsbMouse.validate(&vm).is_ok());
+	}
+
+	#[test]
+	fn rngs_read_from_their_source() {
+		let rng = |source: Option<&str>, max_bytes, period_ms| Rng::Virtio { id: String::new(), source: source.map(String::from), max_bytes, period_ms };
+
+		let mut vm = test_vm();
+		vm.add_device(rng(None, None, None));
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-object"), ["rng-builtin,id=vm.rng0.rng"]);
+		assert_eq!(values(&args, "-device"), ["virtio-rng-pci,rng=vm.rng0.rng,id=vm.rng0"]);
+
+		let mut limited = rng(Some("/dev/u,random"), Some(1024), Some(500));
+		limited.assign_id(1);
+		assert_eq!(limited.as_options(), [
+			"-object", "rng-random,id=vm.rng1.rng,filename=/dev/u,,random",
+			"-device", "virtio-rng-pci,rng=vm.rng1.rng,id=vm.rng1,max-bytes=1024,period=500"
+		]);
+
+		assert!(rng(Some("/dev/urandom"), Some(1024), None).validate(&vm).is_ok());
+		assert!(matches!(limited.validate(&vm), Err(ValidationError::PathNotFound { .. })));
+		assert!(matches!(rng(None, Some(0), None).validate(&vm), Err(ValidationError::InvalidField { field: "max_bytes", .. })));
+		assert!(matches!(rng(None, Some(1024), Some(0)).validate(&vm), Err(ValidationError::InvalidField { field: "period_ms", reason: "must be greater than 0" })));
+		assert!(matches!(rng(None, None, Some(1000)).validate(&vm), Err(ValidationError::InvalidField { field: "period_ms", reason: "only used with max_bytes" })));
+	}
+
+	#[test]
+	fn virtio_crypto_is_wired_to_its_backend() {
+		let mut vm = test_vm();
+		vm.add_device(VirtioCrypto { id: String::new() });
+
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-object"), ["cryptodev-backend-builtin,id=vm.crypto0.cryptodev"]);
+		assert_eq!(values(&args, "-device"), ["virtio-crypto-pci,cryptodev=vm.crypto0.cryptodev,id=vm.crypto0"]);
+	}
+
+	#[test]
+	fn host_ports_can_only_be_forwarded_once() {
+		let forward = |proto, host_addr: Option<&str>, host_port| PortForward { proto, host_addr: host_addr.map(String::from), host_port, guest_addr: None, guest_port: 22 };
+		let vm_with = |forwards: Vec<PortForward>| {
+			let mut vm = test_vm();
+			for (index, forward) in forwards.into_iter().enumerate() {
+				vm.add_device(Network::User { id: format!("lan{index}"), host_forwards: vec![forward] });
+			}
+			vm
+		};
+
+		let clash = vm_with(vec![forward(ForwardProtocol::Tcp, None, 2222), forward(ForwardProtocol::Tcp, Some("127.0.0.1"), 2222)]);
+		let errors = clash.validation_errors();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].error, ValidationError::HostPortClash { proto: "tcp", port: 2222 });
+		assert!(matches!(clash.to_arguments(), Err(VMQemuProcessStartError::ValidationFailed(..))));
+
+		// Different protocols, or different addresses, don't overlap
+		assert!(vm_with(vec![forward(ForwardProtocol::Tcp, None, 2222), forward(ForwardProtocol::Udp, None, 2222)]).to_arguments().is_ok());
+		assert!(vm_with(vec![forward(ForwardProtocol::Tcp, Some("127.0.0.1"), 2222), forward(ForwardProtocol::Tcp, Some("127.0.0.2"), 2222)]).to_arguments().is_ok());
+	}
+
+	#[test]
+	fn only_set_offloads_are_emitted() {
+		assert_eq!(VirtioNetOffloads::default().as_options(), "");
+
+		let offloads = VirtioNetOffloads { csum: Some(false), guest_tso4: Some(false), mrg_rxbuf: Some(true), ..VirtioNetOffloads::default() };
+		assert_eq!(offloads.as_options(), ",csum=off,guest_tso4=off,mrg_rxbuf=on");
+	}
+
+	#[test]
+	fn dependencies_come_before_their_dependents() {
+		let mut vm = test_vm();
+		vm.add_device(NetworkAdapter::Virtio {
+				id: String::from("nic"),
+				netdev: String::from("lan"),
+				mac: None,
+				rombar: None,
+				romfile: None,
+				failover: false,
+				host_mtu: None,
+				offloads: VirtioNetOffloads::default(),
+				queues: None
 			})
-		}
+			.add_drive(hd_drive("root", "/tmp/root.qcow2"))
+			.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(16) })
+			.add_device(Network::User { id: String::from("lan"), host_forwards: Vec::new() })
+			.add_device(DiskController::VirtioScsi { id: String::from("scsi") });
+
+		let labels = vm.to_arguments_annotated().unwrap()
+			.into_iter()
+			.map(|(label, _)| label)
+			.skip_while(|label| label != "NetworkAdapter::Virtio" && label != "Network::User" && label != "DiskController::VirtioScsi")
+			.collect::<Vec<String>>();
+		// The rest keep the order they were added in (with drives after devices)
+		assert_eq!(labels, ["Network::User", "DiskController::VirtioScsi", "NetworkAdapter::Virtio", "GraphicsAdapter::StdVga", "DiskDrive::HdDrive"]);
 	}
 
-	/// Set the name of this VM.
-	pub fn set_name(&mut self, name: &str) -> &mut VirtualMachine<'a> {
-		self.name = String::from(name);
-		self
+	#[test]
+	fn tcg_can_be_tuned() {
+		let tcg = |multithreaded, tb_size_mb| Accelerator::Tcg { multithreaded, tb_size_mb };
+		let vm = test_vm();
+
+		assert_eq!(tcg(None, None).as_options(), ["-accel", "tcg"]);
+		assert_eq!(tcg(Some(true), Some(256)).as_options(), ["-accel", "tcg,thread=multi,tb-size=256"]);
+		assert_eq!(tcg(Some(false), None).as_options(), ["-accel", "tcg,thread=single"]);
+
+		assert!(tcg(Some(true), Some(256)).validate(&vm).is_ok());
+		assert!(matches!(tcg(None, Some(0)).validate(&vm), Err(ValidationError::InvalidField { field: "tb_size_mb", .. })));
 	}
 
-	/// Set the UUID of this VM.
-	pub fn set_uuid(&mut self, uuid: &str) -> &mut VirtualMachine<'a> {
-		self.uuid = Some(String::from(uuid));
-		self
+	#[test]
+	fn s390x_has_no_pci_devices() {
+		let s390x = || {
+			let mut vm = test_vm();
+			vm.set_architecture(Architecture::S390x)
+				.set_machine_type(MachineType::S390CcwVirtio);
+			vm
+		};
+
+		let args = s390x().to_arguments().unwrap();
+		assert!(!args.iter().any(|arg| arg.contains("pcie-root-port") || arg.contains("ioh3420")));
+
+		let mut vm = s390x();
+		vm.add_root_port("rp");
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::RootPortsRequireQ35)));
+
+		let mut vm = s390x();
+		vm.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(16) });
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::PciDeviceWithoutPci)));
 	}
 
-	pub fn set_machine_type(&mut self, machine: MachineType) -> &mut VirtualMachine<'a> {
-		self.machine = Some(machine);
-		self
+	#[test]
+	fn every_numa_node_gets_a_memdev() {
+		let node = |size, backend, cpus| NumaNode { size, backend, prealloc: false, legacy_mem: false, cpus, initiator: None };
+		let numa = NumaTopology {
+			nodes: vec![
+				node(ByteSize::gib(1), None, vec![0]),
+				node(ByteSize::gib(1), Some(MemoryBackend::Memfd { share: true, hugepage_size: None }), vec![1])
+			],
+			hmat: Vec::new()
+		};
+		let memory = |backend| Memory { size: ByteSize::gib(2), prealloc: false, backend, max_size: None, slots: None };
+
+		// Nodes without a backend still get a memdev
+		assert_eq!(numa.as_options(), [
+			"-object", "memory-backend-ram,id=vm.numa0.ram,size=1G,share=off",
+			"-numa", "node,nodeid=0,memdev=vm.numa0.ram,cpus=0",
+			"-object", "memory-backend-memfd,id=vm.numa1.ram,size=1G,share=on",
+			"-numa", "node,nodeid=1,memdev=vm.numa1.ram,cpus=1"
+		]);
+
+		let mut vm = test_vm();
+		vm.add_device(Cpu { model: String::from("qemu64"), ..host_cpu() })
+			.add_device(memory(None));
+		assert!(numa.validate(&vm).is_ok());
+
+		let mut vm = test_vm();
+		vm.add_device(Cpu { model: String::from("qemu64"), ..host_cpu() })
+			.add_device(memory(Some(MemoryBackend::Ram { share: false })));
+		assert!(matches!(numa.validate(&vm), Err(ValidationError::InvalidField { field: "backend", .. })));
 	}
 
-	/// Add something which implements the Options trait to this VM.
-	pub fn add_device<T: QemuOption + 'a>(&mut self, dev: T) -> &mut VirtualMachine<'a> {
-		self.devices.push(Box::new(dev));
-		self
+	#[test]
+	fn numa_node_sizes_adding_up_past_u64_are_rejected() {
+		let node = |size, cpus| NumaNode { size, backend: None, prealloc: false, legacy_mem: false, cpus, initiator: None };
+		let numa = NumaTopology { nodes: vec![node(ByteSize::bytes(u64::MAX), vec![0]), node(ByteSize::gib(2), vec![1])], hmat: Vec::new() };
+		let mut vm = test_vm();
+		vm.add_device(Cpu { model: String::from("qemu64"), ..host_cpu() })
+			.add_device(Memory { size: ByteSize::gib(1), prealloc: false, backend: None, max_size: None, slots: None });
+
+		// Wrapping around would add up to 1G (or panic in debug builds)
+		assert!(matches!(numa.validate(&vm), Err(ValidationError::InvalidField { field: "size", .. })));
+		assert_eq!(ByteSize::bytes(u64::MAX) + ByteSize::gib(2), ByteSize::bytes(u64::MAX));
+		assert_eq!(ByteSize::gib(1) - ByteSize::gib(2), ByteSize::bytes(0));
+		assert_eq!(ByteSize::gib(1) * u64::MAX, ByteSize::bytes(u64::MAX));
+		assert_eq!(ByteSize::gib(u64::MAX), ByteSize::bytes(u64::MAX));
+		assert_eq!(ByteSize::kib(1 << 60), ByteSize::bytes(u64::MAX));
 	}
 
-	pub fn add_drive<T: QemuOption + 'a>(&mut self, dev: T) -> &mut VirtualMachine<'a> {
-		self.drives.push(Box::new(dev));
-		self
+	#[test]
+	fn disk_options_go_by_their_qemu_names() {
+		for format in [DiskFormat::Qcow2, DiskFormat::Raw, DiskFormat::Vmdk, DiskFormat::Vdi, DiskFormat::Vhdx, DiskFormat::Vpc] {
+			assert_eq!(format.to_string().parse::<DiskFormat>().unwrap(), format);
+		}
+		for mode in [CacheMode::Writeback, CacheMode::Writethrough, CacheMode::None, CacheMode::Unsafe, CacheMode::DirectSync] {
+			assert_eq!(String::from(mode).parse::<CacheMode>().unwrap(), mode);
+		}
+		for mode in [AioMode::Threads, AioMode::Native, AioMode::IoUring] {
+			assert_eq!(AioMode::try_from(mode.to_string()).unwrap(), mode);
+		}
+		for mode in [DetectZeroes::Off, DetectZeroes::On, DetectZeroes::Unmap] {
+			assert_eq!(mode.name().parse::<DetectZeroes>().unwrap(), mode);
+		}
+
+		assert_eq!("vpc".parse::<DiskFormat>().unwrap(), DiskFormat::Vpc);
+		assert_eq!("directsync".parse::<CacheMode>().unwrap(), CacheMode::DirectSync);
+		assert_eq!("io_uring".parse::<AioMode>().unwrap(), AioMode::IoUring);
+
+		// Names are QEMU's exactly, so no other spellings
+		assert!(matches!("QCOW2".parse::<DiskFormat>(), Err(InvalidDiskOption { kind: "disk format", value }) if value == "QCOW2"));
+		assert!(matches!("vhd".parse::<DiskFormat>(), Err(InvalidDiskOption { kind: "disk format", .. })));
+		assert!(matches!("direct-sync".parse::<CacheMode>(), Err(InvalidDiskOption { kind: "cache mode", .. })));
+		assert!(matches!("iouring".parse::<AioMode>(), Err(InvalidDiskOption { kind: "aio mode", .. })));
+		assert!(matches!("".parse::<AioMode>(), Err(InvalidDiskOption { kind: "aio mode", .. })));
+		assert!(matches!("yes".parse::<DetectZeroes>(), Err(InvalidDiskOption { kind: "detect-zeroes mode", .. })));
 	}
 
-	/// Generate the QEMU command arguments that will be used to run this VM. This includes some options
-	/// which are always generated, to aid Sunlight's out-of-band management of the VM.
-	pub fn to_arguments(&self) -> Result<Vec<String>, VMQemuProcessStartError> {
+	#[test]
+	fn mac_addresses_are_unicast() {
+		let parse = |addr: &str| addr.parse::<MacAddr>();
 
-		if self.machine.is_none() {
-			return Err(VMQemuProcessStartError::NoMachineType);
+		assert_eq!(parse("52:54:00:12:34:56").unwrap().octets(), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+		assert_eq!(parse("52:54:00:AB:cd:EF").unwrap().to_string(), "52:54:00:ab:cd:ef");
+
+		for bad in ["", "52:54:00:12:34", "52:54:00:12:34:56:78", "52-54-00-12-34-56", "52:54:00:12:34:5", "52:54:00:12:34:5g", "+2:54:00:12:34:56"] {
+			assert!(matches!(parse(bad), Err(InvalidMacAddr { reason: "expected six hex octets separated by colons", .. })), "{bad}");
 		}
 
-		let mut vec = vec![
-			String::from("-nodefaults"),
-			String::from("-accel kvm"),
-			format!("-name {},process=sunlight_{}", self.name, self.name),
-			self.machine.as_ref().unwrap().as_options()
+		// Multicast (and so broadcast) addresses can't be given to a NIC
+		for multicast in ["01:00:5e:00:00:01", "ff:ff:ff:ff:ff:ff", "53:54:00:12:34:56"] {
+			assert!(matches!(parse(multicast), Err(InvalidMacAddr { reason: "the multicast bit is set", .. })), "{multicast}");
+		}
+
+		for addr in ["52:54:00:12:34:56", "02:00:00:00:00:00", "fe:ff:ff:ff:ff:ff"] {
+			assert_eq!(parse(addr).unwrap().to_string(), addr);
+		}
+	}
+
+	#[test]
+	fn derived_mac_addresses_stay_the_same() {
+		let uuid = "00000000-0000-0000-0000-000000000001";
+
+		// Pinned, since a NIC's address changing between releases would look like a new NIC to the guest
+		assert_eq!(MacAddr::derive(uuid, "nic0").to_string(), "52:54:00:09:94:d8");
+		assert_eq!(MacAddr::derive(uuid, "nic0"), MacAddr::derive(uuid, "nic0"));
+		assert_ne!(MacAddr::derive(uuid, "nic0"), MacAddr::derive(uuid, "nic1"));
+		assert_ne!(MacAddr::derive(uuid, "nic0"), MacAddr::derive("00000000-0000-0000-0000-000000000002", "nic0"));
+		assert_eq!(MacAddr::derive(uuid, "nic0").octets()[..3], [0x52, 0x54, 0x00]);
+	}
+
+	#[test]
+	fn byte_sizes_are_written_like_qemu_sizes() {
+		let parse = |size: &str| size.parse::<ByteSize>().ok();
+
+		assert_eq!(parse("4K"), Some(ByteSize::kib(4)));
+		assert_eq!(parse("512m"), Some(ByteSize::mib(512)));
+		assert_eq!(parse("2G"), Some(ByteSize::gib(2)));
+		assert_eq!(parse("1T"), Some(ByteSize::gib(1024)));
+		assert_eq!(parse("1536B"), Some(ByteSize::bytes(1536)));
+		// No suffix is megabytes, like -m
+		assert_eq!(parse("64"), Some(ByteSize::mib(64)));
+
+		for bad in ["", "G", "1.5G", "-1G", "12X", "G12"] {
+			assert_eq!(parse(bad), None, "{bad}");
+		}
+
+		// Too big for a u64, either before or after the suffix is applied
+		assert_eq!(parse("18446744073709551616B"), None);
+		assert_eq!(parse("16777216T"), None);
+
+		assert_eq!(ByteSize::bytes(0).to_string(), "0B");
+		assert_eq!(ByteSize::bytes(1536).to_string(), "1536B");
+		assert_eq!(ByteSize::mib(1536).to_string(), "1536M");
+		assert_eq!(ByteSize::gib(2048).to_string(), "2T");
+
+		for size in [ByteSize::bytes(1), ByteSize::kib(3), ByteSize::mib(1025), ByteSize::gib(7), ByteSize::bytes(u64::MAX)] {
+			assert_eq!(parse(&size.to_string()), Some(size));
+		}
+	}
+
+	#[test]
+	fn numa_nodes_cant_mix_memdev_and_mem() {
+		let node = |legacy_mem, cpus| NumaNode { size: ByteSize::gib(1), backend: None, prealloc: false, legacy_mem, cpus, initiator: None };
+		let mut vm = test_vm();
+		vm.add_device(Cpu { model: String::from("qemu64"), ..host_cpu() })
+			.add_device(Memory { size: ByteSize::gib(2), prealloc: false, backend: None, max_size: None, slots: None });
+
+		let legacy = NumaTopology { nodes: vec![node(true, vec![0]), node(true, vec![1])], hmat: Vec::new() };
+		assert!(legacy.validate(&vm).is_ok());
+		assert_eq!(legacy.as_options(), [
+			"-numa", "node,nodeid=0,mem=1G,cpus=0",
+			"-numa", "node,nodeid=1,mem=1G,cpus=1"
+		]);
+
+		let mixed = NumaTopology { nodes: vec![node(false, vec![0]), node(true, vec![1])], hmat: Vec::new() };
+		assert!(matches!(mixed.validate(&vm), Err(ValidationError::MixedNumaMemory)));
+
+		let mut backed = legacy.clone();
+		backed.nodes[1].backend = Some(MemoryBackend::Ram { share: true });
+		assert!(matches!(backed.validate(&vm), Err(ValidationError::InvalidField { field: "backend", .. })));
+	}
+
+	#[test]
+	fn virtio_serial_has_room_for_every_port() {
+		let agents = |count: usize| {
+			let mut vm = test_vm();
+			for index in 0..count {
+				vm.add_device(GuestAgent { id: format!("qga{index}"), socket_path: format!("/tmp/qga{index}.sock") });
+			}
+			vm
+		};
+		let controller = |vm: &VirtualMachine| values(&vm.to_arguments().unwrap(), "-device")[0].to_owned();
+
+		assert!(!agents(0).to_arguments().unwrap().iter().any(|arg| arg.starts_with("virtio-serial-pci")));
+		assert_eq!(controller(&agents(2)), "virtio-serial-pci,id=vm.virtio-serial,max_ports=31");
+		// Sized to fit, with port 0 left for virtconsole
+		assert_eq!(controller(&agents(40)), "virtio-serial-pci,id=vm.virtio-serial,max_ports=41");
+
+		let mut vm = agents(2);
+		vm.set_virtio_serial_max_ports(3);
+		assert_eq!(controller(&vm), "virtio-serial-pci,id=vm.virtio-serial,max_ports=3");
+
+		vm.set_virtio_serial_max_ports(2);
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::ValidationFailed(errors))
+			if matches!(errors[..], [OptionValidationError { error: ValidationError::TooManyVirtioSerialPorts { ports: 2, max_ports: 2 }, .. }])));
+
+		vm.set_virtio_serial_max_ports(1);
+		assert!(matches!(vm.to_arguments(), Err(VMQemuProcessStartError::ValidationFailed(errors))
+			if matches!(errors[..], [OptionValidationError { error: ValidationError::InvalidVirtioSerialMaxPorts(511), .. }])));
+	}
+
+	#[tokio::test]
+	async fn channels_are_named_virtio_serial_ports() {
+		use tokio::io::AsyncReadExt;
+
+		let channel = |name: &str, backend| VirtioSerialChannel { id: String::new(), name: String::from(name), backend };
+		let socket = temp_path("channel.sock");
+
+		let mut vm = test_vm();
+		vm.add_device(channel("org.example.agent,0", SerialBackend::UnixSocket { path: socket.to_string_lossy().into_owned() }))
+			.add_device(GuestAgent { id: String::from("qga"), socket_path: String::from("/tmp/qga.sock") });
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-device"), [
+			"virtio-serial-pci,id=vm.virtio-serial,max_ports=31",
+			"virtserialport,bus=vm.virtio-serial.0,chardev=vm.channel0.chardev,name=org.example.agent,,0,id=vm.channel0",
+			"virtserialport,bus=vm.virtio-serial.0,chardev=vm.qga.chardev,name=org.qemu.guest_agent.0"
+		]);
+
+		// Names are how the guest tells ports apart, and stdio is shared with serial consoles
+		let mut invalid = test_vm();
+		invalid.add_device(channel("", SerialBackend::Pty))
+			.add_device(channel("org.example.log", SerialBackend::Pty))
+			.add_device(channel("org.example.log", SerialBackend::Pty))
+			.add_device(channel("org.example.shell", SerialBackend::Stdio))
+			.add_device(SerialConsole { id: String::from("serial0"), backend: SerialBackend::Stdio });
+		let errors = invalid.validation_errors();
+		assert_eq!(errors.len(), 5);
+		assert!(matches!(errors[0].error, ValidationError::EmptyField { field: "name" }));
+		assert!(matches!(errors[1].error, ValidationError::InvalidField { field: "name", .. }));
+		assert!(matches!(errors[2].error, ValidationError::InvalidField { field: "name", .. }));
+		assert!(matches!(errors[3].error, ValidationError::InvalidField { field: "backend", .. }));
+		assert!(matches!(errors[4].error, ValidationError::InvalidField { field: "backend", .. }));
+
+		let server = MockQmp::start("channel", |_, _| Ok(json!({})));
+		let mut running = running_test_vm(&server).await;
+		running.add_device(channel("org.example.agent.0", SerialBackend::UnixSocket { path: socket.to_string_lossy().into_owned() }));
+		assert!(matches!(running.channel("org.example.agent.1").await, Err(VMControlError::NoSuchChannel(name)) if name == "org.example.agent.1"));
+
+		let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+		tokio::spawn(async move {
+			use tokio::io::AsyncWriteExt;
+			let (mut guest, _) = listener.accept().await.unwrap();
+			guest.write_all(b"ready").await.unwrap();
+		});
+		let mut io = running.channel("org.example.agent.0").await.unwrap();
+		let mut greeting = [0u8; 5];
+		io.reader.read_exact(&mut greeting).await.unwrap();
+		std::fs::remove_file(&socket).unwrap();
+		assert_eq!(&greeting, b"ready");
+		assert!(server.commands().is_empty());
+	}
+
+	#[test]
+	fn vgpus_need_the_vm_uuid() {
+		let uuid = "a1b2c3d4-0000-0000-0000-000000000000";
+		let mut vm = test_vm();
+		vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat: false, legacy_root_port: false })
+			.add_device(GraphicsAdapter::VgpuVga {
+				uuid: String::from(uuid),
+				use_ramfb: false,
+				pci_vendor_id: None,
+				pci_device_id: None,
+				pci_sub_vendor_id: None,
+				pci_sub_device_id: None,
+				rombar: None,
+				romfile: None,
+				root_port: None
+			});
+
+		assert!(matches!(vm.validation_errors()[0].error, ValidationError::MissingUuid));
+
+		vm.set_uuid("00000000-0000-0000-0000-000000000001");
+		assert!(matches!(
+			&vm.validation_errors()[0].error,
+			ValidationError::UuidMismatch { vm, device } if vm == "00000000-0000-0000-0000-000000000001" && device == uuid
+		));
+
+		vm.set_uuid(uuid);
+		let args = vm.to_arguments().unwrap();
+		assert_eq!(values(&args, "-uuid"), [uuid]);
+		assert!(values(&args, "-device").iter().any(|device| device.starts_with("vfio-pci-nohotplug,sysfsdev=/sys/bus/mdev/devices/a1b2c3d4")));
+	}
+
+	#[test]
+	fn block_sizes_are_checked() {
+		let drive = |logical, physical| {
+			let mut drive = hd_drive("root", "/tmp/root.qcow2");
+			if let DiskDrive::HdDrive { logical_block_size, physical_block_size, .. } = &mut drive {
+				*logical_block_size = logical;
+				*physical_block_size = physical;
+			}
+			drive
+		};
+		let vm = test_vm();
+
+		assert_eq!(values(&drive(Some(4096), Some(4096)).as_options(), "-device"), ["virtio-blk-pci,id=vm.root,drive=vm.root.drive,logical_block_size=4096,physical_block_size=4096"]);
+		assert!(drive(Some(4096), Some(4096)).validate(&vm).is_ok());
+		// 512e: 4K physical sectors, emulating 512 byte ones
+		assert!(drive(None, Some(4096)).validate(&vm).is_ok());
+
+		assert!(matches!(
+			drive(Some(4096), Some(512)).validate(&vm),
+			Err(ValidationError::InvalidField { field: "physical_block_size", reason: "can't be smaller than the logical block size" })
+		));
+		assert!(matches!(drive(Some(4096), None).validate(&vm), Err(ValidationError::InvalidField { field: "physical_block_size", .. })));
+		assert!(matches!(drive(Some(3000), None).validate(&vm), Err(ValidationError::InvalidField { field: "logical_block_size", .. })));
+		assert!(matches!(drive(None, Some(256)).validate(&vm), Err(ValidationError::InvalidField { field: "physical_block_size", reason: "must be a power of two, and at least 512" })));
+	}
+
+	#[test]
+	fn nine_p_shares_use_the_split_form() {
+		let share = |legacy_virtfs| NinePShare {
+			id: String::from("share"),
+			path: String::from("/srv/share"),
+			mount_tag: String::from("host"),
+			security_model: NinePSecurityModel::Mapped,
+			legacy_virtfs
+		};
+
+		assert_eq!(share(false).as_options(), [
+			"-fsdev", "local,id=vm.share.fsdev,path=/srv/share,security_model=mapped-xattr",
+			"-device", "virtio-9p-pci,id=vm.share,fsdev=vm.share.fsdev,mount_tag=host"
+		]);
+		assert_eq!(share(true).as_options(), ["-virtfs", "local,id=vm.share,path=/srv/share,mount_tag=host,security_model=mapped-xattr"]);
+
+		let vm = test_vm();
+		let untagged = NinePShare { mount_tag: String::new(), ..share(false) };
+		assert!(matches!(untagged.validate(&vm), Err(ValidationError::EmptyField { field: "mount_tag" })));
+
+		let missing = NinePShare { path: temp_path("missing-share").to_string_lossy().into_owned(), ..share(false) };
+		assert!(matches!(missing.validate(&vm), Err(ValidationError::PathNotFound { .. })));
+	}
+
+	#[tokio::test]
+	async fn start_runs_qemu_with_the_generated_arguments() {
+		let (binary, started) = fake_qemu("fake-qemu-args");
+		let name = format!("args-{}", std::process::id());
+		let mut vm = fake_qemu_vm(&name, &binary);
+		vm.add_device(Cpu { model: String::from("qemu64"), ..host_cpu() });
+
+		let qmp_path = qmp::socket_path(&name);
+		let server = tokio::spawn(async move {
+			loop {
+				if let Ok(args) = std::fs::read_to_string(&started) {
+					std::fs::remove_file(&started).unwrap();
+					return (args, MockQmp::start_at(&qmp_path, |_, _| Ok(json!({}))));
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		});
+
+		vm.start().await.unwrap();
+		let (args, _server) = server.await.unwrap();
+		assert_eq!(args.lines().collect::<Vec<&str>>(), vm.to_arguments().unwrap());
+		assert_eq!(vm.state(), VMState::Started);
+
+		vm.force_stop().await.unwrap();
+		std::fs::remove_file(&binary).unwrap();
+	}
+
+	#[test]
+	fn arguments_are_separate_argv_entries() {
+		let mut vm = test_vm();
+		vm.add_drive(hd_drive("root", "/tmp/my disks/root,1.qcow2"));
+
+		let args = vm.to_arguments().unwrap();
+		assert!(args.iter().all(|arg| !arg.starts_with('-') || !arg.contains(' ')));
+		assert_eq!(values(&args, "-machine"), ["pc,acpi=on,usb=off"]);
+		// Spaces stay as they are, and commas are escaped for QEMU's option parser
+		assert_eq!(values(&args, "-drive"), ["if=none,file=/tmp/my disks/root,,1.qcow2,format=qcow2,id=vm.root.drive,readonly=off"]);
+
+		let command = vm.to_command_string().unwrap();
+		assert!(command.contains(" -drive 'if=none,file=/tmp/my disks/root,,1.qcow2,format=qcow2,id=vm.root.drive,readonly=off' "), "{command}");
+	}
+
+	#[tokio::test]
+	async fn lifecycle_commands_go_over_qmp() {
+		let server = MockQmp::start("lifecycle", |_, _| Ok(json!({})));
+		let mut vm = test_vm();
+		vm.set_shutdown_timeout(Duration::from_millis(100));
+
+		assert!(matches!(vm.pause().await, Err(VMControlError::InvalidState(VMState::Stopped))));
+		assert!(matches!(vm.shutdown().await, Err(VMControlError::InvalidState(VMState::Stopped))));
+
+		vm.process = Some(Command::new("sleep").arg("30").kill_on_drop(true).spawn().unwrap());
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+
+		vm.pause().await.unwrap();
+		vm.resume().await.unwrap();
+		vm.reset().await.unwrap();
+
+		// The "guest" ignores the power button, so QEMU gets SIGTERM
+		vm.shutdown().await.unwrap();
+		assert_eq!(server.commands(), ["stop", "cont", "system_reset", "system_powerdown"]);
+		assert_eq!(vm.state(), VMState::Stopped);
+		assert!(vm.process.is_none() && vm.qmp.is_none());
+	}
+
+	#[tokio::test]
+	async fn force_stop_quits_qemu() {
+		let server = MockQmp::start("force-stop", |_, _| Ok(json!({})));
+		let mut vm = test_vm();
+		vm.set_shutdown_timeout(Duration::from_millis(100));
+		vm.process = Some(Command::new("sleep").arg("30").kill_on_drop(true).spawn().unwrap());
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Crashed);
+
+		// Even a crashed VM can be gotten rid of
+		vm.force_stop().await.unwrap();
+		assert_eq!(server.commands(), ["quit"]);
+		assert_eq!(vm.state(), VMState::Stopped);
+	}
+
+	#[tokio::test]
+	async fn states_only_move_along_valid_transitions() {
+		let mut vm = test_vm();
+		let mut state = vm.subscribe_state();
+		assert_eq!(*state.borrow_and_update(), VMState::Stopped);
+
+		assert_eq!(vm.transition(&[VMState::Started], VMState::Stopping), Err(VMState::Stopped));
+		assert!(!state.has_changed().unwrap());
+
+		vm.transition(&[VMState::Stopped], VMState::Starting).unwrap();
+		assert!(state.has_changed().unwrap());
+		assert_eq!(*state.borrow_and_update(), VMState::Starting);
+		assert!(matches!(vm.start().await, Err(VMStartError::InvalidState(VMState::Starting))));
+
+		// A start which fails goes back to Stopped
+		let mut vm = VirtualMachine::new("no-machine").unwrap();
+		vm.set_accelerator(Accelerator::Tcg { multithreaded: None, tb_size_mb: None });
+		let mut state = vm.subscribe_state();
+		assert!(vm.start().await.is_err());
+		assert!(state.has_changed().unwrap());
+		assert_eq!(*state.borrow_and_update(), VMState::Stopped);
+	}
+
+	#[test]
+	fn every_validation_failure_is_reported() {
+		let mut vm = test_vm();
+		vm.add_device(GraphicsAdapter::StdVga { vgamem: ByteSize::mib(1024) })
+			.add_device(Tpm { interface: TpmInterface::Tis, socket_path: String::new(), managed: false })
+			.add_drive(hd_drive("root", "/tmp/root.qcow2"));
+
+		let Err(VMQemuProcessStartError::ValidationFailed(errors)) = vm.to_arguments() else {
+			panic!("validation should have failed");
+		};
+		assert_eq!(errors.iter().map(|error| error.option.as_str()).collect::<Vec<&str>>(), ["GraphicsAdapter::StdVga", "Tpm"]);
+		assert!(matches!(errors[1].error, ValidationError::EmptyField { field: "socket_path" }));
+
+		let message = VMQemuProcessStartError::ValidationFailed(errors).to_string();
+		assert!(message.starts_with("2 option(s) failed validation: GraphicsAdapter::StdVga: "), "{message}");
+	}
+
+	#[test]
+	fn uefi_gets_its_own_variable_store() {
+		let dir = temp_path("uefi");
+		let state_dir = dir.join("state");
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("OVMF_CODE.fd"), "code").unwrap();
+		std::fs::write(dir.join("OVMF_VARS.fd"), "template").unwrap();
+
+		let firmware = Firmware::Uefi {
+			code_path: dir.join("OVMF_CODE.fd").to_string_lossy().into_owned(),
+			vars_template: dir.join("OVMF_VARS.fd").to_string_lossy().into_owned()
+		};
+		let mut vm = test_vm();
+		vm.set_firmware(firmware.clone())
+			.set_state_dir(state_dir.to_str().unwrap());
+
+		let vars = Firmware::vars_path(state_dir.to_str().unwrap());
+		assert_eq!(values(&vm.to_arguments().unwrap(), "-drive"), [
+			format!("if=pflash,file={},format=raw,id=vm.firmware.code.drive,readonly=on", dir.join("OVMF_CODE.fd").display()),
+			format!("if=pflash,file={vars},format=raw,id=vm.firmware.vars.drive,readonly=off")
+		]);
+		assert!(firmware.validate().is_ok());
+
+		// Copied on first boot, and left alone after that
+		firmware.prepare(state_dir.to_str().unwrap()).unwrap();
+		assert_eq!(std::fs::read_to_string(&vars).unwrap(), "template");
+		std::fs::write(&vars, "changed").unwrap();
+		firmware.prepare(state_dir.to_str().unwrap()).unwrap();
+		assert_eq!(std::fs::read_to_string(&vars).unwrap(), "changed");
+
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert!(matches!(firmware.validate(), Err(ValidationError::PathNotFound { .. })));
+		assert!(Firmware::Bios.drives("/nonexistent").is_empty());
+	}
+
+	#[test]
+	fn pci_passthrough_options() {
+		let passthrough = PciPassthrough {
+			id: String::from("gpu"),
+			host_address: String::from("0000:ff:1f.7"),
+			rombar: Some(false),
+			romfile: Some(String::from("/opt/roms/gpu,1.rom")),
+			multifunction: true,
+			rebind_driver: false,
+			failover_pair_id: None,
+			root_port: None
+		};
+
+		assert_eq!(passthrough.as_options(), ["-device", "vfio-pci,host=0000:ff:1f.7,id=vm.gpu,multifunction=on,rombar=0,romfile=/opt/roms/gpu,,1.rom"]);
+		assert_eq!(values(&passthrough.as_options_on_port("vm.rp0"), "-device"), ["vfio-pci,host=0000:ff:1f.7,id=vm.gpu,multifunction=on,rombar=0,romfile=/opt/roms/gpu,,1.rom,bus=vm.rp0,addr=0x0"]);
+		assert!(passthrough.wants_root_port());
+
+		// There's no such host device
+		assert!(matches!(
+			passthrough.validate(&test_vm()),
+			Err(ValidationError::PathNotFound { path }) if path == "/sys/bus/pci/devices/0000:ff:1f.7"
+		));
+	}
+
+	#[tokio::test]
+	async fn guest_agents_are_virtio_serial_ports() {
+		let mut vm = test_vm();
+		vm.add_device(GuestAgent { id: String::from("qga"), socket_path: String::from("/tmp/a,b.qga") });
+
+		let args = vm.to_arguments().unwrap();
+		assert!(values(&args, "-chardev").contains(&"socket,id=vm.qga.chardev,path=/tmp/a,,b.qga,server=on,wait=off"));
+		assert!(values(&args, "-device").contains(&"virtserialport,bus=vm.virtio-serial.0,chardev=vm.qga.chardev,name=org.qemu.guest_agent.0"));
+
+		let unset = GuestAgent { id: String::from("qga"), socket_path: String::new() };
+		assert!(matches!(unset.validate(&vm), Err(ValidationError::EmptyField { field: "socket_path" })));
+
+		assert!(matches!(vm.guest_agent().await, Err(VMControlError::InvalidState(VMState::Stopped))));
+
+		let server = MockQmp::start("no-agent", |_, _| Ok(json!({})));
+		let mut vm = test_vm();
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+		assert!(matches!(vm.guest_agent().await, Err(VMControlError::NoGuestAgent)));
+	}
+
+	#[tokio::test]
+	async fn block_jobs_run_to_completion() {
+		use serde_json::Value;
+		use std::collections::BTreeMap;
+
+		// Jobs are ready as soon as they start, and conclude once they're completed or cancelled.
+		// Streaming "root" fails straight away.
+		let jobs = Arc::new(Mutex::new(BTreeMap::<String, Value>::new()));
+		let server = MockQmp::start("block-jobs", {
+			let jobs = jobs.clone();
+			move |execute, args| {
+				let mut jobs = jobs.lock().unwrap();
+				let id = args["job-id"].as_str().or(args["id"].as_str()).unwrap_or_default().to_owned();
+				match execute {
+					"block-commit" | "block-stream" => {
+						let job = match id.as_str() {
+							"vm.root.stream" => json!({ "id": id, "type": "stream", "status": "concluded", "current-progress": 0, "total-progress": 1, "error": "No space left on device" }),
+							_ => json!({ "id": id, "type": "commit", "status": "ready", "current-progress": 1, "total-progress": 1 })
+						};
+						jobs.insert(id, job);
+					},
+					"job-complete" | "job-cancel" => jobs.get_mut(&id).unwrap()["status"] = json!("concluded"),
+					"job-dismiss" => { jobs.remove(&id); },
+					"query-jobs" => return Ok(Value::Array(jobs.values().cloned().collect())),
+					_ => {}
+				}
+				Ok(json!({}))
+			}
+		});
+
+		let mut vm = test_vm();
+		assert!(matches!(vm.snapshot_disk("root", "/tmp/root.overlay.qcow2").await, Err(VMControlError::InvalidState(VMState::Stopped))));
+
+		vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+		vm.state.send_replace(VMState::Started);
+
+		vm.snapshot_disk("root", "/tmp/root.overlay.qcow2").await.unwrap();
+		let snapshot = &server.received()[0];
+		assert_eq!(snapshot.execute, "blockdev-snapshot-sync");
+		assert_eq!(snapshot.arguments, json!({
+			"device": "vm.root.drive",
+			"snapshot-file": "/tmp/root.overlay.qcow2",
+			"format": "qcow2",
+			"mode": "absolute-paths"
+		}));
+
+		// An active commit has to be told to complete once it's ready
+		let commit = vm.block_commit("root").await.unwrap();
+		assert_eq!(commit.id(), "vm.root.commit");
+		commit.wait().await.unwrap();
+		assert_eq!(server.commands()[1..], ["block-commit", "query-jobs", "job-complete", "query-jobs", "job-dismiss"]);
+		assert_eq!(server.received()[1].arguments["auto-dismiss"], json!(false));
+
+		let stream = vm.block_stream("root").await.unwrap();
+		assert!(matches!(stream.wait().await, Err(VMControlError::BlockJobFailed { job, reason }) if job == "vm.root.stream" && reason == "No space left on device"));
+		assert!(jobs.lock().unwrap().is_empty());
+
+		let cancelled = vm.block_stream("data").await.unwrap();
+		cancelled.cancel().await.unwrap();
+		let commands = server.commands();
+		assert_eq!(commands[commands.len() - 4..], ["block-stream", "job-cancel", "query-jobs", "job-dismiss"]);
+	}
+
+	#[test]
+	fn memory_backends_replace_mem_prealloc() {
+		let memory = |prealloc, backend| Memory { size: ByteSize::gib(2), prealloc, backend, max_size: None, slots: None };
+
+		assert_eq!(memory(false, None).as_options(), ["-m", "2G"]);
+		assert_eq!(memory(true, None).as_options(), [
+			"-m", "2G",
+			"-object", "memory-backend-ram,id=vm.ram,size=2G,share=off,prealloc=on",
+			"-machine", "memory-backend=vm.ram"
+		]);
+		assert_eq!(memory(false, Some(MemoryBackend::Memfd { share: true, hugepage_size: Some(ByteSize::mib(2)) })).as_options()[3],
+			"memory-backend-memfd,id=vm.ram,size=2G,share=on,hugetlb=on,hugetlbsize=2M");
+		assert_eq!(memory(false, Some(MemoryBackend::File { mem_path: String::from("/tmp/a,b"), share: true, hugepage_size: None })).as_options()[3],
+			"memory-backend-file,id=vm.ram,size=2G,mem-path=/tmp/a,,b,share=on");
+
+		let vm = test_vm();
+		let backend = |backend| memory(false, Some(backend)).validate(&vm);
+		let file = |mem_path: &str, hugepage_size| backend(MemoryBackend::File { mem_path: String::from(mem_path), share: false, hugepage_size });
+
+		assert!(matches!(
+			Memory { size: ByteSize::kib(1536), ..memory(false, None) }.validate(&vm),
+			Err(ValidationError::InvalidField { field: "size", .. })
+		));
+		assert!(matches!(
+			backend(MemoryBackend::Memfd { share: false, hugepage_size: Some(ByteSize::bytes(0)) }),
+			Err(ValidationError::InvalidField { field: "hugepage_size", .. })
+		));
+		assert!(file("/tmp/guest-ram", None).is_ok());
+		// A bare file name is created in the current directory
+		assert!(file("guest-ram", None).is_ok());
+		assert!(matches!(file("", None), Err(ValidationError::EmptyField { field: "mem_path" })));
+		assert!(matches!(file("/nonexistent/guest-ram", None), Err(ValidationError::PathNotFound { .. })));
+		// /tmp isn't a hugetlbfs mount
+		assert!(matches!(file("/tmp/guest-ram", Some(ByteSize::mib(2))), Err(ValidationError::NoHugepageMount { size, .. }) if size == "2M"));
+	}
+
+	#[test]
+	fn hmat_describes_nodes_with_cpus() {
+		let node = |cpus, initiator| NumaNode { size: ByteSize::gib(1), backend: None, prealloc: false, legacy_mem: false, cpus, initiator };
+		let entry = |initiator, target, data_type, latency_ns, bandwidth| HmatLatencyBandwidth { initiator, target, data_type, latency_ns, bandwidth };
+		let numa = |nodes, hmat| NumaTopology { nodes, hmat };
+		// A node with both vCPUs, and a memory-only node
+		let nodes = || vec![node(vec![0, 1], Some(0)), node(Vec::new(), Some(0))];
+		let hmat = || vec![
+			entry(0, 0, HmatDataType::AccessLatency, Some(5), None),
+			entry(0, 1, HmatDataType::AccessBandwidth, None, Some(ByteSize::gib(10)))
 		];
 
-		// Append devices and drives from the configuration
-		vec.append(&mut join_options(&self.devices, self));
-		vec.append(&mut join_options(&self.drives, self));
+		assert_eq!(numa(nodes(), hmat()).as_options()[4..], [
+			"-object", "memory-backend-ram,id=vm.numa1.ram,size=1G,share=off",
+			"-numa", "node,nodeid=1,memdev=vm.numa1.ram,initiator=0",
+			"-numa", "hmat-lb,initiator=0,target=0,hierarchy=memory,data-type=access-latency,latency=5",
+			"-numa", "hmat-lb,initiator=0,target=1,hierarchy=memory,data-type=access-bandwidth,bandwidth=10G"
+		]);
+
+		let machine = |hmat| {
+			let mut vm = test_vm();
+			vm.set_machine_type(MachineType::Q35 { acpi: true, usb: false, hmat, legacy_root_port: false })
+				.add_device(Cpu { model: String::from("qemu64"), ..host_cpu() })
+				.add_device(Memory { size: ByteSize::gib(2), prealloc: false, backend: None, max_size: None, slots: None });
+			vm
+		};
+		let vm = machine(true);
+		let invalid = |topology: NumaTopology, vm: &VirtualMachine| match topology.validate(vm) {
+			Err(ValidationError::InvalidField { field, .. }) => field,
+			result => panic!("expected an invalid field, got {:?}", result.err())
+		};
+
+		assert!(numa(nodes(), hmat()).validate(&vm).is_ok());
+		assert!(matches!(numa(Vec::new(), Vec::new()).validate(&vm), Err(ValidationError::EmptyField { field: "nodes" })));
+		assert!(matches!(numa(nodes(), hmat()).validate(&test_vm()), Err(ValidationError::NoMemory)));
 
-		let split_arguments = |vec : &Vec<String>| {
-			let mut ret_vec = Vec::new();
-			vec.iter()
-				.for_each(|opt| {
-					// this is dumb but it SHOULD work for now, I suppose
-					// it's not like it has to be particularly high performance anyways,
-					// since it ends up only getting called hopefully a few times in lifecycle
-					let split = opt
-						.split(' ')
-						.collect::<Vec<&str>>();
+		// Against the Cpu (which has 2 vCPUs) and Memory (2G)
+		assert_eq!(invalid(numa(vec![node(vec![0, 2], Some(0)), node(Vec::new(), Some(0))], Vec::new()), &vm), "cpus");
+		assert_eq!(invalid(numa(vec![node(vec![0], Some(0)), node(vec![0], Some(1))], Vec::new()), &vm), "cpus");
+		assert_eq!(invalid(numa(vec![node(vec![0, 1], Some(0))], Vec::new()), &vm), "size");
+		assert_eq!(invalid(numa(vec![node(vec![0, 1], Some(1)), node(Vec::new(), Some(1))], Vec::new()), &vm), "initiator");
 
-					for str in split {
-						ret_vec.push(str.to_string());
-					}
-				});
-		
-			ret_vec
+		// HMAT needs every node's initiator, and the machine type to have it enabled
+		assert!(matches!(numa(vec![node(vec![0, 1], Some(0)), node(Vec::new(), None)], Vec::new()).validate(&vm), Err(ValidationError::EmptyField { field: "initiator" })));
+		assert!(numa(vec![node(vec![0, 1], None), node(Vec::new(), None)], Vec::new()).validate(&machine(false)).is_ok());
+		assert!(matches!(numa(nodes(), hmat()).validate(&machine(false)), Err(ValidationError::UnsupportedMachineType { .. })));
+
+		assert_eq!(invalid(numa(nodes(), vec![entry(1, 0, HmatDataType::ReadLatency, Some(5), None)]), &vm), "initiator");
+		assert_eq!(invalid(numa(nodes(), vec![entry(0, 2, HmatDataType::ReadLatency, Some(5), None)]), &vm), "target");
+		assert_eq!(invalid(numa(nodes(), vec![entry(0, 1, HmatDataType::WriteLatency, None, Some(ByteSize::gib(1)))]), &vm), "latency_ns");
+		assert_eq!(invalid(numa(nodes(), vec![entry(0, 1, HmatDataType::ReadBandwidth, Some(5), None)]), &vm), "bandwidth");
+	}
+
+	#[test]
+	fn spice_listens_on_a_port_or_a_socket() {
+		// A SPICE display on a port without a password, with some fields changed
+		macro_rules! spice {
+			($($field:ident: $value:expr),*) => {{
+				#[allow(unused_mut)]
+				let mut display = DisplayBackend::Spice {
+					port: Some(5930),
+					addr: None,
+					unix_socket: None,
+					tls: None,
+					disable_ticketing: true,
+					password_file: None,
+					agent: false,
+					usb_redirect: 0,
+					gl: false,
+					rendernode: None
+				};
+				if let DisplayBackend::Spice { $($field,)* .. } = &mut display {
+					$(*$field = $value;)*
+				}
+				display
+			}};
+		}
+
+		assert_eq!(spice!().as_options(), ["-spice", "port=5930,disable-ticketing=on", "-display", "none"]);
+
+		let x509_dir = temp_path("spice-x509");
+		std::fs::create_dir_all(&x509_dir).unwrap();
+		for file in ["ca-cert.pem", "server-cert.pem", "password"] {
+			std::fs::write(x509_dir.join(file), "").unwrap();
+		}
+		let password = x509_dir.join("password").to_string_lossy().into_owned();
+		let x509_dir = x509_dir.to_string_lossy().into_owned();
+
+		let full = spice!(
+			addr: Some(String::from("127.0.0.1")),
+			tls: Some(SpiceTls { port: 5931, x509_dir: x509_dir.clone() }),
+			disable_ticketing: false,
+			password_file: Some(password.clone()),
+			agent: true,
+			usb_redirect: 1
+		);
+		assert_eq!(full.as_options(), [
+			"-object", format!("secret,id=vm.spice.password,file={password}").as_str(),
+			"-spice", format!("port=5930,addr=127.0.0.1,tls-port=5931,x509-dir={x509_dir},password-secret=vm.spice.password").as_str(),
+			"-chardev", "spicevmc,id=vm.spice.vdagent,name=vdagent",
+			"-device", "virtserialport,bus=vm.virtio-serial.0,chardev=vm.spice.vdagent,name=com.redhat.spice.0",
+			"-chardev", "spicevmc,id=vm.spice.usbredir0,name=usbredir",
+			"-device", "usb-redir,chardev=vm.spice.usbredir0,id=vm.spice.usbredir0.dev",
+			"-display", "none"
+		]);
+
+		// The agent's port goes on the shared virtio-serial controller, along with any others
+		let mut vm = test_vm();
+		vm.set_display_backend(full.clone())
+			.add_device(GuestAgent::new("test"));
+		assert_eq!(vm.virtio_serial_layout(), Some((2, VIRTIO_SERIAL_DEFAULT_PORTS)));
+
+		let vm = test_vm();
+		let invalid = |display: DisplayBackend| match display.validate(&vm) {
+			Err(ValidationError::InvalidField { field, .. }) => field,
+			result => panic!("expected an invalid field, got {:?}", result.err())
 		};
 
-		Ok(split_arguments(&vec))
+		assert!(spice!().validate(&vm).is_ok());
+		// The key is missing, and then the test VM has no USB bus
+		assert!(matches!(full.validate(&vm), Err(ValidationError::PathNotFound { path }) if path.ends_with("server-key.pem")));
+		std::fs::write(std::path::Path::new(&x509_dir).join("server-key.pem"), "").unwrap();
+		assert!(matches!(full.validate(&vm), Err(ValidationError::NoUsbBus)));
+
+		let socket = spice!(port: None, unix_socket: Some(String::from("/tmp/spice.sock")));
+		assert_eq!(socket.as_options()[1], "unix=on,addr=/tmp/spice.sock,disable-ticketing=on");
+		assert!(matches!(spice!(port: None, unix_socket: Some(String::from("/tmp/spice.sock")), gl: true).validate(&vm), Err(ValidationError::NoGlAdapter)));
+
+		assert_eq!(invalid(spice!(port: None)), "port");
+		assert_eq!(invalid(spice!(unix_socket: Some(String::from("/tmp/spice.sock")))), "unix_socket");
+		assert_eq!(invalid(spice!(password_file: Some(password.clone()))), "password_file");
+		assert_eq!(invalid(spice!(disable_ticketing: false)), "disable_ticketing");
+		assert_eq!(invalid(spice!(gl: true)), "gl");
+		assert_eq!(invalid(spice!(rendernode: Some(String::from("/dev/dri/renderD128")))), "rendernode");
+
+		std::fs::remove_dir_all(&x509_dir).unwrap();
 	}
 
-	pub fn start(&mut self) -> Result<(), VMQemuProcessStartError> {
-		//self.process = Some(Command::new("qemu-system-x86_64"));
+	#[tokio::test]
+	async fn vnc_passwords_are_set_over_qmp() {
+		let password = temp_path("vnc-password");
+		std::fs::write(&password, "hunter2").unwrap();
+		let password = password.to_string_lossy().into_owned();
+		let vnc = |port, password_secret, websocket| DisplayBackend::Vnc { listen: Some(String::from("127.0.0.1")), port, password_secret, websocket };
 
-		let args = match self.to_arguments() {
-			Ok(_args) => _args,
-			Err(..) => return Err(VMQemuProcessStartError::ErrorBuildingCommandLine)
+		assert_eq!(vnc(5901, None, None).as_options(), ["-vnc", "127.0.0.1:1"]);
+		assert_eq!(DisplayBackend::Vnc { listen: None, port: 5900, password_secret: None, websocket: None }.as_options(), ["-vnc", ":0"]);
+		assert_eq!(vnc(5901, Some(password.clone()), Some(5701)).as_options(), [
+			"-object", format!("secret,id=vm.vnc.password,file={password}").as_str(),
+			"-vnc", "127.0.0.1:1,password-secret=vm.vnc.password,websocket=5701"
+		]);
+
+		let vm = test_vm();
+		assert!(vnc(5901, Some(password.clone()), Some(5701)).validate(&vm).is_ok());
+		assert!(matches!(vnc(5899, None, None).validate(&vm), Err(ValidationError::InvalidField { field: "port", .. })));
+		assert!(matches!(vnc(5901, None, Some(5901)).validate(&vm), Err(ValidationError::InvalidField { field: "websocket", .. })));
+		assert!(matches!(vnc(5901, Some(String::from("/nonexistent/password")), None).validate(&vm), Err(ValidationError::PathNotFound { .. })));
+
+		let running = |name, display| async move {
+			let server = MockQmp::start(name, |_, _| Ok(json!({})));
+			let mut vm = test_vm();
+			vm.set_display_backend(display);
+			vm.attach_qmp(QmpClient::connect(server.path()).await.unwrap());
+			vm.state.send_replace(VMState::Started);
+			(server, vm)
 		};
 
-		println!("{:#?}", args);
+		// Without a password to begin with, VNC doesn't ask for one
+		let (_server, vm) = running("vnc-open", vnc(5901, None, None)).await;
+		assert!(matches!(vm.set_vnc_password("secret").await, Err(VMControlError::NoVncPassword)));
 
-		Ok(())
+		let (server, vm) = running("vnc", vnc(5901, Some(password.clone()), None)).await;
+		vm.set_vnc_password("secret").await.unwrap();
+		let received = server.received();
+		assert_eq!(received[0].execute, "set_password");
+		assert_eq!(received[0].arguments["protocol"], json!("vnc"));
+		assert_eq!(received[0].arguments["password"], json!("secret"));
+
+		std::fs::remove_file(&password).unwrap();
 	}
 }